@@ -0,0 +1,309 @@
+// Featurize, the FOSS feature flagging
+// Copyright (C) 2024  Lucy Ekaterina
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use std::{
+    collections::{BTreeMap, HashMap},
+    env,
+    sync::{Arc, Mutex},
+    time::Duration as StdDuration,
+};
+
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+
+const HOUR: i64 = 60 * 60;
+const DAY: i64 = 24 * HOUR;
+
+/// Counts per variation, `None` counts evaluations that fell back to the
+/// default value in code.
+pub type Counts = BTreeMap<Option<usize>, u64>;
+
+type Buckets = BTreeMap<i64, Counts>;
+
+/// Hourly buckets start on the hour and daily ones at midnight, so a bucket
+/// starting at midnight is taken to last a day.
+fn bucket_end(start: i64) -> i64 {
+    if start.rem_euclid(DAY) == 0 {
+        start + DAY
+    } else {
+        start + HOUR
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct AnalyticsConfig {
+    /// How long counts are kept before they are dropped.
+    pub retention: Duration,
+    /// How old hourly buckets get before they are merged into daily ones.
+    pub compact_after: Duration,
+}
+
+impl AnalyticsConfig {
+    pub fn from_env() -> Result<Self, std::num::ParseIntError> {
+        let days = |name: &str, default: i64| match env::var(name) {
+            Ok(days) => days.parse().map(Duration::days),
+            Err(_) => Ok(Duration::days(default)),
+        };
+        Ok(Self {
+            retention: days("ANALYTICS_RETENTION_DAYS", 90)?,
+            compact_after: days("ANALYTICS_COMPACT_AFTER_DAYS", 7)?,
+        })
+    }
+}
+
+/// Evaluation counts per environment, flag and variation, bucketed by hour and
+/// compacted into days as they age. Counts are held in memory and start over
+/// with every restart.
+#[derive(Debug)]
+pub struct Analytics {
+    config: AnalyticsConfig,
+    counts: Mutex<HashMap<(String, String), Buckets>>,
+    started: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DailyUsage {
+    pub day: NaiveDate,
+    pub counts: Counts,
+}
+
+impl Analytics {
+    pub fn new(config: AnalyticsConfig) -> Self {
+        Self {
+            config,
+            counts: Mutex::new(HashMap::new()),
+            started: Utc::now(),
+        }
+    }
+
+    /// Whether evaluations have been counted since `since`. Before that, a
+    /// flag without evaluations may just have been evaluated before a restart.
+    pub fn counting_since(&self, since: DateTime<Utc>) -> bool {
+        self.started <= since
+    }
+
+    /// Adds `count` evaluations at `at`, unless `at` is already past the
+    /// retention or more than an hour ahead.
+    pub fn record(
+        &self,
+        environment: &str,
+        flag: &str,
+        variation: Option<usize>,
+        at: DateTime<Utc>,
+        count: u64,
+    ) {
+        let now = Utc::now();
+        if at < now - self.config.retention || at > now + Duration::hours(1) {
+            return;
+        }
+        let timestamp = at.timestamp();
+        let bucket = timestamp - timestamp.rem_euclid(HOUR);
+        let mut counts = self.counts.lock().unwrap();
+        let total = counts
+            .entry((environment.to_string(), flag.to_string()))
+            .or_default()
+            .entry(bucket)
+            .or_default()
+            .entry(variation)
+            .or_default();
+        *total = total.saturating_add(count);
+    }
+
+    /// Merges hourly buckets older than `compact_after` into daily buckets and
+    /// drops buckets older than `retention`.
+    #[tracing::instrument(skip(self))]
+    pub fn compact(&self, now: DateTime<Utc>) {
+        let compact_before = (now - self.config.compact_after).timestamp();
+        let drop_before = (now - self.config.retention).timestamp();
+        let mut counts = self.counts.lock().unwrap();
+        for buckets in counts.values_mut() {
+            let old: Vec<i64> = buckets.range(..compact_before).map(|(s, _)| *s).collect();
+            for start in old {
+                let day = start - start.rem_euclid(DAY);
+                if day == start {
+                    continue;
+                }
+                let Some(hour) = buckets.remove(&start) else {
+                    continue;
+                };
+                let daily = buckets.entry(day).or_default();
+                for (variation, count) in hour {
+                    let total = daily.entry(variation).or_default();
+                    *total = total.saturating_add(count);
+                }
+            }
+            buckets.retain(|start, _| start + DAY > drop_before);
+        }
+        counts.retain(|_, buckets| !buckets.is_empty());
+    }
+
+    pub async fn compact_periodically(self: Arc<Self>) {
+        let mut interval = actix_web::rt::time::interval(StdDuration::from_secs(HOUR as u64));
+        loop {
+            interval.tick().await;
+            self.compact(Utc::now());
+        }
+    }
+
+    /// Evaluations of `flag` in any environment since `since`, counting whole
+    /// buckets so up to a bucket's worth of earlier evaluations is included.
+    pub fn evaluations_since(&self, flag: &str, since: DateTime<Utc>) -> u64 {
+        let since = since.timestamp();
+        let counts = self.counts.lock().unwrap();
+        counts
+            .iter()
+            .filter(|((_, f), _)| f == flag)
+            .flat_map(|(_, buckets)| buckets.iter())
+            .filter(|(start, _)| bucket_end(**start) > since)
+            .flat_map(|(_, bucket)| bucket.values())
+            .fold(0, |total, count| total.saturating_add(*count))
+    }
+
+    /// The end of the latest bucket with evaluations of `flag` in any
+    /// environment, capped at `now`.
+    pub fn last_evaluated(&self, flag: &str, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let counts = self.counts.lock().unwrap();
+        let end = counts
+            .iter()
+            .filter(|((_, f), _)| f == flag)
+            .flat_map(|(_, buckets)| buckets.iter())
+            .filter(|(_, bucket)| bucket.values().any(|count| *count > 0))
+            .map(|(start, _)| bucket_end(*start))
+            .max()?;
+        DateTime::from_timestamp(end, 0).map(|end| end.min(now))
+    }
+
+    /// Counts per day for the last `days` days, including days without any
+    /// evaluations.
+    pub fn daily_usage(
+        &self,
+        environment: &str,
+        flag: &str,
+        days: i64,
+        now: DateTime<Utc>,
+    ) -> Vec<DailyUsage> {
+        let today = now.date_naive();
+        let mut usage: Vec<DailyUsage> = (0..days)
+            .rev()
+            .map(|ago| DailyUsage {
+                day: today - Duration::days(ago),
+                counts: Counts::new(),
+            })
+            .collect();
+
+        let counts = self.counts.lock().unwrap();
+        let Some(buckets) = counts.get(&(environment.to_string(), flag.to_string())) else {
+            return usage;
+        };
+        for (start, bucket) in buckets {
+            let Some(day) = DateTime::from_timestamp(*start, 0).map(|d| d.date_naive()) else {
+                continue;
+            };
+            if let Some(entry) = usage.iter_mut().find(|u| u.day == day) {
+                for (variation, count) in bucket {
+                    let total = entry.counts.entry(*variation).or_default();
+                    *total = total.saturating_add(*count);
+                }
+            }
+        }
+        usage
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn analytics() -> Analytics {
+        Analytics::new(AnalyticsConfig {
+            retention: Duration::days(30),
+            compact_after: Duration::days(7),
+        })
+    }
+
+    fn buckets(analytics: &Analytics) -> Vec<i64> {
+        let counts = analytics.counts.lock().unwrap();
+        counts[&("production".to_string(), "checkout".to_string())]
+            .keys()
+            .copied()
+            .collect()
+    }
+
+    #[test]
+    fn compacts_old_hours_into_days() {
+        let analytics = analytics();
+        let now = Utc::now();
+        let old = now - Duration::days(10);
+        let day = old.timestamp() - old.timestamp().rem_euclid(DAY);
+        let morning = DateTime::from_timestamp(day + 9 * HOUR, 0).unwrap();
+        let evening = DateTime::from_timestamp(day + 18 * HOUR, 0).unwrap();
+        analytics.record("production", "checkout", Some(0), morning, 2);
+        analytics.record("production", "checkout", Some(0), evening, 3);
+        analytics.record("production", "checkout", Some(1), now, 1);
+
+        analytics.compact(now);
+        let hour = now.timestamp() - now.timestamp().rem_euclid(HOUR);
+        assert_eq!(buckets(&analytics), [day, hour]);
+        assert_eq!(
+            analytics.evaluations_since("checkout", now - Duration::days(11)),
+            6
+        );
+    }
+
+    #[test]
+    fn drops_counts_past_the_retention() {
+        let analytics = analytics();
+        let now = Utc::now();
+        analytics.record(
+            "production",
+            "checkout",
+            Some(0),
+            now - Duration::days(20),
+            1,
+        );
+        analytics.record("production", "checkout", Some(0), now, 1);
+        // Too old, or too far ahead, to be kept at all.
+        analytics.record(
+            "production",
+            "checkout",
+            Some(0),
+            now - Duration::days(40),
+            1,
+        );
+        analytics.record(
+            "production",
+            "checkout",
+            Some(0),
+            now + Duration::days(1),
+            1,
+        );
+        assert_eq!(buckets(&analytics).len(), 2);
+
+        analytics.compact(now + Duration::days(15));
+        assert_eq!(buckets(&analytics).len(), 1);
+        analytics.compact(now + Duration::days(31));
+        assert!(analytics.counts.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn counts_saturate() {
+        let analytics = analytics();
+        let now = Utc::now();
+        analytics.record("production", "checkout", Some(0), now, u64::MAX);
+        analytics.record("production", "checkout", Some(0), now, 1);
+        analytics.record("production", "checkout", Some(1), now, 1);
+        let since = now - Duration::hours(2);
+        assert_eq!(analytics.evaluations_since("checkout", since), u64::MAX);
+    }
+}
@@ -0,0 +1,205 @@
+// Featurize, the FOSS feature flagging
+// Copyright (C) 2024  Lucy Ekaterina
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    sync::Mutex,
+};
+
+use actix_web::{get, web, HttpResponse};
+use chrono::{DateTime, Utc};
+use sentry::{Hub, SentryFutureExt};
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::{
+    flags::{
+        flag_issues, schema_attributes, Attribute, AttributeType, Context, Definitions, Flag,
+        FlagStore, KnownAttributes,
+    },
+    renderer::Renderer,
+    Error,
+};
+
+/// Caps how many attributes are recorded per environment, so clients sending
+/// arbitrary attribute names can't grow the registry without bound.
+const MAX_ATTRIBUTES: usize = 1000;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ObservedAttribute {
+    pub types: BTreeSet<AttributeType>,
+    /// Whether any context sent a list of values.
+    pub list: bool,
+    pub contexts: u64,
+    pub last_seen: DateTime<Utc>,
+}
+
+/// The attributes seen in evaluation contexts, keyed by environment.
+#[derive(Debug, Default)]
+pub struct AttributeRegistry {
+    seen: Mutex<HashMap<String, BTreeMap<String, ObservedAttribute>>>,
+}
+
+impl AttributeRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&self, environment: &str, context: &Context, now: DateTime<Utc>) {
+        let mut seen = self.seen.lock().unwrap();
+        let attributes = seen.entry(environment.to_string()).or_default();
+        for (key, value) in context {
+            if !attributes.contains_key(key) && attributes.len() >= MAX_ATTRIBUTES {
+                continue;
+            }
+            let observed = attributes
+                .entry(key.clone())
+                .or_insert_with(|| ObservedAttribute {
+                    types: BTreeSet::new(),
+                    list: false,
+                    contexts: 0,
+                    last_seen: now,
+                });
+            match value {
+                Value::Array(items) => {
+                    observed.list = true;
+                    observed
+                        .types
+                        .extend(items.iter().filter_map(AttributeType::of));
+                }
+                value => observed.types.extend(AttributeType::of(value)),
+            }
+            observed.contexts += 1;
+            observed.last_seen = now;
+        }
+    }
+
+    pub fn observed(&self, environment: &str) -> BTreeMap<String, ObservedAttribute> {
+        self.seen
+            .lock()
+            .unwrap()
+            .get(environment)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// The declared attributes along with the ones seen in `environment`.
+    pub fn known(&self, definitions: &Definitions, environment: &str) -> KnownAttributes {
+        let mut known = schema_attributes(definitions);
+        for (key, observed) in self.observed(environment) {
+            known.entry(key).or_default().extend(observed.types);
+        }
+        known
+    }
+
+    /// Clauses of `flag` in `environment` that refer to attributes neither
+    /// declared nor seen, or compare them with the wrong type. Nothing is
+    /// reported until something is known about the environment's attributes.
+    pub fn warnings(
+        &self,
+        definitions: &Definitions,
+        flag: &Flag,
+        environment: &str,
+    ) -> Vec<String> {
+        let known = self.known(definitions, environment);
+        if known.is_empty() {
+            return Vec::new();
+        }
+        flag_issues(flag, environment, &known)
+            .into_iter()
+            .map(|issue| format!("{}: {}", issue.path, issue.message))
+            .collect()
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct EnvironmentAttributes<'d> {
+    environment: &'d str,
+    declared: &'d [Attribute],
+    observed: BTreeMap<String, ObservedAttribute>,
+}
+
+/// The declared attributes and the ones seen in an environment's contexts.
+#[tracing::instrument]
+#[get("/attributes/{environment}")]
+pub async fn api_route(
+    flags: web::Data<FlagStore>,
+    registry: web::Data<AttributeRegistry>,
+    environment: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let hub = Hub::current();
+    api_handler(flags, registry, environment)
+        .bind_hub(hub)
+        .await
+}
+
+#[tracing::instrument]
+pub async fn api_handler(
+    flags: web::Data<FlagStore>,
+    registry: web::Data<AttributeRegistry>,
+    environment: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let definitions = flags.definitions();
+    let environment = definitions
+        .environment(&environment)
+        .ok_or(Error::NotFound)?;
+    Ok(HttpResponse::Ok().json(EnvironmentAttributes {
+        environment: &environment.key,
+        declared: &definitions.attributes,
+        observed: registry.observed(&environment.key),
+    }))
+}
+
+#[tracing::instrument]
+#[get("/attributes")]
+pub async fn page_route(
+    renderer: web::Data<Renderer>,
+    flags: web::Data<FlagStore>,
+    registry: web::Data<AttributeRegistry>,
+) -> Result<HttpResponse, Error> {
+    let hub = Hub::current();
+    page_handler(renderer, flags, registry).bind_hub(hub).await
+}
+
+#[tracing::instrument]
+pub async fn page_handler(
+    renderer: web::Data<Renderer>,
+    flags: web::Data<FlagStore>,
+    registry: web::Data<AttributeRegistry>,
+) -> Result<HttpResponse, Error> {
+    let definitions = flags.definitions();
+    let environments: Vec<EnvironmentAttributes> = definitions
+        .environments
+        .iter()
+        .map(|env| EnvironmentAttributes {
+            environment: &env.key,
+            declared: &definitions.attributes,
+            observed: registry.observed(&env.key),
+        })
+        .collect();
+    let declared: BTreeMap<&str, AttributeType> = definitions
+        .attributes
+        .iter()
+        .map(|a| (a.key.as_str(), a.kind))
+        .collect();
+    Ok(renderer
+        .render("attributes.html")
+        .var("attributes", &definitions.attributes)
+        .var("declared", &declared)
+        .var("strict", &definitions.strict_attributes)
+        .var("environments", &environments)
+        .ok()
+        .finish()?)
+}
@@ -0,0 +1,125 @@
+// Featurize, the FOSS feature flagging
+// Copyright (C) 2024  Lucy Ekaterina
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::PathBuf,
+    sync::Mutex,
+};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// A change to a flag, whether made by a person or by featurize itself.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct AuditEntry {
+    pub at: DateTime<Utc>,
+    pub actor: String,
+    /// Empty for changes to the flag itself rather than one environment.
+    pub environment: String,
+    /// Empty for changes to the whole environment.
+    pub flag: String,
+    pub action: String,
+    pub detail: String,
+}
+
+#[derive(Debug, Default)]
+pub struct AuditLog {
+    /// Entries are appended here as JSON lines when set.
+    file: Option<PathBuf>,
+    entries: Mutex<Vec<AuditEntry>>,
+}
+
+impl AuditLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends to the JSON lines file at `path`, reading the entries already
+    /// in it.
+    pub fn open<P: Into<PathBuf>>(path: P) -> io::Result<Self> {
+        let path = path.into();
+        let entries = match fs::read_to_string(&path) {
+            Ok(content) => content
+                .lines()
+                .filter(|line| !line.trim().is_empty())
+                .map(serde_json::from_str)
+                .collect::<Result<_, _>>()?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Self {
+            file: Some(path),
+            entries: Mutex::new(entries),
+        })
+    }
+
+    pub fn record(&self, entry: AuditEntry) {
+        tracing::info!(
+            "{} {} {} in {}: {}",
+            entry.actor,
+            entry.action,
+            entry.flag,
+            entry.environment,
+            entry.detail
+        );
+        if let Some(path) = &self.file {
+            if let Err(e) = append(path, &entry) {
+                tracing::error!("could not write audit log {}: {}", path.display(), e);
+            }
+        }
+        self.entries.lock().unwrap().push(entry);
+    }
+
+    /// Entries for `flag`, newest first.
+    pub fn for_flag(&self, flag: &str) -> Vec<AuditEntry> {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .iter()
+            .rev()
+            .filter(|e| e.flag == flag)
+            .cloned()
+            .collect()
+    }
+
+    /// Entries recorded after the first `seen`, oldest first, moving `seen`
+    /// past them.
+    pub fn after(&self, seen: &mut usize) -> Vec<AuditEntry> {
+        let entries = self.entries.lock().unwrap();
+        let new = entries.get(*seen..).unwrap_or_default().to_vec();
+        *seen = entries.len();
+        new
+    }
+
+    /// Entries for `environment`, including changes to its flags, newest
+    /// first.
+    pub fn for_environment(&self, environment: &str) -> Vec<AuditEntry> {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .iter()
+            .rev()
+            .filter(|e| e.environment == environment)
+            .cloned()
+            .collect()
+    }
+}
+
+fn append(path: &PathBuf, entry: &AuditEntry) -> io::Result<()> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    let mut line = serde_json::to_string(entry)?;
+    line.push('\n');
+    file.write_all(line.as_bytes())
+}
@@ -0,0 +1,663 @@
+// Featurize, the FOSS feature flagging
+// Copyright (C) 2024  Lucy Ekaterina
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use std::{
+    fmt, fs, io,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use actix_web::{get, post, web, HttpResponse};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use sentry::{Hub, SentryFutureExt};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    audit::{AuditEntry, AuditLog},
+    diff,
+    flags::{self, ConfigChange, ConfigConflict, Definitions, FlagConfig, FlagStore, Promotion},
+    ory_client::UserSession,
+    renderer::Renderer,
+    webhooks::Webhooks,
+    Error,
+};
+
+const APPLY_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, thiserror::Error)]
+pub enum ChangeRequestError {
+    #[error("the change makes no difference to {0}")]
+    NoChanges(String),
+    #[error("the change request is {0}")]
+    WrongStatus(Status),
+    #[error("authors can't review their own changes")]
+    OwnChange,
+    #[error("only {0} may review changes to this environment")]
+    NotReviewer(String),
+    #[error("{0}, rebase the change request")]
+    Conflict(#[from] ConfigConflict),
+    #[error("invalid time to apply at: {0}")]
+    ApplyAt(String),
+    #[error("changing the variations, kill switch or archival of \"{flag}\" changes what {environments} serve, which require approvals; change it in the files instead")]
+    Protected { flag: String, environments: String },
+    #[error("\"{flag}\" changes {environments}, which require approvals; propose those changes in featurize instead")]
+    RequiresApprovals { flag: String, environments: String },
+}
+
+/// What a change request changes, kept so it can be made again on top of
+/// changes that landed after it was proposed.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum Change {
+    Promote(Promotion),
+    /// A flag edited in the dashboard or through the API, with its new
+    /// configuration in `environment`.
+    Edit {
+        flag: String,
+        environment: String,
+        config: Option<FlagConfig>,
+    },
+}
+
+impl Change {
+    pub fn environment(&self) -> &str {
+        match self {
+            Change::Promote(promotion) => &promotion.to,
+            Change::Edit { environment, .. } => environment,
+        }
+    }
+
+    fn apply(&self, definitions: &mut Definitions) -> Result<(), Error> {
+        match self {
+            Change::Promote(promotion) => {
+                flags::promote(definitions, promotion)?;
+            }
+            Change::Edit {
+                flag,
+                environment,
+                config,
+            } => {
+                let flag = definitions
+                    .flags
+                    .iter_mut()
+                    .find(|f| &f.key == flag)
+                    .ok_or(Error::NotFound)?;
+                match config {
+                    Some(config) => flag
+                        .environments
+                        .insert(environment.clone(), config.clone()),
+                    None => flag.environments.remove(environment),
+                };
+            }
+        }
+        Ok(())
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Change::Promote(promotion) => format!(
+                "promote {} from {}",
+                promotion.flags.join(", "),
+                promotion.from
+            ),
+            Change::Edit { flag, .. } => format!("edit {}", flag),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Open,
+    Approved,
+    Rejected,
+    Applied,
+    /// Something it changes changed since, it needs a rebase.
+    Conflicted,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Status::Open => "open",
+            Status::Approved => "approved",
+            Status::Rejected => "rejected",
+            Status::Applied => "applied",
+            Status::Conflicted => "conflicted",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Review {
+    pub reviewer: String,
+    pub approved: bool,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub comment: String,
+    pub at: DateTime<Utc>,
+}
+
+/// A change to an environment that requires approvals, made once enough
+/// reviewers approve it.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct ChangeRequest {
+    pub id: u64,
+    pub author: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub comment: String,
+    pub change: Change,
+    /// The configurations it changes, as reviewed.
+    pub configs: Vec<ConfigChange>,
+    pub status: Status,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub reviews: Vec<Review>,
+    /// Applied once approved when not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub apply_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub applied_at: Option<DateTime<Utc>>,
+    /// Why applying it failed the last time it was tried.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl ChangeRequest {
+    fn approvers(&self) -> Vec<&str> {
+        self.reviews
+            .iter()
+            .filter(|r| r.approved)
+            .map(|r| r.reviewer.as_str())
+            .collect()
+    }
+}
+
+/// Change requests, and applying them once approved.
+#[derive(Debug)]
+pub struct ChangeRequests {
+    flags: Arc<FlagStore>,
+    audit: Arc<AuditLog>,
+    webhooks: Webhooks,
+    /// Requests are saved here as JSON when set.
+    file: Option<PathBuf>,
+    requests: RwLock<Vec<ChangeRequest>>,
+}
+
+impl ChangeRequests {
+    pub fn new(flags: Arc<FlagStore>, audit: Arc<AuditLog>, webhooks: Webhooks) -> Self {
+        Self {
+            flags,
+            audit,
+            webhooks,
+            file: None,
+            requests: RwLock::new(Vec::new()),
+        }
+    }
+
+    /// Keeps requests in the JSON file at `path`, reading the ones already in
+    /// it.
+    pub fn with_file<P: Into<PathBuf>>(mut self, path: P) -> io::Result<Self> {
+        let path = path.into();
+        let requests = match fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        self.file = Some(path);
+        self.requests = RwLock::new(requests);
+        Ok(self)
+    }
+
+    /// Newest first.
+    pub fn list(&self) -> Vec<ChangeRequest> {
+        self.requests
+            .read()
+            .unwrap()
+            .iter()
+            .rev()
+            .cloned()
+            .collect()
+    }
+
+    pub fn get(&self, id: u64) -> Option<ChangeRequest> {
+        self.requests
+            .read()
+            .unwrap()
+            .iter()
+            .find(|r| r.id == id)
+            .cloned()
+    }
+
+    /// Whether something `request` changes changed since it was proposed or
+    /// last rebased.
+    pub fn conflict(&self, request: &ChangeRequest) -> Option<ConfigConflict> {
+        let mut definitions = (*self.flags.loaded()).clone();
+        flags::apply_config_changes(
+            &mut definitions,
+            request.change.environment(),
+            &request.configs,
+        )
+        .err()
+    }
+
+    pub fn propose(
+        &self,
+        author: String,
+        comment: String,
+        change: Change,
+        apply_at: Option<DateTime<Utc>>,
+        now: DateTime<Utc>,
+    ) -> Result<u64, Error> {
+        let configs = self.changed_configs(&change)?;
+        let mut requests = self.requests.write().unwrap();
+        let id = requests.last().map_or(1, |r| r.id + 1);
+        requests.push(ChangeRequest {
+            id,
+            author,
+            comment,
+            change,
+            configs,
+            status: Status::Open,
+            reviews: Vec::new(),
+            apply_at,
+            created_at: now,
+            applied_at: None,
+            error: None,
+        });
+        self.save(&requests);
+        Ok(id)
+    }
+
+    /// Records a review by someone with `roles`, applying the request when it
+    /// is the last approval needed and the request isn't scheduled for later.
+    pub fn review(
+        &self,
+        id: u64,
+        reviewer: String,
+        roles: &[String],
+        approved: bool,
+        comment: String,
+        now: DateTime<Utc>,
+    ) -> Result<(), Error> {
+        let mut requests = self.requests.write().unwrap();
+        let request = requests
+            .iter_mut()
+            .find(|r| r.id == id)
+            .ok_or(Error::NotFound)?;
+        if request.status != Status::Open {
+            return Err(ChangeRequestError::WrongStatus(request.status).into());
+        }
+        if request.author == reviewer {
+            return Err(ChangeRequestError::OwnChange.into());
+        }
+        let definitions = self.flags.loaded();
+        // Approvals may have been turned off since, one approval does then.
+        let approvals = definitions
+            .environment(request.change.environment())
+            .and_then(|e| e.approvals.clone());
+        let required = approvals.as_ref().map_or(1, |a| a.required);
+        let reviewers = approvals.map(|a| a.reviewers).unwrap_or_default();
+        if !reviewers.is_empty() && !roles.iter().any(|role| reviewers.contains(role)) {
+            return Err(ChangeRequestError::NotReviewer(reviewers.join(", ")).into());
+        }
+
+        request.reviews.retain(|r| r.reviewer != reviewer);
+        request.reviews.push(Review {
+            reviewer,
+            approved,
+            comment,
+            at: now,
+        });
+        if !approved {
+            request.status = Status::Rejected;
+        } else if request.approvers().len() >= required {
+            request.status = Status::Approved;
+            if request.apply_at.map_or(true, |at| at <= now) {
+                self.apply_request(request, now, false);
+            }
+        }
+        self.save(&requests);
+        Ok(())
+    }
+
+    /// Applies an approved request now, even when it's scheduled for later.
+    pub fn apply(&self, id: u64, now: DateTime<Utc>) -> Result<(), Error> {
+        let mut requests = self.requests.write().unwrap();
+        let request = requests
+            .iter_mut()
+            .find(|r| r.id == id)
+            .ok_or(Error::NotFound)?;
+        if request.status != Status::Approved {
+            return Err(ChangeRequestError::WrongStatus(request.status).into());
+        }
+        self.apply_request(request, now, false);
+        self.save(&requests);
+        Ok(())
+    }
+
+    /// Makes the change again on top of the current definitions. Reviews are
+    /// dropped since the change may have become a different one.
+    pub fn rebase(&self, id: u64) -> Result<(), Error> {
+        let mut requests = self.requests.write().unwrap();
+        let request = requests
+            .iter_mut()
+            .find(|r| r.id == id)
+            .ok_or(Error::NotFound)?;
+        if !matches!(request.status, Status::Open | Status::Conflicted) {
+            return Err(ChangeRequestError::WrongStatus(request.status).into());
+        }
+        request.configs = self.changed_configs(&request.change)?;
+        request.reviews.clear();
+        request.status = Status::Open;
+        request.error = None;
+        self.save(&requests);
+        Ok(())
+    }
+
+    /// Applies approved requests scheduled for `now` or earlier.
+    #[tracing::instrument(skip(self))]
+    pub fn apply_due(&self, now: DateTime<Utc>) {
+        let mut requests = self.requests.write().unwrap();
+        let mut applied = false;
+        for request in requests.iter_mut() {
+            let due = request.apply_at.is_some_and(|at| at <= now);
+            if request.status == Status::Approved && due {
+                self.apply_request(request, now, true);
+                applied = true;
+            }
+        }
+        if applied {
+            self.save(&requests);
+        }
+    }
+
+    pub async fn apply_periodically(self: Arc<Self>) {
+        let mut interval = actix_web::rt::time::interval(APPLY_INTERVAL);
+        loop {
+            interval.tick().await;
+            self.apply_due(Utc::now());
+        }
+    }
+
+    fn changed_configs(&self, change: &Change) -> Result<Vec<ConfigChange>, Error> {
+        let environment = change.environment();
+        let configs = self.flags.check_update(|definitions| {
+            let before = definitions.clone();
+            change.apply(definitions)?;
+            Ok::<_, Error>(flags::config_changes(&before, definitions, environment))
+        })?;
+        if configs.is_empty() {
+            return Err(ChangeRequestError::NoChanges(environment.to_string()).into());
+        }
+        Ok(configs)
+    }
+
+    /// Applies the configurations as reviewed, failing when any of them
+    /// changed since. Failures other than conflicts, like a freeze, are
+    /// retried when the request is scheduled. Scheduled requests are audited
+    /// as `scheduled-change` rather than `change-request`.
+    fn apply_request(&self, request: &mut ChangeRequest, now: DateTime<Utc>, scheduled: bool) {
+        let environment = request.change.environment().to_string();
+        let result = self.flags.update(|definitions| {
+            flags::apply_config_changes(definitions, &environment, &request.configs)
+                .map_err(|conflict| Error::from(ChangeRequestError::from(conflict)))
+        });
+        match result {
+            Ok(()) => {
+                request.status = Status::Applied;
+                request.applied_at = Some(now);
+                request.error = None;
+            }
+            Err(Error::ChangeRequest(ChangeRequestError::Conflict(conflict))) => {
+                request.status = Status::Conflicted;
+                request.error = Some(conflict.to_string());
+                return;
+            }
+            Err(e) => {
+                let error = e.to_string();
+                if request.error.as_ref() != Some(&error) {
+                    tracing::warn!("could not apply change request {}: {}", request.id, error);
+                }
+                request.error = Some(error);
+                return;
+            }
+        }
+
+        let detail = format!(
+            "change request #{}: {}, approved by {}",
+            request.id,
+            request.change.describe(),
+            request.approvers().join(", ")
+        );
+        let definitions = self.flags.definitions();
+        for config in &request.configs {
+            let ConfigChange::Flag { key, .. } = config else {
+                continue;
+            };
+            let entry = AuditEntry {
+                at: now,
+                actor: request.author.clone(),
+                environment: environment.clone(),
+                flag: key.clone(),
+                action: if scheduled {
+                    "scheduled-change"
+                } else {
+                    "change-request"
+                }
+                .to_string(),
+                detail: detail.clone(),
+            };
+            if let Some(flag) = definitions.flag(key) {
+                self.webhooks.notify(&flag.webhooks, &entry);
+            }
+            self.audit.record(entry);
+        }
+    }
+
+    fn save(&self, requests: &[ChangeRequest]) {
+        if let Some(path) = &self.file {
+            let saved = serde_json::to_string(requests)
+                .map_err(io::Error::from)
+                .and_then(|content| fs::write(path, content));
+            if let Err(e) = saved {
+                tracing::error!("could not write change requests {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+/// Times are picked in UTC by a `datetime-local` input, empty for none.
+pub fn parse_apply_at(value: &str) -> Result<Option<DateTime<Utc>>, ChangeRequestError> {
+    if value.trim().is_empty() {
+        return Ok(None);
+    }
+    NaiveDateTime::parse_from_str(value.trim(), "%Y-%m-%dT%H:%M")
+        .map(|at| Some(at.and_utc()))
+        .map_err(|e| ChangeRequestError::ApplyAt(e.to_string()))
+}
+
+#[tracing::instrument]
+#[get("/change-requests")]
+pub async fn list_route(
+    renderer: web::Data<Renderer>,
+    change_requests: web::Data<ChangeRequests>,
+) -> Result<HttpResponse, Error> {
+    let hub = Hub::current();
+    list_handler(renderer, change_requests).bind_hub(hub).await
+}
+
+#[tracing::instrument]
+pub async fn list_handler(
+    renderer: web::Data<Renderer>,
+    change_requests: web::Data<ChangeRequests>,
+) -> Result<HttpResponse, Error> {
+    Ok(renderer
+        .render("change_requests.html")
+        .var("requests", &change_requests.list())
+        .ok()
+        .finish()?)
+}
+
+#[tracing::instrument]
+#[get("/change-requests/{id}")]
+pub async fn page_route(
+    renderer: web::Data<Renderer>,
+    flags: web::Data<FlagStore>,
+    change_requests: web::Data<ChangeRequests>,
+    id: web::Path<u64>,
+) -> Result<HttpResponse, Error> {
+    let hub = Hub::current();
+    page_handler(renderer, flags, change_requests, id)
+        .bind_hub(hub)
+        .await
+}
+
+#[tracing::instrument]
+pub async fn page_handler(
+    renderer: web::Data<Renderer>,
+    flags: web::Data<FlagStore>,
+    change_requests: web::Data<ChangeRequests>,
+    id: web::Path<u64>,
+) -> Result<HttpResponse, Error> {
+    let request = change_requests.get(*id).ok_or(Error::NotFound)?;
+    let definitions = flags.loaded();
+    let approvals = definitions
+        .environment(request.change.environment())
+        .and_then(|e| e.approvals.as_ref());
+    let diffs: Vec<_> = request.configs.iter().map(diff::config).collect();
+    let render = renderer
+        .render("change_request.html")
+        .var("request", &request)
+        .var("environment", request.change.environment())
+        .var("description", &request.change.describe())
+        .var("approvals", &approvals)
+        .var("approved", &request.approvers().len())
+        .var("diffs", &diffs);
+    let conflict = match request.status {
+        Status::Open | Status::Approved => change_requests.conflict(&request),
+        _ => None,
+    };
+    Ok(match conflict {
+        Some(conflict) => render
+            .var("conflict", &conflict.to_string())
+            .ok()
+            .finish()?,
+        None => render.ok().finish()?,
+    })
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Verdict {
+    Approve,
+    Reject,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReviewForm {
+    verdict: Verdict,
+    #[serde(default)]
+    comment: String,
+}
+
+fn see_other(id: u64) -> HttpResponse {
+    HttpResponse::SeeOther()
+        .append_header(("Location", format!("/change-requests/{}", id)))
+        .finish()
+}
+
+#[tracing::instrument(skip(session))]
+#[post("/change-requests/{id}/review")]
+pub async fn review_route(
+    change_requests: web::Data<ChangeRequests>,
+    session: Result<UserSession, Error>,
+    id: web::Path<u64>,
+    form: web::Form<ReviewForm>,
+) -> Result<HttpResponse, Error> {
+    let hub = Hub::current();
+    review_handler(change_requests, session, id, form)
+        .bind_hub(hub)
+        .await
+}
+
+/// Reviewing needs a signed in user, so their roles can be checked.
+#[tracing::instrument(skip(session))]
+pub async fn review_handler(
+    change_requests: web::Data<ChangeRequests>,
+    session: Result<UserSession, Error>,
+    id: web::Path<u64>,
+    form: web::Form<ReviewForm>,
+) -> Result<HttpResponse, Error> {
+    let session = session?;
+    let form = form.into_inner();
+    change_requests.review(
+        *id,
+        session.name(),
+        &session.roles(),
+        matches!(form.verdict, Verdict::Approve),
+        form.comment.trim().to_string(),
+        Utc::now(),
+    )?;
+    Ok(see_other(*id))
+}
+
+#[tracing::instrument(skip(session))]
+#[post("/change-requests/{id}/apply")]
+pub async fn apply_route(
+    change_requests: web::Data<ChangeRequests>,
+    session: Result<UserSession, Error>,
+    id: web::Path<u64>,
+) -> Result<HttpResponse, Error> {
+    let hub = Hub::current();
+    apply_handler(change_requests, session, id)
+        .bind_hub(hub)
+        .await
+}
+
+#[tracing::instrument(skip(session))]
+pub async fn apply_handler(
+    change_requests: web::Data<ChangeRequests>,
+    session: Result<UserSession, Error>,
+    id: web::Path<u64>,
+) -> Result<HttpResponse, Error> {
+    session?;
+    change_requests.apply(*id, Utc::now())?;
+    Ok(see_other(*id))
+}
+
+#[tracing::instrument(skip(session))]
+#[post("/change-requests/{id}/rebase")]
+pub async fn rebase_route(
+    change_requests: web::Data<ChangeRequests>,
+    session: Result<UserSession, Error>,
+    id: web::Path<u64>,
+) -> Result<HttpResponse, Error> {
+    let hub = Hub::current();
+    rebase_handler(change_requests, session, id)
+        .bind_hub(hub)
+        .await
+}
+
+#[tracing::instrument(skip(session))]
+pub async fn rebase_handler(
+    change_requests: web::Data<ChangeRequests>,
+    session: Result<UserSession, Error>,
+    id: web::Path<u64>,
+) -> Result<HttpResponse, Error> {
+    session?;
+    change_requests.rebase(*id)?;
+    Ok(see_other(*id))
+}
@@ -0,0 +1,138 @@
+// Featurize, the FOSS feature flagging
+// Copyright (C) 2024  Lucy Ekaterina
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use std::{collections::BTreeSet, env, fs, path::Path};
+
+use color_eyre::eyre::eyre;
+
+use crate::{
+    flags::{import_file, Definitions, Source},
+    ory_client::SESSION_TOKEN,
+    references::Upload,
+    scan::{ScanConfig, Scanner},
+};
+
+const IMPORT_USAGE: &str = "usage: featurize import <unleash|flagsmith|flagd> <file> [environment]";
+
+const SCAN_USAGE: &str = "usage: featurize scan <featurize url> <directory> [config]";
+
+/// Read from the scanned directory when no config is given.
+const SCAN_CONFIG_FILE: &str = ".featurize.yaml";
+
+/// A Kratos session token the commands that change featurize sign in with.
+pub const SESSION_TOKEN_VAR: &str = "FEATURIZE_SESSION_TOKEN";
+
+/// Sends the session token from [`SESSION_TOKEN_VAR`], if it is set.
+pub fn signed_in(request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+    match env::var(SESSION_TOKEN_VAR) {
+        Ok(token) => request.header(SESSION_TOKEN, token),
+        Err(_) => request,
+    }
+}
+
+/// Converts an export from another tool into featurize definitions, printed as
+/// YAML so they can be dropped into FLAGS_DIR.
+pub fn import(args: &[String]) -> color_eyre::Result<()> {
+    let [source, file, rest @ ..] = args else {
+        return Err(eyre!(IMPORT_USAGE));
+    };
+    let environment = match rest {
+        [] => "default",
+        [environment] => environment,
+        _ => return Err(eyre!(IMPORT_USAGE)),
+    };
+    let source: Source = source
+        .parse()
+        .map_err(|e| eyre!("{}\n{}", e, IMPORT_USAGE))?;
+
+    let import = import_file(source, Path::new(file), environment)?;
+    for unmapped in &import.unmapped {
+        eprintln!("not imported: {}", unmapped);
+    }
+    print!("{}", serde_yaml::to_string(&import.definitions)?);
+    Ok(())
+}
+
+/// Finds the flags of a featurize instance in the code below a directory and
+/// uploads the references to it.
+pub fn scan(args: &[String]) -> color_eyre::Result<()> {
+    let [url, dir, rest @ ..] = args else {
+        return Err(eyre!(SCAN_USAGE));
+    };
+    let dir = Path::new(dir);
+    let config_file = match rest {
+        [] => Some(dir.join(SCAN_CONFIG_FILE)).filter(|path| path.exists()),
+        [config] => Some(config.into()),
+        _ => return Err(eyre!(SCAN_USAGE)),
+    };
+    let config: ScanConfig = match config_file {
+        Some(path) => serde_yaml::from_str(&fs::read_to_string(&path)?)
+            .map_err(|e| eyre!("could not parse {}: {}", path.display(), e))?,
+        None => ScanConfig::default(),
+    };
+    let repository = match &config.repository {
+        Some(repository) => repository.clone(),
+        None => dir
+            .canonicalize()?
+            .file_name()
+            .and_then(|n| n.to_str())
+            .map(str::to_string)
+            .ok_or_else(|| eyre!("set repository in the config to name {}", dir.display()))?,
+    };
+    let base = reqwest::Url::parse(url)?;
+    let endpoint = |segments: &[&str]| {
+        let mut endpoint = base.clone();
+        endpoint
+            .path_segments_mut()
+            .map_err(|_| eyre!("{} is not a featurize url", url))?
+            .pop_if_empty()
+            .extend(segments);
+        Ok::<_, color_eyre::Report>(endpoint)
+    };
+    let mut export = endpoint(&["flags", "export"])?;
+    export.set_query(Some("format=json"));
+    let upload = endpoint(&["references", &repository])?;
+
+    actix_web::rt::System::new().block_on(async {
+        let client = reqwest::Client::new();
+        let definitions: Definitions = client
+            .get(export)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let keys: Vec<String> = definitions.flags.into_iter().map(|f| f.key).collect();
+        let scanner = Scanner::new(&config, &keys).map_err(|e| eyre!(e))?;
+        let references = scanner.scan_dir(dir)?;
+        let flags: BTreeSet<&str> = references.iter().map(|r| r.flag.as_str()).collect();
+        eprintln!(
+            "found {} references to {} of {} flags in {}",
+            references.len(),
+            flags.len(),
+            keys.len(),
+            repository
+        );
+        let response = signed_in(client.post(upload))
+            .json(&Upload { references })
+            .send()
+            .await?;
+        if response.status() == reqwest::StatusCode::UNAUTHORIZED {
+            return Err(eyre!("set {} to sign in", SESSION_TOKEN_VAR));
+        }
+        response.error_for_status()?;
+        Ok(())
+    })
+}
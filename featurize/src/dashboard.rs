@@ -0,0 +1,335 @@
+// Featurize, the FOSS feature flagging
+// Copyright (C) 2024  Lucy Ekaterina
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use actix_web::{get, post, web, HttpResponse};
+use chrono::Utc;
+use sentry::{Hub, SentryFutureExt};
+use serde::Serialize;
+
+use crate::{
+    analytics::{Analytics, DailyUsage},
+    attributes::AttributeRegistry,
+    audit::{AuditEntry, AuditLog},
+    experiments::ExperimentEvents,
+    flags::{run_tests, Experiment, Flag, FlagStore, Override},
+    ory_client::UserSession,
+    references::CodeReferences,
+    renderer::Renderer,
+    search::{self, FlagQuery},
+    webhooks::Webhooks,
+    Error,
+};
+
+const USAGE_DAYS: i64 = 30;
+
+#[derive(Debug, Serialize)]
+struct UsageChart {
+    environment: String,
+    total: u64,
+    days: Vec<UsageDay>,
+}
+
+#[derive(Debug, Serialize)]
+struct UsageDay {
+    day: String,
+    total: u64,
+    /// Height of the bar relative to the busiest day, in percent.
+    height: f64,
+    parts: Vec<UsagePart>,
+}
+
+#[derive(Debug, Serialize)]
+struct UsagePart {
+    /// `None` for evaluations that fell back to the default value in code.
+    variation: Option<usize>,
+    count: u64,
+    /// Share of the day's bar, in percent.
+    height: f64,
+}
+
+impl UsageChart {
+    fn new(environment: &str, usage: Vec<DailyUsage>) -> Self {
+        let busiest = usage
+            .iter()
+            .map(|u| u.counts.values().sum::<u64>())
+            .max()
+            .unwrap_or(0);
+        let days: Vec<UsageDay> = usage
+            .into_iter()
+            .map(|u| {
+                let total: u64 = u.counts.values().sum();
+                UsageDay {
+                    day: u.day.format("%Y-%m-%d").to_string(),
+                    total,
+                    height: percent(total, busiest),
+                    parts: u
+                        .counts
+                        .into_iter()
+                        .map(|(variation, count)| UsagePart {
+                            variation,
+                            count,
+                            height: percent(count, total),
+                        })
+                        .collect(),
+                }
+            })
+            .collect();
+        Self {
+            environment: environment.to_string(),
+            total: days.iter().map(|d| d.total).sum(),
+            days,
+        }
+    }
+}
+
+fn percent(part: u64, whole: u64) -> f64 {
+    if whole == 0 {
+        0.0
+    } else {
+        part as f64 / whole as f64 * 100.0
+    }
+}
+
+#[tracing::instrument]
+#[get("/flags")]
+pub async fn list_route(
+    renderer: web::Data<Renderer>,
+    flags: web::Data<FlagStore>,
+    query: web::Query<FlagQuery>,
+) -> Result<HttpResponse, Error> {
+    let hub = Hub::current();
+    list_handler(renderer, flags, query.into_inner())
+        .bind_hub(hub)
+        .await
+}
+
+#[tracing::instrument]
+pub async fn list_handler(
+    renderer: web::Data<Renderer>,
+    flags: web::Data<FlagStore>,
+    query: FlagQuery,
+) -> Result<HttpResponse, Error> {
+    let definitions = flags.definitions();
+    let results = search::search(&definitions, &query);
+    let next_page = results
+        .next_cursor
+        .as_ref()
+        .and_then(|cursor| page_link(&query, Some(cursor.clone())));
+    let first_page = query.cursor.as_ref().and_then(|_| page_link(&query, None));
+    Ok(renderer
+        .render("flags.html")
+        .var("flags", &results.flags)
+        .var("total", &results.total)
+        .var("query", &query)
+        .var("next_page", &next_page)
+        .var("first_page", &first_page)
+        .var("environments", &definitions.environments)
+        .var("freezes", &flags.freezes())
+        .ok()
+        .finish()?)
+}
+
+/// The query string of another page of the same search.
+fn page_link(query: &FlagQuery, cursor: Option<String>) -> Option<String> {
+    let page = FlagQuery {
+        cursor,
+        ..query.clone()
+    };
+    // The query only holds strings and numbers, serializing it can't fail.
+    serde_urlencoded::to_string(page).ok()
+}
+
+#[tracing::instrument]
+#[get("/flags/{key}")]
+pub async fn flag_route(
+    renderer: web::Data<Renderer>,
+    flags: web::Data<FlagStore>,
+    analytics: web::Data<Analytics>,
+    audit: web::Data<AuditLog>,
+    attributes: web::Data<AttributeRegistry>,
+    references: web::Data<CodeReferences>,
+    key: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let hub = Hub::current();
+    flag_handler(
+        renderer, flags, analytics, audit, attributes, references, key,
+    )
+    .bind_hub(hub)
+    .await
+}
+
+#[tracing::instrument]
+pub async fn flag_handler(
+    renderer: web::Data<Renderer>,
+    flags: web::Data<FlagStore>,
+    analytics: web::Data<Analytics>,
+    audit: web::Data<AuditLog>,
+    attributes: web::Data<AttributeRegistry>,
+    references: web::Data<CodeReferences>,
+    key: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let definitions = flags.definitions();
+    let flag: &Flag = definitions.flag(&key).ok_or(Error::NotFound)?;
+    let warnings: Vec<String> = definitions
+        .environments
+        .iter()
+        .flat_map(|env| attributes.warnings(&definitions, flag, &env.key))
+        .collect();
+    let overrides: Vec<Override> = flags
+        .overrides()
+        .into_iter()
+        .filter(|o| o.flag == flag.key)
+        .collect();
+    let experiments: Vec<&Experiment> = definitions
+        .experiments
+        .iter()
+        .filter(|e| e.flag == flag.key)
+        .collect();
+    let now = Utc::now();
+    let usage: Vec<UsageChart> = definitions
+        .environments
+        .iter()
+        .map(|env| {
+            UsageChart::new(
+                &env.key,
+                analytics.daily_usage(&env.key, &flag.key, USAGE_DAYS, now),
+            )
+        })
+        .collect();
+    Ok(renderer
+        .render("flag.html")
+        .var("flag", flag)
+        .var("usage", &usage)
+        .var("experiments", &experiments)
+        .var("overrides", &overrides)
+        .var("freezes", &flags.freezes())
+        .var("tests", &run_tests(&definitions, flag))
+        .var("history", &audit.for_flag(&flag.key))
+        .var("warnings", &warnings)
+        .var("references", &references.for_flag(&flag.key))
+        .ok()
+        .finish()?)
+}
+
+#[tracing::instrument(skip(session))]
+#[post("/flags/{key}/overrides/{environment}/clear")]
+pub async fn clear_override_route(
+    flags: web::Data<FlagStore>,
+    audit: web::Data<AuditLog>,
+    webhooks: web::Data<Webhooks>,
+    session: Result<UserSession, Error>,
+    path: web::Path<(String, String)>,
+) -> Result<HttpResponse, Error> {
+    let hub = Hub::current();
+    clear_override_handler(flags, audit, webhooks, session, path)
+        .bind_hub(hub)
+        .await
+}
+
+/// Puts the flag back to what its files define, e.g. after a guardrail
+/// rollback has been looked into.
+#[tracing::instrument(skip(session))]
+pub async fn clear_override_handler(
+    flags: web::Data<FlagStore>,
+    audit: web::Data<AuditLog>,
+    webhooks: web::Data<Webhooks>,
+    session: Result<UserSession, Error>,
+    path: web::Path<(String, String)>,
+) -> Result<HttpResponse, Error> {
+    let actor = session?.name();
+    let (key, environment) = path.into_inner();
+    let definitions = flags.definitions();
+    let flag = definitions.flag(&key).ok_or(Error::NotFound)?;
+    let cleared = flags
+        .clear_override(&environment, &key)?
+        .ok_or(Error::NotFound)?;
+    let entry = AuditEntry {
+        at: Utc::now(),
+        actor,
+        environment,
+        flag: key.clone(),
+        action: "clear_override".to_string(),
+        detail: format!("cleared override: {}", cleared.reason),
+    };
+    webhooks.notify(&flag.webhooks, &entry);
+    audit.record(entry);
+    Ok(HttpResponse::SeeOther()
+        .append_header(("Location", format!("/flags/{}", key)))
+        .finish())
+}
+
+#[tracing::instrument]
+#[get("/experiments/{key}")]
+pub async fn experiment_route(
+    renderer: web::Data<Renderer>,
+    flags: web::Data<FlagStore>,
+    events: web::Data<ExperimentEvents>,
+    key: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let hub = Hub::current();
+    experiment_handler(renderer, flags, events, key)
+        .bind_hub(hub)
+        .await
+}
+
+#[tracing::instrument]
+pub async fn experiment_handler(
+    renderer: web::Data<Renderer>,
+    flags: web::Data<FlagStore>,
+    events: web::Data<ExperimentEvents>,
+    key: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let definitions = flags.definitions();
+    let experiment = definitions.experiment(&key).ok_or(Error::NotFound)?;
+    let flag = definitions.flag(&experiment.flag).ok_or(Error::NotFound)?;
+    Ok(renderer
+        .render("experiment.html")
+        .var("experiment", experiment)
+        .var("flag", flag)
+        .var("results", &events.results(experiment, flag))
+        .ok()
+        .finish()?)
+}
+
+#[tracing::instrument]
+#[get("/experiments/{key}/results.csv")]
+pub async fn experiment_csv_route(
+    flags: web::Data<FlagStore>,
+    events: web::Data<ExperimentEvents>,
+    key: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let hub = Hub::current();
+    experiment_csv_handler(flags, events, key)
+        .bind_hub(hub)
+        .await
+}
+
+#[tracing::instrument]
+pub async fn experiment_csv_handler(
+    flags: web::Data<FlagStore>,
+    events: web::Data<ExperimentEvents>,
+    key: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let definitions = flags.definitions();
+    let experiment = definitions.experiment(&key).ok_or(Error::NotFound)?;
+    let flag = definitions.flag(&experiment.flag).ok_or(Error::NotFound)?;
+    Ok(HttpResponse::Ok()
+        .content_type("text/csv")
+        .append_header((
+            "Content-Disposition",
+            format!("attachment; filename=\"{}.csv\"", experiment.key),
+        ))
+        .body(events.results(experiment, flag).to_csv()))
+}
@@ -0,0 +1,70 @@
+// Featurize, the FOSS feature flagging
+// Copyright (C) 2024  Lucy Ekaterina
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use serde::Serialize;
+use similar::{ChangeTag, TextDiff};
+
+use crate::flags::ConfigChange;
+
+#[derive(Debug, Serialize)]
+pub struct DiffLine {
+    /// `equal`, `insert` or `delete`.
+    pub tag: &'static str,
+    pub text: String,
+}
+
+/// A changed flag or segment configuration, rendered by `diff.html`.
+#[derive(Debug, Serialize)]
+pub struct ConfigDiff {
+    /// `flag` or `segment`.
+    pub kind: &'static str,
+    pub key: String,
+    pub lines: Vec<DiffLine>,
+}
+
+/// Line by line differences between `before` and `after`.
+pub fn lines(before: &str, after: &str) -> Vec<DiffLine> {
+    TextDiff::from_lines(before, after)
+        .iter_all_changes()
+        .map(|change| DiffLine {
+            tag: match change.tag() {
+                ChangeTag::Equal => "equal",
+                ChangeTag::Insert => "insert",
+                ChangeTag::Delete => "delete",
+            },
+            text: change.value().trim_end_matches('\n').to_string(),
+        })
+        .collect()
+}
+
+/// The configuration before and after `change` as YAML.
+pub fn config(change: &ConfigChange) -> ConfigDiff {
+    // Serializing a configuration can't fail, it only holds YAML values.
+    fn yaml<T: Serialize>(config: &Option<T>) -> String {
+        config
+            .as_ref()
+            .and_then(|c| serde_yaml::to_string(c).ok())
+            .unwrap_or_default()
+    }
+    let (kind, before, after) = match change {
+        ConfigChange::Flag { before, after, .. } => ("flag", yaml(before), yaml(after)),
+        ConfigChange::Segment { before, after, .. } => ("segment", yaml(before), yaml(after)),
+    };
+    ConfigDiff {
+        kind,
+        key: change.key().to_string(),
+        lines: lines(&before, &after),
+    }
+}
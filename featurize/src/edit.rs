@@ -0,0 +1,892 @@
+// Featurize, the FOSS feature flagging
+// Copyright (C) 2024  Lucy Ekaterina
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use actix_web::{
+    get,
+    http::{
+        header::{self, EntityTag},
+        StatusCode,
+    },
+    patch, post, put, web, HttpMessage, HttpRequest, HttpResponse,
+};
+use chrono::Utc;
+use sentry::{Hub, SentryFutureExt};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    audit::{AuditEntry, AuditLog},
+    change_requests::{Change, ChangeRequestError, ChangeRequests},
+    diff::{self, ConfigDiff},
+    flags::{self, Flag, FlagStore, Lifecycle},
+    ory_client::UserSession,
+    renderer::Renderer,
+    webhooks::Webhooks,
+    Error,
+};
+
+#[derive(Debug, Serialize)]
+struct Saved<'f> {
+    flag: &'f Flag,
+    /// Created for changes to environments that require approvals, which
+    /// aren't part of `flag` yet.
+    change_requests: Vec<u64>,
+}
+
+#[derive(Debug, Serialize)]
+struct StaleFlag<'f> {
+    error: String,
+    version: String,
+    flag: &'f Flag,
+}
+
+fn etag(flag: &Flag) -> header::ETag {
+    header::ETag(EntityTag::new_strong(flag.version()))
+}
+
+/// Whether flag-wide changes alter what environments serve: variations they
+/// may serve changed or were removed, the flag was archived or restored, or
+/// its kill switch changed. New variations and everything else don't.
+fn changes_serving(before: &Flag, after: &Flag) -> bool {
+    let variations = after.variations.len() < before.variations.len()
+        || before
+            .variations
+            .iter()
+            .zip(&after.variations)
+            .any(|(a, b)| a != b);
+    let archived = |flag: &Flag| flag.lifecycle == Lifecycle::Archived;
+    variations || archived(before) != archived(after) || before.kill_switch != after.kill_switch
+}
+
+/// Replaces the flag with `draft` if it is still at `version`, returning the
+/// change requests created for environments that require approvals. Those
+/// environments keep their configuration until the requests are approved,
+/// and flag-wide changes to what they serve are refused.
+#[allow(clippy::too_many_arguments)]
+fn save(
+    flags: &FlagStore,
+    change_requests: &ChangeRequests,
+    audit: &AuditLog,
+    webhooks: &Webhooks,
+    actor: String,
+    comment: String,
+    draft: Flag,
+    version: &str,
+) -> Result<Vec<u64>, Error> {
+    let key = draft.key.clone();
+    let replace = |definitions: &mut flags::Definitions, flag: Flag| -> Result<Flag, Error> {
+        flags::check_version(definitions, &key, version)?;
+        let current = definitions
+            .flags
+            .iter_mut()
+            .find(|f| f.key == key)
+            .ok_or(Error::NotFound)?;
+        Ok(std::mem::replace(current, flag))
+    };
+    // The whole draft has to be valid, not only the part saved right away.
+    flags.check_update(|definitions| replace(definitions, draft.clone()))?;
+
+    let proposed = flags.update(|definitions| {
+        let mut saved = draft.clone();
+        let mut proposed = Vec::new();
+        let current = definitions.flag(&key).cloned().ok_or(Error::NotFound)?;
+        let protected: Vec<&flags::Environment> = definitions
+            .environments
+            .iter()
+            .filter(|e| e.approvals.is_some())
+            .collect();
+        let serving: Vec<&str> = protected
+            .iter()
+            .filter(|e| current.environments.contains_key(&e.key))
+            .map(|e| e.key.as_str())
+            .collect();
+        if !serving.is_empty() && changes_serving(&current, &draft) {
+            return Err(ChangeRequestError::Protected {
+                flag: key.clone(),
+                environments: serving.join(", "),
+            }
+            .into());
+        }
+        for environment in protected {
+            let before = current.environments.get(&environment.key);
+            let after = draft.environments.get(&environment.key);
+            if before == after {
+                continue;
+            }
+            match before {
+                Some(config) => saved
+                    .environments
+                    .insert(environment.key.clone(), config.clone()),
+                None => saved.environments.remove(&environment.key),
+            };
+            proposed.push(Change::Edit {
+                flag: key.clone(),
+                environment: environment.key.clone(),
+                config: after.cloned(),
+            });
+        }
+        let previous = replace(definitions, saved)?;
+        Ok::<_, Error>((proposed, definitions.flag(&key) != Some(&previous)))
+    });
+    let (proposed, changed) = proposed?;
+
+    let now = Utc::now();
+    if changed {
+        let entry = AuditEntry {
+            at: now,
+            actor: actor.clone(),
+            environment: String::new(),
+            flag: key.clone(),
+            action: "edit".to_string(),
+            detail: if comment.is_empty() {
+                "edited".to_string()
+            } else {
+                format!("edited: {}", comment)
+            },
+        };
+        webhooks.notify(&draft.webhooks, &entry);
+        audit.record(entry);
+    }
+    proposed
+        .into_iter()
+        .map(|change| change_requests.propose(actor.clone(), comment.clone(), change, None, now))
+        .collect()
+}
+
+/// The flag as defined in the files, without overrides, with its version as
+/// the ETag.
+#[tracing::instrument]
+#[get("/flags/{key}/definition")]
+pub async fn get_route(
+    flags: web::Data<FlagStore>,
+    key: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let hub = Hub::current();
+    get_handler(flags, key).bind_hub(hub).await
+}
+
+#[tracing::instrument]
+pub async fn get_handler(
+    flags: web::Data<FlagStore>,
+    key: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let loaded = flags.loaded();
+    let flag = loaded.flag(&key).ok_or(Error::NotFound)?;
+    Ok(HttpResponse::Ok().insert_header(etag(flag)).json(flag))
+}
+
+#[allow(clippy::too_many_arguments)]
+#[tracing::instrument(skip(session, draft))]
+#[put("/flags/{key}/definition")]
+pub async fn put_route(
+    flags: web::Data<FlagStore>,
+    change_requests: web::Data<ChangeRequests>,
+    audit: web::Data<AuditLog>,
+    webhooks: web::Data<Webhooks>,
+    session: Result<UserSession, Error>,
+    request: HttpRequest,
+    key: web::Path<String>,
+    draft: web::Json<Flag>,
+) -> Result<HttpResponse, Error> {
+    let hub = Hub::current();
+    put_handler(
+        flags,
+        change_requests,
+        audit,
+        webhooks,
+        session,
+        request,
+        key,
+        draft.into_inner(),
+    )
+    .bind_hub(hub)
+    .await
+}
+
+/// Replaces the flag. `If-Match` has to carry the ETag the flag was read
+/// with, and the write is rejected when someone changed the flag since.
+#[allow(clippy::too_many_arguments)]
+#[tracing::instrument(skip(session, draft))]
+pub async fn put_handler(
+    flags: web::Data<FlagStore>,
+    change_requests: web::Data<ChangeRequests>,
+    audit: web::Data<AuditLog>,
+    webhooks: web::Data<Webhooks>,
+    session: Result<UserSession, Error>,
+    request: HttpRequest,
+    key: web::Path<String>,
+    draft: Flag,
+) -> Result<HttpResponse, Error> {
+    let actor = session?.name();
+    let version = match request.get_header::<header::IfMatch>() {
+        Some(header::IfMatch::Items(tags)) if tags.len() == 1 => tags[0].tag().to_string(),
+        _ => {
+            return Ok(HttpResponse::PreconditionRequired()
+                .body("If-Match with the ETag of the flag is required"))
+        }
+    };
+    if draft.key != *key {
+        return Err(flags::Error::NotWritable("flags can't be renamed".to_string()).into());
+    }
+
+    let saved = save(
+        &flags,
+        &change_requests,
+        &audit,
+        &webhooks,
+        actor,
+        String::new(),
+        draft,
+        &version,
+    );
+    saved_response(&flags, &key, saved)
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PatchRequest {
+    #[serde(default)]
+    comment: String,
+    #[serde(default)]
+    instructions: Vec<flags::Instruction>,
+    #[serde(default)]
+    patch: Option<json_patch::Patch>,
+}
+
+#[allow(clippy::too_many_arguments)]
+#[tracing::instrument(skip(session, body))]
+#[patch("/flags/{key}/definition")]
+pub async fn patch_route(
+    flags: web::Data<FlagStore>,
+    change_requests: web::Data<ChangeRequests>,
+    audit: web::Data<AuditLog>,
+    webhooks: web::Data<Webhooks>,
+    session: Result<UserSession, Error>,
+    request: HttpRequest,
+    key: web::Path<String>,
+    body: web::Json<PatchRequest>,
+) -> Result<HttpResponse, Error> {
+    let hub = Hub::current();
+    patch_handler(
+        flags,
+        change_requests,
+        audit,
+        webhooks,
+        session,
+        request,
+        key,
+        body.into_inner(),
+    )
+    .bind_hub(hub)
+    .await
+}
+
+/// Changes the flag with either semantic instructions or a JSON patch. The
+/// whole patch is checked before anything is written. `If-Match` is
+/// optional here: without it the patch applies to whatever is current.
+#[allow(clippy::too_many_arguments)]
+#[tracing::instrument(skip(session, body))]
+pub async fn patch_handler(
+    flags: web::Data<FlagStore>,
+    change_requests: web::Data<ChangeRequests>,
+    audit: web::Data<AuditLog>,
+    webhooks: web::Data<Webhooks>,
+    session: Result<UserSession, Error>,
+    request: HttpRequest,
+    key: web::Path<String>,
+    body: PatchRequest,
+) -> Result<HttpResponse, Error> {
+    let actor = session?.name();
+    let loaded = flags.loaded();
+    let current = loaded.flag(&key).ok_or(Error::NotFound)?;
+    let version = match request.get_header::<header::IfMatch>() {
+        Some(header::IfMatch::Items(tags)) if tags.len() == 1 => tags[0].tag().to_string(),
+        _ => current.version(),
+    };
+    let draft = match (body.instructions.is_empty(), &body.patch) {
+        (false, None) => flags::apply_instructions(current, &body.instructions)?,
+        (true, Some(patch)) => flags::apply_json_patch(current, patch)?,
+        _ => return Err(flags::PatchError::Empty.into()),
+    };
+    if draft.key != *key {
+        return Err(flags::Error::NotWritable("flags can't be renamed".to_string()).into());
+    }
+
+    let saved = save(
+        &flags,
+        &change_requests,
+        &audit,
+        &webhooks,
+        actor,
+        body.comment,
+        draft,
+        &version,
+    );
+    saved_response(&flags, &key, saved)
+}
+
+/// Answers an API write with the flag as it is now, as a conflict when the
+/// write was based on an older version.
+fn saved_response(
+    flags: &FlagStore,
+    key: &str,
+    saved: Result<Vec<u64>, Error>,
+) -> Result<HttpResponse, Error> {
+    let loaded = flags.loaded();
+    let flag = loaded.flag(key).ok_or(Error::NotFound)?;
+    match saved {
+        Ok(change_requests) => Ok(HttpResponse::Ok().insert_header(etag(flag)).json(Saved {
+            flag,
+            change_requests,
+        })),
+        Err(Error::Flags(stale @ flags::Error::Stale(_))) => Ok(HttpResponse::Conflict()
+            .insert_header(etag(flag))
+            .json(StaleFlag {
+                error: stale.to_string(),
+                version: flag.version(),
+                flag,
+            })),
+        Err(e) => Err(e),
+    }
+}
+
+/// A flag as defined in the files along with its version.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Definition {
+    pub version: String,
+    pub flag: Flag,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DefinitionList {
+    pub flags: Vec<Definition>,
+}
+
+/// One flag changed by [`apply_handler`], on top of the version it was read
+/// at.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum DefinitionChange {
+    Create { flag: Flag },
+    Update { version: String, flag: Flag },
+    Delete { key: String, version: String },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ApplyRequest {
+    #[serde(default)]
+    pub comment: String,
+    /// Checks the changes without writing them.
+    #[serde(default)]
+    pub dry_run: bool,
+    pub changes: Vec<DefinitionChange>,
+}
+
+/// The environments among `protected` in which replacing `before` with
+/// `after` changes what is served, `None` being a flag that doesn't exist.
+fn protected_changes<'e>(
+    protected: &'e [String],
+    before: Option<&Flag>,
+    after: Option<&Flag>,
+) -> Vec<&'e str> {
+    let config = |flag: Option<&Flag>, environment: &String| {
+        flag.and_then(|f| f.environments.get(environment)).cloned()
+    };
+    let serving = match (before, after) {
+        (Some(before), Some(after)) => changes_serving(before, after),
+        _ => true,
+    };
+    protected
+        .iter()
+        .filter(|e| {
+            let configured = config(before, e);
+            configured != config(after, e) || (serving && configured.is_some())
+        })
+        .map(String::as_str)
+        .collect()
+}
+
+/// Makes every change or none of them. Changes to environments that require
+/// approvals are refused, those go through change requests.
+fn apply(
+    flags: &FlagStore,
+    audit: &AuditLog,
+    webhooks: &Webhooks,
+    actor: String,
+    request: ApplyRequest,
+) -> Result<(), Error> {
+    let change = |definitions: &mut flags::Definitions| {
+        let protected: Vec<String> = definitions
+            .environments
+            .iter()
+            .filter(|e| e.approvals.is_some())
+            .map(|e| e.key.clone())
+            .collect();
+        let mut applied = Vec::new();
+        for change in &request.changes {
+            let (key, after) = match change {
+                DefinitionChange::Create { flag } => {
+                    if definitions.flag(&flag.key).is_some() {
+                        return Err(flags::Error::Stale(flag.key.clone()).into());
+                    }
+                    (&flag.key, Some(flag))
+                }
+                DefinitionChange::Update { version, flag } => {
+                    flags::check_version(definitions, &flag.key, version)?;
+                    (&flag.key, Some(flag))
+                }
+                DefinitionChange::Delete { key, version } => {
+                    flags::check_version(definitions, key, version)?;
+                    (key, None)
+                }
+            };
+            let before = definitions.flag(key).cloned();
+            let environments = protected_changes(&protected, before.as_ref(), after);
+            if !environments.is_empty() {
+                return Err(ChangeRequestError::RequiresApprovals {
+                    flag: key.clone(),
+                    environments: environments.join(", "),
+                }
+                .into());
+            }
+            definitions.flags.retain(|f| f.key != *key);
+            definitions.flags.extend(after.cloned());
+            if before.as_ref() != after {
+                applied.push((change, before));
+            }
+        }
+        Ok::<_, Error>(applied)
+    };
+    if request.dry_run {
+        return flags.check_update(change).map(|_| ());
+    }
+
+    let now = Utc::now();
+    for (change, before) in flags.update(change)? {
+        let (action, done, flag) = match (change, &before) {
+            (DefinitionChange::Create { flag }, _) => ("create", "created", flag),
+            (DefinitionChange::Update { flag, .. }, _) => ("edit", "edited", flag),
+            (DefinitionChange::Delete { .. }, Some(flag)) => ("delete", "deleted", flag),
+            (DefinitionChange::Delete { .. }, None) => continue,
+        };
+        let entry = AuditEntry {
+            at: now,
+            actor: actor.clone(),
+            environment: String::new(),
+            flag: flag.key.clone(),
+            action: action.to_string(),
+            detail: if request.comment.is_empty() {
+                done.to_string()
+            } else {
+                format!("{}: {}", done, request.comment)
+            },
+        };
+        webhooks.notify(&flag.webhooks, &entry);
+        audit.record(entry);
+    }
+    Ok(())
+}
+
+/// Every flag as defined in the files, without overrides, with its version.
+#[tracing::instrument]
+#[get("/flags/definitions")]
+pub async fn list_route(flags: web::Data<FlagStore>) -> Result<HttpResponse, Error> {
+    let hub = Hub::current();
+    list_handler(flags).bind_hub(hub).await
+}
+
+#[tracing::instrument]
+pub async fn list_handler(flags: web::Data<FlagStore>) -> Result<HttpResponse, Error> {
+    let loaded = flags.loaded();
+    let flags = loaded
+        .flags
+        .iter()
+        .map(|flag| Definition {
+            version: flag.version(),
+            flag: flag.clone(),
+        })
+        .collect();
+    Ok(HttpResponse::Ok().json(DefinitionList { flags }))
+}
+
+#[tracing::instrument(skip(session, body))]
+#[post("/flags/definitions")]
+pub async fn apply_route(
+    flags: web::Data<FlagStore>,
+    audit: web::Data<AuditLog>,
+    webhooks: web::Data<Webhooks>,
+    session: Result<UserSession, Error>,
+    body: web::Json<ApplyRequest>,
+) -> Result<HttpResponse, Error> {
+    let hub = Hub::current();
+    apply_handler(flags, audit, webhooks, session, body.into_inner())
+        .bind_hub(hub)
+        .await
+}
+
+/// Creates, replaces and deletes flags all at once, each on top of the
+/// version it was read at. Answers with every flag as it is afterwards.
+#[tracing::instrument(skip(session, body))]
+pub async fn apply_handler(
+    flags: web::Data<FlagStore>,
+    audit: web::Data<AuditLog>,
+    webhooks: web::Data<Webhooks>,
+    session: Result<UserSession, Error>,
+    body: ApplyRequest,
+) -> Result<HttpResponse, Error> {
+    let actor = session?.name();
+    let dry_run = body.dry_run;
+    apply(&flags, &audit, &webhooks, actor, body)?;
+    if dry_run {
+        return Ok(HttpResponse::NoContent().finish());
+    }
+    list_handler(flags).await
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EditForm {
+    draft: String,
+    version: String,
+    #[serde(default)]
+    comment: String,
+}
+
+#[tracing::instrument]
+#[get("/flags/{key}/edit")]
+pub async fn page_route(
+    renderer: web::Data<Renderer>,
+    flags: web::Data<FlagStore>,
+    key: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let hub = Hub::current();
+    page_handler(renderer, flags, key).bind_hub(hub).await
+}
+
+#[tracing::instrument]
+pub async fn page_handler(
+    renderer: web::Data<Renderer>,
+    flags: web::Data<FlagStore>,
+    key: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let loaded = flags.loaded();
+    let flag = loaded.flag(&key).ok_or(Error::NotFound)?;
+    Ok(renderer
+        .render("edit.html")
+        .var("flag", flag)
+        .var("environments", &loaded.environments)
+        .var("draft", &serde_yaml::to_string(flag)?)
+        .var("version", &flag.version())
+        .ok()
+        .finish()?)
+}
+
+#[allow(clippy::too_many_arguments)]
+#[tracing::instrument(skip(session, form))]
+#[post("/flags/{key}/edit")]
+pub async fn submit_route(
+    renderer: web::Data<Renderer>,
+    flags: web::Data<FlagStore>,
+    change_requests: web::Data<ChangeRequests>,
+    audit: web::Data<AuditLog>,
+    webhooks: web::Data<Webhooks>,
+    session: Result<UserSession, Error>,
+    key: web::Path<String>,
+    form: web::Form<EditForm>,
+) -> Result<HttpResponse, Error> {
+    let hub = Hub::current();
+    submit_handler(
+        renderer,
+        flags,
+        change_requests,
+        audit,
+        webhooks,
+        session,
+        key,
+        form.into_inner(),
+    )
+    .bind_hub(hub)
+    .await
+}
+
+/// Saves the draft, or shows it again with what went wrong. When someone
+/// else changed the flag since the form was loaded, the page shows how the
+/// draft differs from the flag as it is now and responds with a conflict.
+#[allow(clippy::too_many_arguments)]
+#[tracing::instrument(skip(session, form))]
+pub async fn submit_handler(
+    renderer: web::Data<Renderer>,
+    flags: web::Data<FlagStore>,
+    change_requests: web::Data<ChangeRequests>,
+    audit: web::Data<AuditLog>,
+    webhooks: web::Data<Webhooks>,
+    session: Result<UserSession, Error>,
+    key: web::Path<String>,
+    form: EditForm,
+) -> Result<HttpResponse, Error> {
+    let actor = session?.name();
+    let loaded = flags.loaded();
+    let current = loaded.flag(&key).ok_or(Error::NotFound)?;
+    let render = renderer
+        .render("edit.html")
+        .var("flag", current)
+        .var("environments", &loaded.environments)
+        .var("draft", &form.draft)
+        .var("comment", &form.comment);
+
+    let draft = match serde_yaml::from_str::<Flag>(&form.draft) {
+        Ok(draft) if draft.key == *key => draft,
+        Ok(_) => {
+            return Ok(render
+                .var("version", &form.version)
+                .var("error", "flags can't be renamed")
+                .ok()
+                .finish()?)
+        }
+        Err(e) => {
+            return Ok(render
+                .var("version", &form.version)
+                .var("error", &format!("the draft is not a flag: {}", e))
+                .ok()
+                .finish()?)
+        }
+    };
+    let saved = save(
+        &flags,
+        &change_requests,
+        &audit,
+        &webhooks,
+        actor,
+        form.comment.trim().to_string(),
+        draft.clone(),
+        &form.version,
+    );
+    match saved {
+        Ok(proposed) => {
+            let location = match proposed.first() {
+                Some(id) => format!("/change-requests/{}", id),
+                None => format!("/flags/{}", key),
+            };
+            Ok(HttpResponse::SeeOther()
+                .append_header(("Location", location))
+                .finish())
+        }
+        Err(Error::Flags(flags::Error::Stale(_))) => {
+            let changes = ConfigDiff {
+                kind: "flag",
+                key: key.to_string(),
+                lines: diff::lines(
+                    &serde_yaml::to_string(current)?,
+                    &serde_yaml::to_string(&draft)?,
+                ),
+            };
+            Ok(render
+                .var("version", &current.version())
+                .var("conflict", &changes)
+                .status(StatusCode::CONFLICT)
+                .finish()?)
+        }
+        Err(e) => Ok(render
+            .var("version", &form.version)
+            .var("error", &e.to_string())
+            .ok()
+            .finish()?),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    #[test]
+    fn flag_wide_changes_to_serving() {
+        let flag: Flag = serde_yaml::from_str(
+            r#"
+key: checkout
+variations: [{value: false}, {value: true}]
+"#,
+        )
+        .unwrap();
+        let mut described = flag.clone();
+        described.description = Some("One page checkout".to_string());
+        described.variations.push(flags::Variation {
+            value: "beta".into(),
+            name: None,
+        });
+        assert!(!changes_serving(&flag, &described));
+
+        let mut changed = flag.clone();
+        changed.variations[1].value = "on".into();
+        assert!(changes_serving(&flag, &changed));
+        let mut archived = flag.clone();
+        archived.lifecycle = Lifecycle::Archived;
+        assert!(changes_serving(&flag, &archived));
+    }
+
+    #[test]
+    fn saves_only_on_top_of_the_current_version() {
+        let dir = std::env::temp_dir().join(format!("featurize-edit-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("flags.yaml"),
+            r#"
+environments:
+  - key: production
+flags:
+  - key: checkout
+    variations: [{value: false}, {value: true}]
+"#,
+        )
+        .unwrap();
+        let flags = Arc::new(FlagStore::load(&dir).unwrap());
+        let audit = Arc::new(AuditLog::new());
+        let webhooks = Webhooks::new(reqwest::Client::new());
+        let change_requests = ChangeRequests::new(flags.clone(), audit.clone(), webhooks.clone());
+        let save = |draft: Flag, version: &str| {
+            save(
+                &flags,
+                &change_requests,
+                &audit,
+                &webhooks,
+                "alice@example.com".to_string(),
+                String::new(),
+                draft,
+                version,
+            )
+        };
+
+        let read = flags.loaded().flag("checkout").unwrap().clone();
+        let mut draft = read.clone();
+        draft.description = Some("One page checkout".to_string());
+        let stale = save(draft.clone(), "not-a-version");
+        assert!(
+            matches!(stale, Err(Error::Flags(flags::Error::Stale(_)))),
+            "{:?}",
+            stale
+        );
+        assert_eq!(
+            save(draft.clone(), &read.version()).unwrap(),
+            Vec::<u64>::new()
+        );
+        let saved = flags.loaded().flag("checkout").unwrap().clone();
+        assert_eq!(saved.description, draft.description);
+
+        // The version the first edit started from is stale now.
+        let mut other = read.clone();
+        other.tags = vec!["payments".to_string()];
+        assert!(matches!(
+            save(other, &read.version()),
+            Err(Error::Flags(flags::Error::Stale(_)))
+        ));
+        assert_eq!(flags.loaded().flag("checkout"), Some(&saved));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn applies_every_change_or_none() {
+        let dir = std::env::temp_dir().join(format!("featurize-apply-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("flags.yaml"),
+            r#"
+environments:
+  - key: staging
+  - key: production
+    approvals: {}
+flags:
+  - key: checkout
+    variations: [{value: false}, {value: true}]
+    environments:
+      staging: {on: true, off_variation: 0, fallthrough: {variation: 1}}
+  - key: banner
+    variations: [{value: false}, {value: true}]
+    environments:
+      production: {on: true, off_variation: 0, fallthrough: {variation: 1}}
+"#,
+        )
+        .unwrap();
+        let flags = FlagStore::load(&dir).unwrap();
+        let audit = AuditLog::new();
+        let webhooks = Webhooks::new(reqwest::Client::new());
+        let apply = |changes| {
+            let request = ApplyRequest {
+                comment: String::new(),
+                dry_run: false,
+                changes,
+            };
+            apply(
+                &flags,
+                &audit,
+                &webhooks,
+                "alice@example.com".to_string(),
+                request,
+            )
+        };
+        let loaded = flags.loaded();
+        let checkout = loaded.flag("checkout").unwrap();
+        let banner = loaded.flag("banner").unwrap();
+        let mut search = checkout.clone();
+        search.key = "search".to_string();
+        let mut described = checkout.clone();
+        described.description = Some("One page checkout".to_string());
+        let changes = |version: String, delete: &Flag| {
+            vec![
+                DefinitionChange::Create {
+                    flag: search.clone(),
+                },
+                DefinitionChange::Update {
+                    version,
+                    flag: described.clone(),
+                },
+                DefinitionChange::Delete {
+                    key: delete.key.clone(),
+                    version: delete.version(),
+                },
+            ]
+        };
+
+        // Deleting banner changes production, which requires approvals.
+        let refused = apply(changes(checkout.version(), banner));
+        assert!(
+            matches!(
+                refused,
+                Err(Error::ChangeRequest(
+                    ChangeRequestError::RequiresApprovals { .. }
+                ))
+            ),
+            "{:?}",
+            refused
+        );
+        assert_eq!(flags.loaded(), loaded);
+
+        let stale = apply(changes("not-a-version".to_string(), &described));
+        assert!(
+            matches!(stale, Err(Error::Flags(flags::Error::Stale(_)))),
+            "{:?}",
+            stale
+        );
+        assert_eq!(flags.loaded(), loaded);
+
+        // Updating checkout first makes its new version the one to delete.
+        apply(changes(checkout.version(), &described)).unwrap();
+        let keys: Vec<_> = flags.loaded().flags.iter().map(|f| f.key.clone()).collect();
+        assert_eq!(keys, ["banner", "search"]);
+        assert!(dir.join("search.yaml").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
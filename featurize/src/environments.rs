@@ -0,0 +1,315 @@
+// Featurize, the FOSS feature flagging
+// Copyright (C) 2024  Lucy Ekaterina
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use actix_web::{get, post, web, HttpResponse};
+use chrono::Utc;
+use sentry::{Hub, SentryFutureExt};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    audit::{AuditEntry, AuditLog},
+    flags::{Flag, FlagStore, Freeze, Override, OverrideAction},
+    ory_client::UserSession,
+    renderer::Renderer,
+    webhooks::Webhooks,
+    Error,
+};
+
+#[derive(Debug, Deserialize)]
+pub struct ReasonForm {
+    #[serde(default)]
+    reason: String,
+}
+
+impl ReasonForm {
+    fn or(&self, default: &str) -> String {
+        match self.reason.trim() {
+            "" => default.to_string(),
+            reason => reason.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct KillSwitchState<'d> {
+    flag: &'d Flag,
+    variation: usize,
+    killed: bool,
+}
+
+fn see_other(location: String) -> HttpResponse {
+    HttpResponse::SeeOther()
+        .append_header(("Location", location))
+        .finish()
+}
+
+/// Serves the kill switch variation of `flag` to everyone in `environment`.
+fn kill(
+    flags: &FlagStore,
+    audit: &AuditLog,
+    webhooks: &Webhooks,
+    environment: &str,
+    flag: &Flag,
+    actor: String,
+    reason: String,
+) -> Result<(), Error> {
+    let kill_switch = flag.kill_switch.ok_or(Error::NotFound)?;
+    if !flag.environments.contains_key(environment) {
+        return Err(Error::NotFound);
+    }
+    let now = Utc::now();
+    flags.set_override(Override {
+        environment: environment.to_string(),
+        flag: flag.key.clone(),
+        action: OverrideAction::Kill {
+            variation: kill_switch.variation,
+        },
+        reason: reason.clone(),
+        at: now,
+    })?;
+    let entry = AuditEntry {
+        at: now,
+        actor,
+        environment: environment.to_string(),
+        flag: flag.key.clone(),
+        action: "kill".to_string(),
+        detail: format!(
+            "killed, serving variation {}: {}",
+            kill_switch.variation, reason
+        ),
+    };
+    webhooks.notify(&flag.webhooks, &entry);
+    audit.record(entry);
+    Ok(())
+}
+
+#[tracing::instrument(skip(session))]
+#[post("/flags/{key}/overrides/{environment}/kill")]
+pub async fn kill_route(
+    flags: web::Data<FlagStore>,
+    audit: web::Data<AuditLog>,
+    webhooks: web::Data<Webhooks>,
+    session: Result<UserSession, Error>,
+    path: web::Path<(String, String)>,
+    form: web::Form<ReasonForm>,
+) -> Result<HttpResponse, Error> {
+    let hub = Hub::current();
+    kill_handler(flags, audit, webhooks, session, path, form)
+        .bind_hub(hub)
+        .await
+}
+
+/// Kills one flag. Kills are for emergencies, so they go through even while
+/// the environment is frozen.
+#[tracing::instrument(skip(session))]
+pub async fn kill_handler(
+    flags: web::Data<FlagStore>,
+    audit: web::Data<AuditLog>,
+    webhooks: web::Data<Webhooks>,
+    session: Result<UserSession, Error>,
+    path: web::Path<(String, String)>,
+    form: web::Form<ReasonForm>,
+) -> Result<HttpResponse, Error> {
+    let actor = session?.name();
+    let (key, environment) = path.into_inner();
+    let definitions = flags.definitions();
+    let flag = definitions.flag(&key).ok_or(Error::NotFound)?;
+    let reason = form.or("killed from the dashboard");
+    kill(&flags, &audit, &webhooks, &environment, flag, actor, reason)?;
+    Ok(see_other(format!("/flags/{}", key)))
+}
+
+#[tracing::instrument]
+#[get("/environments/{environment}")]
+pub async fn page_route(
+    renderer: web::Data<Renderer>,
+    flags: web::Data<FlagStore>,
+    audit: web::Data<AuditLog>,
+    environment: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let hub = Hub::current();
+    page_handler(renderer, flags, audit, environment)
+        .bind_hub(hub)
+        .await
+}
+
+#[tracing::instrument]
+pub async fn page_handler(
+    renderer: web::Data<Renderer>,
+    flags: web::Data<FlagStore>,
+    audit: web::Data<AuditLog>,
+    environment: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let definitions = flags.definitions();
+    let env = definitions
+        .environment(&environment)
+        .ok_or(Error::NotFound)?;
+    let overrides = flags.overrides();
+    let kill_switches: Vec<KillSwitchState> = definitions
+        .flags
+        .iter()
+        .filter(|flag| flag.environments.contains_key(&env.key))
+        .filter_map(|flag| {
+            let kill_switch = flag.kill_switch?;
+            let killed = overrides.iter().any(|o| {
+                o.applies_to(&env.key, &flag.key) && matches!(o.action, OverrideAction::Kill { .. })
+            });
+            Some(KillSwitchState {
+                flag,
+                variation: kill_switch.variation,
+                killed,
+            })
+        })
+        .collect();
+    Ok(renderer
+        .render("environment.html")
+        .var("environment", env)
+        .var("freeze", &flags.freezes().get(&env.key))
+        .var("kill_switches", &kill_switches)
+        .var("history", &audit.for_environment(&env.key))
+        .ok()
+        .finish()?)
+}
+
+#[tracing::instrument(skip(session))]
+#[post("/environments/{environment}/kill")]
+pub async fn kill_all_route(
+    flags: web::Data<FlagStore>,
+    audit: web::Data<AuditLog>,
+    webhooks: web::Data<Webhooks>,
+    session: Result<UserSession, Error>,
+    environment: web::Path<String>,
+    form: web::Form<ReasonForm>,
+) -> Result<HttpResponse, Error> {
+    let hub = Hub::current();
+    kill_all_handler(flags, audit, webhooks, session, environment, form)
+        .bind_hub(hub)
+        .await
+}
+
+/// Kills every kill switch in the environment at once.
+#[tracing::instrument(skip(session))]
+pub async fn kill_all_handler(
+    flags: web::Data<FlagStore>,
+    audit: web::Data<AuditLog>,
+    webhooks: web::Data<Webhooks>,
+    session: Result<UserSession, Error>,
+    environment: web::Path<String>,
+    form: web::Form<ReasonForm>,
+) -> Result<HttpResponse, Error> {
+    let actor = session?.name();
+    let definitions = flags.definitions();
+    if definitions.environment(&environment).is_none() {
+        return Err(Error::NotFound);
+    }
+    let reason = form.or("every kill switch killed from the dashboard");
+    let kill_switches = definitions
+        .flags
+        .iter()
+        .filter(|f| f.kill_switch.is_some() && f.environments.contains_key(&*environment));
+    for flag in kill_switches {
+        kill(
+            &flags,
+            &audit,
+            &webhooks,
+            &environment,
+            flag,
+            actor.clone(),
+            reason.clone(),
+        )?;
+    }
+    Ok(see_other(format!("/environments/{}", environment)))
+}
+
+#[tracing::instrument(skip(session))]
+#[post("/environments/{environment}/freeze")]
+pub async fn freeze_route(
+    flags: web::Data<FlagStore>,
+    audit: web::Data<AuditLog>,
+    session: Result<UserSession, Error>,
+    environment: web::Path<String>,
+    form: web::Form<ReasonForm>,
+) -> Result<HttpResponse, Error> {
+    let hub = Hub::current();
+    freeze_handler(flags, audit, session, environment, form)
+        .bind_hub(hub)
+        .await
+}
+
+#[tracing::instrument(skip(session))]
+pub async fn freeze_handler(
+    flags: web::Data<FlagStore>,
+    audit: web::Data<AuditLog>,
+    session: Result<UserSession, Error>,
+    environment: web::Path<String>,
+    form: web::Form<ReasonForm>,
+) -> Result<HttpResponse, Error> {
+    let actor = session?.name();
+    if flags.definitions().environment(&environment).is_none() {
+        return Err(Error::NotFound);
+    }
+    let freeze = Freeze {
+        reason: form.or("frozen from the dashboard"),
+        at: Utc::now(),
+    };
+    flags.freeze(&environment, freeze.clone())?;
+    audit.record(AuditEntry {
+        at: freeze.at,
+        actor,
+        environment: environment.to_string(),
+        flag: String::new(),
+        action: "freeze".to_string(),
+        detail: freeze.reason,
+    });
+    Ok(see_other(format!("/environments/{}", environment)))
+}
+
+#[tracing::instrument(skip(session))]
+#[post("/environments/{environment}/unfreeze")]
+pub async fn unfreeze_route(
+    flags: web::Data<FlagStore>,
+    audit: web::Data<AuditLog>,
+    session: Result<UserSession, Error>,
+    environment: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let hub = Hub::current();
+    unfreeze_handler(flags, audit, session, environment)
+        .bind_hub(hub)
+        .await
+}
+
+#[tracing::instrument(skip(session))]
+pub async fn unfreeze_handler(
+    flags: web::Data<FlagStore>,
+    audit: web::Data<AuditLog>,
+    session: Result<UserSession, Error>,
+    environment: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let actor = session?.name();
+    let lifted = flags.unfreeze(&environment)?.ok_or(Error::NotFound)?;
+    // Pick up the changes to the files that the freeze held back.
+    if let Err(e) = flags.reload() {
+        tracing::error!("keeping previous flag definitions: {}", e);
+    }
+    audit.record(AuditEntry {
+        at: Utc::now(),
+        actor,
+        environment: environment.to_string(),
+        flag: String::new(),
+        action: "unfreeze".to_string(),
+        detail: format!("lifted freeze: {}", lifted.reason),
+    });
+    Ok(see_other(format!("/environments/{}", environment)))
+}
@@ -0,0 +1,75 @@
+// Featurize, the FOSS feature flagging
+// Copyright (C) 2024  Lucy Ekaterina
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use actix_web::{post, web, HttpResponse};
+use chrono::Utc;
+use sentry::{Hub, SentryFutureExt};
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+use crate::{
+    attributes::AttributeRegistry,
+    flags::{Context, Evaluation, Evaluator, FlagStore},
+    Error,
+};
+
+#[derive(Debug, Serialize)]
+struct EvaluatedFlag<'d> {
+    value: &'d Value,
+    #[serde(flatten)]
+    evaluation: Evaluation,
+}
+
+/// Evaluates every flag configured in the environment for the posted context.
+#[tracing::instrument]
+#[post("/evaluate/{environment}")]
+pub async fn route(
+    flags: web::Data<FlagStore>,
+    attributes: web::Data<AttributeRegistry>,
+    environment: web::Path<String>,
+    context: web::Json<Context>,
+) -> Result<HttpResponse, Error> {
+    let hub = Hub::current();
+    handler(flags, attributes, environment, context)
+        .bind_hub(hub)
+        .await
+}
+
+#[tracing::instrument]
+pub async fn handler(
+    flags: web::Data<FlagStore>,
+    attributes: web::Data<AttributeRegistry>,
+    environment: web::Path<String>,
+    context: web::Json<Context>,
+) -> Result<HttpResponse, Error> {
+    let definitions = flags.definitions();
+    if definitions.environment(&environment).is_none() {
+        return Err(Error::NotFound);
+    }
+    attributes.record(&environment, &context, Utc::now());
+    let evaluator = Evaluator::new(&definitions, &environment);
+    let mut evaluated = Map::new();
+    for flag in &definitions.flags {
+        let Some(evaluation) = evaluator.evaluate(flag, &context) else {
+            continue;
+        };
+        let result = EvaluatedFlag {
+            value: &flag.variations[evaluation.variation].value,
+            evaluation,
+        };
+        evaluated.insert(flag.key.clone(), serde_json::to_value(result)?);
+    }
+    Ok(HttpResponse::Ok().json(evaluated))
+}
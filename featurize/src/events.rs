@@ -0,0 +1,186 @@
+// Featurize, the FOSS feature flagging
+// Copyright (C) 2024  Lucy Ekaterina
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use actix_web::{post, web, HttpResponse};
+use chrono::{DateTime, Utc};
+use sentry::{Hub, SentryFutureExt};
+use serde::Deserialize;
+
+use crate::{
+    analytics::Analytics,
+    experiments::ExperimentEvents,
+    flags::{Definitions, FlagStore},
+    Error,
+};
+
+/// Evaluation counts an SDK has collected since it last reported.
+#[derive(Debug, Deserialize)]
+pub struct SummaryEvent {
+    environment: String,
+    start: DateTime<Utc>,
+    counters: Vec<Counter>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Counter {
+    flag: String,
+    #[serde(default)]
+    variation: Option<usize>,
+    count: u64,
+}
+
+/// Events an SDK tracked for individual contexts, used by experiments.
+#[derive(Debug, Deserialize)]
+pub struct TrackEvents {
+    environment: String,
+    events: Vec<TrackEvent>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum TrackEvent {
+    /// `context` was served `variation` of `flag`.
+    Exposure {
+        flag: String,
+        context: String,
+        variation: usize,
+    },
+    /// A `track` call for a custom metric.
+    Metric {
+        metric: String,
+        context: String,
+        #[serde(default = "one")]
+        value: f64,
+    },
+}
+
+fn one() -> f64 {
+    1.0
+}
+
+/// Whether an experiment or a guardrail in `environment` looks at exposures
+/// to `flag`.
+fn tracks_flag(definitions: &Definitions, environment: &str, flag: &str) -> bool {
+    let experiment = definitions
+        .experiments
+        .iter()
+        .any(|e| e.environment == environment && e.flag == flag);
+    let guardrails = definitions
+        .flag(flag)
+        .and_then(|f| f.environments.get(environment))
+        .is_some_and(|config| config.guardrails.is_some());
+    experiment || guardrails
+}
+
+/// Whether an experiment or a guardrail in `environment` looks at `metric`.
+fn tracks_metric(definitions: &Definitions, environment: &str, metric: &str) -> bool {
+    let experiment = definitions
+        .experiments
+        .iter()
+        .filter(|e| e.environment == environment)
+        .any(|e| e.metrics.iter().any(|m| m.key == metric));
+    let guardrails = definitions
+        .flags
+        .iter()
+        .filter_map(|f| f.environments.get(environment)?.guardrails.as_ref())
+        .any(|g| g.metrics.iter().any(|m| m.metric == metric));
+    experiment || guardrails
+}
+
+#[tracing::instrument]
+#[post("/events/summary")]
+pub async fn summary_route(
+    flags: web::Data<FlagStore>,
+    analytics: web::Data<Analytics>,
+    event: web::Json<SummaryEvent>,
+) -> Result<HttpResponse, Error> {
+    let hub = Hub::current();
+    summary_handler(flags, analytics, event).bind_hub(hub).await
+}
+
+#[tracing::instrument]
+pub async fn summary_handler(
+    flags: web::Data<FlagStore>,
+    analytics: web::Data<Analytics>,
+    event: web::Json<SummaryEvent>,
+) -> Result<HttpResponse, Error> {
+    let definitions = flags.definitions();
+    if definitions.environment(&event.environment).is_none() {
+        return Err(Error::NotFound);
+    }
+    // Counts of flags and variations that don't exist aren't kept.
+    let exists = |counter: &Counter| {
+        definitions
+            .flag(&counter.flag)
+            .is_some_and(|f| counter.variation.map_or(true, |v| v < f.variations.len()))
+    };
+    for counter in event.counters.iter().filter(|c| exists(c)) {
+        analytics.record(
+            &event.environment,
+            &counter.flag,
+            counter.variation,
+            event.start,
+            counter.count,
+        );
+    }
+    Ok(HttpResponse::Accepted().finish())
+}
+
+#[tracing::instrument]
+#[post("/events/track")]
+pub async fn track_route(
+    flags: web::Data<FlagStore>,
+    experiments: web::Data<ExperimentEvents>,
+    events: web::Json<TrackEvents>,
+) -> Result<HttpResponse, Error> {
+    let hub = Hub::current();
+    track_handler(flags, experiments, events)
+        .bind_hub(hub)
+        .await
+}
+
+#[tracing::instrument]
+pub async fn track_handler(
+    flags: web::Data<FlagStore>,
+    experiments: web::Data<ExperimentEvents>,
+    events: web::Json<TrackEvents>,
+) -> Result<HttpResponse, Error> {
+    let definitions = flags.definitions();
+    if definitions.environment(&events.environment).is_none() {
+        return Err(Error::NotFound);
+    }
+    // Events nothing looks at aren't kept.
+    let environment = &events.environment;
+    for event in &events.events {
+        match event {
+            TrackEvent::Exposure {
+                flag,
+                context,
+                variation,
+            } if tracks_flag(&definitions, environment, flag) => {
+                experiments.record_exposure(environment, flag, context, *variation)
+            }
+            TrackEvent::Metric {
+                metric,
+                context,
+                value,
+            } if tracks_metric(&definitions, environment, metric) => {
+                experiments.record_metric(environment, metric, context, *value)
+            }
+            _ => {}
+        }
+    }
+    Ok(HttpResponse::Accepted().finish())
+}
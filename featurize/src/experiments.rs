@@ -0,0 +1,341 @@
+// Featurize, the FOSS feature flagging
+// Copyright (C) 2024  Lucy Ekaterina
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use std::{collections::HashMap, sync::Mutex};
+
+use serde::Serialize;
+
+use crate::flags::{Experiment, Flag, MetricKind};
+
+mod stats;
+
+/// Conventional threshold below which a difference counts as significant.
+const SIGNIFICANCE: f64 = 0.05;
+
+/// Contexts kept per flag and per metric in an environment. Results are based
+/// on the first contexts seen once a flag or metric reaches it.
+const MAX_CONTEXTS: usize = 100_000;
+
+#[derive(Debug, Default, Clone, Copy)]
+struct MetricTotal {
+    count: u64,
+    sum: f64,
+}
+
+/// Which variation each context was served and the metric events tracked for
+/// it, keyed by environment.
+#[derive(Debug)]
+pub struct ExperimentEvents {
+    /// The first variation a context was exposed to per (environment, flag).
+    exposures: Mutex<HashMap<(String, String), HashMap<String, usize>>>,
+    /// Totals per context per (environment, metric).
+    metrics: Mutex<HashMap<(String, String), HashMap<String, MetricTotal>>>,
+    max_contexts: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ExperimentResults {
+    pub experiment: String,
+    pub flag: String,
+    pub environment: String,
+    pub metrics: Vec<MetricResults>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MetricResults {
+    pub metric: String,
+    pub kind: MetricKind,
+    pub variations: Vec<VariationResult>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct VariationResult {
+    pub variation: usize,
+    pub name: String,
+    pub control: bool,
+    /// Contexts exposed to the variation.
+    pub units: u64,
+    /// Contexts that tracked the metric at least once.
+    pub conversions: u64,
+    /// The conversion rate or the mean value per context.
+    pub value: f64,
+    /// 95% confidence interval for `value`.
+    pub low: f64,
+    pub high: f64,
+    /// Relative change of `value` against the control.
+    pub change: Option<f64>,
+    /// Two-sided p-value of the difference to the control.
+    pub p_value: Option<f64>,
+    pub significant: bool,
+}
+
+impl ExperimentEvents {
+    pub fn new() -> Self {
+        Self::with_max_contexts(MAX_CONTEXTS)
+    }
+
+    pub fn with_max_contexts(max_contexts: usize) -> Self {
+        Self {
+            exposures: Mutex::default(),
+            metrics: Mutex::default(),
+            max_contexts,
+        }
+    }
+
+    /// Records that `context` was served `variation`. Only the first exposure
+    /// counts, so a context stays in one group if the flag changes later.
+    pub fn record_exposure(&self, environment: &str, flag: &str, context: &str, variation: usize) {
+        let mut exposures = self.exposures.lock().unwrap();
+        let contexts = exposures
+            .entry((environment.to_string(), flag.to_string()))
+            .or_default();
+        if contexts.len() < self.max_contexts {
+            contexts.entry(context.to_string()).or_insert(variation);
+        }
+    }
+
+    /// Adds `value` to the total of `context`. Contexts beyond the limit are
+    /// dropped, contexts already tracked keep adding up.
+    pub fn record_metric(&self, environment: &str, metric: &str, context: &str, value: f64) {
+        let mut metrics = self.metrics.lock().unwrap();
+        let contexts = metrics
+            .entry((environment.to_string(), metric.to_string()))
+            .or_default();
+        if !contexts.contains_key(context) && contexts.len() >= self.max_contexts {
+            return;
+        }
+        let total = contexts.entry(context.to_string()).or_default();
+        total.count += 1;
+        total.sum += value;
+    }
+
+    pub fn results(&self, experiment: &Experiment, flag: &Flag) -> ExperimentResults {
+        ExperimentResults {
+            experiment: experiment.key.clone(),
+            flag: experiment.flag.clone(),
+            environment: experiment.environment.clone(),
+            metrics: experiment
+                .metrics
+                .iter()
+                .map(|metric| {
+                    self.compare(
+                        &experiment.environment,
+                        flag,
+                        experiment.control,
+                        &metric.key,
+                        metric.kind,
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    /// Compares `metric` between the contexts exposed to each variation of
+    /// `flag` and those exposed to `control`.
+    pub fn compare(
+        &self,
+        environment: &str,
+        flag: &Flag,
+        control: usize,
+        metric: &str,
+        kind: MetricKind,
+    ) -> MetricResults {
+        let mut samples = vec![stats::Sample::default(); flag.variations.len()];
+        {
+            let exposures = self.exposures.lock().unwrap();
+            let metrics = self.metrics.lock().unwrap();
+            let assigned = exposures.get(&(environment.to_string(), flag.key.clone()));
+            let totals = metrics.get(&(environment.to_string(), metric.to_string()));
+            for (context, variation) in assigned.into_iter().flatten() {
+                let Some(sample) = samples.get_mut(*variation) else {
+                    continue;
+                };
+                let total = totals
+                    .and_then(|t| t.get(context))
+                    .copied()
+                    .unwrap_or_default();
+                let value = match kind {
+                    MetricKind::Conversion => (total.count > 0) as u8 as f64,
+                    MetricKind::Mean => total.sum,
+                };
+                sample.add(value, total.count > 0);
+            }
+        }
+
+        let estimate = |sample: &stats::Sample| match kind {
+            MetricKind::Conversion => stats::proportion(sample),
+            MetricKind::Mean => stats::mean(sample),
+        };
+        let baseline = samples.get(control).copied().unwrap_or_default();
+        let (control_value, _, _) = estimate(&baseline);
+        let variations = samples
+            .iter()
+            .enumerate()
+            .map(|(i, sample)| {
+                let (value, low, high) = estimate(sample);
+                let is_control = i == control;
+                let p_value = if is_control {
+                    None
+                } else {
+                    match kind {
+                        MetricKind::Conversion => stats::two_proportion_test(sample, &baseline),
+                        MetricKind::Mean => stats::welch_test(sample, &baseline),
+                    }
+                };
+                let change = (!is_control && control_value != 0.0)
+                    .then(|| (value - control_value) / control_value);
+                VariationResult {
+                    variation: i,
+                    name: flag.variations[i]
+                        .name
+                        .clone()
+                        .unwrap_or_else(|| i.to_string()),
+                    control: is_control,
+                    units: sample.n,
+                    conversions: sample.conversions,
+                    value,
+                    low,
+                    high,
+                    change,
+                    p_value,
+                    significant: p_value.is_some_and(|p| p < SIGNIFICANCE),
+                }
+            })
+            .collect();
+        MetricResults {
+            metric: metric.to_string(),
+            kind,
+            variations,
+        }
+    }
+}
+
+impl ExperimentResults {
+    /// One row per metric and variation.
+    pub fn to_csv(&self) -> String {
+        let mut csv = csv::Writer::from_writer(Vec::new());
+        let optional = |v: Option<f64>| v.map(|v| v.to_string()).unwrap_or_default();
+        // Writing to memory can't fail.
+        let _ = csv.write_record([
+            "experiment",
+            "flag",
+            "environment",
+            "metric",
+            "kind",
+            "variation",
+            "name",
+            "control",
+            "units",
+            "conversions",
+            "value",
+            "ci_low",
+            "ci_high",
+            "change",
+            "p_value",
+            "significant",
+        ]);
+        for metric in &self.metrics {
+            let kind = match metric.kind {
+                MetricKind::Conversion => "conversion",
+                MetricKind::Mean => "mean",
+            };
+            for v in &metric.variations {
+                let _ = csv.write_record([
+                    &self.experiment,
+                    &self.flag,
+                    &self.environment,
+                    &metric.metric,
+                    kind,
+                    &v.variation.to_string(),
+                    &v.name,
+                    &v.control.to_string(),
+                    &v.units.to_string(),
+                    &v.conversions.to_string(),
+                    &v.value.to_string(),
+                    &v.low.to_string(),
+                    &v.high.to_string(),
+                    &optional(v.change),
+                    &optional(v.p_value),
+                    &v.significant.to_string(),
+                ]);
+            }
+        }
+        csv.into_inner()
+            .ok()
+            .and_then(|bytes| String::from_utf8(bytes).ok())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quotes_csv_fields() {
+        let results = ExperimentResults {
+            experiment: "checkout, \"v2\"".to_string(),
+            flag: "checkout".to_string(),
+            environment: "production".to_string(),
+            metrics: vec![MetricResults {
+                metric: "purchase".to_string(),
+                kind: MetricKind::Conversion,
+                variations: vec![VariationResult {
+                    variation: 0,
+                    name: "control".to_string(),
+                    control: true,
+                    units: 10,
+                    conversions: 5,
+                    value: 0.5,
+                    low: 0.25,
+                    high: 0.75,
+                    change: None,
+                    p_value: None,
+                    significant: false,
+                }],
+            }],
+        };
+        let csv = results.to_csv();
+        let mut lines = csv.lines();
+        assert!(lines.next().unwrap().starts_with("experiment,flag,"));
+        assert_eq!(
+            lines.next(),
+            Some(
+                "\"checkout, \"\"v2\"\"\",checkout,production,purchase,conversion,0,control,true,10,5,0.5,0.25,0.75,,,false"
+            )
+        );
+        assert_eq!(lines.next(), None);
+    }
+
+    #[test]
+    fn keeps_at_most_max_contexts() {
+        let events = ExperimentEvents::with_max_contexts(2);
+        for context in ["a", "b", "c"] {
+            events.record_exposure("production", "checkout", context, 0);
+            events.record_metric("production", "purchase", context, 1.0);
+        }
+        events.record_metric("production", "purchase", "a", 2.0);
+
+        let exposures = events.exposures.lock().unwrap();
+        let exposed = &exposures[&("production".to_string(), "checkout".to_string())];
+        assert_eq!(exposed.len(), 2);
+        assert!(!exposed.contains_key("c"));
+        let metrics = events.metrics.lock().unwrap();
+        let totals = &metrics[&("production".to_string(), "purchase".to_string())];
+        assert_eq!(totals.len(), 2);
+        assert_eq!(totals["a"].count, 2);
+        assert_eq!(totals["a"].sum, 3.0);
+    }
+}
@@ -0,0 +1,192 @@
+// Featurize, the FOSS feature flagging
+// Copyright (C) 2024  Lucy Ekaterina
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//! Frequentist estimates for comparing a variation against the control. Tests
+//! use the normal approximation, which holds for the sample sizes experiments
+//! need to reach significance anyway.
+
+/// z for a two-sided 95% confidence interval.
+const Z_95: f64 = 1.959_963_984_540_054;
+
+/// Per context values of one variation for one metric.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Sample {
+    pub n: u64,
+    pub conversions: u64,
+    sum: f64,
+    sum_of_squares: f64,
+}
+
+impl Sample {
+    pub fn add(&mut self, value: f64, converted: bool) {
+        self.n += 1;
+        self.conversions += converted as u64;
+        self.sum += value;
+        self.sum_of_squares += value * value;
+    }
+
+    fn mean(&self) -> f64 {
+        self.sum / self.n as f64
+    }
+
+    fn variance(&self) -> f64 {
+        let n = self.n as f64;
+        ((self.sum_of_squares - self.sum * self.sum / n) / (n - 1.0)).max(0.0)
+    }
+}
+
+/// Conversion rate with a Wilson score interval, as `(rate, low, high)`.
+pub fn proportion(sample: &Sample) -> (f64, f64, f64) {
+    if sample.n == 0 {
+        return (0.0, 0.0, 0.0);
+    }
+    let n = sample.n as f64;
+    let p = sample.conversions as f64 / n;
+    let z2 = Z_95 * Z_95;
+    let denominator = 1.0 + z2 / n;
+    let centre = (p + z2 / (2.0 * n)) / denominator;
+    let margin = Z_95 * (p * (1.0 - p) / n + z2 / (4.0 * n * n)).sqrt() / denominator;
+    (p, (centre - margin).max(0.0), (centre + margin).min(1.0))
+}
+
+/// Mean value per context with a normal interval, as `(mean, low, high)`.
+pub fn mean(sample: &Sample) -> (f64, f64, f64) {
+    match sample.n {
+        0 => (0.0, 0.0, 0.0),
+        1 => (sample.mean(), sample.mean(), sample.mean()),
+        n => {
+            let mean = sample.mean();
+            let margin = Z_95 * (sample.variance() / n as f64).sqrt();
+            (mean, mean - margin, mean + margin)
+        }
+    }
+}
+
+/// Two-sided p-value of a pooled two-proportion z-test.
+pub fn two_proportion_test(a: &Sample, b: &Sample) -> Option<f64> {
+    if a.n == 0 || b.n == 0 {
+        return None;
+    }
+    let (na, nb) = (a.n as f64, b.n as f64);
+    let pooled = (a.conversions + b.conversions) as f64 / (na + nb);
+    let se = (pooled * (1.0 - pooled) * (1.0 / na + 1.0 / nb)).sqrt();
+    if se == 0.0 {
+        return None;
+    }
+    let z = (a.conversions as f64 / na - b.conversions as f64 / nb) / se;
+    Some(two_sided(z))
+}
+
+/// Two-sided p-value of Welch's test for a difference in means.
+pub fn welch_test(a: &Sample, b: &Sample) -> Option<f64> {
+    if a.n < 2 || b.n < 2 {
+        return None;
+    }
+    let se = (a.variance() / a.n as f64 + b.variance() / b.n as f64).sqrt();
+    if se == 0.0 {
+        return None;
+    }
+    Some(two_sided((a.mean() - b.mean()) / se))
+}
+
+fn two_sided(z: f64) -> f64 {
+    (2.0 * (1.0 - normal_cdf(z.abs()))).clamp(0.0, 1.0)
+}
+
+fn normal_cdf(x: f64) -> f64 {
+    0.5 * (1.0 + erf(x / std::f64::consts::SQRT_2))
+}
+
+/// Abramowitz and Stegun 7.1.26, accurate to about 1.5e-7.
+fn erf(x: f64) -> f64 {
+    let sign = x.signum();
+    let x = x.abs();
+    let t = 1.0 / (1.0 + 0.327_591_1 * x);
+    let poly = t
+        * (0.254_829_592
+            + t * (-0.284_496_736
+                + t * (1.421_413_741 + t * (-1.453_152_027 + t * 1.061_405_429))));
+    sign * (1.0 - poly * (-x * x).exp())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(values: &[f64]) -> Sample {
+        let mut sample = Sample::default();
+        for value in values {
+            sample.add(*value, *value > 0.0);
+        }
+        sample
+    }
+
+    fn conversions(converted: u64, n: u64) -> Sample {
+        let mut sample = Sample::default();
+        for i in 0..n {
+            sample.add((i < converted) as u8 as f64, i < converted);
+        }
+        sample
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-6,
+            "{} is not {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn erf_matches_known_values() {
+        assert_close(erf(0.0), 0.0);
+        assert_close(erf(0.5), 0.520_499_877_8);
+        assert_close(erf(1.0), 0.842_700_792_9);
+        assert_close(erf(-1.0), -0.842_700_792_9);
+        assert_close(erf(2.0), 0.995_322_265_0);
+    }
+
+    #[test]
+    fn wilson_interval_matches_known_values() {
+        let (rate, low, high) = proportion(&conversions(50, 100));
+        assert_close(rate, 0.5);
+        assert_close(low, 0.403_831_530_4);
+        assert_close(high, 0.596_168_469_6);
+
+        // Stays inside [0, 1] at the edges, unlike the normal interval.
+        let (rate, low, high) = proportion(&conversions(10, 10));
+        assert_close(rate, 1.0);
+        assert_close(low, 0.722_467_200_1);
+        assert_close(high, 1.0);
+        let (_, low, high) = proportion(&conversions(0, 20));
+        assert_close(low, 0.0);
+        assert_close(high, 0.161_125_158_1);
+    }
+
+    #[test]
+    fn tests_match_known_p_values() {
+        // Means 3 and 4, variances 2.5, so z = -1.
+        let a = sample(&[1.0, 2.0, 3.0, 4.0, 5.0]);
+        let b = sample(&[2.0, 3.0, 4.0, 5.0, 6.0]);
+        assert_close(welch_test(&a, &b).unwrap(), 0.317_310_507_9);
+        assert_close(welch_test(&b, &a).unwrap(), 0.317_310_507_9);
+        assert_close(welch_test(&a, &a).unwrap(), 1.0);
+        assert_eq!(welch_test(&sample(&[1.0]), &b), None);
+
+        let p = two_proportion_test(&conversions(50, 100), &conversions(40, 100));
+        assert_close(p.unwrap(), 0.155_218_489_7);
+    }
+}
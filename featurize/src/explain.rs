@@ -0,0 +1,282 @@
+// Featurize, the FOSS feature flagging
+// Copyright (C) 2024  Lucy Ekaterina
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use actix_web::{get, post, web, HttpResponse};
+use chrono::{DateTime, Utc};
+use sentry::{Hub, SentryFutureExt};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    flags::{Context, Definitions, Evaluation, Evaluator, FlagStore, Step},
+    renderer::Renderer,
+    Error,
+};
+
+#[derive(Debug, Serialize)]
+struct Explanation<'d> {
+    /// `None` when the flag is not configured in the environment.
+    value: Option<&'d Value>,
+    evaluation: Option<Evaluation>,
+    steps: Vec<Step>,
+}
+
+fn explain<'d>(
+    definitions: &'d Definitions,
+    environment: &str,
+    flag: &str,
+    context: &Context,
+    at: Option<DateTime<Utc>>,
+) -> Result<Explanation<'d>, Error> {
+    definitions
+        .environment(environment)
+        .ok_or(Error::NotFound)?;
+    let flag = definitions.flag(flag).ok_or(Error::NotFound)?;
+    let mut evaluator = Evaluator::new(definitions, environment);
+    if let Some(at) = at {
+        evaluator = evaluator.at(at);
+    }
+    let (evaluation, steps) = evaluator.explain(flag, context);
+    Ok(Explanation {
+        value: evaluation
+            .as_ref()
+            .map(|e| &flag.variations[e.variation].value),
+        evaluation,
+        steps,
+    })
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExplainQuery {
+    /// Evaluates as if it were this time, for `during` and `schedule` clauses.
+    at: Option<DateTime<Utc>>,
+}
+
+/// Traces how a flag is evaluated for the posted context.
+#[tracing::instrument]
+#[post("/explain/{environment}/{flag}")]
+pub async fn api_route(
+    flags: web::Data<FlagStore>,
+    path: web::Path<(String, String)>,
+    query: web::Query<ExplainQuery>,
+    context: web::Json<Context>,
+) -> Result<HttpResponse, Error> {
+    let hub = Hub::current();
+    api_handler(flags, path, query, context).bind_hub(hub).await
+}
+
+#[tracing::instrument]
+pub async fn api_handler(
+    flags: web::Data<FlagStore>,
+    path: web::Path<(String, String)>,
+    query: web::Query<ExplainQuery>,
+    context: web::Json<Context>,
+) -> Result<HttpResponse, Error> {
+    let (environment, flag) = path.into_inner();
+    let definitions = flags.definitions();
+    let explanation = explain(&definitions, &environment, &flag, &context, query.at)?;
+    Ok(HttpResponse::Ok().json(explanation))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExplainForm {
+    environment: String,
+    context: String,
+    /// An RFC 3339 time, the current time when empty.
+    #[serde(default)]
+    at: String,
+}
+
+#[tracing::instrument]
+#[get("/flags/{key}/explain")]
+pub async fn page_route(
+    renderer: web::Data<Renderer>,
+    flags: web::Data<FlagStore>,
+    key: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let hub = Hub::current();
+    page_handler(renderer, flags, key, None).bind_hub(hub).await
+}
+
+#[tracing::instrument]
+#[post("/flags/{key}/explain")]
+pub async fn submit_route(
+    renderer: web::Data<Renderer>,
+    flags: web::Data<FlagStore>,
+    key: web::Path<String>,
+    form: web::Form<ExplainForm>,
+) -> Result<HttpResponse, Error> {
+    let hub = Hub::current();
+    page_handler(renderer, flags, key, Some(form.into_inner()))
+        .bind_hub(hub)
+        .await
+}
+
+#[tracing::instrument]
+pub async fn page_handler(
+    renderer: web::Data<Renderer>,
+    flags: web::Data<FlagStore>,
+    key: web::Path<String>,
+    form: Option<ExplainForm>,
+) -> Result<HttpResponse, Error> {
+    let definitions = flags.definitions();
+    let flag = definitions.flag(&key).ok_or(Error::NotFound)?;
+    let mut render = renderer
+        .render("explain.html")
+        .var("flag", flag)
+        .var("environments", &definitions.environments);
+    if let Some(form) = form {
+        let at = match form.at.trim() {
+            "" => Ok(None),
+            at => DateTime::parse_from_rfc3339(at).map(|at| Some(at.with_timezone(&Utc))),
+        };
+        match (serde_json::from_str::<Context>(&form.context), at) {
+            (Ok(context), Ok(at)) => {
+                let explanation =
+                    explain(&definitions, &form.environment, &flag.key, &context, at)?;
+                render = render.var("explanation", &explanation);
+            }
+            (Err(e), _) => {
+                render = render.var("context_error", &e.to_string());
+            }
+            (_, Err(e)) => {
+                render = render.var("at_error", &e.to_string());
+            }
+        }
+        render = render
+            .var("environment", &form.environment)
+            .var("context", &form.context)
+            .var("at", &form.at);
+    }
+    Ok(render.ok().finish()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flags::Reason;
+
+    const DEFINITIONS: &str = r#"
+environments:
+  - key: production
+flags:
+  - key: beta
+    variations:
+      - value: true
+      - value: false
+    environments:
+      production:
+        on: true
+        off_variation: 1
+        targets:
+          - variation: 1
+            values: [bob]
+        fallthrough:
+          variation: 0
+  - key: checkout
+    variations:
+      - value: old
+      - value: new
+    environments:
+      production:
+        on: true
+        off_variation: 0
+        prerequisites:
+          - flag: beta
+            variation: 0
+        targets:
+          - variation: 1
+            values: [alice]
+        rules:
+          - clauses:
+              - attribute: country
+                op: in
+                values: [NZ]
+            serve:
+              variation: 1
+        fallthrough:
+          variation: 0
+"#;
+
+    fn explain_for(context: Value) -> (Evaluation, Step, Value) {
+        let definitions: Definitions = serde_yaml::from_str(DEFINITIONS).unwrap();
+        let context = context.as_object().unwrap().clone();
+        let explanation = explain(&definitions, "production", "checkout", &context, None).unwrap();
+        let value = explanation.value.unwrap().clone();
+        let deciding = explanation.steps.last().unwrap().clone();
+        (explanation.evaluation.unwrap(), deciding, value)
+    }
+
+    #[test]
+    fn names_the_step_that_decided() {
+        let (evaluation, step, value) = explain_for(serde_json::json!({ "key": "alice" }));
+        assert_eq!(evaluation.reason, Reason::TargetMatch);
+        assert_eq!(
+            step,
+            Step::Target {
+                variation: 1,
+                matched: true
+            }
+        );
+        assert_eq!(value, "new");
+
+        let (evaluation, step, value) =
+            explain_for(serde_json::json!({ "key": "carol", "country": "NZ" }));
+        assert_eq!(evaluation.reason, Reason::RuleMatch { rule: 0 });
+        assert_eq!(
+            step,
+            Step::Rule {
+                rule: 0,
+                matched: true
+            }
+        );
+        assert_eq!(value, "new");
+
+        let (evaluation, step, value) = explain_for(serde_json::json!({ "key": "dave" }));
+        assert_eq!(evaluation.reason, Reason::Fallthrough);
+        assert_eq!(step, Step::Fallthrough);
+        assert_eq!(value, "old");
+
+        let (evaluation, step, value) = explain_for(serde_json::json!({ "key": "bob" }));
+        assert_eq!(
+            evaluation.reason,
+            Reason::PrerequisiteFailed {
+                flag: "beta".to_string()
+            }
+        );
+        let Step::Prerequisite {
+            flag,
+            expected,
+            served,
+            passed,
+            steps,
+        } = step
+        else {
+            panic!("decided by {:?}", step);
+        };
+        assert_eq!(
+            (flag.as_str(), expected, served, passed),
+            ("beta", 0, Some(1), false)
+        );
+        assert_eq!(
+            steps.last(),
+            Some(&Step::Target {
+                variation: 1,
+                matched: true
+            })
+        );
+        assert_eq!(value, "old");
+    }
+}
@@ -0,0 +1,71 @@
+// Featurize, the FOSS feature flagging
+// Copyright (C) 2024  Lucy Ekaterina
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use actix_web::{get, web, HttpResponse};
+use sentry::{Hub, SentryFutureExt};
+use serde::Deserialize;
+
+use crate::{flags::FlagStore, Error};
+
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    #[default]
+    Yaml,
+    Json,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExportQuery {
+    #[serde(default)]
+    format: ExportFormat,
+}
+
+#[tracing::instrument]
+#[get("/flags/export")]
+pub async fn route(
+    flags: web::Data<FlagStore>,
+    query: web::Query<ExportQuery>,
+) -> Result<HttpResponse, Error> {
+    let hub = Hub::current();
+    handler(flags, query).bind_hub(hub).await
+}
+
+#[tracing::instrument]
+pub async fn handler(
+    flags: web::Data<FlagStore>,
+    query: web::Query<ExportQuery>,
+) -> Result<HttpResponse, Error> {
+    let definitions = flags.definitions();
+    let (content_type, extension, body) = match query.format {
+        ExportFormat::Yaml => (
+            "application/yaml",
+            "yaml",
+            serde_yaml::to_string(&*definitions)?,
+        ),
+        ExportFormat::Json => (
+            "application/json",
+            "json",
+            serde_json::to_string_pretty(&*definitions)?,
+        ),
+    };
+    Ok(HttpResponse::Ok()
+        .content_type(content_type)
+        .append_header((
+            "Content-Disposition",
+            format!("attachment; filename=\"flags.{}\"", extension),
+        ))
+        .body(body))
+}
@@ -0,0 +1,50 @@
+// Featurize, the FOSS feature flagging
+// Copyright (C) 2024  Lucy Ekaterina
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use actix_web::{get, web, HttpResponse};
+use sentry::{Hub, SentryFutureExt};
+
+use crate::{
+    flags::{self, FlagStore},
+    Error,
+};
+
+/// Serves an environment's flags in the flagd flag definition format so flagd
+/// can use featurize as an HTTP sync source.
+#[tracing::instrument]
+#[get("/flagd/{environment}")]
+pub async fn route(
+    flags: web::Data<FlagStore>,
+    environment: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let hub = Hub::current();
+    handler(flags, environment).bind_hub(hub).await
+}
+
+#[tracing::instrument]
+pub async fn handler(
+    flags: web::Data<FlagStore>,
+    environment: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let definitions = flags.definitions();
+    if definitions.environment(&environment).is_none() {
+        return Err(Error::NotFound);
+    }
+    let (document, unmapped) = flags::flagd::export(&definitions, &environment);
+    for unmapped in unmapped {
+        tracing::warn!("not served to flagd: {}", unmapped);
+    }
+    Ok(HttpResponse::Ok().json(document))
+}
@@ -0,0 +1,73 @@
+// Featurize, the FOSS feature flagging
+// Copyright (C) 2024  Lucy Ekaterina
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use std::path::PathBuf;
+
+mod attributes;
+mod changes;
+mod evaluate;
+pub mod flagd;
+mod import;
+mod model;
+mod operands;
+mod overrides;
+mod patch;
+mod promote;
+mod schedule;
+mod simulate;
+mod store;
+mod test_cases;
+mod validate;
+
+pub use attributes::*;
+pub use changes::*;
+pub use evaluate::*;
+pub use import::*;
+pub use model::*;
+pub use overrides::*;
+pub use patch::*;
+pub use promote::*;
+pub use schedule::*;
+pub use simulate::*;
+pub use store::*;
+pub use test_cases::*;
+pub use validate::*;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("could not read {}: {source}", path.display())]
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("could not parse {}: {message}", path.display())]
+    Parse { path: PathBuf, message: String },
+    #[error("invalid flag definitions:\n{0}")]
+    Invalid(ValidationErrors),
+    #[error("flag tests failed:\n{0}")]
+    TestsFailed(TestFailures),
+    #[error("environment \"{0}\" is frozen")]
+    Frozen(String),
+    #[error("cannot write flag definitions: {0}")]
+    NotWritable(String),
+    #[error("flag \"{0}\" changed since it was read")]
+    Stale(String),
+    #[error("change rejected: {0}")]
+    Rejected(String),
+    #[error("invalid override: {0}")]
+    InvalidOverride(String),
+    #[error("could not watch flag definitions: {0}")]
+    Watch(#[from] notify::Error),
+}
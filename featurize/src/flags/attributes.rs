@@ -0,0 +1,239 @@
+// Featurize, the FOSS feature flagging
+// Copyright (C) 2024  Lucy Ekaterina
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use std::collections::{BTreeMap, BTreeSet};
+
+use super::{AttributeType, Clause, Definitions, Flag, Op, Subject, ValidationError};
+
+/// The types each attribute is known to have, from the schema or from the
+/// contexts seen. An attribute with no types is only known by name.
+pub type KnownAttributes = BTreeMap<String, BTreeSet<AttributeType>>;
+
+/// Suggestions for misspelt attributes are at most this many edits away.
+const MAX_SUGGESTION_DISTANCE: usize = 2;
+
+pub fn schema_attributes(definitions: &Definitions) -> KnownAttributes {
+    definitions
+        .attributes
+        .iter()
+        .map(|a| (a.key.clone(), BTreeSet::from([a.kind])))
+        .collect()
+}
+
+/// Checks every flag and segment against the declared schema.
+pub fn schema_issues(definitions: &Definitions) -> Vec<ValidationError> {
+    let known = schema_attributes(definitions);
+    let mut issues = Vec::new();
+    for flag in &definitions.flags {
+        for environment in flag.environments.keys() {
+            issues.extend(flag_issues(flag, environment, &known));
+        }
+    }
+    for segment in &definitions.segments {
+        let subject = Subject::Segment(segment.key.clone());
+        for (environment, config) in &segment.environments {
+            let rules = config.rules.iter().map(|r| r.clauses.as_slice());
+            let path = format!("environments.{}", environment);
+            check_rules(&subject, &path, rules, &known, &mut issues);
+        }
+    }
+    issues
+}
+
+/// Checks the rules of `flag` in `environment`.
+pub fn flag_issues(
+    flag: &Flag,
+    environment: &str,
+    known: &KnownAttributes,
+) -> Vec<ValidationError> {
+    let mut issues = Vec::new();
+    if let Some(config) = flag.environments.get(environment) {
+        let rules = config.rules.iter().map(|r| r.clauses.as_slice());
+        let path = format!("environments.{}", environment);
+        check_rules(
+            &Subject::Flag(flag.key.clone()),
+            &path,
+            rules,
+            known,
+            &mut issues,
+        );
+    }
+    issues
+}
+
+fn check_rules<'r>(
+    subject: &Subject,
+    path: &str,
+    rules: impl Iterator<Item = &'r [Clause]>,
+    known: &KnownAttributes,
+    issues: &mut Vec<ValidationError>,
+) {
+    for (i, clauses) in rules.enumerate() {
+        for (j, clause) in clauses.iter().enumerate() {
+            if let Some(message) = clause_issue(clause, known) {
+                issues.push(ValidationError {
+                    file: None,
+                    subject: subject.clone(),
+                    path: format!("{}.rules[{}].clauses[{}]", path, i, j),
+                    message,
+                });
+            }
+        }
+    }
+}
+
+fn clause_issue(clause: &Clause, known: &KnownAttributes) -> Option<String> {
+    // Every context has a key, it needs no declaring.
+    if clause.op == Op::SegmentMatch || clause.op.uses_clock() || clause.attribute == "key" {
+        return None;
+    }
+    let Some(types) = known.get(&clause.attribute) else {
+        let mut message = format!("unknown attribute \"{}\"", clause.attribute);
+        if let Some(similar) = similar(&clause.attribute, known.keys()) {
+            message.push_str(&format!(", did you mean \"{}\"?", similar));
+        }
+        return Some(message);
+    };
+    let expected = expected_types(clause);
+    if types.is_empty() || expected.is_empty() || !types.is_disjoint(&expected) {
+        return None;
+    }
+    Some(format!(
+        "\"{}\" is {} but the clause expects {}",
+        clause.attribute,
+        describe(types),
+        describe(&expected)
+    ))
+}
+
+/// The attribute types that can match `clause`.
+fn expected_types(clause: &Clause) -> BTreeSet<AttributeType> {
+    match clause.op {
+        Op::In => clause.values.iter().filter_map(AttributeType::of).collect(),
+        Op::StartsWith
+        | Op::EndsWith
+        | Op::Contains
+        | Op::Matches
+        | Op::InCidr
+        | Op::SemverEqual
+        | Op::SemverLessThan
+        | Op::SemverLessThanOrEqual
+        | Op::SemverGreaterThan
+        | Op::SemverGreaterThanOrEqual => BTreeSet::from([AttributeType::String]),
+        Op::LessThan | Op::LessThanOrEqual | Op::GreaterThan | Op::GreaterThanOrEqual => {
+            BTreeSet::from([AttributeType::Number])
+        }
+        Op::Before | Op::After => BTreeSet::from([AttributeType::String, AttributeType::Number]),
+        Op::SegmentMatch | Op::During | Op::Schedule => BTreeSet::new(),
+    }
+}
+
+fn describe(types: &BTreeSet<AttributeType>) -> String {
+    let names: Vec<String> = types.iter().map(|t| format!("a {}", t)).collect();
+    names.join(" or ")
+}
+
+/// The closest of `candidates` to `name`, if any is close enough to be a typo.
+fn similar<'a>(name: &str, candidates: impl Iterator<Item = &'a String>) -> Option<&'a str> {
+    candidates
+        .map(|candidate| (edit_distance(name, candidate), candidate))
+        .filter(|(distance, _)| *distance <= MAX_SUGGESTION_DISTANCE)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, candidate)| candidate.as_str())
+}
+
+/// Levenshtein distance between `a` and `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != *cb);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn definitions(yaml: &str) -> Definitions {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn clauses_are_checked_against_the_schema() {
+        let definitions = definitions(
+            r#"
+environments:
+  - key: production
+attributes:
+  - key: plan
+    type: string
+  - key: age
+    type: number
+flags:
+  - key: pricing
+    variations:
+      - value: true
+      - value: false
+    environments:
+      production:
+        on: true
+        off_variation: 1
+        fallthrough:
+          variation: 1
+        rules:
+          - clauses:
+              - attribute: plann
+                op: in
+                values: [pro]
+              - attribute: age
+                op: starts_with
+                values: ["1"]
+              - attribute: age
+                op: in
+                values: [18, "18"]
+              - attribute: key
+                op: in
+                values: [alice]
+            serve:
+              variation: 0
+"#,
+        );
+
+        let issues: Vec<(String, String)> = schema_issues(&definitions)
+            .into_iter()
+            .map(|issue| (issue.path, issue.message))
+            .collect();
+        assert_eq!(
+            issues,
+            [
+                (
+                    "environments.production.rules[0].clauses[0]".to_string(),
+                    "unknown attribute \"plann\", did you mean \"plan\"?".to_string()
+                ),
+                (
+                    "environments.production.rules[0].clauses[1]".to_string(),
+                    "\"age\" is a number but the clause expects a string".to_string()
+                ),
+            ]
+        );
+    }
+}
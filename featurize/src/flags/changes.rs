@@ -0,0 +1,183 @@
+// Featurize, the FOSS feature flagging
+// Copyright (C) 2024  Lucy Ekaterina
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use serde::{Deserialize, Serialize};
+
+use super::{Definitions, FlagConfig, SegmentConfig};
+
+/// The configuration of a flag or segment in one environment before and after
+/// a change, `None` where it isn't configured.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum ConfigChange {
+    Flag {
+        key: String,
+        before: Option<FlagConfig>,
+        after: Option<FlagConfig>,
+    },
+    Segment {
+        key: String,
+        before: Option<SegmentConfig>,
+        after: Option<SegmentConfig>,
+    },
+}
+
+impl ConfigChange {
+    pub fn key(&self) -> &str {
+        match self {
+            ConfigChange::Flag { key, .. } | ConfigChange::Segment { key, .. } => key,
+        }
+    }
+}
+
+/// The configuration in `environment` changed by something else since it was
+/// read.
+#[derive(Debug, thiserror::Error)]
+#[error("{kind} \"{key}\" changed in {environment} since")]
+pub struct ConfigConflict {
+    pub kind: &'static str,
+    pub key: String,
+    pub environment: String,
+}
+
+/// Every flag and segment configured differently in `environment` by `after`.
+/// Flags and segments missing from either are left out.
+pub fn config_changes(
+    before: &Definitions,
+    after: &Definitions,
+    environment: &str,
+) -> Vec<ConfigChange> {
+    let flags = after.flags.iter().filter_map(|flag| {
+        let old = before.flag(&flag.key)?.environments.get(environment);
+        let new = flag.environments.get(environment);
+        (old != new).then(|| ConfigChange::Flag {
+            key: flag.key.clone(),
+            before: old.cloned(),
+            after: new.cloned(),
+        })
+    });
+    let segments = after.segments.iter().filter_map(|segment| {
+        let old = before
+            .segments
+            .iter()
+            .find(|s| s.key == segment.key)?
+            .environments
+            .get(environment);
+        let new = segment.environments.get(environment);
+        (old != new).then(|| ConfigChange::Segment {
+            key: segment.key.clone(),
+            before: old.cloned(),
+            after: new.cloned(),
+        })
+    });
+    flags.chain(segments).collect()
+}
+
+/// Applies `changes` to `environment`, as long as everything they change is
+/// still configured as it was before them.
+pub fn apply_config_changes(
+    definitions: &mut Definitions,
+    environment: &str,
+    changes: &[ConfigChange],
+) -> Result<(), ConfigConflict> {
+    for change in changes {
+        let conflict = |kind| ConfigConflict {
+            kind,
+            key: change.key().to_string(),
+            environment: environment.to_string(),
+        };
+        match change {
+            ConfigChange::Flag { key, before, after } => {
+                let flag = definitions
+                    .flags
+                    .iter_mut()
+                    .find(|f| &f.key == key)
+                    .ok_or_else(|| conflict("flag"))?;
+                replace(&mut flag.environments, environment, before, after)
+                    .ok_or_else(|| conflict("flag"))?;
+            }
+            ConfigChange::Segment { key, before, after } => {
+                let segment = definitions
+                    .segments
+                    .iter_mut()
+                    .find(|s| &s.key == key)
+                    .ok_or_else(|| conflict("segment"))?;
+                replace(&mut segment.environments, environment, before, after)
+                    .ok_or_else(|| conflict("segment"))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn replace<T: Clone + PartialEq>(
+    environments: &mut std::collections::BTreeMap<String, T>,
+    environment: &str,
+    before: &Option<T>,
+    after: &Option<T>,
+) -> Option<()> {
+    if environments.get(environment) != before.as_ref() {
+        return None;
+    }
+    match after {
+        Some(config) => environments.insert(environment.to_string(), config.clone()),
+        None => environments.remove(environment),
+    };
+    Some(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flags::Serve;
+
+    const DEFINITIONS: &str = r#"
+environments:
+  - key: production
+flags:
+  - key: checkout
+    variations:
+      - value: false
+      - value: true
+    environments:
+      production:
+        off_variation: 0
+        fallthrough:
+          variation: 0
+"#;
+
+    #[test]
+    fn conflicts_with_changes_made_since() {
+        let before: Definitions = serde_yaml::from_str(DEFINITIONS).unwrap();
+        let mut after = before.clone();
+        let config = after.flags[0].environments.get_mut("production").unwrap();
+        config.fallthrough = Serve::Variation(1);
+        let changes = config_changes(&before, &after, "production");
+        assert_eq!(changes.len(), 1);
+
+        let mut current = before.clone();
+        apply_config_changes(&mut current, "production", &changes).unwrap();
+        assert_eq!(current, after);
+
+        let mut changed_since = before.clone();
+        changed_since.flags[0]
+            .environments
+            .get_mut("production")
+            .unwrap()
+            .on = true;
+        let conflict = apply_config_changes(&mut changed_since, "production", &changes);
+        assert_eq!(conflict.unwrap_err().key, "checkout");
+    }
+}
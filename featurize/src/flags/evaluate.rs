@@ -0,0 +1,670 @@
+// Featurize, the FOSS feature flagging
+// Copyright (C) 2024  Lucy Ekaterina
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use std::fmt;
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::{Map, Value};
+use sha1::{Digest, Sha1};
+
+use super::{
+    operands::{self, Operand},
+    Clause, Definitions, Experiment, Flag, FlagConfig, Lifecycle, Op, Rollout, Serve,
+    ROLLOUT_WEIGHT_TOTAL,
+};
+
+/// The attributes a flag is evaluated for, `key` identifies the context.
+pub type Context = Map<String, Value>;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Evaluation {
+    pub variation: usize,
+    pub reason: Reason,
+    /// Set when the context takes part in an experiment.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub experiment: Option<String>,
+    /// SDKs report an exposure for these evaluations, experiments and
+    /// guardrails compare metrics between the contexts served each variation.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub track_exposure: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Reason {
+    Off,
+    PrerequisiteFailed {
+        flag: String,
+    },
+    TargetMatch,
+    /// In the environment's holdout, served the experiment's control.
+    Holdout,
+    /// Outside the experiment's slice of its layer, served its control.
+    NotInExperiment,
+    RuleMatch {
+        rule: usize,
+    },
+    Fallthrough,
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Reason::Off => write!(f, "the flag is off"),
+            Reason::PrerequisiteFailed { flag } => write!(f, "prerequisite {} failed", flag),
+            Reason::TargetMatch => write!(f, "individually targeted"),
+            Reason::Holdout => write!(f, "in the holdout"),
+            Reason::NotInExperiment => write!(f, "not in the experiment"),
+            Reason::RuleMatch { rule } => write!(f, "matched rule {}", rule),
+            Reason::Fallthrough => write!(f, "fallthrough"),
+        }
+    }
+}
+
+/// One step of an evaluation, recorded when explaining why a context is
+/// served a variation.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "step", rename_all = "snake_case")]
+pub enum Step {
+    Archived,
+    NotConfigured,
+    Off {
+        variation: usize,
+    },
+    Prerequisite {
+        flag: String,
+        expected: usize,
+        /// `None` when the prerequisite is not configured in the environment.
+        served: Option<usize>,
+        passed: bool,
+        steps: Vec<Step>,
+    },
+    Target {
+        variation: usize,
+        matched: bool,
+    },
+    Holdout {
+        bucket: u32,
+        holdout: u32,
+        held_out: bool,
+    },
+    Experiment {
+        experiment: String,
+        bucket: u32,
+        start: u32,
+        end: u32,
+        included: bool,
+    },
+    /// Clauses are checked in order until one does not match.
+    Clause {
+        rule: usize,
+        clause: usize,
+        attribute: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        value: Option<Value>,
+        matched: bool,
+    },
+    /// A clause that compares the time of evaluation.
+    Schedule {
+        rule: usize,
+        clause: usize,
+        at: DateTime<Utc>,
+        matched: bool,
+    },
+    Rule {
+        rule: usize,
+        matched: bool,
+    },
+    Fallthrough,
+    Rollout {
+        bucket_by: String,
+        bucket: u32,
+        variation: usize,
+    },
+}
+
+/// Where an evaluation records its steps. `()` records nothing and never
+/// builds a step, so evaluating without a trace costs nothing extra.
+pub trait Trace: Default {
+    fn record(&mut self, step: impl FnOnce() -> Step);
+
+    fn into_steps(self) -> Vec<Step>;
+}
+
+impl Trace for () {
+    #[inline(always)]
+    fn record(&mut self, _: impl FnOnce() -> Step) {}
+
+    fn into_steps(self) -> Vec<Step> {
+        Vec::new()
+    }
+}
+
+impl Trace for Vec<Step> {
+    fn record(&mut self, step: impl FnOnce() -> Step) {
+        self.push(step());
+    }
+
+    fn into_steps(self) -> Vec<Step> {
+        self
+    }
+}
+
+/// Evaluates flags against contexts in one environment.
+#[derive(Debug, Clone, Copy)]
+pub struct Evaluator<'d> {
+    definitions: &'d Definitions,
+    environment: &'d str,
+    /// The time `during` and `schedule` clauses are checked against.
+    now: DateTime<Utc>,
+}
+
+impl<'d> Evaluator<'d> {
+    pub fn new(definitions: &'d Definitions, environment: &'d str) -> Self {
+        Self {
+            definitions,
+            environment,
+            now: Utc::now(),
+        }
+    }
+
+    /// Evaluates as if it were `now` instead of the current time.
+    pub fn at(mut self, now: DateTime<Utc>) -> Self {
+        self.now = now;
+        self
+    }
+
+    /// `None` when the flag is archived or not configured in the environment,
+    /// callers should fall back to the default value in code.
+    pub fn evaluate(&self, flag: &Flag, context: &Context) -> Option<Evaluation> {
+        self.evaluate_traced(flag, context, &mut ())
+    }
+
+    /// Evaluates like [`Evaluator::evaluate`] and returns every step taken.
+    pub fn explain(&self, flag: &Flag, context: &Context) -> (Option<Evaluation>, Vec<Step>) {
+        let mut steps = Vec::new();
+        let evaluation = self.evaluate_traced(flag, context, &mut steps);
+        (evaluation, steps)
+    }
+
+    fn evaluate_traced<T: Trace>(
+        &self,
+        flag: &Flag,
+        context: &Context,
+        trace: &mut T,
+    ) -> Option<Evaluation> {
+        if flag.lifecycle == Lifecycle::Archived {
+            trace.record(|| Step::Archived);
+            return None;
+        }
+        let Some(config) = flag.environments.get(self.environment) else {
+            trace.record(|| Step::NotConfigured);
+            return None;
+        };
+        let served = |variation, reason| Evaluation {
+            variation,
+            reason,
+            experiment: None,
+            track_exposure: false,
+        };
+        if !config.on {
+            trace.record(|| Step::Off {
+                variation: config.off_variation,
+            });
+            return Some(served(config.off_variation, Reason::Off));
+        }
+        for prerequisite in &config.prerequisites {
+            let mut steps = T::default();
+            let evaluation = self
+                .definitions
+                .flag(&prerequisite.flag)
+                .and_then(|flag| self.evaluate_traced(flag, context, &mut steps));
+            let passed = evaluation
+                .as_ref()
+                .is_some_and(|e| e.reason != Reason::Off && e.variation == prerequisite.variation);
+            trace.record(|| Step::Prerequisite {
+                flag: prerequisite.flag.clone(),
+                expected: prerequisite.variation,
+                served: evaluation.as_ref().map(|e| e.variation),
+                passed,
+                steps: steps.into_steps(),
+            });
+            if !passed {
+                let reason = Reason::PrerequisiteFailed {
+                    flag: prerequisite.flag.clone(),
+                };
+                return Some(served(config.off_variation, reason));
+            }
+        }
+        let key = context_key(context);
+        for target in &config.targets {
+            let matched = key.is_some_and(|key| target.values.iter().any(|v| v == key));
+            trace.record(|| Step::Target {
+                variation: target.variation,
+                matched,
+            });
+            if matched {
+                return Some(served(target.variation, Reason::TargetMatch));
+            }
+        }
+
+        let experiment = self
+            .definitions
+            .running_experiment(&flag.key, self.environment);
+        if let Some(experiment) = experiment {
+            if let Some(reason) = self.excluded_from(experiment, key, trace) {
+                return Some(served(experiment.control, reason));
+            }
+        }
+        let mut evaluation = self.serve_rules(flag, config, context, trace);
+        evaluation.experiment = experiment.map(|e| e.key.clone());
+        evaluation.track_exposure = experiment.is_some() || config.guardrails.is_some();
+        Some(evaluation)
+    }
+
+    fn serve_rules<T: Trace>(
+        &self,
+        flag: &Flag,
+        config: &FlagConfig,
+        context: &Context,
+        trace: &mut T,
+    ) -> Evaluation {
+        let matched = config.rules.iter().enumerate().find(|(i, rule)| {
+            let matched = rule.clauses.iter().enumerate().all(|(j, clause)| {
+                let matched = self.matches(clause, context);
+                trace.record(|| {
+                    if clause.op.uses_clock() {
+                        Step::Schedule {
+                            rule: *i,
+                            clause: j,
+                            at: self.now,
+                            matched,
+                        }
+                    } else {
+                        Step::Clause {
+                            rule: *i,
+                            clause: j,
+                            attribute: clause.attribute.clone(),
+                            value: context.get(&clause.attribute).cloned(),
+                            matched,
+                        }
+                    }
+                });
+                matched
+            });
+            trace.record(|| Step::Rule { rule: *i, matched });
+            matched
+        });
+        let (serve, reason) = match matched {
+            Some((i, rule)) => (&rule.serve, Reason::RuleMatch { rule: i }),
+            None => {
+                trace.record(|| Step::Fallthrough);
+                (&config.fallthrough, Reason::Fallthrough)
+            }
+        };
+        let variation = match serve {
+            Serve::Variation(variation) => *variation,
+            Serve::Rollout(rollout) => {
+                let (variation, bucket) = rollout_variation(&flag.key, rollout, context);
+                trace.record(|| Step::Rollout {
+                    bucket_by: rollout
+                        .bucket_by
+                        .clone()
+                        .unwrap_or_else(|| "key".to_string()),
+                    bucket,
+                    variation,
+                });
+                variation
+            }
+        };
+        Evaluation {
+            variation,
+            reason,
+            experiment: None,
+            track_exposure: false,
+        }
+    }
+
+    /// Why a context does not take part in `experiment`, if it doesn't.
+    fn excluded_from<T: Trace>(
+        &self,
+        experiment: &Experiment,
+        key: Option<&str>,
+        trace: &mut T,
+    ) -> Option<Reason> {
+        let Some(key) = key else {
+            return Some(Reason::NotInExperiment);
+        };
+        let holdout = self
+            .definitions
+            .environment(self.environment)
+            .and_then(|e| e.holdout)
+            .unwrap_or(0);
+        if holdout > 0 {
+            let bucket = bucket(&format!("holdout.{}", self.environment), key);
+            trace.record(|| Step::Holdout {
+                bucket,
+                holdout,
+                held_out: bucket < holdout,
+            });
+            if bucket < holdout {
+                return Some(Reason::Holdout);
+            }
+        }
+        let (salt, start) = match &experiment.layer {
+            Some(layer) => {
+                let start: u32 = self
+                    .definitions
+                    .experiments
+                    .iter()
+                    .take_while(|e| e.key != experiment.key)
+                    .filter(|e| {
+                        e.layer.as_ref() == Some(layer) && e.environment == experiment.environment
+                    })
+                    .map(|e| e.traffic)
+                    .sum();
+                (format!("layer.{}.{}", layer, self.environment), start)
+            }
+            None => (format!("experiment.{}", experiment.key), 0),
+        };
+        let slot = bucket(&salt, key);
+        let end = start.saturating_add(experiment.traffic);
+        let included = (start..end).contains(&slot);
+        trace.record(|| Step::Experiment {
+            experiment: experiment.key.clone(),
+            bucket: slot,
+            start,
+            end,
+            included,
+        });
+        if !included {
+            return Some(Reason::NotInExperiment);
+        }
+        None
+    }
+
+    fn matches(&self, clause: &Clause, context: &Context) -> bool {
+        self.clause_matches(clause, context, &mut Vec::new())
+    }
+
+    /// `visiting` holds the segments being matched, guarding against segments
+    /// that reference each other.
+    fn clause_matches<'a>(
+        &'a self,
+        clause: &'a Clause,
+        context: &Context,
+        visiting: &mut Vec<&'a str>,
+    ) -> bool {
+        let matched = if clause.op == Op::SegmentMatch {
+            clause
+                .values
+                .iter()
+                .filter_map(Value::as_str)
+                .any(|segment| self.in_segment(segment, context, visiting))
+        } else if clause.op.uses_clock() {
+            clause.operands().iter().any(|operand| match operand {
+                Ok(Operand::Window(window)) => window.contains(self.now),
+                Ok(Operand::Schedule(schedule)) => schedule.contains(self.now),
+                _ => false,
+            })
+        } else {
+            let items = match context.get(&clause.attribute) {
+                Some(Value::Array(items)) => items.as_slice(),
+                Some(value) => std::slice::from_ref(value),
+                None => return false,
+            };
+            let results: Vec<bool> = items
+                .iter()
+                .filter_map(|item| matches_value(clause, item))
+                .collect();
+            // Like a missing attribute, one that can't be read as the type the
+            // operator compares fails the clause even when it is negated.
+            if results.is_empty() {
+                return false;
+            }
+            results.contains(&true)
+        };
+        matched != clause.negate
+    }
+
+    fn in_segment<'a>(
+        &'a self,
+        key: &'a str,
+        context: &Context,
+        visiting: &mut Vec<&'a str>,
+    ) -> bool {
+        if visiting.contains(&key) {
+            return false;
+        }
+        let Some(config) = self
+            .definitions
+            .segments
+            .iter()
+            .find(|s| s.key == key)
+            .and_then(|s| s.environments.get(self.environment))
+        else {
+            return false;
+        };
+        if let Some(context_key) = context_key(context) {
+            if config.included.iter().any(|k| k == context_key) {
+                return true;
+            }
+            if config.excluded.iter().any(|k| k == context_key) {
+                return false;
+            }
+        }
+        visiting.push(key);
+        let matched = config.rules.iter().any(|rule| {
+            rule.clauses
+                .iter()
+                .all(|clause| self.clause_matches(clause, context, visiting))
+        });
+        visiting.pop();
+        matched
+    }
+}
+
+fn context_key(context: &Context) -> Option<&str> {
+    context.get("key").and_then(Value::as_str)
+}
+
+/// `None` when the attribute can't be read as a version, time or address.
+fn matches_value(clause: &Clause, attribute: &Value) -> Option<bool> {
+    let mut values = clause.values.iter();
+    let mut parsed = clause.operands().iter().filter_map(|o| o.as_ref().ok());
+    let matched = match clause.op {
+        Op::SemverEqual
+        | Op::SemverLessThan
+        | Op::SemverLessThanOrEqual
+        | Op::SemverGreaterThan
+        | Op::SemverGreaterThanOrEqual => {
+            let version = operands::version_attribute(attribute)?;
+            parsed.any(|operand| {
+                let Operand::Version(value) = operand else {
+                    return false;
+                };
+                let ordering = version.cmp_precedence(value);
+                match clause.op {
+                    Op::SemverEqual => ordering.is_eq(),
+                    Op::SemverLessThan => ordering.is_lt(),
+                    Op::SemverLessThanOrEqual => ordering.is_le(),
+                    Op::SemverGreaterThan => ordering.is_gt(),
+                    _ => ordering.is_ge(),
+                }
+            })
+        }
+        Op::Before | Op::After => {
+            let time = operands::time(attribute).ok()?;
+            parsed.any(|operand| match (clause.op, operand) {
+                (Op::Before, Operand::Time(value)) => time < *value,
+                (Op::After, Operand::Time(value)) => time > *value,
+                _ => false,
+            })
+        }
+        Op::Matches => {
+            let text = attribute.as_str()?;
+            parsed.any(|operand| matches!(operand, Operand::Pattern(p) if p.is_match(text)))
+        }
+        Op::InCidr => {
+            let address = operands::address(attribute)?;
+            parsed.any(|operand| matches!(operand, Operand::Network(n) if n.contains(&address)))
+        }
+        op => values.any(|value| match op {
+            Op::In => attribute == value,
+            Op::StartsWith => strings(attribute, value).is_some_and(|(a, v)| a.starts_with(v)),
+            Op::EndsWith => strings(attribute, value).is_some_and(|(a, v)| a.ends_with(v)),
+            Op::Contains => strings(attribute, value).is_some_and(|(a, v)| a.contains(v)),
+            Op::LessThan => numbers(attribute, value).is_some_and(|(a, v)| a < v),
+            Op::LessThanOrEqual => numbers(attribute, value).is_some_and(|(a, v)| a <= v),
+            Op::GreaterThan => numbers(attribute, value).is_some_and(|(a, v)| a > v),
+            Op::GreaterThanOrEqual => numbers(attribute, value).is_some_and(|(a, v)| a >= v),
+            _ => false,
+        }),
+    };
+    Some(matched)
+}
+
+fn strings<'v>(attribute: &'v Value, value: &'v Value) -> Option<(&'v str, &'v str)> {
+    Some((attribute.as_str()?, value.as_str()?))
+}
+
+fn numbers(attribute: &Value, value: &Value) -> Option<(f64, f64)> {
+    Some((attribute.as_f64()?, value.as_f64()?))
+}
+
+/// The variation served and the bucket the context fell in.
+fn rollout_variation(flag: &str, rollout: &Rollout, context: &Context) -> (usize, u32) {
+    let attribute = rollout.bucket_by.as_deref().unwrap_or("key");
+    let slot = match context.get(attribute) {
+        Some(Value::String(value)) => bucket(flag, value),
+        Some(Value::Number(value)) => bucket(flag, &value.to_string()),
+        _ => 0,
+    };
+    let mut end = 0;
+    for weighted in &rollout.variations {
+        end += weighted.weight;
+        if slot < end {
+            return (weighted.variation, slot);
+        }
+    }
+    let last = rollout
+        .variations
+        .last()
+        .map(|w| w.variation)
+        .unwrap_or_default();
+    (last, slot)
+}
+
+/// Deterministically places `value` in one of `ROLLOUT_WEIGHT_TOTAL` slots.
+/// Different salts give independent placements for the same value.
+pub fn bucket(salt: &str, value: &str) -> u32 {
+    let digest = Sha1::new()
+        .chain_update(salt)
+        .chain_update(".")
+        .chain_update(value)
+        .finalize();
+    let mut prefix = [0; 8];
+    prefix.copy_from_slice(&digest[..8]);
+    (u64::from_be_bytes(prefix) % ROLLOUT_WEIGHT_TOTAL as u64) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEFINITIONS: &str = r#"
+environments:
+  - key: production
+    holdout: 20000
+layers:
+  - key: checkout
+flags:
+  - key: new-checkout
+    variations:
+      - value: true
+      - value: false
+    environments:
+      production:
+        on: true
+        off_variation: 1
+        fallthrough:
+          variation: 0
+  - key: one-click
+    variations:
+      - value: true
+      - value: false
+    environments:
+      production:
+        on: true
+        off_variation: 1
+        fallthrough:
+          variation: 0
+experiments:
+  - key: new-checkout-test
+    flag: new-checkout
+    environment: production
+    control: 1
+    metrics:
+      - key: purchase
+    layer: checkout
+    traffic: 50000
+  - key: one-click-test
+    flag: one-click
+    environment: production
+    control: 1
+    metrics:
+      - key: purchase
+    layer: checkout
+    traffic: 50000
+"#;
+
+    fn context(key: &str) -> Context {
+        let mut context = Context::new();
+        context.insert("key".to_string(), Value::from(key));
+        context
+    }
+
+    #[test]
+    fn layers_keep_experiments_apart_and_holdouts_get_the_control() {
+        let definitions: Definitions = serde_yaml::from_str(DEFINITIONS).unwrap();
+        let evaluator = Evaluator::new(&definitions, "production");
+        let mut held_out = 0;
+        for i in 0..1000 {
+            let context = context(&format!("user-{}", i));
+            let evaluations: Vec<Evaluation> = definitions
+                .flags
+                .iter()
+                .map(|flag| evaluator.evaluate(flag, &context).unwrap())
+                .collect();
+            let entered = evaluations.iter().filter(|e| e.experiment.is_some());
+            if evaluations.iter().any(|e| e.reason == Reason::Holdout) {
+                held_out += 1;
+                assert!(evaluations
+                    .iter()
+                    .all(|e| e.reason == Reason::Holdout && e.variation == 1));
+            } else {
+                // The two experiments split the whole layer between them.
+                assert_eq!(entered.count(), 1, "user-{}", i);
+            }
+            for evaluation in &evaluations {
+                if evaluation.experiment.is_none() {
+                    assert_eq!(evaluation.variation, 1);
+                }
+            }
+        }
+        assert!((150..250).contains(&held_out), "{} held out", held_out);
+    }
+}
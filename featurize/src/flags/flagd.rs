@@ -0,0 +1,1070 @@
+// Featurize, the FOSS feature flagging
+// Copyright (C) 2024  Lucy Ekaterina
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use std::collections::BTreeMap;
+
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+use super::{
+    import::{clause, normalize_weights, to_key},
+    operands, Clause, Definitions, Environment, Flag, FlagConfig, FlagKind, Import, Lifecycle, Op,
+    Rollout, Rule, SegmentConfig, Serve, Unmapped, Variation,
+};
+
+/// Targeting is flattened into an OR of ANDs, this caps how many ANDs a single
+/// condition may expand to.
+const MAX_ALTERNATIVES: usize = 32;
+
+#[derive(Debug, Deserialize)]
+pub struct Document {
+    #[serde(default)]
+    flags: BTreeMap<String, FlagdFlag>,
+    #[serde(default, rename = "$evaluators")]
+    evaluators: Map<String, Value>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct FlagdFlag {
+    state: String,
+    variants: Map<String, Value>,
+    default_variant: String,
+    #[serde(default)]
+    targeting: Option<Value>,
+}
+
+pub fn import(document: Document, environment: &str) -> Import {
+    let mut import = Import::default();
+    let env = to_key(environment);
+    import.definitions.environments.push(Environment {
+        key: env.clone(),
+        name: None,
+        holdout: None,
+        approvals: None,
+    });
+
+    for (name, flag) in &document.flags {
+        let key = to_key(name);
+        let item = format!("flag {}", name);
+        let variants: Vec<&String> = flag.variants.keys().collect();
+        let Some(default_variant) = variants.iter().position(|v| **v == flag.default_variant)
+        else {
+            import.unmapped(
+                item,
+                format!(
+                    "default variant {} does not exist, flag skipped",
+                    flag.default_variant
+                ),
+            );
+            continue;
+        };
+
+        let translator = Targeting {
+            evaluators: &document.evaluators,
+            variants: &variants,
+        };
+        let (rules, fallthrough) = match flag.targeting.as_ref().map(|t| translator.targeting(t)) {
+            None => (Vec::new(), None),
+            Some(Ok(translated)) => translated,
+            Some(Err(reason)) => {
+                import.unmapped(
+                    item.clone(),
+                    format!(
+                        "{}, targeting skipped and the default variant is served",
+                        reason
+                    ),
+                );
+                (Vec::new(), None)
+            }
+        };
+
+        import.definitions.flags.push(Flag {
+            name: (key != *name).then(|| name.clone()),
+            key,
+            description: None,
+            kind: FlagKind::default(),
+            lifecycle: Lifecycle::default(),
+            tags: Vec::new(),
+            owner: None,
+            kill_switch: None,
+            variations: flag
+                .variants
+                .iter()
+                .map(|(name, value)| Variation {
+                    value: value.clone(),
+                    name: Some(name.clone()),
+                })
+                .collect(),
+            environments: BTreeMap::from([(
+                env.clone(),
+                FlagConfig {
+                    on: flag.state == "ENABLED",
+                    off_variation: default_variant,
+                    fallthrough: fallthrough.unwrap_or(Serve::Variation(default_variant)),
+                    prerequisites: Vec::new(),
+                    targets: Vec::new(),
+                    rules,
+                    guardrails: None,
+                },
+            )]),
+            webhooks: Vec::new(),
+            tests: Vec::new(),
+        });
+    }
+
+    import
+}
+
+/// Builds the flagd flag definitions for an environment. Segments are served
+/// as `$evaluators` that rules refer to with `$ref`. Flags with prerequisites
+/// and clauses using the time, schedule, regular expression and CIDR
+/// operators have no flagd equivalent, so those flags and segments are left
+/// out and clients fall back to their defaults. Flags under an experiment are
+/// exported without its holdout and traffic allocation, so flagd puts every
+/// context in the experiment.
+pub fn export(definitions: &Definitions, environment: &str) -> (Value, Vec<Unmapped>) {
+    let mut unmapped = Vec::new();
+
+    let empty = SegmentConfig::default();
+    let segments: Vec<(&str, &SegmentConfig)> = definitions
+        .segments
+        .iter()
+        .map(|s| {
+            (
+                s.key.as_str(),
+                s.environments.get(environment).unwrap_or(&empty),
+            )
+        })
+        .collect();
+    // Segments can refer to each other, so keep going until no more are left out.
+    let mut left_out = BTreeMap::new();
+    loop {
+        let before = left_out.len();
+        for (key, config) in &segments {
+            if left_out.contains_key(key) {
+                continue;
+            }
+            let reason = config
+                .rules
+                .iter()
+                .find_map(|rule| unsupported(&rule.clauses, &left_out));
+            if let Some(reason) = reason {
+                left_out.insert(*key, reason);
+            }
+        }
+        if left_out.len() == before {
+            break;
+        }
+    }
+
+    let mut evaluators = Map::new();
+    for (key, config) in &segments {
+        if let Some(reason) = left_out.get(key) {
+            unmapped.push(Unmapped {
+                item: format!("segment {}", key),
+                reason: format!("{}, segment left out", reason),
+            });
+            continue;
+        }
+        evaluators.insert(segment_evaluator(key), segment_logic(config));
+    }
+
+    let mut flags = Map::new();
+    for flag in &definitions.flags {
+        let Some(config) = flag.environments.get(environment) else {
+            continue;
+        };
+        if flag.lifecycle == Lifecycle::Archived {
+            continue;
+        }
+        if !config.prerequisites.is_empty() {
+            unmapped.push(Unmapped {
+                item: format!("flag {}", flag.key),
+                reason: "prerequisites are not supported by flagd, flag left out".to_string(),
+            });
+            continue;
+        }
+        if let Some(reason) = config
+            .rules
+            .iter()
+            .find_map(|rule| unsupported(&rule.clauses, &left_out))
+        {
+            unmapped.push(Unmapped {
+                item: format!("flag {}", flag.key),
+                reason: format!("{}, flag left out", reason),
+            });
+            continue;
+        }
+        if let Some(experiment) = definitions.running_experiment(&flag.key, environment) {
+            unmapped.push(Unmapped {
+                item: format!("experiment {}", experiment.key),
+                reason: "holdouts and layer traffic are not supported by flagd".to_string(),
+            });
+        }
+        flags.insert(flag.key.clone(), export_flag(flag, config));
+    }
+
+    let mut document = Map::new();
+    document.insert(
+        "$schema".to_string(),
+        "https://flagd.dev/schema/v0/flags.json".into(),
+    );
+    document.insert("flags".to_string(), Value::Object(flags));
+    if !evaluators.is_empty() {
+        document.insert("$evaluators".to_string(), Value::Object(evaluators));
+    }
+    (Value::Object(document), unmapped)
+}
+
+/// Why flagd can't evaluate `clauses`, given the segments already left out.
+fn unsupported(clauses: &[Clause], left_out: &BTreeMap<&str, String>) -> Option<String> {
+    clauses.iter().find_map(|clause| match clause.op {
+        Op::Before => Some("operator before is not supported by flagd".to_string()),
+        Op::After => Some("operator after is not supported by flagd".to_string()),
+        Op::Matches => Some("operator matches is not supported by flagd".to_string()),
+        Op::InCidr => Some("operator in_cidr is not supported by flagd".to_string()),
+        Op::During => Some("operator during is not supported by flagd".to_string()),
+        Op::Schedule => Some("operator schedule is not supported by flagd".to_string()),
+        Op::SegmentMatch => clause
+            .values
+            .iter()
+            .filter_map(Value::as_str)
+            .find(|segment| left_out.contains_key(segment))
+            .map(|segment| format!("refers to segment {} which is left out", segment)),
+        _ => None,
+    })
+}
+
+/// The names flag evaluators know each variation by: its name, unless it has
+/// none or an earlier variation took it.
+pub fn variant_names(flag: &Flag) -> Vec<String> {
+    let mut names: Vec<String> = Vec::with_capacity(flag.variations.len());
+    for (i, variation) in flag.variations.iter().enumerate() {
+        let name = match &variation.name {
+            Some(name) if !names.contains(name) => name.clone(),
+            _ => format!("variation-{}", i),
+        };
+        names.push(name);
+    }
+    names
+}
+
+fn export_flag(flag: &Flag, config: &FlagConfig) -> Value {
+    let names = variant_names(flag);
+    let variants: Map<String, Value> = names
+        .iter()
+        .cloned()
+        .zip(flag.variations.iter().map(|v| v.value.clone()))
+        .collect();
+
+    let mut exported = Map::new();
+    exported.insert("state".to_string(), "ENABLED".into());
+    exported.insert("variants".to_string(), Value::Object(variants));
+    if !config.on {
+        exported.insert(
+            "defaultVariant".to_string(),
+            names[config.off_variation].clone().into(),
+        );
+        return Value::Object(exported);
+    }
+
+    let mut branches = Vec::new();
+    for target in &config.targets {
+        branches.push(json_logic(
+            "in",
+            vec![var("key"), target.values.clone().into()],
+        ));
+        branches.push(names[target.variation].clone().into());
+    }
+    for rule in &config.rules {
+        branches.push(all(rule.clauses.iter().map(clause_logic).collect()));
+        branches.push(serve_logic(&names, &rule.serve));
+    }
+    let default_variant = match &config.fallthrough {
+        Serve::Variation(variation) => *variation,
+        Serve::Rollout(_) => {
+            branches.push(serve_logic(&names, &config.fallthrough));
+            config.off_variation
+        }
+    };
+    exported.insert(
+        "defaultVariant".to_string(),
+        names[default_variant].clone().into(),
+    );
+
+    let targeting = match branches.len() {
+        0 => None,
+        1 => branches.pop(),
+        _ => Some(json_logic("if", branches)),
+    };
+    if let Some(targeting) = targeting {
+        exported.insert("targeting".to_string(), targeting);
+    }
+    Value::Object(exported)
+}
+
+fn segment_evaluator(key: &str) -> String {
+    format!("segment-{}", key)
+}
+
+fn segment_logic(config: &SegmentConfig) -> Value {
+    let included = json_logic("in", vec![var("key"), config.included.clone().into()]);
+    let mut matches = Vec::new();
+    if !config.included.is_empty() {
+        matches.push(included.clone());
+    }
+    if !config.rules.is_empty() {
+        let rules = any(config
+            .rules
+            .iter()
+            .map(|rule| all(rule.clauses.iter().map(clause_logic).collect()))
+            .collect());
+        if config.excluded.is_empty() {
+            matches.push(rules);
+        } else {
+            let excluded = json_logic("in", vec![var("key"), config.excluded.clone().into()]);
+            matches.push(all(vec![json_logic("!", vec![excluded]), rules]));
+        }
+    }
+    if matches.is_empty() {
+        included
+    } else {
+        any(matches)
+    }
+}
+
+fn clause_logic(clause: &Clause) -> Value {
+    let attribute = var(&clause.attribute);
+    let logic = match (clause.op, clause.values.as_slice()) {
+        (Op::In, [value]) => json_logic("==", vec![attribute, value.clone()]),
+        (Op::In, values) => json_logic("in", vec![attribute, values.to_vec().into()]),
+        (Op::SegmentMatch, values) => any(values
+            .iter()
+            .map(|v| {
+                let key = v.as_str().unwrap_or_default();
+                let mut reference = Map::new();
+                reference.insert("$ref".to_string(), segment_evaluator(key).into());
+                Value::Object(reference)
+            })
+            .collect()),
+        (op, values) => any(values
+            .iter()
+            .map(|value| match op {
+                Op::Contains => json_logic("in", vec![value.clone(), attribute.clone()]),
+                Op::SemverEqual
+                | Op::SemverLessThan
+                | Op::SemverLessThanOrEqual
+                | Op::SemverGreaterThan
+                | Op::SemverGreaterThanOrEqual => json_logic(
+                    "sem_ver",
+                    vec![
+                        attribute.clone(),
+                        comparison_operator(op).into(),
+                        value.clone(),
+                    ],
+                ),
+                op => json_logic(
+                    comparison_operator(op),
+                    vec![attribute.clone(), value.clone()],
+                ),
+            })
+            .collect()),
+    };
+    if clause.negate {
+        json_logic("!", vec![logic])
+    } else {
+        logic
+    }
+}
+
+fn comparison_operator(op: Op) -> &'static str {
+    match op {
+        Op::StartsWith => "starts_with",
+        Op::EndsWith => "ends_with",
+        Op::SemverEqual => "=",
+        Op::LessThan | Op::SemverLessThan => "<",
+        Op::LessThanOrEqual | Op::SemverLessThanOrEqual => "<=",
+        Op::GreaterThan | Op::SemverGreaterThan => ">",
+        Op::GreaterThanOrEqual | Op::SemverGreaterThanOrEqual => ">=",
+        Op::In
+        | Op::Contains
+        | Op::SegmentMatch
+        | Op::Before
+        | Op::After
+        | Op::Matches
+        | Op::InCidr
+        | Op::During
+        | Op::Schedule => unreachable!(),
+    }
+}
+
+fn serve_logic(names: &[String], serve: &Serve) -> Value {
+    match serve {
+        Serve::Variation(variation) => names[*variation].clone().into(),
+        Serve::Rollout(rollout) => {
+            let scale = if rollout.variations.iter().all(|v| v.weight % 1000 == 0) {
+                1000
+            } else {
+                1
+            };
+            let mut args = Vec::new();
+            if let Some(bucket_by) = &rollout.bucket_by {
+                args.push(var(bucket_by));
+            }
+            for weighted in &rollout.variations {
+                args.push(Value::Array(vec![
+                    names[weighted.variation].clone().into(),
+                    (weighted.weight / scale).into(),
+                ]));
+            }
+            json_logic("fractional", args)
+        }
+    }
+}
+
+fn var(attribute: &str) -> Value {
+    let name = if attribute == "key" {
+        "targetingKey"
+    } else {
+        attribute
+    };
+    json_logic("var", vec![name.into()])
+}
+
+fn json_logic(operator: &str, args: Vec<Value>) -> Value {
+    let args = match (operator, args.as_slice()) {
+        ("var" | "!", [arg]) => arg.clone(),
+        _ => Value::Array(args),
+    };
+    let mut logic = Map::new();
+    logic.insert(operator.to_string(), args);
+    Value::Object(logic)
+}
+
+fn all(mut conditions: Vec<Value>) -> Value {
+    if conditions.len() == 1 {
+        conditions.remove(0)
+    } else {
+        json_logic("and", conditions)
+    }
+}
+
+fn any(mut conditions: Vec<Value>) -> Value {
+    if conditions.len() == 1 {
+        conditions.remove(0)
+    } else {
+        json_logic("or", conditions)
+    }
+}
+
+/// Translates JsonLogic targeting into rules. Only the shapes that have an
+/// equivalent rule are supported: an `if` chain whose conditions are
+/// comparisons of a context attribute with literals combined with `and`, `or`
+/// and `!`, and whose results are variants or `fractional` splits.
+struct Targeting<'a> {
+    evaluators: &'a Map<String, Value>,
+    variants: &'a [&'a String],
+}
+
+impl<'a> Targeting<'a> {
+    fn targeting(&self, targeting: &Value) -> Result<(Vec<Rule>, Option<Serve>), String> {
+        let targeting = self.resolve(targeting)?;
+        match targeting {
+            Value::Null => Ok((Vec::new(), None)),
+            Value::Object(map) if map.is_empty() => Ok((Vec::new(), None)),
+            Value::Object(map) if map.contains_key("if") => {
+                let args = as_args(&map["if"]);
+                let mut rules = Vec::new();
+                for pair in args.chunks(2) {
+                    match pair {
+                        [condition, result] => {
+                            let serve = self.serve(result)?;
+                            for clauses in self.condition(condition)? {
+                                if clauses.is_empty() {
+                                    return Err(
+                                        "conditions that are always true are not supported"
+                                            .to_string(),
+                                    );
+                                }
+                                rules.push(Rule {
+                                    clauses,
+                                    serve: serve.clone(),
+                                });
+                            }
+                        }
+                        [otherwise] => {
+                            return Ok((rules, self.optional_serve(otherwise)?));
+                        }
+                        _ => unreachable!(),
+                    }
+                }
+                Ok((rules, None))
+            }
+            other => Ok((Vec::new(), self.optional_serve(other)?)),
+        }
+    }
+
+    fn resolve<'v>(&'v self, value: &'v Value) -> Result<&'v Value, String> {
+        match value.get("$ref").and_then(Value::as_str) {
+            Some(name) => self
+                .evaluators
+                .get(name)
+                .ok_or_else(|| format!("unknown evaluator {}", name)),
+            None => Ok(value),
+        }
+    }
+
+    fn optional_serve(&self, result: &Value) -> Result<Option<Serve>, String> {
+        match result {
+            Value::Null => Ok(None),
+            result => self.serve(result).map(Some),
+        }
+    }
+
+    fn variant(&self, name: &str) -> Result<usize, String> {
+        self.variants
+            .iter()
+            .position(|v| *v == name)
+            .ok_or_else(|| format!("unknown variant {}", name))
+    }
+
+    fn serve(&self, result: &Value) -> Result<Serve, String> {
+        match self.resolve(result)? {
+            Value::String(variant) => Ok(Serve::Variation(self.variant(variant)?)),
+            Value::Object(map) if map.contains_key("fractional") => {
+                self.fractional(as_args(&map["fractional"]))
+            }
+            _ => Err("only variants and fractional splits can be served".to_string()),
+        }
+    }
+
+    fn fractional(&self, args: &[Value]) -> Result<Serve, String> {
+        let (bucket_by, buckets) = match args.first() {
+            Some(Value::Array(_)) | None => (None, args),
+            Some(expression) => (Some(attribute(expression)?), &args[1..]),
+        };
+        let weights = buckets
+            .iter()
+            .map(|bucket| match bucket.as_array().map(Vec::as_slice) {
+                Some([Value::String(variant)]) => Ok((self.variant(variant)?, 1.0)),
+                Some([Value::String(variant), weight]) => Ok((
+                    self.variant(variant)?,
+                    weight
+                        .as_f64()
+                        .ok_or_else(|| "fractional weights must be numbers".to_string())?,
+                )),
+                _ => Err("fractional buckets must be [variant, weight]".to_string()),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if weights.is_empty() {
+            return Err("fractional split has no buckets".to_string());
+        }
+        Ok(Serve::Rollout(Rollout {
+            variations: normalize_weights(&weights),
+            bucket_by: bucket_by.filter(|b| b != "key"),
+        }))
+    }
+
+    /// Flattens a condition into a list of alternatives that each need all of
+    /// their clauses to match.
+    fn condition(&self, condition: &Value) -> Result<Vec<Vec<Clause>>, String> {
+        let condition = self.resolve(condition)?;
+        let Some((operator, args)) = condition
+            .as_object()
+            .filter(|map| map.len() == 1)
+            .and_then(|map| map.iter().next())
+        else {
+            return Err(format!("unsupported condition {}", condition));
+        };
+        let args = as_args(args);
+        match operator.as_str() {
+            "and" => {
+                let mut alternatives = vec![Vec::new()];
+                for arg in args {
+                    let right = self.condition(arg)?;
+                    if alternatives.len() * right.len() > MAX_ALTERNATIVES {
+                        return Err("targeting is too complex to import".to_string());
+                    }
+                    alternatives = alternatives
+                        .iter()
+                        .flat_map(|left| {
+                            right.iter().map(move |r| {
+                                let mut clauses = left.clone();
+                                clauses.extend(r.iter().cloned());
+                                clauses
+                            })
+                        })
+                        .collect();
+                }
+                Ok(alternatives)
+            }
+            "or" => {
+                let mut alternatives = Vec::new();
+                for arg in args {
+                    alternatives.extend(self.condition(arg)?);
+                }
+                if alternatives.len() > MAX_ALTERNATIVES {
+                    return Err("targeting is too complex to import".to_string());
+                }
+                Ok(alternatives)
+            }
+            "!" => match self
+                .condition(args.first().unwrap_or(&Value::Null))?
+                .as_slice()
+            {
+                [alternative] if alternative.len() == 1 => {
+                    let mut clause = alternative[0].clone();
+                    clause.negate = !clause.negate;
+                    Ok(vec![vec![clause]])
+                }
+                _ => Err("only single comparisons can be negated".to_string()),
+            },
+            operator => Ok(vec![vec![comparison(operator, args)?]]),
+        }
+    }
+}
+
+fn as_args(value: &Value) -> &[Value] {
+    match value {
+        Value::Array(args) => args,
+        other => std::slice::from_ref(other),
+    }
+}
+
+/// Maps a `var` expression to the attribute it reads.
+fn attribute(expression: &Value) -> Result<String, String> {
+    let name = match expression.get("var") {
+        Some(Value::String(name)) => name,
+        Some(Value::Array(args)) => args
+            .first()
+            .and_then(Value::as_str)
+            .ok_or_else(|| format!("unsupported variable {}", expression))?,
+        _ => return Err(format!("expected a variable but found {}", expression)),
+    };
+    match name {
+        "targetingKey" => Ok("key".to_string()),
+        name if name.starts_with("$flagd.") => {
+            Err(format!("flagd property {} is not supported", name))
+        }
+        name => Ok(name.to_string()),
+    }
+}
+
+fn comparison(operator: &str, args: &[Value]) -> Result<Clause, String> {
+    if operator == "sem_ver" {
+        return sem_ver(args);
+    }
+    const SUPPORTED: &[&str] = &[
+        "==",
+        "===",
+        "!=",
+        "!==",
+        "<",
+        "<=",
+        ">",
+        ">=",
+        "starts_with",
+        "ends_with",
+        "in",
+    ];
+    if !SUPPORTED.contains(&operator) {
+        return Err(format!("operator {} is not supported", operator));
+    }
+    let [left, right] = args else {
+        return Err(format!("{} expects two arguments", operator));
+    };
+    let (attribute_expr, literal, flipped) = match (left.get("var"), right.get("var")) {
+        (Some(_), None) => (left, right, false),
+        (None, Some(_)) => (right, left, true),
+        _ => {
+            return Err(format!(
+                "{} must compare a variable with a literal",
+                operator
+            ))
+        }
+    };
+    let attribute = attribute(attribute_expr)?;
+
+    let (op, values, negate) = match (operator, flipped) {
+        ("==" | "===", _) => (Op::In, vec![literal.clone()], false),
+        ("!=" | "!==", _) => (Op::In, vec![literal.clone()], true),
+        ("<", false) | (">", true) => (Op::LessThan, vec![literal.clone()], false),
+        ("<=", false) | (">=", true) => (Op::LessThanOrEqual, vec![literal.clone()], false),
+        (">", false) | ("<", true) => (Op::GreaterThan, vec![literal.clone()], false),
+        (">=", false) | ("<=", true) => (Op::GreaterThanOrEqual, vec![literal.clone()], false),
+        ("starts_with", false) => (Op::StartsWith, vec![literal.clone()], false),
+        ("ends_with", false) => (Op::EndsWith, vec![literal.clone()], false),
+        ("in", false) => match literal {
+            Value::Array(values) => (Op::In, values.clone(), false),
+            _ => return Err("in expects a list of values".to_string()),
+        },
+        ("in", true) if literal.is_string() => (Op::Contains, vec![literal.clone()], false),
+        (operator, _) => {
+            return Err(format!(
+                "operator {} with the variable on this side is not supported",
+                operator
+            ))
+        }
+    };
+    Ok(clause(&attribute, op, values, negate))
+}
+
+fn sem_ver(args: &[Value]) -> Result<Clause, String> {
+    let [variable, Value::String(operator), version] = args else {
+        return Err("sem_ver expects a variable, an operator and a version".to_string());
+    };
+    let attribute = attribute(variable)?;
+    let (op, negate) = match operator.as_str() {
+        "=" => (Op::SemverEqual, false),
+        "!=" => (Op::SemverEqual, true),
+        "<" => (Op::SemverLessThan, false),
+        "<=" => (Op::SemverLessThanOrEqual, false),
+        ">" => (Op::SemverGreaterThan, false),
+        ">=" => (Op::SemverGreaterThanOrEqual, false),
+        operator => return Err(format!("sem_ver operator {} is not supported", operator)),
+    };
+    operands::version(version)?;
+    Ok(clause(&attribute, op, vec![version.clone()], negate))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn definitions(yaml: &str) -> Definitions {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    fn round_trip(document: &Value) -> Value {
+        let import = import(
+            serde_json::from_value(document.clone()).unwrap(),
+            "production",
+        );
+        assert!(import.unmapped.is_empty(), "{:?}", import.unmapped);
+        crate::flags::validate(&import.definitions).unwrap();
+        let (exported, unmapped) = export(&import.definitions, "production");
+        assert!(unmapped.is_empty(), "{:?}", unmapped);
+        exported
+    }
+
+    #[test]
+    fn flagd_definitions_survive_a_round_trip() {
+        let document = json!({
+            "$schema": "https://flagd.dev/schema/v0/flags.json",
+            "flags": {
+                "color": {
+                    "state": "ENABLED",
+                    "variants": { "blue": "#00f", "green": "#0f0", "red": "#f00" },
+                    "defaultVariant": "red",
+                    "targeting": {
+                        "if": [
+                            { "and": [
+                                { "ends_with": [{ "var": "email" }, "@example.com"] },
+                                { ">=": [{ "var": "age" }, 21] }
+                            ] },
+                            "blue",
+                            { "!": { "in": [{ "var": "country" }, ["us", "ca"]] } },
+                            "green",
+                            { "in": ["beta", { "var": "groups" }] },
+                            { "fractional": [{ "var": "email" }, ["red", 50], ["green", 50]] },
+                            { "==": [{ "var": "targetingKey" }, "admin"] },
+                            "blue"
+                        ]
+                    }
+                },
+                "checkout": {
+                    "state": "ENABLED",
+                    "variants": { "off": false, "on": true },
+                    "defaultVariant": "off",
+                    "targeting": {
+                        "if": [
+                            { "<": [{ "var": "accountAge" }, 30] },
+                            "on",
+                            { "sem_ver": [{ "var": "appVersion" }, ">=", "4.2.0"] },
+                            "on",
+                            { "fractional": [["on", 25], ["off", 75]] }
+                        ]
+                    }
+                }
+            }
+        });
+
+        assert_eq!(round_trip(&document), document);
+    }
+
+    #[test]
+    fn rules_survive_a_round_trip() {
+        let definitions = definitions(
+            r#"
+environments:
+  - key: production
+flags:
+  - key: checkout
+    variations:
+      - value: v1
+        name: old
+      - value: v2
+        name: new
+    environments:
+      production:
+        on: true
+        off_variation: 0
+        fallthrough:
+          rollout:
+            bucket_by: company
+            variations:
+              - variation: 0
+                weight: 33333
+              - variation: 1
+                weight: 66667
+        rules:
+          - clauses:
+              - attribute: plan
+                op: in
+                values: [pro, team]
+              - attribute: email
+                op: contains
+                values: ["@example.com"]
+                negate: true
+            serve:
+              variation: 1
+          - clauses:
+              - attribute: key
+                op: starts_with
+                values: [test-]
+              - attribute: age
+                op: greater_than
+                values: [17]
+            serve:
+              variation: 0
+"#,
+        );
+
+        let (exported, unmapped) = export(&definitions, "production");
+        assert!(unmapped.is_empty());
+        let imported = import(serde_json::from_value(exported).unwrap(), "production");
+        assert!(imported.unmapped.is_empty(), "{:?}", imported.unmapped);
+
+        let original = &definitions.flags[0];
+        let flag = &imported.definitions.flags[0];
+        assert_eq!(flag.variations, original.variations);
+        let original = &original.environments["production"];
+        let config = &flag.environments["production"];
+        assert_eq!(config.rules, original.rules);
+        assert_eq!(config.fallthrough, original.fallthrough);
+    }
+
+    #[test]
+    fn segments_are_served_as_evaluators() {
+        let definitions = definitions(
+            r#"
+environments:
+  - key: production
+segments:
+  - key: beta
+    environments:
+      production:
+        included: [alice]
+        rules:
+          - clauses:
+              - attribute: plan
+                op: in
+                values: [pro]
+flags:
+  - key: checkout
+    variations:
+      - value: false
+      - value: true
+    environments:
+      production:
+        on: true
+        off_variation: 0
+        fallthrough:
+          variation: 0
+        rules:
+          - clauses:
+              - attribute: key
+                op: segment_match
+                values: [beta]
+            serve:
+              variation: 1
+"#,
+        );
+
+        let (exported, _) = export(&definitions, "production");
+        assert_eq!(
+            exported["$evaluators"]["segment-beta"],
+            json!({ "or": [
+                { "in": [{ "var": "targetingKey" }, ["alice"]] },
+                { "==": [{ "var": "plan" }, "pro"] }
+            ] })
+        );
+        assert_eq!(
+            exported["flags"]["checkout"]["targeting"],
+            json!({ "if": [{ "$ref": "segment-beta" }, "variation-1"] })
+        );
+
+        let imported = import(serde_json::from_value(exported).unwrap(), "production");
+        let rules = &imported.definitions.flags[0].environments["production"].rules;
+        assert_eq!(rules.len(), 2);
+        assert_eq!(rules[0].clauses[0].attribute, "key");
+        assert_eq!(rules[1].clauses[0].attribute, "plan");
+        assert!(rules.iter().all(|r| r.serve == Serve::Variation(1)));
+    }
+
+    #[test]
+    fn off_flags_serve_their_off_variation() {
+        let definitions = definitions(
+            r#"
+environments:
+  - key: production
+flags:
+  - key: checkout
+    variations:
+      - value: false
+      - value: true
+    environments:
+      production:
+        on: false
+        off_variation: 0
+        fallthrough:
+          variation: 1
+        targets:
+          - variation: 1
+            values: [alice]
+"#,
+        );
+
+        let (exported, _) = export(&definitions, "production");
+        assert_eq!(
+            exported["flags"]["checkout"],
+            json!({
+                "state": "ENABLED",
+                "variants": { "variation-0": false, "variation-1": true },
+                "defaultVariant": "variation-0"
+            })
+        );
+    }
+
+    #[test]
+    fn flags_with_prerequisites_are_left_out() {
+        let definitions = definitions(
+            r#"
+environments:
+  - key: production
+flags:
+  - key: base
+    variations:
+      - value: true
+  - key: checkout
+    variations:
+      - value: true
+    environments:
+      production:
+        on: true
+        off_variation: 0
+        fallthrough:
+          variation: 0
+        prerequisites:
+          - flag: base
+            variation: 0
+"#,
+        );
+
+        let (exported, unmapped) = export(&definitions, "production");
+        assert_eq!(exported["flags"], json!({}));
+        assert_eq!(unmapped.len(), 1);
+    }
+
+    #[test]
+    fn clauses_flagd_cannot_evaluate_are_left_out() {
+        let definitions = definitions(
+            r#"
+environments:
+  - key: production
+segments:
+  - key: office
+    environments:
+      production:
+        rules:
+          - clauses:
+              - attribute: ip
+                op: in_cidr
+                values: [10.0.0.0/8]
+  - key: staff
+    environments:
+      production:
+        rules:
+          - clauses:
+              - attribute: key
+                op: segment_match
+                values: [office]
+flags:
+  - key: checkout
+    variations:
+      - value: true
+      - value: false
+    environments:
+      production:
+        on: true
+        off_variation: 1
+        fallthrough:
+          variation: 1
+        rules:
+          - clauses:
+              - attribute: key
+                op: segment_match
+                values: [staff]
+            serve:
+              variation: 0
+  - key: banner
+    variations:
+      - value: true
+      - value: false
+    environments:
+      production:
+        on: true
+        off_variation: 1
+        fallthrough:
+          variation: 1
+        rules:
+          - clauses:
+              - attribute: email
+                op: matches
+                values: ["@example\\.com$"]
+            serve:
+              variation: 0
+"#,
+        );
+        crate::flags::validate(&definitions).unwrap();
+
+        let (exported, unmapped) = export(&definitions, "production");
+        assert_eq!(exported["flags"], json!({}));
+        assert!(exported.get("$evaluators").is_none());
+        let items: Vec<&str> = unmapped.iter().map(|u| u.item.as_str()).collect();
+        assert_eq!(
+            items,
+            [
+                "segment office",
+                "segment staff",
+                "flag checkout",
+                "flag banner"
+            ]
+        );
+    }
+}
@@ -0,0 +1,266 @@
+// Featurize, the FOSS feature flagging
+// Copyright (C) 2024  Lucy Ekaterina
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::{self, Display},
+    fs,
+    path::Path,
+    str::FromStr,
+};
+
+use super::{
+    validate, Clause, Definitions, Error, Op, Rollout, Serve, Variation, WeightedVariation,
+    ROLLOUT_WEIGHT_TOTAL,
+};
+
+mod flagsmith;
+mod unleash;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    Unleash,
+    Flagsmith,
+    Flagd,
+}
+
+impl FromStr for Source {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "unleash" => Ok(Source::Unleash),
+            "flagsmith" => Ok(Source::Flagsmith),
+            "flagd" => Ok(Source::Flagd),
+            other => Err(format!("unknown import source \"{}\"", other)),
+        }
+    }
+}
+
+/// Something from the imported data that has no equivalent in featurize and
+/// was either dropped or approximated.
+#[derive(Debug, Clone)]
+pub struct Unmapped {
+    pub item: String,
+    pub reason: String,
+}
+
+impl Display for Unmapped {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.item, self.reason)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Import {
+    pub definitions: Definitions,
+    pub unmapped: Vec<Unmapped>,
+}
+
+impl Import {
+    pub(super) fn unmapped<I: Into<String>, R: Into<String>>(&mut self, item: I, reason: R) {
+        self.unmapped.push(Unmapped {
+            item: item.into(),
+            reason: reason.into(),
+        });
+    }
+}
+
+/// Reads an export from another feature flag tool. `environment` is used for
+/// sources that have no notion of environments.
+pub fn import_file(source: Source, path: &Path, environment: &str) -> Result<Import, Error> {
+    let content = fs::read_to_string(path).map_err(|source| Error::Io {
+        path: path.to_owned(),
+        source,
+    })?;
+    let parse_error = |e: serde_json::Error| Error::Parse {
+        path: path.to_owned(),
+        message: e.to_string(),
+    };
+    let import = match source {
+        Source::Unleash => unleash::import(serde_json::from_str(&content).map_err(parse_error)?),
+        Source::Flagsmith => {
+            flagsmith::import(serde_json::from_str(&content).map_err(parse_error)?)
+        }
+        Source::Flagd => super::flagd::import(
+            serde_json::from_str(&content).map_err(parse_error)?,
+            environment,
+        ),
+    };
+    validate(&import.definitions).map_err(Error::Invalid)?;
+    Ok(import)
+}
+
+/// Turns a name from another tool into a valid featurize key.
+pub(super) fn to_key(name: &str) -> String {
+    let key: String = name
+        .trim()
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.') {
+                c.to_ascii_lowercase()
+            } else {
+                '-'
+            }
+        })
+        .collect();
+    if key.is_empty() {
+        "unnamed".to_string()
+    } else {
+        key
+    }
+}
+
+/// Hands out one key per name, made with [`to_key`]. A name whose key is
+/// taken by another name gets a number added instead of failing validation,
+/// so "My Flag" and "my-flag" import as `my-flag` and `my-flag-2`.
+#[derive(Debug, Default)]
+pub(super) struct Keys {
+    by_name: HashMap<String, String>,
+    taken: HashSet<String>,
+}
+
+impl Keys {
+    /// `item` describes what is named, for the report of renamed keys.
+    pub(super) fn key(&mut self, import: &mut Import, item: &str, name: &str) -> String {
+        if let Some(key) = self.by_name.get(name) {
+            return key.clone();
+        }
+        let key = to_key(name);
+        let unique = (1..)
+            .map(|n| match n {
+                1 => key.clone(),
+                n => format!("{}-{}", key, n),
+            })
+            .find(|k| !self.taken.contains(k))
+            .unwrap_or_default();
+        if unique != key {
+            import.unmapped(
+                item,
+                format!(
+                    "key \"{}\" is already taken, imported as \"{}\"",
+                    key, unique
+                ),
+            );
+        }
+        self.taken.insert(unique.clone());
+        self.by_name.insert(name.to_string(), unique.clone());
+        unique
+    }
+}
+
+/// Keeps the original name around when it had to be changed to make a key.
+fn display_name(key: &str, name: &str) -> Option<String> {
+    (key != name).then(|| name.to_string())
+}
+
+/// Parses a value that other tools store as a string but compare as a number
+/// or boolean where possible.
+fn typed_value(value: &str) -> serde_json::Value {
+    if let Ok(b) = value.parse::<bool>() {
+        return b.into();
+    }
+    number(value).unwrap_or_else(|| value.into())
+}
+
+fn number(value: &str) -> Option<serde_json::Value> {
+    if let Ok(i) = value.trim().parse::<i64>() {
+        return Some(i.into());
+    }
+    value
+        .trim()
+        .parse::<f64>()
+        .ok()
+        .and_then(serde_json::Number::from_f64)
+        .map(serde_json::Value::Number)
+}
+
+pub(super) fn clause(
+    attribute: &str,
+    op: Op,
+    values: Vec<serde_json::Value>,
+    negate: bool,
+) -> Clause {
+    Clause {
+        attribute: attribute.to_string(),
+        op,
+        values,
+        negate,
+        parsed: Default::default(),
+    }
+}
+
+/// Serves `on` to `percentage` percent of contexts and `off` to the rest.
+fn percentage_rollout(on: usize, off: usize, percentage: f64, bucket_by: Option<String>) -> Serve {
+    let weight = (percentage * 1000.0)
+        .round()
+        .clamp(0.0, ROLLOUT_WEIGHT_TOTAL as f64) as u32;
+    match weight {
+        0 => Serve::Variation(off),
+        ROLLOUT_WEIGHT_TOTAL => Serve::Variation(on),
+        weight => Serve::Rollout(Rollout {
+            variations: vec![
+                WeightedVariation {
+                    variation: on,
+                    weight,
+                },
+                WeightedVariation {
+                    variation: off,
+                    weight: ROLLOUT_WEIGHT_TOTAL - weight,
+                },
+            ],
+            bucket_by,
+        }),
+    }
+}
+
+/// Scales relative weights so they add up to `ROLLOUT_WEIGHT_TOTAL`, giving
+/// any rounding remainder to the last variation.
+pub(super) fn normalize_weights(weights: &[(usize, f64)]) -> Vec<WeightedVariation> {
+    let total: f64 = weights.iter().map(|(_, w)| w).sum();
+    let mut variations: Vec<_> = weights
+        .iter()
+        .map(|(variation, weight)| WeightedVariation {
+            variation: *variation,
+            weight: if total > 0.0 {
+                (weight / total * ROLLOUT_WEIGHT_TOTAL as f64).floor() as u32
+            } else {
+                0
+            },
+        })
+        .collect();
+    let assigned: u32 = variations.iter().map(|v| v.weight).sum();
+    if let Some(last) = variations.last_mut() {
+        last.weight += ROLLOUT_WEIGHT_TOTAL.saturating_sub(assigned);
+    }
+    variations
+}
+
+/// Collects the distinct values a flag can serve, in the order they are first
+/// seen.
+#[derive(Debug, Default)]
+struct Variations(Vec<Variation>);
+
+impl Variations {
+    fn index(&mut self, value: serde_json::Value) -> usize {
+        match self.0.iter().position(|v| v.value == value) {
+            Some(i) => i,
+            None => {
+                self.0.push(Variation { value, name: None });
+                self.0.len() - 1
+            }
+        }
+    }
+}
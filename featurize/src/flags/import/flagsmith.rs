@@ -0,0 +1,717 @@
+// Featurize, the FOSS feature flagging
+// Copyright (C) 2024  Lucy Ekaterina
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use std::collections::BTreeMap;
+
+use serde::Deserialize;
+
+use super::{
+    clause, display_name, normalize_weights, number, typed_value, Import, Keys, Variations,
+};
+use crate::flags::{
+    operands, Clause, Environment, Flag, FlagConfig, FlagKind, Lifecycle, Op, Rollout, Rule,
+    Segment, SegmentConfig, SegmentRule, Serve, Target,
+};
+
+/// Segment rules are flattened into an OR of ANDs, this caps how many ANDs a
+/// single segment may expand to.
+const MAX_SEGMENT_RULES: usize = 32;
+
+/// Either a single environment document or a list of them, one per
+/// environment.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum Export {
+    One(Box<EnvironmentDocument>),
+    Many(Vec<EnvironmentDocument>),
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EnvironmentDocument {
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    api_key: Option<String>,
+    project: Project,
+    #[serde(default)]
+    feature_states: Vec<FeatureState>,
+    #[serde(default)]
+    identity_overrides: Vec<IdentityOverride>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Project {
+    #[serde(default)]
+    segments: Vec<FlagsmithSegment>,
+}
+
+#[derive(Debug, Deserialize)]
+struct FlagsmithSegment {
+    name: String,
+    #[serde(default)]
+    rules: Vec<SegmentRuleNode>,
+    #[serde(default)]
+    feature_states: Vec<FeatureState>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SegmentRuleNode {
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    rules: Vec<SegmentRuleNode>,
+    #[serde(default)]
+    conditions: Vec<Condition>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Condition {
+    operator: String,
+    #[serde(default)]
+    property_: Option<String>,
+    #[serde(default)]
+    value: serde_json::Value,
+}
+
+#[derive(Debug, Deserialize)]
+struct FeatureState {
+    feature: FlagsmithFeature,
+    enabled: bool,
+    #[serde(default)]
+    feature_state_value: serde_json::Value,
+    #[serde(default)]
+    multivariate_feature_state_values: Vec<MultivariateValue>,
+    #[serde(default)]
+    feature_segment: Option<FeatureSegment>,
+}
+
+#[derive(Debug, Deserialize)]
+struct FlagsmithFeature {
+    name: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct MultivariateValue {
+    multivariate_feature_option: MultivariateOption,
+    percentage_allocation: f64,
+}
+
+#[derive(Debug, Deserialize)]
+struct MultivariateOption {
+    value: serde_json::Value,
+}
+
+#[derive(Debug, Deserialize)]
+struct FeatureSegment {
+    #[serde(default)]
+    priority: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct IdentityOverride {
+    identifier: String,
+    #[serde(default)]
+    identity_features: Vec<FeatureState>,
+}
+
+impl Condition {
+    fn to_clause(&self) -> Result<Clause, String> {
+        let attribute = self
+            .property_
+            .as_deref()
+            .ok_or_else(|| "condition has no property".to_string())?;
+        let value = match &self.value {
+            serde_json::Value::String(s) => s.clone(),
+            other => other.to_string(),
+        };
+        if let Some(version) = value.strip_suffix(":semver") {
+            let (op, negate) = match self.operator.as_str() {
+                "EQUAL" => (Op::SemverEqual, false),
+                "NOT_EQUAL" => (Op::SemverEqual, true),
+                "GREATER_THAN" => (Op::SemverGreaterThan, false),
+                "GREATER_THAN_INCLUSIVE" => (Op::SemverGreaterThanOrEqual, false),
+                "LESS_THAN" => (Op::SemverLessThan, false),
+                "LESS_THAN_INCLUSIVE" => (Op::SemverLessThanOrEqual, false),
+                other => {
+                    return Err(format!(
+                        "condition operator {} is not supported for versions",
+                        other
+                    ))
+                }
+            };
+            let version = version.into();
+            operands::check_operand(op, &version)?;
+            return Ok(clause(attribute, op, vec![version], negate));
+        }
+        let numeric =
+            |value: &str| number(value).ok_or_else(|| format!("\"{}\" is not a number", value));
+        let (op, values, negate) = match self.operator.as_str() {
+            "EQUAL" => (Op::In, vec![typed_value(&value)], false),
+            "NOT_EQUAL" => (Op::In, vec![typed_value(&value)], true),
+            "CONTAINS" => (Op::Contains, vec![value.into()], false),
+            "NOT_CONTAINS" => (Op::Contains, vec![value.into()], true),
+            "IN" => (
+                Op::In,
+                value.split(',').map(|v| typed_value(v.trim())).collect(),
+                false,
+            ),
+            "GREATER_THAN" => (Op::GreaterThan, vec![numeric(&value)?], false),
+            "GREATER_THAN_INCLUSIVE" => (Op::GreaterThanOrEqual, vec![numeric(&value)?], false),
+            "LESS_THAN" => (Op::LessThan, vec![numeric(&value)?], false),
+            "LESS_THAN_INCLUSIVE" => (Op::LessThanOrEqual, vec![numeric(&value)?], false),
+            "REGEX" => (Op::Matches, vec![value.into()], false),
+            other => return Err(format!("condition operator {} is not supported", other)),
+        };
+        for value in &values {
+            operands::check_operand(op, value)?;
+        }
+        Ok(clause(attribute, op, values, negate))
+    }
+}
+
+impl SegmentRuleNode {
+    /// Flattens the rule into a list of alternatives that each need all of
+    /// their clauses to match.
+    fn flatten(&self) -> Result<Vec<Vec<Clause>>, String> {
+        match self.kind.as_str() {
+            "ALL" => {
+                let mut alternatives = vec![Vec::new()];
+                for condition in &self.conditions {
+                    let clause = condition.to_clause()?;
+                    for alternative in &mut alternatives {
+                        alternative.push(clause.clone());
+                    }
+                }
+                for rule in &self.rules {
+                    alternatives = combine(&alternatives, &rule.flatten()?)?;
+                }
+                Ok(alternatives)
+            }
+            "ANY" => {
+                let mut alternatives = Vec::new();
+                for condition in &self.conditions {
+                    alternatives.push(vec![condition.to_clause()?]);
+                }
+                for rule in &self.rules {
+                    alternatives.extend(rule.flatten()?);
+                }
+                Ok(alternatives)
+            }
+            "NONE" => {
+                if !self.rules.is_empty() {
+                    return Err("nested rules inside NONE are not supported".to_string());
+                }
+                let clauses = self
+                    .conditions
+                    .iter()
+                    .map(|c| {
+                        c.to_clause().map(|mut clause| {
+                            clause.negate = !clause.negate;
+                            clause
+                        })
+                    })
+                    .collect::<Result<_, _>>()?;
+                Ok(vec![clauses])
+            }
+            other => Err(format!("rule type {} is not supported", other)),
+        }
+    }
+}
+
+fn combine(left: &[Vec<Clause>], right: &[Vec<Clause>]) -> Result<Vec<Vec<Clause>>, String> {
+    if left.len() * right.len() > MAX_SEGMENT_RULES {
+        return Err("segment rules are too complex to import".to_string());
+    }
+    Ok(left
+        .iter()
+        .flat_map(|l| {
+            right.iter().map(move |r| {
+                let mut clauses = l.clone();
+                clauses.extend(r.iter().cloned());
+                clauses
+            })
+        })
+        .collect())
+}
+
+fn segment_rules(segment: &FlagsmithSegment) -> Result<Vec<SegmentRule>, String> {
+    let mut alternatives = vec![Vec::new()];
+    for rule in &segment.rules {
+        alternatives = combine(&alternatives, &rule.flatten()?)?;
+    }
+    if alternatives.iter().any(Vec::is_empty) {
+        return Err("segments that match everyone are not supported".to_string());
+    }
+    Ok(alternatives
+        .into_iter()
+        .map(|clauses| SegmentRule { clauses })
+        .collect())
+}
+
+/// How the states of a single feature are turned into variations: boolean
+/// features serve whether they are enabled, features with values serve the
+/// value.
+struct FeatureVariations {
+    boolean: bool,
+    variations: Variations,
+}
+
+impl FeatureVariations {
+    fn new(boolean: bool) -> Self {
+        let mut variations = Variations::default();
+        if boolean {
+            variations.index(true.into());
+            variations.index(false.into());
+        }
+        Self {
+            boolean,
+            variations,
+        }
+    }
+
+    fn serve(&mut self, state: &FeatureState) -> usize {
+        if self.boolean {
+            self.variations.index(state.enabled.into())
+        } else {
+            self.variations.index(state.feature_state_value.clone())
+        }
+    }
+}
+
+pub fn import(export: Export) -> Import {
+    let documents = match export {
+        Export::One(document) => vec![*document],
+        Export::Many(documents) => documents,
+    };
+    let mut import = Import::default();
+
+    let mut keys = Keys::default();
+    let environment_keys: Vec<String> = documents
+        .iter()
+        .enumerate()
+        .map(|(i, document)| {
+            let name = document
+                .name
+                .clone()
+                .or_else(|| document.api_key.clone())
+                .unwrap_or_else(|| format!("environment-{}", i + 1));
+            let key = keys.key(&mut import, &format!("environment {}", name), &name);
+            import.definitions.environments.push(Environment {
+                name: display_name(&key, &name),
+                key: key.clone(),
+                holdout: None,
+                approvals: None,
+            });
+            key
+        })
+        .collect();
+
+    // Segments are shared by environments, the same name gets the same key
+    // in every document.
+    let mut segment_keys = Keys::default();
+    let mut segments: BTreeMap<String, Segment> = BTreeMap::new();
+    for (document, env) in documents.iter().zip(&environment_keys) {
+        for segment in &document.project.segments {
+            let key = segment_keys.key(
+                &mut import,
+                &format!("segment {}", segment.name),
+                &segment.name,
+            );
+            match segment_rules(segment) {
+                Ok(rules) => {
+                    segments
+                        .entry(key.clone())
+                        .or_insert_with(|| Segment {
+                            name: display_name(&key, &segment.name),
+                            key: key.clone(),
+                            description: None,
+                            environments: BTreeMap::new(),
+                        })
+                        .environments
+                        .insert(
+                            env.clone(),
+                            SegmentConfig {
+                                rules,
+                                ..Default::default()
+                            },
+                        );
+                }
+                Err(reason) => import.unmapped(
+                    format!("segment {} in {}", segment.name, env),
+                    format!("{}, segment skipped", reason),
+                ),
+            }
+        }
+    }
+
+    let mut feature_names: Vec<&str> = Vec::new();
+    for document in &documents {
+        for state in &document.feature_states {
+            if !feature_names.contains(&state.feature.name.as_str()) {
+                feature_names.push(&state.feature.name);
+            }
+        }
+    }
+
+    let mut keys = Keys::default();
+    for name in feature_names {
+        let states = || {
+            documents.iter().flat_map(|d| {
+                d.feature_states
+                    .iter()
+                    .chain(d.project.segments.iter().flat_map(|s| &s.feature_states))
+                    .chain(
+                        d.identity_overrides
+                            .iter()
+                            .flat_map(|o| &o.identity_features),
+                    )
+                    .filter(|s| s.feature.name == name)
+            })
+        };
+        let boolean = states().all(|s| {
+            s.feature_state_value.is_null() && s.multivariate_feature_state_values.is_empty()
+        });
+        let mut variations = FeatureVariations::new(boolean);
+
+        let mut environments = BTreeMap::new();
+        for (document, env) in documents.iter().zip(&environment_keys) {
+            let Some(state) = document
+                .feature_states
+                .iter()
+                .find(|s| s.feature.name == name)
+            else {
+                continue;
+            };
+            let item = format!("feature {} in {}", name, env);
+            let served = variations.serve(state);
+            let fallthrough = if state.multivariate_feature_state_values.is_empty() {
+                Serve::Variation(served)
+            } else {
+                let mut weights: Vec<_> = state
+                    .multivariate_feature_state_values
+                    .iter()
+                    .map(|mv| {
+                        (
+                            variations
+                                .variations
+                                .index(mv.multivariate_feature_option.value.clone()),
+                            mv.percentage_allocation,
+                        )
+                    })
+                    .collect();
+                let allocated: f64 = weights.iter().map(|(_, w)| w).sum();
+                weights.push((served, (100.0 - allocated).max(0.0)));
+                Serve::Rollout(Rollout {
+                    variations: normalize_weights(&weights),
+                    bucket_by: None,
+                })
+            };
+
+            let mut overrides: Vec<_> = document
+                .project
+                .segments
+                .iter()
+                .flat_map(|segment| {
+                    segment
+                        .feature_states
+                        .iter()
+                        .filter(|s| s.feature.name == name)
+                        .map(move |s| (segment, s))
+                })
+                .collect();
+            overrides.sort_by_key(|(_, s)| {
+                s.feature_segment
+                    .as_ref()
+                    .and_then(|f| f.priority)
+                    .unwrap_or(i64::MAX)
+            });
+            let mut rules = Vec::new();
+            for (segment, state) in overrides {
+                let key = segment_keys.key(
+                    &mut import,
+                    &format!("segment {}", segment.name),
+                    &segment.name,
+                );
+                let imported = segments
+                    .get(&key)
+                    .map_or(false, |s| s.environments.contains_key(env));
+                if !imported {
+                    import.unmapped(
+                        item.clone(),
+                        format!("override for segment {} skipped", segment.name),
+                    );
+                    continue;
+                }
+                if !boolean && !state.enabled {
+                    import.unmapped(
+                        item.clone(),
+                        format!(
+                            "override for segment {} is disabled, its value is served anyway",
+                            segment.name
+                        ),
+                    );
+                }
+                rules.push(Rule {
+                    clauses: vec![clause("key", Op::SegmentMatch, vec![key.into()], false)],
+                    serve: Serve::Variation(variations.serve(state)),
+                });
+            }
+
+            let mut targets: BTreeMap<usize, Vec<String>> = BTreeMap::new();
+            for identity in &document.identity_overrides {
+                for state in identity
+                    .identity_features
+                    .iter()
+                    .filter(|s| s.feature.name == name)
+                {
+                    targets
+                        .entry(variations.serve(state))
+                        .or_default()
+                        .push(identity.identifier.clone());
+                }
+            }
+
+            environments.insert(
+                env.clone(),
+                FlagConfig {
+                    on: boolean || state.enabled,
+                    off_variation: served,
+                    fallthrough,
+                    prerequisites: Vec::new(),
+                    targets: targets
+                        .into_iter()
+                        .map(|(variation, values)| Target { variation, values })
+                        .collect(),
+                    rules,
+                    guardrails: None,
+                },
+            );
+        }
+
+        let key = keys.key(&mut import, &format!("feature {}", name), name);
+        import.definitions.flags.push(Flag {
+            name: display_name(&key, name),
+            key,
+            description: None,
+            kind: FlagKind::default(),
+            lifecycle: Lifecycle::default(),
+            tags: Vec::new(),
+            owner: None,
+            kill_switch: None,
+            variations: variations.variations.0,
+            environments,
+            webhooks: Vec::new(),
+            tests: Vec::new(),
+        });
+    }
+
+    import.definitions.segments = segments.into_values().collect();
+    import
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::flags::{validate, Definitions};
+
+    fn definitions(yaml: &str) -> Definitions {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    fn unmapped(import: &Import) -> Vec<String> {
+        import.unmapped.iter().map(ToString::to_string).collect()
+    }
+
+    fn state(feature: &str, enabled: bool, value: Value) -> Value {
+        json!({"feature": {"name": feature}, "enabled": enabled, "feature_state_value": value})
+    }
+
+    #[test]
+    fn imports_environment_documents() {
+        let beta = json!({
+            "name": "Beta Testers",
+            "rules": [{
+                "type": "ALL",
+                "rules": [
+                    {"type": "ANY", "conditions": [
+                        {"operator": "EQUAL", "property_": "plan", "value": "pro"},
+                        {"operator": "GREATER_THAN_INCLUSIVE", "property_": "seats", "value": "10"}
+                    ]},
+                    {"type": "NONE", "conditions": [
+                        {"operator": "REGEX", "property_": "email", "value": "@rival\\.com$"}
+                    ]}
+                ],
+                "conditions": [
+                    {"operator": "LESS_THAN", "property_": "appVersion", "value": "5.0.0:semver"}
+                ]
+            }],
+            "feature_states": [{
+                "feature": {"name": "banner_text"}, "enabled": true,
+                "feature_state_value": "Try the beta", "feature_segment": {"priority": 1}
+            }]
+        });
+        let fuzzy = json!({
+            "name": "fuzzy",
+            "rules": [{"type": "ALL", "conditions": [
+                {"operator": "PERCENTAGE_SPLIT", "property_": "", "value": "30"}
+            ]}],
+            "feature_states": [{
+                "feature": {"name": "banner_text"}, "enabled": true,
+                "feature_state_value": "Hello?", "feature_segment": {"priority": 0}
+            }]
+        });
+        let export = json!([
+            {
+                "name": "Development",
+                "project": {"segments": [beta]},
+                "feature_states": [
+                    state("dark_mode", true, Value::Null),
+                    state("banner_text", true, json!("Welcome")),
+                ]
+            },
+            {
+                "api_key": "prod",
+                "project": {"segments": [beta, fuzzy]},
+                "feature_states": [
+                    state("dark_mode", false, Value::Null),
+                    {
+                        "feature": {"name": "banner_text"}, "enabled": false,
+                        "feature_state_value": "Welcome",
+                        "multivariate_feature_state_values": [{
+                            "multivariate_feature_option": {"value": "Hi there"},
+                            "percentage_allocation": 20
+                        }]
+                    }
+                ],
+                "identity_overrides": [{
+                    "identifier": "alice",
+                    "identity_features": [state("dark_mode", true, Value::Null)]
+                }]
+            }
+        ]);
+
+        let import = import(serde_json::from_value(export).unwrap());
+        validate(&import.definitions).unwrap();
+        let expected = definitions(
+            r#"
+environments:
+  - key: development
+    name: Development
+  - key: prod
+segments:
+  - key: beta-testers
+    name: Beta Testers
+    environments:
+      development: &beta
+        rules:
+          - clauses:
+              - {attribute: appVersion, op: semver_less_than, values: [5.0.0]}
+              - {attribute: plan, op: in, values: [pro]}
+              - {attribute: email, op: matches, values: ["@rival\\.com$"], negate: true}
+          - clauses:
+              - {attribute: appVersion, op: semver_less_than, values: [5.0.0]}
+              - {attribute: seats, op: greater_than_or_equal, values: [10]}
+              - {attribute: email, op: matches, values: ["@rival\\.com$"], negate: true}
+      prod: *beta
+flags:
+  - key: dark_mode
+    variations: [{value: true}, {value: false}]
+    environments:
+      development: {on: true, off_variation: 0, fallthrough: {variation: 0}}
+      prod:
+        on: true
+        off_variation: 1
+        fallthrough: {variation: 1}
+        targets: [{variation: 0, values: [alice]}]
+  - key: banner_text
+    variations: [{value: Welcome}, {value: Try the beta}, {value: Hi there}]
+    environments:
+      development:
+        on: true
+        off_variation: 0
+        fallthrough: {variation: 0}
+        rules:
+          - clauses: [{attribute: key, op: segment_match, values: [beta-testers]}]
+            serve: {variation: 1}
+      prod:
+        on: false
+        off_variation: 0
+        fallthrough:
+          rollout:
+            variations: [{variation: 2, weight: 20000}, {variation: 0, weight: 80000}]
+        rules:
+          - clauses: [{attribute: key, op: segment_match, values: [beta-testers]}]
+            serve: {variation: 1}
+"#,
+        );
+        assert_eq!(import.definitions, expected);
+        assert_eq!(
+            unmapped(&import),
+            [
+                "segment fuzzy in prod: condition operator PERCENTAGE_SPLIT is not supported, segment skipped",
+                "feature banner_text in prod: override for segment fuzzy skipped",
+            ]
+        );
+    }
+
+    #[test]
+    fn names_with_the_same_key_get_unique_keys() {
+        let segment = |name: &str, plan: &str| {
+            json!({
+                "name": name,
+                "rules": [{"type": "ALL", "conditions": [
+                    {"operator": "EQUAL", "property_": "plan", "value": plan}
+                ]}]
+            })
+        };
+        let document = |name: &str| {
+            json!({
+                "name": name,
+                "project": {"segments": [segment("Power Users", "pro"), segment("power-users", "team")]},
+                "feature_states": [state("New Flag", true, Value::Null), state("new-flag", false, Value::Null)]
+            })
+        };
+        let export = json!([document("production"), document("Production")]);
+
+        let import = import(serde_json::from_value(export).unwrap());
+        validate(&import.definitions).unwrap();
+        let definitions = &import.definitions;
+        let keys: Vec<&str> = definitions
+            .environments
+            .iter()
+            .map(|e| e.key.as_str())
+            .collect();
+        assert_eq!(keys, ["production", "production-2"]);
+        let keys: Vec<&str> = definitions
+            .segments
+            .iter()
+            .map(|s| s.key.as_str())
+            .collect();
+        assert_eq!(keys, ["power-users", "power-users-2"]);
+        assert_eq!(definitions.segments[1].environments.len(), 2);
+        let keys: Vec<&str> = definitions.flags.iter().map(|f| f.key.as_str()).collect();
+        assert_eq!(keys, ["new-flag", "new-flag-2"]);
+        assert_eq!(
+            unmapped(&import),
+            [
+                "environment Production: key \"production\" is already taken, imported as \"production-2\"",
+                "segment power-users: key \"power-users\" is already taken, imported as \"power-users-2\"",
+                "feature new-flag: key \"new-flag\" is already taken, imported as \"new-flag-2\"",
+            ]
+        );
+    }
+}
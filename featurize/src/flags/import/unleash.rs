@@ -0,0 +1,696 @@
+// Featurize, the FOSS feature flagging
+// Copyright (C) 2024  Lucy Ekaterina
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::IpAddr,
+};
+
+use ipnet::IpNet;
+use serde::Deserialize;
+
+use super::{clause, display_name, number, percentage_rollout, Import, Keys};
+use crate::flags::{
+    operands, Clause, Environment, Flag, FlagConfig, FlagKind, Lifecycle, Op, Rule, Segment,
+    SegmentConfig, SegmentRule, Serve, Target, Variation,
+};
+
+const ON: usize = 0;
+const OFF: usize = 1;
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Export {
+    #[serde(default)]
+    features: Vec<Feature>,
+    #[serde(default)]
+    environments: Vec<UnleashEnvironment>,
+    #[serde(default)]
+    feature_strategies: Vec<FeatureStrategy>,
+    #[serde(default)]
+    feature_environments: Vec<FeatureEnvironment>,
+    #[serde(default)]
+    segments: Vec<UnleashSegment>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Feature {
+    name: String,
+    #[serde(default)]
+    description: Option<String>,
+    #[serde(default)]
+    variants: Vec<serde_json::Value>,
+    /// `release`, `experiment`, `operational`, `kill-switch` or `permission`.
+    #[serde(default, rename = "type")]
+    kind: Option<String>,
+    #[serde(default)]
+    stale: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct UnleashEnvironment {
+    name: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct FeatureStrategy {
+    feature_name: String,
+    environment: String,
+    strategy_name: String,
+    #[serde(default)]
+    parameters: serde_json::Map<String, serde_json::Value>,
+    #[serde(default)]
+    constraints: Vec<Constraint>,
+    #[serde(default)]
+    segments: Vec<u64>,
+    #[serde(default)]
+    sort_order: Option<i64>,
+    #[serde(default)]
+    disabled: Option<bool>,
+}
+
+impl FeatureStrategy {
+    fn parameter(&self, name: &str) -> Option<String> {
+        match self.parameters.get(name)? {
+            serde_json::Value::String(s) => Some(s.clone()),
+            serde_json::Value::Null => None,
+            other => Some(other.to_string()),
+        }
+    }
+
+    fn list_parameter(&self, name: &str) -> Vec<serde_json::Value> {
+        self.parameter(name)
+            .map(|p| {
+                p.split(',')
+                    .map(str::trim)
+                    .filter(|s| !s.is_empty())
+                    .map(serde_json::Value::from)
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    fn percentage(&self, name: &str) -> f64 {
+        self.parameter(name)
+            .and_then(|p| p.trim().parse().ok())
+            .unwrap_or(0.0)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct FeatureEnvironment {
+    feature_name: String,
+    environment: String,
+    enabled: bool,
+    #[serde(default)]
+    variants: Vec<serde_json::Value>,
+}
+
+#[derive(Debug, Deserialize)]
+struct UnleashSegment {
+    id: u64,
+    name: String,
+    #[serde(default)]
+    constraints: Vec<Constraint>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Constraint {
+    context_name: String,
+    operator: String,
+    #[serde(default)]
+    values: Vec<String>,
+    #[serde(default)]
+    value: Option<String>,
+    #[serde(default)]
+    inverted: bool,
+    #[serde(default)]
+    case_insensitive: bool,
+}
+
+impl Constraint {
+    fn to_clause(&self) -> Result<Clause, String> {
+        let attribute = match self.context_name.as_str() {
+            "userId" => "key",
+            other => other,
+        };
+        let (op, negate, numeric) = match self.operator.as_str() {
+            "IN" => (Op::In, false, false),
+            "NOT_IN" => (Op::In, true, false),
+            "STR_CONTAINS" => (Op::Contains, false, false),
+            "STR_STARTS_WITH" => (Op::StartsWith, false, false),
+            "STR_ENDS_WITH" => (Op::EndsWith, false, false),
+            "NUM_EQ" => (Op::In, false, true),
+            "NUM_GT" => (Op::GreaterThan, false, true),
+            "NUM_GTE" => (Op::GreaterThanOrEqual, false, true),
+            "NUM_LT" => (Op::LessThan, false, true),
+            "NUM_LTE" => (Op::LessThanOrEqual, false, true),
+            "SEMVER_EQ" => (Op::SemverEqual, false, false),
+            "SEMVER_GT" => (Op::SemverGreaterThan, false, false),
+            "SEMVER_LT" => (Op::SemverLessThan, false, false),
+            "DATE_BEFORE" => (Op::Before, false, false),
+            "DATE_AFTER" => (Op::After, false, false),
+            other => return Err(format!("constraint operator {} is not supported", other)),
+        };
+        if self.case_insensitive && !numeric {
+            return Err("case insensitive constraints are not supported".to_string());
+        }
+        let values = self
+            .values
+            .iter()
+            .chain(self.value.iter())
+            .map(|v| {
+                let value = if numeric {
+                    number(v).ok_or_else(|| format!("\"{}\" is not a number", v))?
+                } else {
+                    v.as_str().into()
+                };
+                operands::check_operand(op, &value)?;
+                Ok(value)
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(clause(attribute, op, values, negate != self.inverted))
+    }
+}
+
+pub fn import(export: Export) -> Import {
+    let mut import = Import::default();
+
+    let mut environment_names: Vec<&str> = export
+        .environments
+        .iter()
+        .map(|e| e.name.as_str())
+        .collect();
+    if environment_names.is_empty() {
+        for name in export
+            .feature_environments
+            .iter()
+            .map(|e| e.environment.as_str())
+            .chain(
+                export
+                    .feature_strategies
+                    .iter()
+                    .map(|s| s.environment.as_str()),
+            )
+        {
+            if !environment_names.contains(&name) {
+                environment_names.push(name);
+            }
+        }
+    }
+    let mut keys = Keys::default();
+    let environment_keys: HashMap<&str, String> = environment_names
+        .iter()
+        .map(|name| {
+            let key = keys.key(&mut import, &format!("environment {}", name), name);
+            (*name, key)
+        })
+        .collect();
+    for name in &environment_names {
+        let key = environment_keys[name].clone();
+        import.definitions.environments.push(Environment {
+            name: display_name(&key, name),
+            key,
+            holdout: None,
+            approvals: None,
+        });
+    }
+
+    // Flags, segments and environments each have keys of their own.
+    let mut keys = Keys::default();
+    let mut segment_keys = HashMap::new();
+    for segment in &export.segments {
+        let item = format!("segment {}", segment.name);
+        let key = keys.key(&mut import, &item, &segment.name);
+        if segment.constraints.is_empty() {
+            import.unmapped(item, "segments without constraints are not supported");
+            continue;
+        }
+        let clauses: Result<Vec<_>, _> = segment
+            .constraints
+            .iter()
+            .map(Constraint::to_clause)
+            .collect();
+        match clauses {
+            Ok(clauses) => {
+                let config = SegmentConfig {
+                    rules: vec![SegmentRule { clauses }],
+                    ..Default::default()
+                };
+                import.definitions.segments.push(Segment {
+                    name: display_name(&key, &segment.name),
+                    key: key.clone(),
+                    description: None,
+                    environments: environment_names
+                        .iter()
+                        .map(|env| (environment_keys[env].clone(), config.clone()))
+                        .collect(),
+                });
+                segment_keys.insert(segment.id, key);
+            }
+            Err(reason) => import.unmapped(item, format!("{}, segment skipped", reason)),
+        }
+    }
+
+    let mut keys = Keys::default();
+    for feature in &export.features {
+        let key = keys.key(
+            &mut import,
+            &format!("feature {}", feature.name),
+            &feature.name,
+        );
+        let has_variants = !feature.variants.is_empty()
+            || export
+                .feature_environments
+                .iter()
+                .any(|e| e.feature_name == feature.name && !e.variants.is_empty());
+        if has_variants {
+            import.unmapped(
+                format!("feature {}", feature.name),
+                "variants are not imported, the feature was imported as a boolean flag",
+            );
+        }
+
+        let mut environments = BTreeMap::new();
+        for env in &environment_names {
+            let enabled = export
+                .feature_environments
+                .iter()
+                .any(|e| e.feature_name == feature.name && e.environment == *env && e.enabled);
+            let mut strategies: Vec<_> = export
+                .feature_strategies
+                .iter()
+                .filter(|s| s.feature_name == feature.name && s.environment == *env)
+                .collect();
+            strategies.sort_by_key(|s| s.sort_order.unwrap_or(i64::MAX));
+            let config = flag_config(
+                &mut import,
+                &feature.name,
+                env,
+                enabled,
+                &strategies,
+                &segment_keys,
+            );
+            environments.insert(environment_keys[env].clone(), config);
+        }
+
+        import.definitions.flags.push(Flag {
+            name: display_name(&key, &feature.name),
+            key,
+            description: feature.description.clone().filter(|d| !d.is_empty()),
+            kind: match feature.kind.as_deref() {
+                Some("kill-switch" | "operational" | "permission") => FlagKind::Permanent,
+                _ => FlagKind::Temporary,
+            },
+            lifecycle: if feature.stale {
+                Lifecycle::Deprecated
+            } else {
+                Lifecycle::Active
+            },
+            tags: Vec::new(),
+            owner: None,
+            kill_switch: None,
+            variations: vec![
+                Variation {
+                    value: true.into(),
+                    name: None,
+                },
+                Variation {
+                    value: false.into(),
+                    name: None,
+                },
+            ],
+            environments,
+            webhooks: Vec::new(),
+            tests: Vec::new(),
+        });
+    }
+
+    import
+}
+
+fn flag_config(
+    import: &mut Import,
+    feature: &str,
+    env: &str,
+    enabled: bool,
+    strategies: &[&FeatureStrategy],
+    segment_keys: &HashMap<u64, String>,
+) -> FlagConfig {
+    let mut config = FlagConfig {
+        on: enabled,
+        off_variation: OFF,
+        fallthrough: Serve::Variation(OFF),
+        prerequisites: Vec::new(),
+        targets: Vec::new(),
+        rules: Vec::new(),
+        guardrails: None,
+    };
+    let mut fallthrough_set = false;
+    let mut partial_rule = false;
+
+    for strategy in strategies {
+        let item = format!(
+            "feature {} in {}: strategy {}",
+            feature, env, strategy.strategy_name
+        );
+        if strategy.disabled == Some(true) {
+            import.unmapped(item, "disabled strategies are not imported");
+            continue;
+        }
+
+        let mut clauses = Vec::new();
+        let mut skipped = None;
+        for constraint in &strategy.constraints {
+            match constraint.to_clause() {
+                Ok(clause) => clauses.push(clause),
+                Err(reason) => {
+                    skipped = Some(reason);
+                    break;
+                }
+            }
+        }
+        for id in &strategy.segments {
+            match segment_keys.get(id) {
+                Some(key) => clauses.push(clause(
+                    "key",
+                    Op::SegmentMatch,
+                    vec![key.as_str().into()],
+                    false,
+                )),
+                None => {
+                    skipped = Some(format!("segment {} was not imported", id));
+                    break;
+                }
+            }
+        }
+        if let Some(reason) = skipped {
+            import.unmapped(item, format!("{}, strategy skipped", reason));
+            continue;
+        }
+
+        let serve = match strategy.strategy_name.as_str() {
+            "default" => Serve::Variation(ON),
+            "userWithId" => {
+                let ids = strategy.list_parameter("userIds");
+                if clauses.is_empty() {
+                    config.targets.push(Target {
+                        variation: ON,
+                        values: ids
+                            .iter()
+                            .filter_map(|v| v.as_str().map(str::to_string))
+                            .collect(),
+                    });
+                    continue;
+                }
+                clauses.push(clause("key", Op::In, ids, false));
+                Serve::Variation(ON)
+            }
+            "remoteAddress" => {
+                // Single addresses become one-address blocks, /32 or /128.
+                let mut networks = Vec::new();
+                for ip in strategy.list_parameter("IPs") {
+                    let text = ip.as_str().unwrap_or_default();
+                    match text.parse::<IpNet>() {
+                        Ok(network) => networks.push(network),
+                        Err(_) => match text.parse::<IpAddr>() {
+                            Ok(address) => networks.push(IpNet::from(address)),
+                            Err(_) => import.unmapped(
+                                item.clone(),
+                                format!("\"{}\" is not an IP address or CIDR range, skipped", text),
+                            ),
+                        },
+                    }
+                }
+                if networks.is_empty() {
+                    import.unmapped(item, "no IP addresses, strategy skipped");
+                    continue;
+                }
+                let values = networks.iter().map(|n| n.to_string().into()).collect();
+                clauses.push(clause("remoteAddress", Op::InCidr, values, false));
+                Serve::Variation(ON)
+            }
+            "applicationHostname" => {
+                clauses.push(clause(
+                    "hostname",
+                    Op::In,
+                    strategy.list_parameter("hostNames"),
+                    false,
+                ));
+                Serve::Variation(ON)
+            }
+            "flexibleRollout" => {
+                let bucket_by = match strategy.parameter("stickiness").as_deref() {
+                    None | Some("default") | Some("userId") => None,
+                    Some("random") => {
+                        import.unmapped(
+                            item.clone(),
+                            "random stickiness is not supported, contexts are bucketed by key",
+                        );
+                        None
+                    }
+                    Some(other) => Some(other.to_string()),
+                };
+                percentage_rollout(ON, OFF, strategy.percentage("rollout"), bucket_by)
+            }
+            "gradualRolloutUserId" => {
+                percentage_rollout(ON, OFF, strategy.percentage("percentage"), None)
+            }
+            "gradualRolloutSessionId" => percentage_rollout(
+                ON,
+                OFF,
+                strategy.percentage("percentage"),
+                Some("sessionId".to_string()),
+            ),
+            "gradualRolloutRandom" => {
+                import.unmapped(
+                    item.clone(),
+                    "random rollouts are not supported, contexts are bucketed by key",
+                );
+                percentage_rollout(ON, OFF, strategy.percentage("percentage"), None)
+            }
+            other => {
+                import.unmapped(item, format!("custom strategy {} is not supported", other));
+                continue;
+            }
+        };
+
+        if partial_rule {
+            import.unmapped(
+                item.clone(),
+                "an earlier strategy is a partial rollout with constraints, contexts it does not roll out to will not reach this strategy",
+            );
+        }
+
+        if clauses.is_empty() {
+            if fallthrough_set {
+                import.unmapped(
+                    item,
+                    "only the first strategy without constraints is imported",
+                );
+                continue;
+            }
+            config.fallthrough = serve;
+            fallthrough_set = true;
+        } else {
+            partial_rule |= matches!(serve, Serve::Rollout(_));
+            config.rules.push(Rule { clauses, serve });
+        }
+    }
+
+    config
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::flags::{validate, Definitions};
+
+    fn definitions(yaml: &str) -> Definitions {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    fn unmapped(import: &Import) -> Vec<String> {
+        import.unmapped.iter().map(ToString::to_string).collect()
+    }
+
+    #[test]
+    fn imports_a_state_export() {
+        let export = json!({
+            "version": 1,
+            "features": [
+                {"name": "New Checkout", "description": "Checkout redesign", "type": "release"},
+                {"name": "maintenance-mode", "description": "", "type": "kill-switch", "stale": true}
+            ],
+            "environments": [{"name": "development"}, {"name": "Production"}],
+            "featureEnvironments": [
+                {"featureName": "New Checkout", "environment": "Production", "enabled": true},
+                {"featureName": "maintenance-mode", "environment": "development", "enabled": false}
+            ],
+            "featureStrategies": [
+                {
+                    "featureName": "New Checkout", "environment": "Production",
+                    "strategyName": "remoteAddress", "sortOrder": 1,
+                    "parameters": {"IPs": "10.0.0.0/8, 192.168.1.7, 2001:db8::/32, office"}
+                },
+                {
+                    "featureName": "New Checkout", "environment": "Production",
+                    "strategyName": "flexibleRollout", "sortOrder": 2,
+                    "parameters": {"rollout": "25", "stickiness": "default", "groupId": "New Checkout"},
+                    "constraints": [
+                        {"contextName": "appVersion", "operator": "SEMVER_GT", "value": "4.2.0"},
+                        {"contextName": "country", "operator": "NOT_IN", "values": ["FR", "DE"]}
+                    ]
+                },
+                {
+                    "featureName": "New Checkout", "environment": "Production",
+                    "strategyName": "userWithId", "sortOrder": 0,
+                    "parameters": {"userIds": "alice, bob"}
+                },
+                {
+                    "featureName": "New Checkout", "environment": "Production",
+                    "strategyName": "default", "sortOrder": 3, "segments": [1]
+                },
+                {
+                    "featureName": "New Checkout", "environment": "development",
+                    "strategyName": "gradualRolloutRandom", "parameters": {"percentage": "50"}
+                },
+                {
+                    "featureName": "maintenance-mode", "environment": "development",
+                    "strategyName": "maintenanceWindow"
+                },
+                {
+                    "featureName": "maintenance-mode", "environment": "Production",
+                    "strategyName": "default", "segments": [2]
+                }
+            ],
+            "segments": [
+                {
+                    "id": 1, "name": "Beta Users",
+                    "constraints": [{"contextName": "email", "operator": "STR_ENDS_WITH", "values": ["@example.com"]}]
+                },
+                {"id": 2, "name": "everyone", "constraints": []}
+            ]
+        });
+
+        let import = import(serde_json::from_value(export).unwrap());
+        validate(&import.definitions).unwrap();
+        let expected = definitions(
+            r#"
+environments:
+  - key: development
+  - key: production
+    name: Production
+segments:
+  - key: beta-users
+    name: Beta Users
+    environments:
+      development: &beta
+        rules:
+          - clauses:
+              - {attribute: email, op: ends_with, values: ["@example.com"]}
+      production: *beta
+flags:
+  - key: new-checkout
+    name: New Checkout
+    description: Checkout redesign
+    variations: [{value: true}, {value: false}]
+    environments:
+      development:
+        on: false
+        off_variation: 1
+        fallthrough:
+          rollout:
+            variations: [{variation: 0, weight: 50000}, {variation: 1, weight: 50000}]
+      production:
+        on: true
+        off_variation: 1
+        fallthrough: {variation: 1}
+        targets: [{variation: 0, values: [alice, bob]}]
+        rules:
+          - clauses:
+              - attribute: remoteAddress
+                op: in_cidr
+                values: [10.0.0.0/8, 192.168.1.7/32, "2001:db8::/32"]
+            serve: {variation: 0}
+          - clauses:
+              - {attribute: appVersion, op: semver_greater_than, values: [4.2.0]}
+              - {attribute: country, op: in, values: [FR, DE], negate: true}
+            serve:
+              rollout:
+                variations: [{variation: 0, weight: 25000}, {variation: 1, weight: 75000}]
+          - clauses:
+              - {attribute: key, op: segment_match, values: [beta-users]}
+            serve: {variation: 0}
+  - key: maintenance-mode
+    kind: permanent
+    lifecycle: deprecated
+    variations: [{value: true}, {value: false}]
+    environments:
+      development: {on: false, off_variation: 1, fallthrough: {variation: 1}}
+      production: {on: false, off_variation: 1, fallthrough: {variation: 1}}
+"#,
+        );
+        assert_eq!(import.definitions, expected);
+        assert_eq!(
+            unmapped(&import),
+            [
+                "segment everyone: segments without constraints are not supported",
+                "feature New Checkout in development: strategy gradualRolloutRandom: random rollouts are not supported, contexts are bucketed by key",
+                "feature New Checkout in Production: strategy remoteAddress: \"office\" is not an IP address or CIDR range, skipped",
+                "feature New Checkout in Production: strategy default: an earlier strategy is a partial rollout with constraints, contexts it does not roll out to will not reach this strategy",
+                "feature maintenance-mode in development: strategy maintenanceWindow: custom strategy maintenanceWindow is not supported",
+                "feature maintenance-mode in Production: strategy default: segment 2 was not imported, strategy skipped",
+            ]
+        );
+    }
+
+    #[test]
+    fn names_with_the_same_key_get_unique_keys() {
+        let export = json!({
+            "features": [{"name": "My Flag"}, {"name": "my-flag"}, {"name": "my flag"}],
+            "environments": [{"name": "production"}]
+        });
+
+        let import = import(serde_json::from_value(export).unwrap());
+        validate(&import.definitions).unwrap();
+        let flags: Vec<(&str, Option<&str>)> = import
+            .definitions
+            .flags
+            .iter()
+            .map(|f| (f.key.as_str(), f.name.as_deref()))
+            .collect();
+        assert_eq!(
+            flags,
+            [
+                ("my-flag", Some("My Flag")),
+                ("my-flag-2", Some("my-flag")),
+                ("my-flag-3", Some("my flag")),
+            ]
+        );
+        assert_eq!(
+            unmapped(&import),
+            [
+                "feature my-flag: key \"my-flag\" is already taken, imported as \"my-flag-2\"",
+                "feature my flag: key \"my-flag\" is already taken, imported as \"my-flag-3\"",
+            ]
+        );
+    }
+}
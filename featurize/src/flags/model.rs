@@ -0,0 +1,522 @@
+// Featurize, the FOSS feature flagging
+// Copyright (C) 2024  Lucy Ekaterina
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use std::{collections::BTreeMap, fmt};
+
+use chrono::{DateTime, Utc};
+
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+
+use super::operands::Operands;
+
+/// Rollout weights are expressed in thousandths of a percent.
+pub const ROLLOUT_WEIGHT_TOTAL: u32 = 100_000;
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Definitions {
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub environments: Vec<Environment>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub segments: Vec<Segment>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub flags: Vec<Flag>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub layers: Vec<Layer>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub experiments: Vec<Experiment>,
+    /// The context attributes rules may refer to.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub attributes: Vec<Attribute>,
+    /// Rejects rules that refer to attributes missing from `attributes` or
+    /// compare them with the wrong type, instead of only warning about them.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub strict_attributes: bool,
+}
+
+impl Definitions {
+    pub fn environment(&self, key: &str) -> Option<&Environment> {
+        self.environments.iter().find(|e| e.key == key)
+    }
+
+    pub fn flag(&self, key: &str) -> Option<&Flag> {
+        self.flags.iter().find(|f| f.key == key)
+    }
+
+    pub fn experiment(&self, key: &str) -> Option<&Experiment> {
+        self.experiments.iter().find(|e| e.key == key)
+    }
+
+    /// What evaluating in `environment` needs: that environment, and flags,
+    /// segments and experiments with only their configuration there. What
+    /// only the dashboard uses, approvals, owners, webhooks and tests, is
+    /// left out.
+    pub fn for_environment(&self, environment: &str) -> Definitions {
+        let mut scoped = self.clone();
+        scoped.environments.retain(|e| e.key == environment);
+        for environment in &mut scoped.environments {
+            environment.approvals = None;
+        }
+        for flag in &mut scoped.flags {
+            flag.environments.retain(|key, _| key == environment);
+            flag.owner = None;
+            flag.webhooks.clear();
+            flag.tests.clear();
+        }
+        for segment in &mut scoped.segments {
+            segment.environments.retain(|key, _| key == environment);
+        }
+        scoped.experiments.retain(|e| e.environment == environment);
+        scoped
+    }
+
+    /// The experiment running on `flag` in `environment`, there is at most one.
+    pub fn running_experiment(&self, flag: &str, environment: &str) -> Option<&Experiment> {
+        self.experiments
+            .iter()
+            .find(|e| e.flag == flag && e.environment == environment)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Environment {
+    pub key: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Share of contexts, in rollout weight units, kept out of every experiment
+    /// in this environment. They are always served the control variation.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub holdout: Option<u32>,
+    /// Changes made from the dashboard need approving when set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub approvals: Option<Approvals>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Approvals {
+    /// Approvals needed from reviewers other than the author.
+    #[serde(default = "one")]
+    pub required: usize,
+    /// Roles, from the `roles` trait of reviewers' identities, allowed to
+    /// review. Anyone may when empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub reviewers: Vec<String>,
+}
+
+fn one() -> usize {
+    1
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Segment {
+    pub key: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub environments: BTreeMap<String, SegmentConfig>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SegmentConfig {
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub included: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub excluded: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub rules: Vec<SegmentRule>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct SegmentRule {
+    pub clauses: Vec<Clause>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Flag {
+    pub key: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub kind: FlagKind,
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub lifecycle: Lifecycle,
+    /// Free-form labels to find the flag by, like a product area or epic.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<Owner>,
+    pub variations: Vec<Variation>,
+    #[serde(default)]
+    pub environments: BTreeMap<String, FlagConfig>,
+    /// Lets the flag be killed from the dashboard, serving one variation to
+    /// everyone.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kill_switch: Option<KillSwitch>,
+    /// URLs that are posted to when featurize changes the flag by itself.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub webhooks: Vec<String>,
+    /// Checked whenever the definitions are loaded, definitions with failing
+    /// tests are rejected.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tests: Vec<FlagTest>,
+}
+
+fn is_default<T: Default + PartialEq>(value: &T) -> bool {
+    *value == T::default()
+}
+
+impl Flag {
+    /// Changes whenever anything about the flag does, so edits can be made
+    /// on top of the version they started from.
+    pub fn version(&self) -> String {
+        // Flags only hold JSON values, serializing them can't fail.
+        let json = serde_json::to_vec(self).unwrap_or_default();
+        let mut prefix = [0; 8];
+        prefix.copy_from_slice(&Sha1::digest(json)[..8]);
+        format!("{:016x}", u64::from_be_bytes(prefix))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct KillSwitch {
+    /// The safe variation served to every context once killed.
+    pub variation: usize,
+}
+
+/// Who is responsible for a flag.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Owner {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub team: Option<String>,
+    /// Email address of the person looking after the flag.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub maintainer: Option<String>,
+}
+
+/// Temporary flags are expected to be removed once they have done their job,
+/// permanent ones (kill switches, entitlements) are never cleanup candidates.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FlagKind {
+    #[default]
+    Temporary,
+    Permanent,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Lifecycle {
+    #[default]
+    Active,
+    /// Fully released, the flag only waits to be removed from code.
+    Launched,
+    /// Should no longer be used in new code.
+    Deprecated,
+    /// No longer evaluated, kept for its history.
+    Archived,
+}
+
+impl fmt::Display for Lifecycle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Lifecycle::Active => "active",
+            Lifecycle::Launched => "launched",
+            Lifecycle::Deprecated => "deprecated",
+            Lifecycle::Archived => "archived",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FlagTest {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub environment: String,
+    pub context: serde_json::Map<String, serde_json::Value>,
+    /// Evaluates as if it were this time, so tests of `during` and `schedule`
+    /// clauses don't depend on when they run.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub at: Option<DateTime<Utc>>,
+    /// The variation the context must be served.
+    pub variation: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Variation {
+    pub value: serde_json::Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FlagConfig {
+    #[serde(default)]
+    pub on: bool,
+    pub off_variation: usize,
+    #[serde(with = "serde_yaml::with::singleton_map")]
+    pub fallthrough: Serve,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub prerequisites: Vec<Prerequisite>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub targets: Vec<Target>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rules: Vec<Rule>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub guardrails: Option<Guardrails>,
+}
+
+/// Metrics watched while the flag rolls out. When another variation does
+/// significantly worse than `safe_variation` the rollout is stopped and every
+/// context is served `safe_variation`.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Guardrails {
+    pub safe_variation: usize,
+    pub metrics: Vec<Guardrail>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Guardrail {
+    pub metric: String,
+    #[serde(default)]
+    pub kind: MetricKind,
+    /// Largest relative increase over the safe variation that is tolerated,
+    /// for metrics like error rates.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_increase: Option<f64>,
+    /// Largest relative decrease that is tolerated, for metrics like
+    /// conversions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_decrease: Option<f64>,
+    /// Contexts each variation needs before the guardrail is checked.
+    #[serde(default = "default_min_units")]
+    pub min_units: u64,
+}
+
+fn default_min_units() -> u64 {
+    100
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Prerequisite {
+    pub flag: String,
+    pub variation: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Target {
+    pub variation: usize,
+    pub values: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    pub clauses: Vec<Clause>,
+    #[serde(with = "serde_yaml::with::singleton_map")]
+    pub serve: Serve,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Serve {
+    Variation(usize),
+    Rollout(Rollout),
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rollout {
+    pub variations: Vec<WeightedVariation>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bucket_by: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct WeightedVariation {
+    pub variation: usize,
+    pub weight: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Clause {
+    /// Empty for operators that compare the time of evaluation.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub attribute: String,
+    pub op: Op,
+    pub values: Vec<serde_json::Value>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub negate: bool,
+    /// `values` parsed for `op`, see [`Clause::operands`].
+    #[serde(skip)]
+    pub parsed: Operands,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Op {
+    In,
+    StartsWith,
+    EndsWith,
+    Contains,
+    LessThan,
+    LessThanOrEqual,
+    GreaterThan,
+    GreaterThanOrEqual,
+    SegmentMatch,
+    SemverEqual,
+    SemverLessThan,
+    SemverLessThanOrEqual,
+    SemverGreaterThan,
+    SemverGreaterThanOrEqual,
+    Before,
+    After,
+    /// Regular expression search, anchor with `^` and `$` to match the whole
+    /// value.
+    Matches,
+    InCidr,
+    /// Absolute time windows.
+    During,
+    /// Recurring days of the week and times of day.
+    Schedule,
+}
+
+impl Op {
+    /// Whether clauses compare the time of evaluation rather than an attribute.
+    pub fn uses_clock(self) -> bool {
+        matches!(self, Op::During | Op::Schedule)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Attribute {
+    pub key: String,
+    #[serde(rename = "type")]
+    pub kind: AttributeType,
+    /// Contexts send a list of values, clauses match if any of them does.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub list: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AttributeType {
+    String,
+    Number,
+    Boolean,
+}
+
+impl AttributeType {
+    /// The type of a single JSON value, `None` for null, lists and objects.
+    pub fn of(value: &serde_json::Value) -> Option<Self> {
+        match value {
+            serde_json::Value::String(_) => Some(AttributeType::String),
+            serde_json::Value::Number(_) => Some(AttributeType::Number),
+            serde_json::Value::Bool(_) => Some(AttributeType::Boolean),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for AttributeType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AttributeType::String => write!(f, "string"),
+            AttributeType::Number => write!(f, "number"),
+            AttributeType::Boolean => write!(f, "boolean"),
+        }
+    }
+}
+
+/// Compares the variations of a flag in one environment against `control`
+/// using metric events tracked for the contexts that were served them.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Experiment {
+    pub key: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    pub flag: String,
+    pub environment: String,
+    pub control: usize,
+    pub metrics: Vec<Metric>,
+    /// Experiments in the same layer and environment never share a context.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub layer: Option<String>,
+    /// Share of contexts, in rollout weight units, that enter the experiment.
+    /// The rest are served the control variation.
+    #[serde(default = "all_traffic")]
+    pub traffic: u32,
+}
+
+fn all_traffic() -> u32 {
+    ROLLOUT_WEIGHT_TOTAL
+}
+
+/// Partitions traffic between experiments, each experiment in a layer gets
+/// the next `traffic` sized slice of its contexts in the order declared.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Layer {
+    pub key: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Metric {
+    pub key: String,
+    #[serde(default)]
+    pub kind: MetricKind,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MetricKind {
+    /// The share of contexts that tracked the metric at least once.
+    #[default]
+    Conversion,
+    /// The average of the values tracked per context.
+    Mean,
+}
@@ -0,0 +1,341 @@
+// Featurize, the FOSS feature flagging
+// Copyright (C) 2024  Lucy Ekaterina
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//! Parsing for the operators that compare typed values. Clause values are
+//! parsed strictly since validation rejects the ones that don't parse, while
+//! context attributes are read leniently and simply don't match when they
+//! can't be read.
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, OnceLock, RwLock,
+    },
+};
+
+use chrono::{DateTime, NaiveDate, Utc};
+use ipnet::IpNet;
+use regex::Regex;
+use semver::Version;
+use serde_json::Value;
+
+use super::{Clause, Op, Schedule, Window};
+
+/// A clause value read as the type its operator compares.
+#[derive(Debug, Clone)]
+pub enum Operand {
+    Version(Version),
+    Time(DateTime<Utc>),
+    Pattern(Regex),
+    Network(IpNet),
+    Window(Window),
+    Schedule(Schedule),
+    /// Compared as it is.
+    Value,
+}
+
+/// Checks that `value` is a valid operand for `op`.
+pub fn check_operand(op: Op, value: &Value) -> Result<(), String> {
+    operand(op, value).map(drop)
+}
+
+pub fn operand(op: Op, value: &Value) -> Result<Operand, String> {
+    match op {
+        Op::SemverEqual
+        | Op::SemverLessThan
+        | Op::SemverLessThanOrEqual
+        | Op::SemverGreaterThan
+        | Op::SemverGreaterThanOrEqual => version(value).map(Operand::Version),
+        Op::Before | Op::After => time(value).map(Operand::Time),
+        Op::Matches => pattern(value).map(Operand::Pattern),
+        Op::InCidr => network(value).map(Operand::Network),
+        Op::During => Window::parse(value).map(Operand::Window),
+        Op::Schedule => Schedule::parse(value).map(Operand::Schedule),
+        _ => Ok(Operand::Value),
+    }
+}
+
+/// The values of a clause parsed for its operator, once: validation parses
+/// them as definitions load and evaluations reuse them. Clones of the clause
+/// share them and they never make two clauses differ.
+#[derive(Debug, Clone, Default)]
+pub struct Operands(OnceLock<Arc<[Parsed]>>);
+
+/// An operand or why the value isn't one.
+pub type Parsed = Result<Operand, String>;
+
+impl PartialEq for Operands {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+impl Clause {
+    /// One operand or parse error per value.
+    pub fn operands(&self) -> &[Parsed] {
+        self.parsed
+            .0
+            .get_or_init(|| {
+                self.values
+                    .iter()
+                    .map(|value| operand(self.op, value))
+                    .collect()
+            })
+            .as_ref()
+    }
+}
+
+pub fn version(value: &Value) -> Result<Version, String> {
+    let text = value
+        .as_str()
+        .ok_or_else(|| format!("{} is not a version string", value))?;
+    Version::parse(text).map_err(|e| format!("\"{}\" is not a semantic version: {}", text, e))
+}
+
+/// Also accepts a leading `v` and versions missing their minor or patch
+/// number, so "v4.2" reads as 4.2.0.
+pub fn version_attribute(value: &Value) -> Option<Version> {
+    let text = value.as_str()?;
+    let text = text.strip_prefix('v').unwrap_or(text);
+    if let Ok(version) = Version::parse(text) {
+        return Some(version);
+    }
+    if !text.chars().all(|c| c.is_ascii_digit() || c == '.') {
+        return None;
+    }
+    let padded = match text.split('.').count() {
+        1 => format!("{}.0.0", text),
+        2 => format!("{}.0", text),
+        _ => return None,
+    };
+    Version::parse(&padded).ok()
+}
+
+/// An RFC 3339 timestamp, a date (midnight UTC) or Unix milliseconds.
+pub fn time(value: &Value) -> Result<DateTime<Utc>, String> {
+    match value {
+        Value::String(text) => DateTime::parse_from_rfc3339(text)
+            .map(|t| t.with_timezone(&Utc))
+            .or_else(|_| {
+                NaiveDate::parse_from_str(text, "%Y-%m-%d")
+                    .map(|d| d.and_hms_opt(0, 0, 0).unwrap_or_default().and_utc())
+            })
+            .map_err(|_| {
+                format!(
+                    "\"{}\" is not an RFC 3339 timestamp or a YYYY-MM-DD date",
+                    text
+                )
+            }),
+        Value::Number(millis) => millis
+            .as_i64()
+            .and_then(DateTime::from_timestamp_millis)
+            .ok_or_else(|| format!("{} is not a time in Unix milliseconds", millis)),
+        other => Err(format!("{} is not a time", other)),
+    }
+}
+
+/// How many compiled patterns are kept. Drafts and simulations can bring any
+/// number of patterns, the least recently used ones are dropped past this.
+const COMPILED_PATTERNS: usize = 1024;
+
+pub fn pattern(value: &Value) -> Result<Regex, String> {
+    let text = value
+        .as_str()
+        .ok_or_else(|| format!("{} is not a regular expression string", value))?;
+    static COMPILED: OnceLock<Patterns> = OnceLock::new();
+    COMPILED
+        .get_or_init(|| Patterns::new(COMPILED_PATTERNS))
+        .get(text)
+}
+
+/// Compiled patterns with when they were last used.
+struct Patterns {
+    capacity: usize,
+    compiled: RwLock<HashMap<String, (Regex, AtomicU64)>>,
+    clock: AtomicU64,
+}
+
+impl Patterns {
+    fn new(capacity: usize) -> Self {
+        Patterns {
+            capacity,
+            compiled: Default::default(),
+            clock: AtomicU64::new(0),
+        }
+    }
+
+    fn get(&self, text: &str) -> Result<Regex, String> {
+        if let Some((regex, used)) = self.compiled.read().unwrap().get(text) {
+            used.store(
+                self.clock.fetch_add(1, Ordering::Relaxed),
+                Ordering::Relaxed,
+            );
+            return Ok(regex.clone());
+        }
+        let regex = Regex::new(text).map_err(|e| {
+            // Syntax errors draw a diagram of the pattern above the actual message.
+            let message = e.to_string();
+            let message = message
+                .lines()
+                .last()
+                .unwrap_or_default()
+                .trim()
+                .trim_start_matches("error: ")
+                .to_string();
+            format!(
+                "\"{}\" is not a valid regular expression: {}",
+                text, message
+            )
+        })?;
+        let mut compiled = self.compiled.write().unwrap();
+        if compiled.len() >= self.capacity {
+            let least_recent = compiled
+                .iter()
+                .min_by_key(|(_, (_, used))| used.load(Ordering::Relaxed))
+                .map(|(text, _)| text.clone());
+            if let Some(text) = least_recent {
+                compiled.remove(&text);
+            }
+        }
+        let used = AtomicU64::new(self.clock.fetch_add(1, Ordering::Relaxed));
+        compiled.insert(text.to_string(), (regex.clone(), used));
+        Ok(regex)
+    }
+}
+
+pub fn network(value: &Value) -> Result<IpNet, String> {
+    let text = value
+        .as_str()
+        .ok_or_else(|| format!("{} is not a CIDR block string", value))?;
+    text.parse()
+        .map_err(|_| format!("\"{}\" is not a CIDR block such as 10.0.0.0/8", text))
+}
+
+pub fn address(value: &Value) -> Option<IpAddr> {
+    value.as_str()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn parses_versions() {
+        assert_eq!(
+            version(&json!("1.2.3-beta.1")),
+            Ok(Version::parse("1.2.3-beta.1").unwrap())
+        );
+        for value in [json!("1.2"), json!("v1.2.3"), json!(123)] {
+            assert!(version(&value).is_err(), "{}", value);
+        }
+        assert_eq!(
+            version_attribute(&json!("v4.2")),
+            Some(Version::new(4, 2, 0))
+        );
+        assert_eq!(version_attribute(&json!("7")), Some(Version::new(7, 0, 0)));
+        assert_eq!(version_attribute(&json!("4.x")), None);
+        assert_eq!(version_attribute(&json!(4)), None);
+    }
+
+    #[test]
+    fn parses_times() {
+        let expected = DateTime::parse_from_rfc3339("2024-03-01T00:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        for value in [
+            json!("2024-03-01T01:00:00+01:00"),
+            json!("2024-03-01"),
+            json!(1709251200000_i64),
+        ] {
+            assert_eq!(time(&value), Ok(expected), "{}", value);
+        }
+        for value in [json!("2024-03-01 00:00"), json!("yesterday"), json!(true)] {
+            assert!(time(&value).is_err(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn parses_patterns() {
+        assert!(pattern(&json!("^beta-[0-9]+$"))
+            .unwrap()
+            .is_match("beta-42"));
+        let error = pattern(&json!("beta-(")).unwrap_err();
+        assert_eq!(
+            error,
+            "\"beta-(\" is not a valid regular expression: unclosed group"
+        );
+        assert!(pattern(&json!(["beta"])).is_err());
+    }
+
+    #[test]
+    fn parses_networks() {
+        let network = network(&json!("10.0.0.0/8")).unwrap();
+        assert!(network.contains(&address(&json!("10.1.2.3")).unwrap()));
+        assert!(!network.contains(&address(&json!("192.168.0.1")).unwrap()));
+        assert!(super::network(&json!("2001:db8::/32")).is_ok());
+        for value in [json!("10.0.0.1"), json!("10.0.0.0/33"), json!(10)] {
+            assert!(super::network(&value).is_err(), "{}", value);
+        }
+        assert_eq!(address(&json!("not an address")), None);
+    }
+
+    #[test]
+    fn evicts_the_least_recently_used_pattern() {
+        let patterns = Patterns::new(2);
+        patterns.get("a").unwrap();
+        patterns.get("b").unwrap();
+        patterns.get("a").unwrap();
+        patterns.get("c").unwrap();
+        let compiled = patterns.compiled.read().unwrap();
+        let mut kept: Vec<&str> = compiled.keys().map(String::as_str).collect();
+        kept.sort();
+        assert_eq!(kept, ["a", "c"]);
+    }
+
+    #[test]
+    fn parses_clause_values_once() {
+        let clause: Clause = serde_json::from_value(
+            json!({"attribute": "version", "op": "semver_less_than", "values": ["2.0.0", "two"]}),
+        )
+        .unwrap();
+        let operands = clause.operands();
+        assert!(matches!(operands[0], Ok(Operand::Version(ref v)) if *v == Version::new(2, 0, 0)));
+        assert!(operands[1].is_err());
+        assert!(std::ptr::eq(operands, clause.clone().operands()));
+    }
+
+    #[test]
+    fn keeps_parsed_windows_and_schedules() {
+        let during: Clause = serde_json::from_value(json!({
+            "op": "during",
+            "values": [{"start": "2024-03-01", "end": "2024-03-02"}]
+        }))
+        .unwrap();
+        let now = DateTime::parse_from_rfc3339("2024-03-01T12:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        assert!(matches!(&during.operands()[0], Ok(Operand::Window(w)) if w.contains(now)));
+
+        let schedule: Clause = serde_json::from_value(json!({
+            "op": "schedule",
+            "values": [{"days": ["Fri"], "start": "09:00", "end": "17:00"}]
+        }))
+        .unwrap();
+        assert!(matches!(&schedule.operands()[0], Ok(Operand::Schedule(s)) if s.contains(now)));
+    }
+}
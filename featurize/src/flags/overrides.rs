@@ -0,0 +1,186 @@
+// Featurize, the FOSS feature flagging
+// Copyright (C) 2024  Lucy Ekaterina
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use super::{Definitions, Serve};
+
+/// A change made at runtime on top of the definitions loaded from files. It is
+/// kept, across reloads, until it is cleared.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Override {
+    pub environment: String,
+    pub flag: String,
+    pub action: OverrideAction,
+    pub reason: String,
+    pub at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum OverrideAction {
+    /// Serve `variation` wherever the flag would roll out between variations.
+    Rollback { variation: usize },
+    /// Serve `variation` to every context, as if the flag were off.
+    Kill { variation: usize },
+}
+
+/// Stops every change to an environment until it is lifted, except overrides
+/// such as kills and rollbacks, which are meant for emergencies.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Freeze {
+    pub reason: String,
+    pub at: DateTime<Utc>,
+}
+
+/// Everything changed at runtime, kept in the overrides file.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct RuntimeChanges {
+    pub overrides: Vec<Override>,
+    /// Keyed by environment.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub freezes: BTreeMap<String, Freeze>,
+}
+
+/// Overrides files used to hold only the list of overrides.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub(super) enum OverridesFile {
+    Changes(RuntimeChanges),
+    Overrides(Vec<Override>),
+}
+
+impl From<OverridesFile> for RuntimeChanges {
+    fn from(file: OverridesFile) -> Self {
+        match file {
+            OverridesFile::Changes(changes) => changes,
+            OverridesFile::Overrides(overrides) => Self {
+                overrides,
+                freezes: BTreeMap::new(),
+            },
+        }
+    }
+}
+
+impl Override {
+    pub fn applies_to(&self, environment: &str, flag: &str) -> bool {
+        self.environment == environment && self.flag == flag
+    }
+
+    pub fn variation(&self) -> usize {
+        match self.action {
+            OverrideAction::Rollback { variation } | OverrideAction::Kill { variation } => {
+                variation
+            }
+        }
+    }
+
+    /// Fails when the flag has no such variation, such as after an edit
+    /// removed it. Overrides of flags that don't exist are fine, they don't
+    /// apply to anything.
+    pub fn check(&self, definitions: &Definitions) -> Result<(), String> {
+        match definitions.flag(&self.flag) {
+            Some(flag) if self.variation() >= flag.variations.len() => Err(format!(
+                "flag \"{}\" has no variation {}",
+                self.flag,
+                self.variation()
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The definitions with every override applied. Overrides for flags or
+/// environments that no longer exist, or of variations that don't, are
+/// ignored.
+pub fn apply(definitions: &Definitions, overrides: &[Override]) -> Definitions {
+    let mut definitions = definitions.clone();
+    for o in overrides {
+        if let Err(e) = o.check(&definitions) {
+            tracing::warn!("ignoring override in {}: {}", o.environment, e);
+            continue;
+        }
+        let Some(config) = definitions
+            .flags
+            .iter_mut()
+            .find(|f| f.key == o.flag)
+            .and_then(|f| f.environments.get_mut(&o.environment))
+        else {
+            continue;
+        };
+        match o.action {
+            OverrideAction::Rollback { variation } => {
+                let serves = std::iter::once(&mut config.fallthrough)
+                    .chain(config.rules.iter_mut().map(|r| &mut r.serve));
+                for serve in serves {
+                    if let Serve::Rollout(_) = serve {
+                        *serve = Serve::Variation(variation);
+                    }
+                }
+            }
+            OverrideAction::Kill { variation } => {
+                config.on = false;
+                config.off_variation = variation;
+            }
+        }
+    }
+    definitions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ignores_overrides_of_missing_variations() {
+        let definitions: Definitions = serde_yaml::from_str(
+            r#"
+environments:
+  - key: production
+flags:
+  - key: checkout
+    variations: [{value: false}, {value: true}]
+    environments:
+      production:
+        on: true
+        off_variation: 0
+        fallthrough: {variation: 1}
+"#,
+        )
+        .unwrap();
+        let kill = |variation| Override {
+            environment: "production".to_string(),
+            flag: "checkout".to_string(),
+            action: OverrideAction::Kill { variation },
+            reason: String::new(),
+            at: Utc::now(),
+        };
+        assert!(kill(2).check(&definitions).is_err());
+        let applied = apply(&definitions, &[kill(2)]);
+        assert_eq!(applied, definitions);
+        let applied = apply(&definitions, &[kill(1)]);
+        assert!(!applied.flags[0].environments["production"].on);
+
+        let rollback = |variation| Override {
+            action: OverrideAction::Rollback { variation },
+            ..kill(0)
+        };
+        assert!(rollback(5).check(&definitions).is_err());
+        assert_eq!(apply(&definitions, &[rollback(5)]), definitions);
+    }
+}
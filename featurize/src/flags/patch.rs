@@ -0,0 +1,263 @@
+// Featurize, the FOSS feature flagging
+// Copyright (C) 2024  Lucy Ekaterina
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use serde::Deserialize;
+
+use super::{Flag, FlagConfig, Rule, Serve, Target};
+
+#[derive(Debug, thiserror::Error)]
+pub enum PatchError {
+    #[error("instruction {index}: {message}")]
+    Instruction { index: usize, message: String },
+    #[error("{0}")]
+    JsonPatch(#[from] json_patch::PatchError),
+    #[error("the patched flag is not a flag: {0}")]
+    NotAFlag(String),
+    #[error("a patch needs either instructions or a JSON patch")]
+    Empty,
+}
+
+/// One step of a semantic patch, made on the flag's configuration in
+/// `environment`. Rules are referred to by their position, starting at 0.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "kind", rename_all = "camelCase", deny_unknown_fields)]
+pub enum Instruction {
+    TurnOn {
+        environment: String,
+    },
+    TurnOff {
+        environment: String,
+    },
+    /// Inserts `rule` at `index`, after the other rules without one.
+    AddRule {
+        environment: String,
+        rule: Rule,
+        #[serde(default)]
+        index: Option<usize>,
+    },
+    RemoveRule {
+        environment: String,
+        index: usize,
+    },
+    /// Changes what a rule serves, or the fallthrough without `rule`.
+    UpdateRollout {
+        environment: String,
+        #[serde(default)]
+        rule: Option<usize>,
+        #[serde(with = "serde_yaml::with::singleton_map")]
+        serve: Serve,
+    },
+    AddTargets {
+        environment: String,
+        variation: usize,
+        values: Vec<String>,
+    },
+    RemoveTargets {
+        environment: String,
+        variation: usize,
+        values: Vec<String>,
+    },
+    /// Puts the rules in the order of their current positions in `order`.
+    ReorderRules {
+        environment: String,
+        order: Vec<usize>,
+    },
+}
+
+impl Instruction {
+    fn environment(&self) -> &str {
+        match self {
+            Instruction::TurnOn { environment }
+            | Instruction::TurnOff { environment }
+            | Instruction::AddRule { environment, .. }
+            | Instruction::RemoveRule { environment, .. }
+            | Instruction::UpdateRollout { environment, .. }
+            | Instruction::AddTargets { environment, .. }
+            | Instruction::RemoveTargets { environment, .. }
+            | Instruction::ReorderRules { environment, .. } => environment,
+        }
+    }
+
+    fn apply(&self, config: &mut FlagConfig) -> Result<(), String> {
+        let rule_count = config.rules.len();
+        let no_rule =
+            |index: usize| format!("there is no rule {}, the flag has {}", index, rule_count);
+        match self {
+            Instruction::TurnOn { .. } => config.on = true,
+            Instruction::TurnOff { .. } => config.on = false,
+            Instruction::AddRule { rule, index, .. } => {
+                let index = index.unwrap_or(rule_count);
+                if index > rule_count {
+                    return Err(no_rule(index));
+                }
+                config.rules.insert(index, rule.clone());
+            }
+            Instruction::RemoveRule { index, .. } => {
+                if *index >= rule_count {
+                    return Err(no_rule(*index));
+                }
+                config.rules.remove(*index);
+            }
+            Instruction::UpdateRollout { rule, serve, .. } => {
+                let target = match rule {
+                    Some(index) => {
+                        &mut config
+                            .rules
+                            .get_mut(*index)
+                            .ok_or_else(|| no_rule(*index))?
+                            .serve
+                    }
+                    None => &mut config.fallthrough,
+                };
+                *target = serve.clone();
+            }
+            Instruction::AddTargets {
+                variation, values, ..
+            } => {
+                let target = match config
+                    .targets
+                    .iter_mut()
+                    .find(|t| t.variation == *variation)
+                {
+                    Some(target) => target,
+                    None => {
+                        config.targets.push(Target {
+                            variation: *variation,
+                            values: Vec::new(),
+                        });
+                        config.targets.last_mut().unwrap()
+                    }
+                };
+                for value in values {
+                    if !target.values.contains(value) {
+                        target.values.push(value.clone());
+                    }
+                }
+            }
+            Instruction::RemoveTargets {
+                variation, values, ..
+            } => {
+                for target in config
+                    .targets
+                    .iter_mut()
+                    .filter(|t| t.variation == *variation)
+                {
+                    target.values.retain(|v| !values.contains(v));
+                }
+                config.targets.retain(|t| !t.values.is_empty());
+            }
+            Instruction::ReorderRules { order, .. } => {
+                let mut sorted = order.clone();
+                sorted.sort_unstable();
+                if sorted != (0..rule_count).collect::<Vec<_>>() {
+                    return Err(format!(
+                        "order has to list every rule from 0 to {} once",
+                        rule_count.saturating_sub(1)
+                    ));
+                }
+                let rules = std::mem::take(&mut config.rules);
+                let mut rules: Vec<Option<Rule>> = rules.into_iter().map(Some).collect();
+                config.rules = order.iter().filter_map(|i| rules[*i].take()).collect();
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Makes every instruction on a copy of `flag`, in order. Nothing is changed
+/// when one of them fails.
+pub fn apply_instructions(flag: &Flag, instructions: &[Instruction]) -> Result<Flag, PatchError> {
+    let mut patched = flag.clone();
+    for (index, instruction) in instructions.iter().enumerate() {
+        let environment = instruction.environment();
+        let config =
+            patched
+                .environments
+                .get_mut(environment)
+                .ok_or_else(|| PatchError::Instruction {
+                    index,
+                    message: format!("the flag is not configured in {}", environment),
+                })?;
+        instruction
+            .apply(config)
+            .map_err(|message| PatchError::Instruction { index, message })?;
+    }
+    Ok(patched)
+}
+
+/// Applies an RFC 6902 JSON patch to the JSON form of `flag`.
+pub fn apply_json_patch(flag: &Flag, patch: &json_patch::Patch) -> Result<Flag, PatchError> {
+    let mut document =
+        serde_json::to_value(flag).map_err(|e| PatchError::NotAFlag(e.to_string()))?;
+    json_patch::patch(&mut document, &patch.0)?;
+    serde_json::from_value(document).map_err(|e| PatchError::NotAFlag(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FLAG: &str = r#"
+key: checkout
+variations:
+  - value: false
+  - value: true
+environments:
+  production:
+    off_variation: 0
+    fallthrough:
+      variation: 0
+    rules:
+      - clauses:
+          - attribute: country
+            op: in
+            values: [NZ]
+        serve:
+          variation: 1
+"#;
+
+    #[test]
+    fn applies_instructions_in_order() {
+        let flag: Flag = serde_yaml::from_str(FLAG).unwrap();
+        let instructions: Vec<Instruction> = serde_json::from_str(
+            r#"[
+                {"kind": "turnOn", "environment": "production"},
+                {"kind": "addRule", "environment": "production", "rule": {
+                    "clauses": [{"attribute": "country", "op": "in", "values": ["AU"]}],
+                    "serve": {"variation": 0}
+                }},
+                {"kind": "reorderRules", "environment": "production", "order": [1, 0]},
+                {"kind": "addTargets", "environment": "production", "variation": 1, "values": ["alice"]},
+                {"kind": "updateRollout", "environment": "production", "serve": {"variation": 1}}
+            ]"#,
+        )
+        .unwrap();
+        let patched = apply_instructions(&flag, &instructions).unwrap();
+        let config = &patched.environments["production"];
+        assert!(config.on);
+        assert_eq!(config.rules[0].clauses[0].values[0], "AU");
+        assert_eq!(config.targets[0].values, vec!["alice".to_string()]);
+        assert_eq!(config.fallthrough, Serve::Variation(1));
+
+        let invalid: Vec<Instruction> = serde_json::from_str(
+            r#"[{"kind": "removeRule", "environment": "production", "index": 3}]"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            apply_instructions(&flag, &invalid),
+            Err(PatchError::Instruction { index: 0, .. })
+        ));
+    }
+}
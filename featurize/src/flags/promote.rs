@@ -0,0 +1,218 @@
+// Featurize, the FOSS feature flagging
+// Copyright (C) 2024  Lucy Ekaterina
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use std::collections::BTreeSet;
+
+use serde::{Deserialize, Serialize};
+
+use super::{Clause, Definitions, Op};
+
+#[derive(Debug, thiserror::Error)]
+pub enum PromotionError {
+    #[error("unknown environment \"{0}\"")]
+    UnknownEnvironment(String),
+    #[error("cannot promote from \"{0}\" to itself")]
+    SameEnvironment(String),
+    #[error("no flags selected")]
+    NoFlags,
+    #[error("unknown flag \"{0}\"")]
+    UnknownFlag(String),
+    #[error("flag \"{flag}\" is not configured in \"{environment}\"")]
+    NotConfigured { flag: String, environment: String },
+    #[error(transparent)]
+    Flags(#[from] super::Error),
+}
+
+/// Copies the configuration of `flags` in `from` to `to`.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Promotion {
+    pub from: String,
+    pub to: String,
+    pub flags: Vec<String>,
+    /// Keep the individual targets and the included and excluded lists of
+    /// segments in `to`, which usually name different users per environment.
+    pub keep_targets: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(tag = "kind", content = "key", rename_all = "lowercase")]
+pub enum Promoted {
+    Flag(String),
+    Segment(String),
+}
+
+/// Applies `promotion` to `definitions`, returning the flags and segments it
+/// changed. Flags keep whether they are on in `to`, and flags new to `to`
+/// start off, so promoting never turns a flag on. Segments the copied rules
+/// match on are promoted along with the flags.
+pub fn promote(
+    definitions: &mut Definitions,
+    promotion: &Promotion,
+) -> Result<Vec<Promoted>, PromotionError> {
+    for environment in [&promotion.from, &promotion.to] {
+        if definitions.environment(environment).is_none() {
+            return Err(PromotionError::UnknownEnvironment(environment.clone()));
+        }
+    }
+    if promotion.from == promotion.to {
+        return Err(PromotionError::SameEnvironment(promotion.from.clone()));
+    }
+    if promotion.flags.is_empty() {
+        return Err(PromotionError::NoFlags);
+    }
+
+    let mut changed = Vec::new();
+    let mut segments = BTreeSet::new();
+    for key in &promotion.flags {
+        let flag = definitions
+            .flags
+            .iter_mut()
+            .find(|f| &f.key == key)
+            .ok_or_else(|| PromotionError::UnknownFlag(key.clone()))?;
+        let mut config = flag
+            .environments
+            .get(&promotion.from)
+            .cloned()
+            .ok_or_else(|| PromotionError::NotConfigured {
+                flag: key.clone(),
+                environment: promotion.from.clone(),
+            })?;
+        let current = flag.environments.get(&promotion.to);
+        config.on = current.is_some_and(|c| c.on);
+        if promotion.keep_targets {
+            config.targets = current.map(|c| c.targets.clone()).unwrap_or_default();
+        }
+        segments.extend(matched_segments(
+            config.rules.iter().flat_map(|r| &r.clauses),
+        ));
+        if current != Some(&config) {
+            flag.environments.insert(promotion.to.clone(), config);
+            changed.push(Promoted::Flag(key.clone()));
+        }
+    }
+
+    // Segments can match other segments, promote those too.
+    let mut pending: Vec<String> = segments.iter().cloned().collect();
+    while let Some(key) = pending.pop() {
+        let Some(segment) = definitions.segments.iter_mut().find(|s| s.key == key) else {
+            continue;
+        };
+        let Some(mut config) = segment.environments.get(&promotion.from).cloned() else {
+            continue;
+        };
+        for nested in matched_segments(config.rules.iter().flat_map(|r| &r.clauses)) {
+            if segments.insert(nested.clone()) {
+                pending.push(nested);
+            }
+        }
+        let current = segment.environments.get(&promotion.to);
+        if promotion.keep_targets {
+            config.included = current.map(|c| c.included.clone()).unwrap_or_default();
+            config.excluded = current.map(|c| c.excluded.clone()).unwrap_or_default();
+        }
+        if current != Some(&config) {
+            segment.environments.insert(promotion.to.clone(), config);
+            changed.push(Promoted::Segment(key));
+        }
+    }
+    changed.sort();
+    Ok(changed)
+}
+
+fn matched_segments<'c>(clauses: impl Iterator<Item = &'c Clause>) -> Vec<String> {
+    clauses
+        .filter(|c| c.op == Op::SegmentMatch)
+        .flat_map(|c| &c.values)
+        .filter_map(|v| v.as_str().map(str::to_string))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEFINITIONS: &str = r#"
+environments:
+  - key: staging
+  - key: production
+segments:
+  - key: beta
+    environments:
+      staging:
+        included: [alice]
+        rules:
+          - clauses:
+              - attribute: country
+                op: in
+                values: [NZ]
+      production:
+        included: [bob]
+flags:
+  - key: checkout
+    variations:
+      - value: false
+      - value: true
+    environments:
+      staging:
+        on: true
+        off_variation: 0
+        fallthrough:
+          variation: 0
+        targets:
+          - variation: 1
+            values: [alice]
+        rules:
+          - clauses:
+              - attribute: key
+                op: segment_match
+                values: [beta]
+            serve:
+              variation: 1
+"#;
+
+    fn promotion(keep_targets: bool) -> Promotion {
+        Promotion {
+            from: "staging".to_string(),
+            to: "production".to_string(),
+            flags: vec!["checkout".to_string()],
+            keep_targets,
+        }
+    }
+
+    #[test]
+    fn promotes_flags_off_with_their_segments() {
+        let mut definitions: Definitions = serde_yaml::from_str(DEFINITIONS).unwrap();
+        let changed = promote(&mut definitions, &promotion(true)).unwrap();
+        assert_eq!(
+            changed,
+            vec![
+                Promoted::Flag("checkout".to_string()),
+                Promoted::Segment("beta".to_string()),
+            ]
+        );
+        let production = &definitions.flags[0].environments["production"];
+        assert!(!production.on);
+        assert!(production.targets.is_empty());
+        assert_eq!(production.rules.len(), 1);
+        let beta = &definitions.segments[0].environments["production"];
+        assert_eq!(beta.included, vec!["bob".to_string()]);
+        assert_eq!(beta.rules.len(), 1);
+
+        let mut definitions: Definitions = serde_yaml::from_str(DEFINITIONS).unwrap();
+        promote(&mut definitions, &promotion(false)).unwrap();
+        let production = &definitions.flags[0].environments["production"];
+        assert_eq!(production.targets[0].values, vec!["alice".to_string()]);
+    }
+}
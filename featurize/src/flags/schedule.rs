@@ -0,0 +1,260 @@
+// Featurize, the FOSS feature flagging
+// Copyright (C) 2024  Lucy Ekaterina
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use chrono::{DateTime, Datelike, NaiveTime, Utc, Weekday};
+use chrono_tz::Tz;
+use serde::Deserialize;
+use serde_json::Value;
+
+use super::operands;
+
+/// The values of a `during` clause, `start` is inclusive and `end` exclusive.
+/// Either may be left out for a window that is open on that side.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawWindow {
+    #[serde(default)]
+    start: Option<Value>,
+    #[serde(default)]
+    end: Option<Value>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Window {
+    start: Option<DateTime<Utc>>,
+    end: Option<DateTime<Utc>>,
+}
+
+impl Window {
+    pub fn parse(value: &Value) -> Result<Self, String> {
+        let raw: RawWindow = serde_json::from_value(value.clone())
+            .map_err(|e| format!("{} is not a time window: {}", value, e))?;
+        let start = raw.start.as_ref().map(operands::time).transpose()?;
+        let end = raw.end.as_ref().map(operands::time).transpose()?;
+        match (start, end) {
+            (None, None) => Err("a time window needs a start, an end or both".to_string()),
+            (Some(start), Some(end)) if end <= start => {
+                Err("a time window must end after it starts".to_string())
+            }
+            _ => Ok(Self { start, end }),
+        }
+    }
+
+    pub fn contains(&self, now: DateTime<Utc>) -> bool {
+        self.start.map_or(true, |start| start <= now) && self.end.map_or(true, |end| now < end)
+    }
+}
+
+/// The values of a `schedule` clause. Times of day are local to `timezone`,
+/// an IANA name that defaults to UTC. A window whose end is not after its
+/// start runs past midnight and belongs to the day it starts on.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawSchedule {
+    #[serde(default)]
+    days: Vec<String>,
+    #[serde(default)]
+    start: Option<String>,
+    #[serde(default)]
+    end: Option<String>,
+    #[serde(default)]
+    timezone: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Schedule {
+    /// Every day when empty.
+    days: Vec<Weekday>,
+    start: NaiveTime,
+    /// `None` runs until midnight.
+    end: Option<NaiveTime>,
+    timezone: Tz,
+}
+
+impl Schedule {
+    pub fn parse(value: &Value) -> Result<Self, String> {
+        let raw: RawSchedule = serde_json::from_value(value.clone())
+            .map_err(|e| format!("{} is not a schedule: {}", value, e))?;
+        let days = raw
+            .days
+            .iter()
+            .map(|day| {
+                day.parse()
+                    .map_err(|_| format!("\"{}\" is not a day of the week", day))
+            })
+            .collect::<Result<_, _>>()?;
+        let start = raw
+            .start
+            .as_deref()
+            .map(time_of_day)
+            .transpose()?
+            .unwrap_or(NaiveTime::MIN);
+        let end = raw.end.as_deref().map(time_of_day).transpose()?;
+        if end == Some(start) {
+            return Err("a schedule must not start and end at the same time".to_string());
+        }
+        let timezone = match raw.timezone.as_deref() {
+            Some(name) => name
+                .parse()
+                .map_err(|_| format!("\"{}\" is not an IANA time zone", name))?,
+            None => Tz::UTC,
+        };
+        Ok(Self {
+            days,
+            start,
+            end,
+            timezone,
+        })
+    }
+
+    pub fn contains(&self, now: DateTime<Utc>) -> bool {
+        let local = now.with_timezone(&self.timezone);
+        let today = local.weekday();
+        let time = local.time();
+        match self.end {
+            Some(end) if end < self.start => {
+                (self.on(today) && self.start <= time) || (self.on(today.pred()) && time < end)
+            }
+            Some(end) => self.on(today) && self.start <= time && time < end,
+            None => self.on(today) && self.start <= time,
+        }
+    }
+
+    fn on(&self, day: Weekday) -> bool {
+        self.days.is_empty() || self.days.contains(&day)
+    }
+}
+
+/// `HH:MM` or `HH:MM:SS`.
+fn time_of_day(text: &str) -> Result<NaiveTime, String> {
+    NaiveTime::parse_from_str(text, "%H:%M")
+        .or_else(|_| NaiveTime::parse_from_str(text, "%H:%M:%S"))
+        .map_err(|_| format!("\"{}\" is not a time of day such as 09:30", text))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::flags::{Definitions, Evaluator};
+
+    fn at(time: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(time).unwrap().into()
+    }
+
+    #[test]
+    fn schedules_use_local_days_and_times() {
+        let schedule = Schedule::parse(&json!({
+            "days": ["mon", "tue", "wed", "thu", "fri"],
+            "start": "09:00",
+            "end": "17:30",
+            "timezone": "America/New_York"
+        }))
+        .unwrap();
+
+        // Monday 2024-03-04, New York is UTC-5.
+        assert!(!schedule.contains(at("2024-03-04T13:59:59Z")));
+        assert!(schedule.contains(at("2024-03-04T14:00:00Z")));
+        assert!(schedule.contains(at("2024-03-04T22:29:59Z")));
+        assert!(!schedule.contains(at("2024-03-04T22:30:00Z")));
+        assert!(!schedule.contains(at("2024-03-09T15:00:00Z")));
+        // After the clocks go forward New York is UTC-4.
+        assert!(schedule.contains(at("2024-03-11T13:00:00Z")));
+    }
+
+    #[test]
+    fn schedules_ending_before_they_start_run_past_midnight() {
+        let schedule = Schedule::parse(&json!({
+            "days": ["friday"],
+            "start": "22:00",
+            "end": "02:00"
+        }))
+        .unwrap();
+
+        assert!(schedule.contains(at("2024-03-08T23:00:00Z")));
+        assert!(schedule.contains(at("2024-03-09T01:59:00Z")));
+        assert!(!schedule.contains(at("2024-03-09T02:00:00Z")));
+        // The window belongs to Friday, so Friday's early hours are outside it.
+        assert!(!schedule.contains(at("2024-03-08T01:00:00Z")));
+    }
+
+    #[test]
+    fn invalid_schedules_are_rejected() {
+        for value in [
+            json!({ "days": ["someday"] }),
+            json!({ "start": "9am" }),
+            json!({ "start": "09:00", "end": "09:00" }),
+            json!({ "timezone": "Mars/Olympus_Mons" }),
+            json!({ "every": "day" }),
+        ] {
+            assert!(Schedule::parse(&value).is_err(), "{}", value);
+        }
+        for value in [
+            json!({}),
+            json!({ "start": "2024-12-01", "end": "2024-11-29" }),
+        ] {
+            assert!(Window::parse(&value).is_err(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn rules_are_evaluated_at_the_injected_time() {
+        let definitions: Definitions = serde_yaml::from_str(
+            r#"
+environments:
+  - key: production
+flags:
+  - key: sale
+    variations:
+      - value: true
+      - value: false
+    environments:
+      production:
+        on: true
+        off_variation: 1
+        fallthrough:
+          variation: 1
+        rules:
+          - clauses:
+              - op: during
+                values:
+                  - start: 2024-11-29T00:00:00Z
+                    end: 2024-12-03T00:00:00Z
+              - op: schedule
+                values:
+                  - days: [saturday, sunday]
+                    timezone: Europe/London
+            serve:
+              variation: 0
+"#,
+        )
+        .unwrap();
+        crate::flags::validate(&definitions).unwrap();
+        let flag = &definitions.flags[0];
+        let context = json!({ "key": "alice" }).as_object().unwrap().clone();
+        let served = |time| {
+            Evaluator::new(&definitions, "production")
+                .at(at(time))
+                .evaluate(flag, &context)
+                .unwrap()
+                .variation
+        };
+
+        assert_eq!(served("2024-11-29T12:00:00Z"), 1);
+        assert_eq!(served("2024-11-30T12:00:00Z"), 0);
+        assert_eq!(served("2024-12-07T12:00:00Z"), 1);
+    }
+}
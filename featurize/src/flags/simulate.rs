@@ -0,0 +1,199 @@
+// Featurize, the FOSS feature flagging
+// Copyright (C) 2024  Lucy Ekaterina
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::{validate, Context, Definitions, Evaluator, Flag, ValidationErrors};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ContextFormat {
+    /// A header row of attribute names, then one context per row.
+    #[default]
+    Csv,
+    /// One JSON object per line.
+    Ndjson,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum SimulationError {
+    #[error("line {line}: {message}")]
+    Contexts { line: u64, message: String },
+    #[error("the draft is invalid:\n{0}")]
+    Invalid(ValidationErrors),
+}
+
+#[derive(Debug, Serialize)]
+pub struct Simulation {
+    pub contexts: usize,
+    pub variations: Vec<VariationCounts>,
+    /// Contexts served the default value in code because the flag is not
+    /// configured in the environment.
+    pub not_configured: VariationCounts,
+    pub changed: Vec<ChangedContext>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct VariationCounts {
+    pub variation: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub current: u64,
+    pub draft: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ChangedContext {
+    /// Line of the context in the uploaded file.
+    pub line: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    pub current: Option<usize>,
+    pub draft: Option<usize>,
+}
+
+impl Simulation {
+    fn counts(&mut self, variation: Option<usize>) -> &mut VariationCounts {
+        match variation {
+            Some(v) => &mut self.variations[v],
+            None => &mut self.not_configured,
+        }
+    }
+}
+
+/// Numbers and booleans in CSV cells are typed so numeric operators work, the
+/// `key` column is always a string. Empty cells leave the attribute out.
+pub fn parse_contexts(
+    content: &str,
+    format: ContextFormat,
+) -> Result<Vec<(u64, Context)>, SimulationError> {
+    match format {
+        ContextFormat::Csv => parse_csv(content),
+        ContextFormat::Ndjson => content
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(i, line)| {
+                let line_number = i as u64 + 1;
+                serde_json::from_str(line)
+                    .map(|context| (line_number, context))
+                    .map_err(|e| SimulationError::Contexts {
+                        line: line_number,
+                        message: e.to_string(),
+                    })
+            })
+            .collect(),
+    }
+}
+
+fn parse_csv(content: &str) -> Result<Vec<(u64, Context)>, SimulationError> {
+    let error = |line: u64, e: csv::Error| SimulationError::Contexts {
+        line,
+        message: e.to_string(),
+    };
+    let mut reader = csv::Reader::from_reader(content.as_bytes());
+    let headers = reader.headers().map_err(|e| error(1, e))?.clone();
+    let mut contexts = Vec::new();
+    for record in reader.records() {
+        let record = record.map_err(|e| {
+            let line = e.position().map(|p| p.line()).unwrap_or_default();
+            error(line, e)
+        })?;
+        let line = record.position().map(|p| p.line()).unwrap_or_default();
+        let context: Context = headers
+            .iter()
+            .zip(record.iter())
+            .filter(|(_, cell)| !cell.is_empty())
+            .map(|(attribute, cell)| {
+                let value = if attribute == "key" {
+                    Value::String(cell.to_string())
+                } else {
+                    typed_cell(cell)
+                };
+                (attribute.to_string(), value)
+            })
+            .collect();
+        contexts.push((line, context));
+    }
+    Ok(contexts)
+}
+
+fn typed_cell(cell: &str) -> Value {
+    match cell {
+        "true" => Value::Bool(true),
+        "false" => Value::Bool(false),
+        _ => serde_json::from_str::<serde_json::Number>(cell)
+            .map(Value::Number)
+            .unwrap_or_else(|_| Value::String(cell.to_string())),
+    }
+}
+
+/// Evaluates `draft` and the flag it would replace against every context.
+pub fn simulate(
+    definitions: &Definitions,
+    environment: &str,
+    draft: Flag,
+    contexts: &[(u64, Context)],
+) -> Result<Simulation, SimulationError> {
+    let key = draft.key.clone();
+    let mut drafted = definitions.clone();
+    match drafted.flags.iter_mut().find(|f| f.key == draft.key) {
+        Some(flag) => *flag = draft,
+        None => drafted.flags.push(draft),
+    }
+    validate(&drafted).map_err(SimulationError::Invalid)?;
+
+    let draft = drafted.flag(&key).expect("the draft was just added");
+    let current = definitions.flag(&key);
+    let variation_count = draft
+        .variations
+        .len()
+        .max(current.map(|f| f.variations.len()).unwrap_or(0));
+    let mut simulation = Simulation {
+        contexts: contexts.len(),
+        variations: (0..variation_count)
+            .map(|i| VariationCounts {
+                variation: Some(i),
+                name: draft.variations.get(i).and_then(|v| v.name.clone()),
+                ..Default::default()
+            })
+            .collect(),
+        not_configured: VariationCounts::default(),
+        changed: Vec::new(),
+    };
+
+    let current_evaluator = Evaluator::new(definitions, environment);
+    let draft_evaluator = Evaluator::new(&drafted, environment);
+    for (line, context) in contexts {
+        let before = current
+            .and_then(|f| current_evaluator.evaluate(f, context))
+            .map(|e| e.variation);
+        let after = draft_evaluator
+            .evaluate(draft, context)
+            .map(|e| e.variation);
+        simulation.counts(before).current += 1;
+        simulation.counts(after).draft += 1;
+        if before != after {
+            simulation.changed.push(ChangedContext {
+                line: *line,
+                key: context.get("key").and_then(Value::as_str).map(String::from),
+                current: before,
+                draft: after,
+            });
+        }
+    }
+    Ok(simulation)
+}
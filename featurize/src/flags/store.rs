@@ -0,0 +1,1052 @@
+// Featurize, the FOSS feature flagging
+// Copyright (C) 2024  Lucy Ekaterina
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use std::{
+    collections::{btree_map::Entry, BTreeMap, HashMap},
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+};
+
+use chrono::{DateTime, Utc};
+use futures::channel::mpsc;
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+
+use super::{
+    failing_tests, flagd,
+    overrides::{self, OverridesFile},
+    schema_issues, validate, Definitions, Error, FlagConfig, Freeze, Lifecycle, Override,
+    RuntimeChanges, SegmentConfig, Subject, TestFailure, TestFailures, ValidationError, Variation,
+};
+
+/// The file each environment, segment, flag, experiment, layer and attribute
+/// is defined in.
+pub type Origins = HashMap<Subject, PathBuf>;
+
+/// Decides whether changed definitions may replace the current ones, called
+/// with the current definitions and the changed ones.
+pub type Guard = Box<dyn Fn(&Definitions, &Definitions) -> Result<(), String> + Send + Sync>;
+
+#[derive(Debug)]
+pub struct FlagStore {
+    dir: Option<PathBuf>,
+    overrides_file: Option<PathBuf>,
+    guards: Guards,
+    /// Whether freezes only get served, not enforced.
+    mirror: bool,
+    state: RwLock<State>,
+    /// Woken up whenever the state changes, see [`FlagStore::subscribe`].
+    subscribers: Mutex<Vec<mpsc::Sender<()>>>,
+}
+
+#[derive(Default)]
+struct Guards(Vec<Guard>);
+
+impl fmt::Debug for Guards {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} guards", self.0.len())
+    }
+}
+
+#[derive(Debug)]
+struct State {
+    /// As loaded from the files.
+    loaded: Arc<Definitions>,
+    origins: Origins,
+    runtime: RuntimeChanges,
+    /// `loaded` with the runtime overrides applied.
+    definitions: Arc<Definitions>,
+    /// When each flag last changed in the files.
+    changed: HashMap<String, DateTime<Utc>>,
+}
+
+impl State {
+    /// Overrides of variations `loaded` doesn't have are dropped.
+    fn new(
+        loaded: Definitions,
+        origins: Origins,
+        mut runtime: RuntimeChanges,
+        changed: HashMap<String, DateTime<Utc>>,
+    ) -> Self {
+        runtime.overrides.retain(|o| match o.check(&loaded) {
+            Ok(()) => true,
+            Err(e) => {
+                tracing::warn!("dropping override in {}: {}", o.environment, e);
+                false
+            }
+        });
+        let definitions = Arc::new(overrides::apply(&loaded, &runtime.overrides));
+        Self {
+            loaded: Arc::new(loaded),
+            origins,
+            runtime,
+            definitions,
+            changed,
+        }
+    }
+
+    fn with_runtime(&self, runtime: RuntimeChanges) -> Self {
+        Self::new(
+            (*self.loaded).clone(),
+            self.origins.clone(),
+            runtime,
+            self.changed.clone(),
+        )
+    }
+
+    /// Replaces the definitions loaded from the files, flags that differ from
+    /// the current ones changed now.
+    fn with_loaded(&self, loaded: Definitions, origins: Origins) -> Self {
+        let now = Utc::now();
+        let changed = loaded
+            .flags
+            .iter()
+            .map(|flag| {
+                let unchanged = self.loaded.flag(&flag.key) == Some(flag);
+                let at = match self.changed.get(&flag.key) {
+                    Some(at) if unchanged => *at,
+                    _ => now,
+                };
+                (flag.key.clone(), at)
+            })
+            .collect();
+        Self::new(loaded, origins, self.runtime.clone(), changed)
+    }
+
+    fn check_not_frozen(&self, environment: &str) -> Result<(), Error> {
+        if self.runtime.freezes.contains_key(environment) {
+            return Err(Error::Frozen(environment.to_string()));
+        }
+        Ok(())
+    }
+}
+
+impl FlagStore {
+    pub fn empty() -> Self {
+        Self {
+            dir: None,
+            overrides_file: None,
+            guards: Guards::default(),
+            mirror: false,
+            state: RwLock::new(State::new(
+                Definitions::default(),
+                Origins::new(),
+                RuntimeChanges::default(),
+                HashMap::new(),
+            )),
+            subscribers: Mutex::default(),
+        }
+    }
+
+    pub fn load<P: Into<PathBuf>>(dir: P) -> Result<Self, Error> {
+        let dir = dir.into();
+        let (definitions, origins) = load_dir(&dir)?;
+        // Until a reload says otherwise, a flag last changed when its file did.
+        let changed = definitions
+            .flags
+            .iter()
+            .filter_map(|flag| {
+                let file = origins.get(&Subject::Flag(flag.key.clone()))?;
+                let modified = fs::metadata(file).and_then(|m| m.modified()).ok()?;
+                Some((flag.key.clone(), modified.into()))
+            })
+            .collect();
+        Ok(Self {
+            dir: Some(dir),
+            overrides_file: None,
+            guards: Guards::default(),
+            mirror: false,
+            state: RwLock::new(State::new(
+                definitions,
+                origins,
+                RuntimeChanges::default(),
+                changed,
+            )),
+            subscribers: Mutex::default(),
+        })
+    }
+
+    /// Checks every reload with `guard`, definitions it refuses are rejected
+    /// like invalid ones.
+    pub fn with_guard(mut self, guard: Guard) -> Self {
+        self.guards.0.push(guard);
+        self
+    }
+
+    /// Serves freezes without rejecting the changes they cover, for copies of
+    /// another featurize that enforced them already and whose definitions
+    /// arrive with its kills and rollbacks.
+    pub fn mirroring(mut self) -> Self {
+        self.mirror = true;
+        self
+    }
+
+    /// Keeps overrides and freezes in `path` so they survive restarts, loading
+    /// the ones already there.
+    pub fn with_overrides_file<P: Into<PathBuf>>(mut self, path: P) -> Result<Self, Error> {
+        let path = path.into();
+        let runtime = match fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str::<OverridesFile>(&content)
+                .map_err(|e| Error::Parse {
+                    path: path.clone(),
+                    message: e.to_string(),
+                })?
+                .into(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => RuntimeChanges::default(),
+            Err(source) => return Err(Error::Io { path, source }),
+        };
+        let state = self.state.get_mut().unwrap();
+        *state = state.with_runtime(runtime);
+        self.overrides_file = Some(path);
+        Ok(self)
+    }
+
+    pub fn definitions(&self) -> Arc<Definitions> {
+        self.state.read().unwrap().definitions.clone()
+    }
+
+    /// The definitions as loaded from the files, without overrides.
+    pub fn loaded(&self) -> Arc<Definitions> {
+        self.state.read().unwrap().loaded.clone()
+    }
+
+    pub fn overrides(&self) -> Vec<Override> {
+        self.state.read().unwrap().runtime.overrides.clone()
+    }
+
+    pub fn freezes(&self) -> BTreeMap<String, Freeze> {
+        self.state.read().unwrap().runtime.freezes.clone()
+    }
+
+    /// When `flag` last changed in the files, `None` for unknown flags.
+    pub fn last_changed(&self, flag: &str) -> Option<DateTime<Utc>> {
+        self.state.read().unwrap().changed.get(flag).copied()
+    }
+
+    /// Replaces any override of the same flag in the same environment.
+    /// Overrides are meant for emergencies, so they are set even while the
+    /// environment is frozen.
+    pub fn set_override(&self, new: Override) -> Result<(), Error> {
+        let mut state = self.state.write().unwrap();
+        new.check(&state.loaded).map_err(Error::InvalidOverride)?;
+        let mut runtime = state.runtime.clone();
+        runtime
+            .overrides
+            .retain(|o| !o.applies_to(&new.environment, &new.flag));
+        runtime.overrides.push(new);
+        self.save_runtime(&runtime)?;
+        *state = state.with_runtime(runtime);
+        self.publish();
+        Ok(())
+    }
+
+    pub fn clear_override(&self, environment: &str, flag: &str) -> Result<Option<Override>, Error> {
+        let mut state = self.state.write().unwrap();
+        let Some(cleared) = state
+            .runtime
+            .overrides
+            .iter()
+            .find(|o| o.applies_to(environment, flag))
+            .cloned()
+        else {
+            return Ok(None);
+        };
+        state.check_not_frozen(environment)?;
+        let mut runtime = state.runtime.clone();
+        runtime
+            .overrides
+            .retain(|o| !o.applies_to(environment, flag));
+        self.save_runtime(&runtime)?;
+        *state = state.with_runtime(runtime);
+        self.publish();
+        Ok(Some(cleared))
+    }
+
+    /// Rejects changes to `environment`, from the files or the dashboard,
+    /// until it is unfrozen. Freezing a frozen environment replaces the
+    /// freeze.
+    pub fn freeze(&self, environment: &str, freeze: Freeze) -> Result<(), Error> {
+        let mut state = self.state.write().unwrap();
+        let mut runtime = state.runtime.clone();
+        runtime.freezes.insert(environment.to_string(), freeze);
+        self.save_runtime(&runtime)?;
+        *state = state.with_runtime(runtime);
+        self.publish();
+        Ok(())
+    }
+
+    pub fn unfreeze(&self, environment: &str) -> Result<Option<Freeze>, Error> {
+        let mut state = self.state.write().unwrap();
+        let mut runtime = state.runtime.clone();
+        let Some(lifted) = runtime.freezes.remove(environment) else {
+            return Ok(None);
+        };
+        self.save_runtime(&runtime)?;
+        *state = state.with_runtime(runtime);
+        self.publish();
+        Ok(Some(lifted))
+    }
+
+    fn save_runtime(&self, runtime: &RuntimeChanges) -> Result<(), Error> {
+        let Some(path) = &self.overrides_file else {
+            return Ok(());
+        };
+        let content = serde_json::to_string_pretty(runtime).map_err(|e| Error::Parse {
+            path: path.clone(),
+            message: e.to_string(),
+        })?;
+        fs::write(path, content).map_err(|source| Error::Io {
+            path: path.clone(),
+            source,
+        })
+    }
+
+    #[tracing::instrument(skip(self))]
+    pub fn reload(&self) -> Result<(), Error> {
+        let Some(dir) = &self.dir else {
+            return Ok(());
+        };
+        // Read under the lock so a reload can't see the files halfway through
+        // an update.
+        let mut state = self.state.write().unwrap();
+        let (definitions, origins) = load_dir(dir)?;
+        self.accept(&state, &definitions)?;
+        let reloaded = state.with_loaded(definitions, origins);
+        self.replace(&mut state, reloaded);
+        Ok(())
+    }
+
+    /// Saves the overrides when the new state dropped some of them.
+    fn replace(&self, state: &mut State, new: State) {
+        if new.runtime != state.runtime {
+            if let Err(e) = self.save_runtime(&new.runtime) {
+                tracing::error!("could not save dropped overrides: {}", e);
+            }
+        }
+        *state = new;
+        self.publish();
+    }
+
+    /// Wakes the returned receiver up whenever the definitions, overrides
+    /// included, or the freezes may have changed. Wake-ups that come while
+    /// one is pending are merged into it.
+    pub fn subscribe(&self) -> mpsc::Receiver<()> {
+        let (sender, receiver) = mpsc::channel(0);
+        self.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    fn publish(&self) {
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain_mut(|subscriber| match subscriber.try_send(()) {
+                Ok(()) => true,
+                Err(e) => e.is_full(),
+            });
+    }
+
+    /// Changes the definitions loaded from the files, without overrides, and
+    /// writes the flags and segments `change` modified back to the files they
+    /// are defined in. Flags `change` adds go to a file named after them and
+    /// removed ones are taken out of theirs. The changed definitions are
+    /// checked like a reload first, and every file is written before any
+    /// replaces the previous one.
+    pub fn update<T, E: From<Error>>(
+        &self,
+        change: impl FnOnce(&mut Definitions) -> Result<T, E>,
+    ) -> Result<T, E> {
+        let mut state = self.state.write().unwrap();
+        let (result, definitions, origins, files) = self.prepare(&state, change)?;
+        write_files(files)?;
+        let updated = state.with_loaded(definitions, origins);
+        self.replace(&mut state, updated);
+        Ok(result)
+    }
+
+    /// Checks a change like [`FlagStore::update`] without writing it.
+    pub fn check_update<T, E: From<Error>>(
+        &self,
+        change: impl FnOnce(&mut Definitions) -> Result<T, E>,
+    ) -> Result<T, E> {
+        let state = self.state.read().unwrap();
+        let (result, _, _, _) = self.prepare(&state, change)?;
+        Ok(result)
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn prepare<T, E: From<Error>>(
+        &self,
+        state: &State,
+        change: impl FnOnce(&mut Definitions) -> Result<T, E>,
+    ) -> Result<(T, Definitions, Origins, Vec<(PathBuf, String)>), E> {
+        let Some(dir) = &self.dir else {
+            return Err(Error::NotWritable("no FLAGS_DIR to write to".to_string()).into());
+        };
+        let mut definitions = (*state.loaded).clone();
+        let result = change(&mut definitions)?;
+        let mut origins = state.origins.clone();
+        for flag in &definitions.flags {
+            let subject = Subject::Flag(flag.key.clone());
+            if origins.contains_key(&subject) {
+                continue;
+            }
+            // Reloads skip hidden files.
+            if flag.key.starts_with('.') {
+                return Err(Error::NotWritable(format!("{} can't be created", subject)).into());
+            }
+            origins.insert(subject, dir.join(format!("{}.yaml", flag.key)));
+        }
+        check(&definitions, &origins)?;
+        self.accept(state, &definitions)?;
+        let files = rewrite_files(&state.loaded, &definitions, &origins)?;
+        origins.retain(|subject, _| match subject {
+            Subject::Flag(key) => definitions.flag(key).is_some(),
+            _ => true,
+        });
+        Ok((result, definitions, origins, files))
+    }
+
+    /// Checks the freezes and guards before `definitions` replace the
+    /// current ones.
+    fn accept(&self, state: &State, definitions: &Definitions) -> Result<(), Error> {
+        for environment in state.runtime.freezes.keys() {
+            if !self.mirror && changes_environment(&state.loaded, definitions, environment) {
+                return Err(Error::Frozen(environment.clone()));
+            }
+        }
+        for guard in &self.guards.0 {
+            guard(&state.loaded, definitions).map_err(Error::Rejected)?;
+        }
+        Ok(())
+    }
+
+    /// Reloads the definitions whenever a file in the directory changes. If the
+    /// new definitions are invalid the previous ones are kept.
+    pub fn watch(self: &Arc<Self>) -> Result<Option<RecommendedWatcher>, Error> {
+        let Some(dir) = self.dir.clone() else {
+            return Ok(None);
+        };
+        let store = Arc::downgrade(self);
+        let mut watcher =
+            notify::recommended_watcher(move |res: notify::Result<notify::Event>| {
+                let Some(store) = store.upgrade() else {
+                    return;
+                };
+                match res {
+                    Ok(event) if event.kind.is_access() => {}
+                    Ok(_) => match store.reload() {
+                        Ok(()) => tracing::info!("reloaded flag definitions"),
+                        Err(e) => tracing::error!("keeping previous flag definitions: {}", e),
+                    },
+                    Err(e) => tracing::error!("error watching flag definitions: {}", e),
+                }
+            })?;
+        watcher.watch(&dir, RecursiveMode::Recursive)?;
+        Ok(Some(watcher))
+    }
+}
+
+/// Fails unless `flag` is at `version`, for changes made on top of what was
+/// read earlier.
+pub fn check_version(definitions: &Definitions, flag: &str, version: &str) -> Result<(), Error> {
+    match definitions.flag(flag) {
+        Some(current) if current.version() == version => Ok(()),
+        _ => Err(Error::Stale(flag.to_string())),
+    }
+}
+
+/// Loads every YAML and JSON file below `dir` and merges them into a single set
+/// of definitions, along with the file each key was defined in.
+pub fn load_dir(dir: &Path) -> Result<(Definitions, Origins), Error> {
+    let (definitions, origins) = read_dir(dir)?;
+    check(&definitions, &origins)?;
+    Ok((definitions, origins))
+}
+
+/// Loads the files below `dir` like [`load_dir`] without checking them, for
+/// definitions that only make sense next to others, like the flags
+/// `featurize plan` compares with a server.
+pub fn read_dir_unchecked(dir: &Path) -> Result<Definitions, Error> {
+    read_dir(dir).map(|(definitions, _)| definitions)
+}
+
+fn read_dir(dir: &Path) -> Result<(Definitions, Origins), Error> {
+    let mut files = Vec::new();
+    find_files(dir, &mut files)?;
+    files.sort();
+
+    let mut definitions = Definitions::default();
+    let mut origins = HashMap::new();
+    for file in files {
+        let parsed = parse_file(&file)?;
+        for environment in &parsed.environments {
+            origins
+                .entry(Subject::Environment(environment.key.clone()))
+                .or_insert_with(|| file.clone());
+        }
+        for segment in &parsed.segments {
+            origins
+                .entry(Subject::Segment(segment.key.clone()))
+                .or_insert_with(|| file.clone());
+        }
+        for flag in &parsed.flags {
+            origins
+                .entry(Subject::Flag(flag.key.clone()))
+                .or_insert_with(|| file.clone());
+        }
+        for experiment in &parsed.experiments {
+            origins
+                .entry(Subject::Experiment(experiment.key.clone()))
+                .or_insert_with(|| file.clone());
+        }
+        for layer in &parsed.layers {
+            origins
+                .entry(Subject::Layer(layer.key.clone()))
+                .or_insert_with(|| file.clone());
+        }
+        definitions.environments.extend(parsed.environments);
+        definitions.segments.extend(parsed.segments);
+        definitions.flags.extend(parsed.flags);
+        definitions.layers.extend(parsed.layers);
+        definitions.experiments.extend(parsed.experiments);
+        for attribute in &parsed.attributes {
+            origins
+                .entry(Subject::Attribute(attribute.key.clone()))
+                .or_insert_with(|| file.clone());
+        }
+        definitions.attributes.extend(parsed.attributes);
+        definitions.strict_attributes |= parsed.strict_attributes;
+    }
+    Ok((definitions, origins))
+}
+
+/// Validates `definitions` and runs the flag tests, logging schema issues
+/// when attributes aren't strict.
+fn check(definitions: &Definitions, origins: &Origins) -> Result<(), Error> {
+    validate(definitions).map_err(|mut errors| {
+        for error in &mut errors.0 {
+            error.file = origins.get(&error.subject).cloned();
+        }
+        Error::Invalid(errors)
+    })?;
+
+    if !definitions.strict_attributes && !definitions.attributes.is_empty() {
+        for issue in schema_issues(definitions) {
+            let file = origins.get(&issue.subject).cloned();
+            tracing::warn!("{}", ValidationError { file, ..issue });
+        }
+    }
+
+    let failures = failing_tests(definitions);
+    if !failures.is_empty() {
+        return Err(Error::TestsFailed(TestFailures(
+            failures
+                .into_iter()
+                .map(|result| TestFailure {
+                    file: origins.get(&Subject::Flag(result.flag.clone())).cloned(),
+                    result,
+                })
+                .collect(),
+        )));
+    }
+    Ok(())
+}
+
+/// The new content of every file defining a flag or segment that differs
+/// between `current` and `changed`, or a flag only one of them has.
+fn rewrite_files(
+    current: &Definitions,
+    changed: &Definitions,
+    origins: &Origins,
+) -> Result<Vec<(PathBuf, String)>, Error> {
+    let unchanged = Definitions {
+        flags: Vec::new(),
+        segments: Vec::new(),
+        ..current.clone()
+    } == Definitions {
+        flags: Vec::new(),
+        segments: Vec::new(),
+        ..changed.clone()
+    };
+    let same_keys = |a: Vec<&String>, b: Vec<&String>| a == b;
+    if !unchanged
+        || !same_keys(
+            current.segments.iter().map(|s| &s.key).collect(),
+            changed.segments.iter().map(|s| &s.key).collect(),
+        )
+    {
+        return Err(Error::NotWritable(
+            "only flags and existing segments can be changed".to_string(),
+        ));
+    }
+
+    let mut files: BTreeMap<&PathBuf, Definitions> = BTreeMap::new();
+    for old in &current.flags {
+        if changed.flag(&old.key).is_none() {
+            let file = origin_file(&mut files, origins, Subject::Flag(old.key.clone()))?;
+            file.flags.retain(|f| f.key != old.key);
+        }
+    }
+    for new in &changed.flags {
+        match current.flag(&new.key) {
+            Some(old) if old == new => {}
+            Some(_) => {
+                let file = origin_file(&mut files, origins, Subject::Flag(new.key.clone()))?;
+                for flag in file.flags.iter_mut().filter(|f| f.key == new.key) {
+                    *flag = new.clone();
+                }
+            }
+            None => {
+                let file = origin_file(&mut files, origins, Subject::Flag(new.key.clone()))?;
+                file.flags.push(new.clone());
+            }
+        }
+    }
+    for (old, new) in current.segments.iter().zip(&changed.segments) {
+        if old != new {
+            let file = origin_file(&mut files, origins, Subject::Segment(new.key.clone()))?;
+            for segment in file.segments.iter_mut().filter(|s| s.key == new.key) {
+                *segment = new.clone();
+            }
+        }
+    }
+
+    files
+        .into_iter()
+        .map(|(path, definitions)| {
+            let content = match path.extension().and_then(|e| e.to_str()) {
+                Some("json") => {
+                    serde_json::to_string_pretty(&definitions).map_err(|e| e.to_string())
+                }
+                _ => serde_yaml::to_string(&definitions).map_err(|e| e.to_string()),
+            };
+            content
+                .map(|content| (path.clone(), content))
+                .map_err(|message| Error::Parse {
+                    path: path.clone(),
+                    message,
+                })
+        })
+        .collect()
+}
+
+/// The parsed content of the file `subject` is defined in, read on first use.
+fn origin_file<'f, 'o>(
+    files: &'f mut BTreeMap<&'o PathBuf, Definitions>,
+    origins: &'o Origins,
+    subject: Subject,
+) -> Result<&'f mut Definitions, Error> {
+    let path = origins
+        .get(&subject)
+        .ok_or_else(|| Error::NotWritable(format!("{} is not defined in a file", subject)))?;
+    let is_flagd = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .is_some_and(|n| n.ends_with(".flagd.json"));
+    if is_flagd {
+        return Err(Error::NotWritable(format!(
+            "{} is defined in the flagd file {}",
+            subject,
+            path.display()
+        )));
+    }
+    Ok(match files.entry(path) {
+        Entry::Occupied(entry) => entry.into_mut(),
+        Entry::Vacant(entry) if !path.exists() => entry.insert(Definitions::default()),
+        Entry::Vacant(entry) => entry.insert(parse_file(path)?),
+    })
+}
+
+/// Writes every file next to the one it replaces before renaming them over
+/// the originals, so a failed write leaves the definitions as they were. When
+/// a rename fails, the files already renamed get their previous content back.
+/// A crash halfway through the renames can still leave some files changed.
+fn write_files(files: Vec<(PathBuf, String)>) -> Result<(), Error> {
+    let mut written: Vec<(PathBuf, PathBuf)> = Vec::new();
+    for (path, content) in files {
+        let temporary = temporary_path(&path);
+        if let Err(source) = fs::write(&temporary, content) {
+            for (temporary, _) in written.iter().chain([&(temporary.clone(), path.clone())]) {
+                let _ = fs::remove_file(temporary);
+            }
+            return Err(Error::Io {
+                path: temporary,
+                source,
+            });
+        }
+        written.push((temporary, path));
+    }
+
+    // `None` for files that didn't exist before.
+    let mut renamed: Vec<(PathBuf, Option<Vec<u8>>)> = Vec::new();
+    let mut pending = written.into_iter();
+    while let Some((temporary, path)) = pending.next() {
+        let original = match fs::read(&path) {
+            Ok(original) => Ok(Some(original)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        };
+        let replaced = original.and_then(|original| {
+            fs::rename(&temporary, &path)?;
+            Ok(original)
+        });
+        match replaced {
+            Ok(original) => renamed.push((path, original)),
+            Err(source) => {
+                for (temporary, _) in [(temporary, path.clone())].into_iter().chain(pending) {
+                    let _ = fs::remove_file(temporary);
+                }
+                for (path, original) in renamed.into_iter().rev() {
+                    let temporary = temporary_path(&path);
+                    let restored = match original {
+                        Some(original) => fs::write(&temporary, original)
+                            .and_then(|()| fs::rename(&temporary, &path)),
+                        None => fs::remove_file(&path),
+                    };
+                    if let Err(e) = restored {
+                        let _ = fs::remove_file(&temporary);
+                        tracing::error!("could not restore {}: {}", path.display(), e);
+                    }
+                }
+                return Err(Error::Io { path, source });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Hidden, so a reload doesn't pick it up.
+fn temporary_path(path: &Path) -> PathBuf {
+    let name = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or_default();
+    path.with_file_name(format!(".{}.tmp", name))
+}
+
+/// Whether anything evaluated in `environment` differs between `current` and
+/// `changed`.
+fn changes_environment<'d>(
+    current: &'d Definitions,
+    changed: &'d Definitions,
+    environment: &str,
+) -> bool {
+    type FlagView<'d> = (&'d [Variation], Lifecycle, Option<&'d FlagConfig>);
+    let flags = |definitions: &'d Definitions| -> BTreeMap<&'d str, FlagView<'d>> {
+        definitions
+            .flags
+            .iter()
+            .map(|f| {
+                let view = (
+                    f.variations.as_slice(),
+                    f.lifecycle,
+                    f.environments.get(environment),
+                );
+                (f.key.as_str(), view)
+            })
+            .collect()
+    };
+    let segments = |definitions: &'d Definitions| -> BTreeMap<&'d str, Option<&'d SegmentConfig>> {
+        definitions
+            .segments
+            .iter()
+            .map(|s| (s.key.as_str(), s.environments.get(environment)))
+            .collect()
+    };
+    flags(current) != flags(changed)
+        || segments(current) != segments(changed)
+        || current.environment(environment) != changed.environment(environment)
+}
+
+fn find_files(dir: &Path, files: &mut Vec<PathBuf>) -> Result<(), Error> {
+    let io_error = |source| Error::Io {
+        path: dir.to_owned(),
+        source,
+    };
+    for entry in fs::read_dir(dir).map_err(io_error)? {
+        let path = entry.map_err(io_error)?.path();
+        let hidden = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .map_or(false, |n| n.starts_with('.'));
+        if hidden {
+            continue;
+        }
+        if path.is_dir() {
+            find_files(&path, files)?;
+        } else if matches!(
+            path.extension().and_then(|e| e.to_str()),
+            Some("yaml" | "yml" | "json")
+        ) {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn parse_file(path: &Path) -> Result<Definitions, Error> {
+    let content = fs::read_to_string(path).map_err(|source| Error::Io {
+        path: path.to_owned(),
+        source,
+    })?;
+    if content.trim().is_empty() {
+        return Ok(Definitions::default());
+    }
+    let file_name = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or_default();
+    if let Some(environment) = file_name.strip_suffix(".flagd.json") {
+        return parse_flagd(path, &content, environment);
+    }
+    let parsed = match path.extension().and_then(|e| e.to_str()) {
+        Some("json") => serde_json::from_str(&content).map_err(|e| e.to_string()),
+        _ => serde_yaml::from_str(&content).map_err(|e| e.to_string()),
+    };
+    parsed.map_err(|message| Error::Parse {
+        path: path.to_owned(),
+        message,
+    })
+}
+
+/// Reads flagd definitions from `<environment>.flagd.json`. The environment
+/// itself still has to be declared in one of the other files.
+fn parse_flagd(path: &Path, content: &str, environment: &str) -> Result<Definitions, Error> {
+    let document = serde_json::from_str(content).map_err(|e| Error::Parse {
+        path: path.to_owned(),
+        message: e.to_string(),
+    })?;
+    let mut import = flagd::import(document, environment);
+    for unmapped in &import.unmapped {
+        tracing::warn!("{}: {}", path.display(), unmapped);
+    }
+    import.definitions.environments.clear();
+    Ok(import.definitions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flags::{OverrideAction, Serve};
+
+    /// An empty directory of its own for each test.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("featurize-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn flag_file(variations: &str, fallthrough: usize) -> String {
+        format!(
+            r#"
+environments:
+  - key: production
+flags:
+  - key: checkout
+    variations: {}
+    environments:
+      production:
+        on: true
+        off_variation: 0
+        fallthrough: {{variation: {}}}
+"#,
+            variations, fallthrough
+        )
+    }
+
+    #[test]
+    fn drops_overrides_of_removed_variations() {
+        let dir = temp_dir("overrides");
+        let file = dir.join("flags.yaml");
+        fs::write(&file, flag_file("[{value: false}, {value: true}]", 1)).unwrap();
+        let store = FlagStore::load(&dir).unwrap();
+        let kill = |variation| Override {
+            environment: "production".to_string(),
+            flag: "checkout".to_string(),
+            action: OverrideAction::Kill { variation },
+            reason: String::new(),
+            at: Utc::now(),
+        };
+        assert!(matches!(
+            store.set_override(kill(2)),
+            Err(Error::InvalidOverride(_))
+        ));
+        let rollback = Override {
+            action: OverrideAction::Rollback { variation: 2 },
+            ..kill(0)
+        };
+        assert!(matches!(
+            store.set_override(rollback),
+            Err(Error::InvalidOverride(_))
+        ));
+        store.set_override(kill(1)).unwrap();
+        assert_eq!(
+            store.definitions().flags[0].environments["production"].off_variation,
+            1
+        );
+
+        fs::write(&file, flag_file("[{value: false}]", 0)).unwrap();
+        store.reload().unwrap();
+        assert!(store.overrides().is_empty());
+        assert!(store.definitions().flags[0].environments["production"].on);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn wakes_subscribers_up_on_changes() {
+        let dir = temp_dir("subscribe");
+        fs::write(
+            dir.join("flags.yaml"),
+            flag_file("[{value: false}, {value: true}]", 1),
+        )
+        .unwrap();
+        let store = FlagStore::load(&dir).unwrap();
+        let mut changes = store.subscribe();
+        assert!(changes.try_next().is_err());
+
+        store
+            .set_override(Override {
+                environment: "production".to_string(),
+                flag: "checkout".to_string(),
+                action: OverrideAction::Kill { variation: 0 },
+                reason: String::new(),
+                at: Utc::now(),
+            })
+            .unwrap();
+        assert_eq!(changes.try_next().unwrap(), Some(()));
+
+        let freeze = Freeze {
+            reason: String::new(),
+            at: Utc::now(),
+        };
+        store.freeze("production", freeze).unwrap();
+        store.unfreeze("production").unwrap();
+        assert_eq!(changes.try_next().unwrap(), Some(()));
+        assert!(changes.try_next().is_err());
+
+        drop(changes);
+        store.reload().unwrap();
+        assert!(store.subscribers.lock().unwrap().is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn failing_tests_block_updates() {
+        let dir = temp_dir("tests");
+        let file = dir.join("flags.yaml");
+        let tests = r#"
+    tests:
+      - environment: production
+        context: {key: alice}
+        variation: 1
+"#;
+        let content = flag_file("[{value: false}, {value: true}]", 1) + tests;
+        fs::write(&file, &content).unwrap();
+        let store = FlagStore::load(&dir).unwrap();
+        let serve = |variation| {
+            move |definitions: &mut Definitions| {
+                let config = definitions.flags[0]
+                    .environments
+                    .get_mut("production")
+                    .unwrap();
+                config.fallthrough = Serve::Variation(variation);
+                Ok::<_, Error>(())
+            }
+        };
+
+        let failed = store.update(serve(0));
+        assert!(matches!(failed, Err(Error::TestsFailed(_))), "{:?}", failed);
+        assert_eq!(fs::read_to_string(&file).unwrap(), content);
+        assert_eq!(
+            store.definitions().flags[0].environments["production"].fallthrough,
+            Serve::Variation(1)
+        );
+
+        store
+            .update(|definitions| {
+                definitions.flags[0].description = Some("still serves alice".to_string());
+                serve(1)(definitions)
+            })
+            .unwrap();
+        let loaded = load_dir(&dir).unwrap().0;
+        assert_eq!(
+            loaded.flags[0].description.as_deref(),
+            Some("still serves alice")
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn creates_and_deletes_flags() {
+        let dir = temp_dir("create");
+        let file = dir.join("flags.yaml");
+        fs::write(&file, flag_file("[{value: false}, {value: true}]", 1)).unwrap();
+        let store = FlagStore::load(&dir).unwrap();
+
+        store
+            .update(|definitions| {
+                let mut banner = definitions.flags.remove(0);
+                banner.key = "banner".to_string();
+                definitions.flags.push(banner);
+                Ok::<_, Error>(())
+            })
+            .unwrap();
+        let (loaded, origins) = load_dir(&dir).unwrap();
+        let keys: Vec<_> = loaded.flags.iter().map(|f| f.key.as_str()).collect();
+        assert_eq!(keys, ["banner"]);
+        assert_eq!(
+            origins[&Subject::Flag("banner".to_string())],
+            dir.join("banner.yaml")
+        );
+
+        // The new file is where later changes go.
+        store
+            .update(|definitions| {
+                definitions.flags[0].description = Some("moved".to_string());
+                Ok::<_, Error>(())
+            })
+            .unwrap();
+        assert!(parse_file(&file).unwrap().flags.is_empty());
+        let banner = parse_file(&dir.join("banner.yaml")).unwrap();
+        assert_eq!(banner.flags[0].description.as_deref(), Some("moved"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn failed_renames_restore_the_files_already_written() {
+        let dir = temp_dir("write");
+        let written = dir.join("a.yaml");
+        fs::write(&written, "before").unwrap();
+        // Renaming a file over a directory fails.
+        let directory = dir.join("b.yaml");
+        fs::create_dir_all(directory.join("c")).unwrap();
+
+        let result = write_files(vec![
+            (written.clone(), "after".to_string()),
+            (directory, "after".to_string()),
+        ]);
+        assert!(matches!(result, Err(Error::Io { .. })));
+        assert_eq!(fs::read_to_string(&written).unwrap(), "before");
+        let mut left: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect();
+        left.sort();
+        assert_eq!(left, ["a.yaml", "b.yaml"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
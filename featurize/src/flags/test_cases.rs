@@ -0,0 +1,116 @@
+// Featurize, the FOSS feature flagging
+// Copyright (C) 2024  Lucy Ekaterina
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use std::{fmt, path::PathBuf};
+
+use serde::Serialize;
+
+use super::{Definitions, Evaluator, Flag, Lifecycle, Reason};
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TestResult {
+    pub flag: String,
+    /// Index into the flag's `tests`.
+    pub test: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub environment: String,
+    pub expected: usize,
+    /// `None` when the flag is not configured in the environment.
+    pub served: Option<usize>,
+    pub reason: Option<Reason>,
+    pub passed: bool,
+}
+
+impl fmt::Display for TestResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "flag \"{}\" tests[{}]", self.flag, self.test)?;
+        if let Some(name) = &self.name {
+            write!(f, " ({})", name)?;
+        }
+        write!(
+            f,
+            " in {}: expected variation {}",
+            self.environment, self.expected
+        )?;
+        match (self.served, &self.reason) {
+            (Some(served), Some(reason)) => write!(f, ", got {} ({})", served, reason),
+            _ => write!(f, ", the flag is not configured"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct TestFailure {
+    pub file: Option<PathBuf>,
+    pub result: TestResult,
+}
+
+#[derive(Debug)]
+pub struct TestFailures(pub Vec<TestFailure>);
+
+impl fmt::Display for TestFailures {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, failure) in self.0.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "  - ")?;
+            if let Some(file) = &failure.file {
+                write!(f, "{}: ", file.display())?;
+            }
+            write!(f, "{}", failure.result)?;
+        }
+        Ok(())
+    }
+}
+
+/// Runs the test cases of `flag` against `definitions`.
+pub fn run_tests(definitions: &Definitions, flag: &Flag) -> Vec<TestResult> {
+    flag.tests
+        .iter()
+        .enumerate()
+        .map(|(i, test)| {
+            let mut evaluator = Evaluator::new(definitions, &test.environment);
+            if let Some(at) = test.at {
+                evaluator = evaluator.at(at);
+            }
+            let evaluation = evaluator.evaluate(flag, &test.context);
+            let served = evaluation.as_ref().map(|e| e.variation);
+            TestResult {
+                flag: flag.key.clone(),
+                test: i,
+                name: test.name.clone(),
+                environment: test.environment.clone(),
+                expected: test.variation,
+                served,
+                reason: evaluation.map(|e| e.reason),
+                passed: served == Some(test.variation),
+            }
+        })
+        .collect()
+}
+
+/// Runs the test cases of every flag that is not archived and returns the
+/// ones that fail.
+pub fn failing_tests(definitions: &Definitions) -> Vec<TestResult> {
+    definitions
+        .flags
+        .iter()
+        .filter(|flag| flag.lifecycle != Lifecycle::Archived)
+        .flat_map(|flag| run_tests(definitions, flag))
+        .filter(|result| !result.passed)
+        .collect()
+}
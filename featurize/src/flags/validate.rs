@@ -0,0 +1,657 @@
+// Featurize, the FOSS feature flagging
+// Copyright (C) 2024  Lucy Ekaterina
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::{self, Display},
+    path::PathBuf,
+};
+
+use super::{
+    attributes, Clause, Definitions, Experiment, Flag, FlagConfig, Lifecycle, Op, Serve,
+    ROLLOUT_WEIGHT_TOTAL,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Subject {
+    Environment(String),
+    Segment(String),
+    Flag(String),
+    Experiment(String),
+    Layer(String),
+    Attribute(String),
+}
+
+impl Display for Subject {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Subject::Environment(key) => write!(f, "environment \"{}\"", key),
+            Subject::Segment(key) => write!(f, "segment \"{}\"", key),
+            Subject::Flag(key) => write!(f, "flag \"{}\"", key),
+            Subject::Experiment(key) => write!(f, "experiment \"{}\"", key),
+            Subject::Layer(key) => write!(f, "layer \"{}\"", key),
+            Subject::Attribute(key) => write!(f, "attribute \"{}\"", key),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ValidationError {
+    pub file: Option<PathBuf>,
+    pub subject: Subject,
+    pub path: String,
+    pub message: String,
+}
+
+impl Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(file) = &self.file {
+            write!(f, "{}: ", file.display())?;
+        }
+        write!(f, "{}", self.subject)?;
+        if !self.path.is_empty() {
+            write!(f, " at {}", self.path)?;
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ValidationErrors(pub Vec<ValidationError>);
+
+impl Display for ValidationErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for error in &self.0 {
+            writeln!(f, "  - {}", error)?;
+        }
+        Ok(())
+    }
+}
+
+pub fn validate(definitions: &Definitions) -> Result<(), ValidationErrors> {
+    let mut validator = Validator::new(definitions);
+    validator.run();
+    if validator.errors.is_empty() {
+        Ok(())
+    } else {
+        Err(ValidationErrors(validator.errors))
+    }
+}
+
+fn is_valid_key(key: &str) -> bool {
+    !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+struct Validator<'d> {
+    definitions: &'d Definitions,
+    environments: HashSet<&'d str>,
+    segments: HashSet<&'d str>,
+    flags: HashMap<&'d str, &'d Flag>,
+    layers: HashSet<&'d str>,
+    errors: Vec<ValidationError>,
+}
+
+impl<'d> Validator<'d> {
+    fn new(definitions: &'d Definitions) -> Self {
+        Self {
+            definitions,
+            environments: HashSet::new(),
+            segments: HashSet::new(),
+            flags: HashMap::new(),
+            layers: HashSet::new(),
+            errors: Vec::new(),
+        }
+    }
+
+    fn error(&mut self, subject: &Subject, path: impl Into<String>, message: impl Into<String>) {
+        self.errors.push(ValidationError {
+            file: None,
+            subject: subject.clone(),
+            path: path.into(),
+            message: message.into(),
+        });
+    }
+
+    fn run(&mut self) {
+        self.collect_keys();
+
+        for segment in &self.definitions.segments {
+            let subject = Subject::Segment(segment.key.clone());
+            for (env, config) in &segment.environments {
+                let path = format!("environments.{}", env);
+                self.check_environment(&subject, &path, env);
+                for (i, rule) in config.rules.iter().enumerate() {
+                    let path = format!("{}.rules[{}]", path, i);
+                    if rule.clauses.is_empty() {
+                        self.error(&subject, &path, "rule has no clauses");
+                    }
+                    for (j, clause) in rule.clauses.iter().enumerate() {
+                        self.check_clause(&subject, &format!("{}.clauses[{}]", path, j), clause);
+                    }
+                }
+            }
+        }
+
+        for flag in &self.definitions.flags {
+            self.check_flag(flag);
+        }
+
+        let mut experiments = HashSet::new();
+        let mut running = HashSet::new();
+        let mut layer_traffic: HashMap<(&str, &str), u32> = HashMap::new();
+        for experiment in &self.definitions.experiments {
+            let subject = Subject::Experiment(experiment.key.clone());
+            if !experiments.insert(experiment.key.as_str()) {
+                self.error(&subject, "", "is defined more than once");
+            }
+            if !running.insert((experiment.flag.as_str(), experiment.environment.as_str())) {
+                self.error(
+                    &subject,
+                    "flag",
+                    format!(
+                        "another experiment already runs on flag \"{}\" in environment \"{}\"",
+                        experiment.flag, experiment.environment
+                    ),
+                );
+            }
+            if let Some(layer) = &experiment.layer {
+                let traffic = layer_traffic
+                    .entry((layer.as_str(), experiment.environment.as_str()))
+                    .or_default();
+                *traffic = traffic.saturating_add(experiment.traffic);
+                if *traffic > ROLLOUT_WEIGHT_TOTAL {
+                    self.error(
+                        &subject,
+                        "traffic",
+                        format!(
+                            "experiments in layer \"{}\" take more than {} in environment \"{}\"",
+                            layer, ROLLOUT_WEIGHT_TOTAL, experiment.environment
+                        ),
+                    );
+                }
+            }
+            self.check_experiment(experiment);
+        }
+
+        self.check_prerequisite_cycles();
+
+        if self.definitions.strict_attributes {
+            self.errors
+                .extend(attributes::schema_issues(self.definitions));
+        }
+    }
+
+    fn collect_keys(&mut self) {
+        let definitions = self.definitions;
+
+        for environment in &definitions.environments {
+            let subject = Subject::Environment(environment.key.clone());
+            if !is_valid_key(&environment.key) {
+                self.error(
+                    &subject,
+                    "key",
+                    "keys may only contain letters, digits, '-', '_' and '.'",
+                );
+            }
+            if !self.environments.insert(&environment.key) {
+                self.error(&subject, "", "is defined more than once");
+            }
+            if environment
+                .holdout
+                .is_some_and(|h| h > ROLLOUT_WEIGHT_TOTAL)
+            {
+                self.error(
+                    &subject,
+                    "holdout",
+                    format!("holdout may be at most {}", ROLLOUT_WEIGHT_TOTAL),
+                );
+            }
+            if environment
+                .approvals
+                .as_ref()
+                .is_some_and(|a| a.required == 0)
+            {
+                self.error(&subject, "approvals.required", "must be at least 1");
+            }
+        }
+
+        for segment in &definitions.segments {
+            let subject = Subject::Segment(segment.key.clone());
+            if !is_valid_key(&segment.key) {
+                self.error(
+                    &subject,
+                    "key",
+                    "keys may only contain letters, digits, '-', '_' and '.'",
+                );
+            }
+            if !self.segments.insert(&segment.key) {
+                self.error(&subject, "", "is defined more than once");
+            }
+        }
+
+        let mut attributes = HashSet::new();
+        for attribute in &definitions.attributes {
+            let subject = Subject::Attribute(attribute.key.clone());
+            if attribute.key.is_empty() {
+                self.error(&subject, "key", "attribute has no key");
+            }
+            if !attributes.insert(&attribute.key) {
+                self.error(&subject, "", "is defined more than once");
+            }
+        }
+
+        for flag in &definitions.flags {
+            let subject = Subject::Flag(flag.key.clone());
+            if !is_valid_key(&flag.key) {
+                self.error(
+                    &subject,
+                    "key",
+                    "keys may only contain letters, digits, '-', '_' and '.'",
+                );
+            }
+            if self.flags.insert(&flag.key, flag).is_some() {
+                self.error(&subject, "", "is defined more than once");
+            }
+        }
+
+        for layer in &definitions.layers {
+            let subject = Subject::Layer(layer.key.clone());
+            if !is_valid_key(&layer.key) {
+                self.error(
+                    &subject,
+                    "key",
+                    "keys may only contain letters, digits, '-', '_' and '.'",
+                );
+            }
+            if !self.layers.insert(&layer.key) {
+                self.error(&subject, "", "is defined more than once");
+            }
+        }
+    }
+
+    fn check_environment(&mut self, subject: &Subject, path: &str, env: &str) {
+        if !self.environments.contains(env) {
+            self.error(subject, path, format!("unknown environment \"{}\"", env));
+        }
+    }
+
+    fn check_variation(&mut self, subject: &Subject, path: &str, flag: &Flag, variation: usize) {
+        if variation >= flag.variations.len() {
+            self.error(
+                subject,
+                path,
+                format!(
+                    "variation {} does not exist, the flag has {} variations",
+                    variation,
+                    flag.variations.len()
+                ),
+            );
+        }
+    }
+
+    fn check_flag(&mut self, flag: &'d Flag) {
+        let subject = Subject::Flag(flag.key.clone());
+
+        if flag.variations.is_empty() {
+            self.error(
+                &subject,
+                "variations",
+                "a flag needs at least one variation",
+            );
+        }
+
+        for (env, config) in &flag.environments {
+            let path = format!("environments.{}", env);
+            self.check_environment(&subject, &path, env);
+            self.check_flag_config(&subject, &path, flag, config);
+        }
+
+        let mut tags = HashSet::new();
+        for (i, tag) in flag.tags.iter().enumerate() {
+            if tag.trim().is_empty() {
+                self.error(&subject, format!("tags[{}]", i), "tags can't be empty");
+            } else if !tags.insert(tag) {
+                self.error(&subject, format!("tags[{}]", i), "is listed more than once");
+            }
+        }
+
+        if let Some(kill_switch) = &flag.kill_switch {
+            self.check_variation(
+                &subject,
+                "kill_switch.variation",
+                flag,
+                kill_switch.variation,
+            );
+        }
+
+        for (i, test) in flag.tests.iter().enumerate() {
+            let path = format!("tests[{}]", i);
+            if !flag.environments.contains_key(&test.environment) {
+                self.error(
+                    &subject,
+                    format!("{}.environment", path),
+                    format!(
+                        "the flag is not configured in environment \"{}\"",
+                        test.environment
+                    ),
+                );
+            }
+            self.check_variation(
+                &subject,
+                &format!("{}.variation", path),
+                flag,
+                test.variation,
+            );
+        }
+
+        for (i, url) in flag.webhooks.iter().enumerate() {
+            if !url.starts_with("http://") && !url.starts_with("https://") {
+                self.error(
+                    &subject,
+                    format!("webhooks[{}]", i),
+                    "webhooks must be http or https URLs",
+                );
+            }
+        }
+    }
+
+    fn check_flag_config(
+        &mut self,
+        subject: &Subject,
+        path: &str,
+        flag: &Flag,
+        config: &FlagConfig,
+    ) {
+        self.check_variation(
+            subject,
+            &format!("{}.off_variation", path),
+            flag,
+            config.off_variation,
+        );
+        self.check_serve(
+            subject,
+            &format!("{}.fallthrough", path),
+            flag,
+            &config.fallthrough,
+        );
+
+        for (i, prerequisite) in config.prerequisites.iter().enumerate() {
+            let path = format!("{}.prerequisites[{}]", path, i);
+            if prerequisite.flag == flag.key {
+                self.error(subject, &path, "a flag cannot be its own prerequisite");
+                continue;
+            }
+            match self.flags.get(prerequisite.flag.as_str()) {
+                Some(other)
+                    if other.lifecycle == Lifecycle::Archived
+                        && flag.lifecycle != Lifecycle::Archived =>
+                {
+                    self.error(
+                        subject,
+                        &path,
+                        format!(
+                            "flag \"{}\" is archived and can no longer be a prerequisite",
+                            prerequisite.flag
+                        ),
+                    )
+                }
+                Some(other) => self.check_variation(subject, &path, other, prerequisite.variation),
+                None => self.error(
+                    subject,
+                    &path,
+                    format!("unknown flag \"{}\"", prerequisite.flag),
+                ),
+            }
+        }
+
+        for (i, target) in config.targets.iter().enumerate() {
+            self.check_variation(
+                subject,
+                &format!("{}.targets[{}]", path, i),
+                flag,
+                target.variation,
+            );
+        }
+
+        for (i, rule) in config.rules.iter().enumerate() {
+            let path = format!("{}.rules[{}]", path, i);
+            if rule.clauses.is_empty() {
+                self.error(subject, &path, "rule has no clauses");
+            }
+            for (j, clause) in rule.clauses.iter().enumerate() {
+                self.check_clause(subject, &format!("{}.clauses[{}]", path, j), clause);
+            }
+            self.check_serve(subject, &format!("{}.serve", path), flag, &rule.serve);
+        }
+
+        if let Some(guardrails) = &config.guardrails {
+            let path = format!("{}.guardrails", path);
+            self.check_variation(
+                subject,
+                &format!("{}.safe_variation", path),
+                flag,
+                guardrails.safe_variation,
+            );
+            if guardrails.metrics.is_empty() {
+                self.error(subject, &path, "guardrails need at least one metric");
+            }
+            for (i, guardrail) in guardrails.metrics.iter().enumerate() {
+                let path = format!("{}.metrics[{}]", path, i);
+                if !is_valid_key(&guardrail.metric) {
+                    self.error(
+                        subject,
+                        format!("{}.metric", path),
+                        "keys may only contain letters, digits, '-', '_' and '.'",
+                    );
+                }
+                if guardrail.max_increase.is_none() && guardrail.max_decrease.is_none() {
+                    self.error(
+                        subject,
+                        &path,
+                        "a guardrail needs max_increase or max_decrease",
+                    );
+                }
+                let thresholds = [guardrail.max_increase, guardrail.max_decrease];
+                if thresholds
+                    .into_iter()
+                    .flatten()
+                    .any(|t| t.is_nan() || t < 0.0)
+                {
+                    self.error(subject, &path, "thresholds may not be negative");
+                }
+            }
+        }
+    }
+
+    fn check_serve(&mut self, subject: &Subject, path: &str, flag: &Flag, serve: &Serve) {
+        match serve {
+            Serve::Variation(variation) => self.check_variation(subject, path, flag, *variation),
+            Serve::Rollout(rollout) => {
+                if rollout.variations.is_empty() {
+                    self.error(subject, path, "a rollout needs at least one variation");
+                }
+                let mut total = 0u32;
+                for (i, weighted) in rollout.variations.iter().enumerate() {
+                    let path = format!("{}.variations[{}]", path, i);
+                    self.check_variation(subject, &path, flag, weighted.variation);
+                    total = total.saturating_add(weighted.weight);
+                }
+                if total != ROLLOUT_WEIGHT_TOTAL {
+                    self.error(
+                        subject,
+                        path,
+                        format!(
+                            "rollout weights add up to {} but must add up to {}",
+                            total, ROLLOUT_WEIGHT_TOTAL
+                        ),
+                    );
+                }
+            }
+        }
+    }
+
+    fn check_clause(&mut self, subject: &Subject, path: &str, clause: &Clause) {
+        if clause.op.uses_clock() {
+            if !clause.attribute.is_empty() {
+                self.error(
+                    subject,
+                    path,
+                    "during and schedule clauses compare the time of evaluation and take no attribute",
+                );
+            }
+        } else if clause.attribute.is_empty() {
+            self.error(subject, path, "clause has no attribute");
+        }
+        if clause.values.is_empty() {
+            self.error(subject, path, "clause has no values");
+        }
+        if clause.op == Op::SegmentMatch {
+            for value in &clause.values {
+                match value.as_str() {
+                    Some(segment) if self.segments.contains(segment) => {}
+                    Some(segment) => {
+                        self.error(subject, path, format!("unknown segment \"{}\"", segment))
+                    }
+                    None => self.error(subject, path, "segment_match values must be segment keys"),
+                }
+            }
+        }
+        for message in clause.operands().iter().filter_map(|o| o.as_ref().err()) {
+            self.error(subject, path, message.clone());
+        }
+    }
+
+    fn check_experiment(&mut self, experiment: &Experiment) {
+        let subject = Subject::Experiment(experiment.key.clone());
+        if !is_valid_key(&experiment.key) {
+            self.error(
+                &subject,
+                "key",
+                "keys may only contain letters, digits, '-', '_' and '.'",
+            );
+        }
+        self.check_environment(&subject, "environment", &experiment.environment);
+        match self.flags.get(experiment.flag.as_str()).copied() {
+            Some(flag) => {
+                if flag.lifecycle == Lifecycle::Archived {
+                    self.error(
+                        &subject,
+                        "flag",
+                        format!("flag \"{}\" is archived", experiment.flag),
+                    );
+                }
+                if !flag.environments.contains_key(&experiment.environment) {
+                    self.error(
+                        &subject,
+                        "flag",
+                        format!(
+                            "flag \"{}\" is not configured in environment \"{}\"",
+                            experiment.flag, experiment.environment
+                        ),
+                    );
+                }
+                self.check_variation(&subject, "control", flag, experiment.control);
+            }
+            None => self.error(
+                &subject,
+                "flag",
+                format!("unknown flag \"{}\"", experiment.flag),
+            ),
+        }
+        if let Some(layer) = &experiment.layer {
+            if !self.layers.contains(layer.as_str()) {
+                self.error(&subject, "layer", format!("unknown layer \"{}\"", layer));
+            }
+        }
+        if experiment.traffic > ROLLOUT_WEIGHT_TOTAL {
+            self.error(
+                &subject,
+                "traffic",
+                format!("traffic may be at most {}", ROLLOUT_WEIGHT_TOTAL),
+            );
+        }
+        if experiment.metrics.is_empty() {
+            self.error(
+                &subject,
+                "metrics",
+                "an experiment needs at least one metric",
+            );
+        }
+        for (i, metric) in experiment.metrics.iter().enumerate() {
+            if !is_valid_key(&metric.key) {
+                self.error(
+                    &subject,
+                    format!("metrics[{}].key", i),
+                    "keys may only contain letters, digits, '-', '_' and '.'",
+                );
+            }
+        }
+    }
+
+    fn check_prerequisite_cycles(&mut self) {
+        for environment in &self.definitions.environments {
+            let mut finished = HashSet::new();
+            for flag in &self.definitions.flags {
+                let mut stack = Vec::new();
+                if let Some(cycle) =
+                    self.find_cycle(&environment.key, &flag.key, &mut stack, &mut finished)
+                {
+                    let subject = Subject::Flag(flag.key.clone());
+                    self.error(
+                        &subject,
+                        format!("environments.{}.prerequisites", environment.key),
+                        format!("prerequisites form a cycle: {}", cycle.join(" -> ")),
+                    );
+                    return;
+                }
+            }
+        }
+    }
+
+    fn find_cycle(
+        &self,
+        env: &str,
+        key: &'d str,
+        stack: &mut Vec<&'d str>,
+        finished: &mut HashSet<&'d str>,
+    ) -> Option<Vec<&'d str>> {
+        if finished.contains(key) {
+            return None;
+        }
+        if let Some(start) = stack.iter().position(|k| *k == key) {
+            let mut cycle = stack[start..].to_vec();
+            cycle.push(key);
+            return Some(cycle);
+        }
+        let flag: &'d Flag = *self.flags.get(key)?;
+        stack.push(key);
+        if let Some(config) = flag.environments.get(env) {
+            for prerequisite in &config.prerequisites {
+                if prerequisite.flag == key {
+                    continue;
+                }
+                if let Some(cycle) = self.find_cycle(env, &prerequisite.flag, stack, finished) {
+                    return Some(cycle);
+                }
+            }
+        }
+        stack.pop();
+        finished.insert(key);
+        None
+    }
+}
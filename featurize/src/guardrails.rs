@@ -0,0 +1,160 @@
+// Featurize, the FOSS feature flagging
+// Copyright (C) 2024  Lucy Ekaterina
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use std::{sync::Arc, time::Duration};
+
+use chrono::{DateTime, Utc};
+
+use crate::{
+    audit::{AuditEntry, AuditLog},
+    experiments::ExperimentEvents,
+    flags::{Flag, FlagConfig, FlagStore, Guardrail, Override, OverrideAction, Serve},
+    webhooks::Webhooks,
+};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Watches the guardrail metrics of flags that are rolling out and rolls them
+/// back to their safe variation when one is breached.
+#[derive(Debug)]
+pub struct GuardrailMonitor {
+    flags: Arc<FlagStore>,
+    events: Arc<ExperimentEvents>,
+    audit: Arc<AuditLog>,
+    webhooks: Webhooks,
+}
+
+impl GuardrailMonitor {
+    pub fn new(
+        flags: Arc<FlagStore>,
+        events: Arc<ExperimentEvents>,
+        audit: Arc<AuditLog>,
+        webhooks: Webhooks,
+    ) -> Self {
+        Self {
+            flags,
+            events,
+            audit,
+            webhooks,
+        }
+    }
+
+    #[tracing::instrument(skip(self))]
+    pub fn check(&self, now: DateTime<Utc>) {
+        let definitions = self.flags.definitions();
+        for flag in &definitions.flags {
+            for (environment, config) in &flag.environments {
+                let Some(guardrails) = &config.guardrails else {
+                    continue;
+                };
+                if !config.on || !rolling_out(config) {
+                    continue;
+                }
+                let breach = guardrails
+                    .metrics
+                    .iter()
+                    .find_map(|g| self.breach(environment, flag, guardrails.safe_variation, g));
+                if let Some(reason) = breach {
+                    self.roll_back(environment, flag, guardrails.safe_variation, reason, now);
+                }
+            }
+        }
+    }
+
+    /// Describes how `guardrail` is breached, if it is.
+    fn breach(
+        &self,
+        environment: &str,
+        flag: &Flag,
+        safe_variation: usize,
+        guardrail: &Guardrail,
+    ) -> Option<String> {
+        let results = self.events.compare(
+            environment,
+            flag,
+            safe_variation,
+            &guardrail.metric,
+            guardrail.kind,
+        );
+        let safe = results.variations.get(safe_variation)?;
+        if safe.units < guardrail.min_units {
+            return None;
+        }
+        results.variations.iter().find_map(|v| {
+            if v.control || v.units < guardrail.min_units || !v.significant {
+                return None;
+            }
+            let change = v.change?;
+            let breached = guardrail.max_increase.is_some_and(|max| change > max)
+                || guardrail.max_decrease.is_some_and(|max| -change > max);
+            breached.then(|| {
+                format!(
+                    "{} changed by {:+.1}% for variation {} against the safe variation (p = {:.4})",
+                    guardrail.metric,
+                    change * 100.0,
+                    v.name,
+                    v.p_value.unwrap_or_default(),
+                )
+            })
+        })
+    }
+
+    fn roll_back(
+        &self,
+        environment: &str,
+        flag: &Flag,
+        variation: usize,
+        reason: String,
+        now: DateTime<Utc>,
+    ) {
+        let rollback = Override {
+            environment: environment.to_string(),
+            flag: flag.key.clone(),
+            action: OverrideAction::Rollback { variation },
+            reason: reason.clone(),
+            at: now,
+        };
+        // The flag may have changed since the results were read, the store
+        // refuses variations the current flag doesn't have.
+        if let Err(e) = self.flags.set_override(rollback) {
+            tracing::error!("could not roll back {} in {}: {}", flag.key, environment, e);
+            return;
+        }
+        let entry = AuditEntry {
+            at: now,
+            actor: "guardrails".to_string(),
+            environment: environment.to_string(),
+            flag: flag.key.clone(),
+            action: "rollback".to_string(),
+            detail: reason,
+        };
+        self.webhooks.notify(&flag.webhooks, &entry);
+        self.audit.record(entry);
+    }
+
+    pub async fn monitor_periodically(self: Arc<Self>) {
+        let mut interval = actix_web::rt::time::interval(CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            self.check(Utc::now());
+        }
+    }
+}
+
+fn rolling_out(config: &FlagConfig) -> bool {
+    std::iter::once(&config.fallthrough)
+        .chain(config.rules.iter().map(|r| &r.serve))
+        .any(|serve| matches!(serve, Serve::Rollout(_)))
+}
@@ -0,0 +1,277 @@
+// Featurize, the FOSS feature flagging
+// Copyright (C) 2024  Lucy Ekaterina
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use std::{collections::BTreeSet, sync::Arc};
+
+use actix_web::{get, web, HttpResponse};
+use chrono::{DateTime, Duration, Utc};
+use sentry::{Hub, SentryFutureExt};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    analytics::Analytics,
+    flags::{Definitions, Flag, FlagConfig, FlagKind, FlagStore, Guard, Lifecycle, Serve},
+    renderer::Renderer,
+    Error,
+};
+
+/// How long a flag has to go unevaluated or unchanged to be worth cleaning up.
+pub const STALE_AFTER_DAYS: i64 = 30;
+
+/// A flag evaluated this recently is still in use and can't be archived.
+const IN_USE_DAYS: i64 = 7;
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum StaleReason {
+    /// Not evaluated for `STALE_AFTER_DAYS`, only given once evaluations have
+    /// been counted that long.
+    Unused,
+    /// Every environment has served this variation to every context for
+    /// `STALE_AFTER_DAYS`.
+    FullyRolledOut {
+        variation: usize,
+    },
+    Launched,
+    Deprecated,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CleanupCandidate<'d> {
+    pub flag: &'d str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<&'d str>,
+    pub lifecycle: Lifecycle,
+    pub reasons: Vec<StaleReason>,
+    pub evaluations: u64,
+    pub last_evaluated: Option<DateTime<Utc>>,
+    pub last_changed: Option<DateTime<Utc>>,
+}
+
+/// Temporary flags that are not archived and look like they have done their
+/// job, with the reasons why.
+pub fn cleanup_candidates<'d>(
+    definitions: &'d Definitions,
+    flags: &FlagStore,
+    analytics: &Analytics,
+    now: DateTime<Utc>,
+) -> Vec<CleanupCandidate<'d>> {
+    let stale_before = now - Duration::days(STALE_AFTER_DAYS);
+    definitions
+        .flags
+        .iter()
+        .filter(|flag| flag.kind == FlagKind::Temporary && flag.lifecycle != Lifecycle::Archived)
+        .filter_map(|flag| {
+            let evaluations = analytics.evaluations_since(&flag.key, stale_before);
+            let last_changed = flags.last_changed(&flag.key);
+            // New flags may not be in code yet, they aren't unused.
+            let unchanged = last_changed.is_some_and(|at| at < stale_before);
+            let mut reasons = Vec::new();
+            if evaluations == 0 && unchanged && analytics.counting_since(stale_before) {
+                reasons.push(StaleReason::Unused);
+            }
+            if let Some(variation) = single_variation(flag).filter(|_| unchanged) {
+                reasons.push(StaleReason::FullyRolledOut { variation });
+            }
+            match flag.lifecycle {
+                Lifecycle::Launched => reasons.push(StaleReason::Launched),
+                Lifecycle::Deprecated => reasons.push(StaleReason::Deprecated),
+                Lifecycle::Active | Lifecycle::Archived => {}
+            }
+            (!reasons.is_empty()).then(|| CleanupCandidate {
+                flag: &flag.key,
+                name: flag.name.as_deref(),
+                lifecycle: flag.lifecycle,
+                reasons,
+                evaluations,
+                last_evaluated: analytics.last_evaluated(&flag.key, now),
+                last_changed,
+            })
+        })
+        .collect()
+}
+
+/// The variation every environment serves every context, if there is one.
+fn single_variation(flag: &Flag) -> Option<usize> {
+    let mut served = BTreeSet::new();
+    for config in flag.environments.values() {
+        served.extend(served_variations(config));
+    }
+    match served.len() {
+        1 => served.pop_first(),
+        _ => None,
+    }
+}
+
+fn served_variations(config: &FlagConfig) -> BTreeSet<usize> {
+    if !config.on {
+        return BTreeSet::from([config.off_variation]);
+    }
+    let mut served = BTreeSet::new();
+    if !config.prerequisites.is_empty() {
+        served.insert(config.off_variation);
+    }
+    served.extend(config.targets.iter().map(|t| t.variation));
+    let serves = config
+        .rules
+        .iter()
+        .map(|r| &r.serve)
+        .chain([&config.fallthrough]);
+    for serve in serves {
+        match serve {
+            Serve::Variation(variation) => {
+                served.insert(*variation);
+            }
+            Serve::Rollout(rollout) => served.extend(
+                rollout
+                    .variations
+                    .iter()
+                    .filter(|v| v.weight > 0)
+                    .map(|v| v.variation),
+            ),
+        }
+    }
+    served
+}
+
+/// Refuses to archive flags that have been evaluated in the last
+/// `IN_USE_DAYS`, also when featurize hasn't been running that long: no
+/// evaluations since a restart don't prove a flag unused, but any prove it in
+/// use. Prerequisites of flags still in use are checked by validation.
+pub fn archive_guard(analytics: Arc<Analytics>) -> Guard {
+    Box::new(move |current, changed| {
+        let since = Utc::now() - Duration::days(IN_USE_DAYS);
+        let in_use: Vec<String> = changed
+            .flags
+            .iter()
+            .filter(|flag| flag.lifecycle == Lifecycle::Archived)
+            .filter(|flag| {
+                current
+                    .flag(&flag.key)
+                    .is_some_and(|f| f.lifecycle != Lifecycle::Archived)
+            })
+            .filter_map(|flag| {
+                let evaluations = analytics.evaluations_since(&flag.key, since);
+                (evaluations > 0).then(|| {
+                    format!(
+                        "flag \"{}\" was evaluated {} times in the last {} days and can't be archived yet",
+                        flag.key, evaluations, IN_USE_DAYS
+                    )
+                })
+            })
+            .collect();
+        if in_use.is_empty() {
+            Ok(())
+        } else {
+            Err(in_use.join(", "))
+        }
+    })
+}
+
+/// The cleanup candidates as JSON.
+#[tracing::instrument]
+#[get("/flags/stale")]
+pub async fn api_route(
+    flags: web::Data<FlagStore>,
+    analytics: web::Data<Analytics>,
+) -> Result<HttpResponse, Error> {
+    let hub = Hub::current();
+    api_handler(flags, analytics).bind_hub(hub).await
+}
+
+#[tracing::instrument]
+pub async fn api_handler(
+    flags: web::Data<FlagStore>,
+    analytics: web::Data<Analytics>,
+) -> Result<HttpResponse, Error> {
+    let definitions = flags.definitions();
+    let candidates = cleanup_candidates(&definitions, &flags, &analytics, Utc::now());
+    Ok(HttpResponse::Ok().json(candidates))
+}
+
+#[tracing::instrument]
+#[get("/cleanup")]
+pub async fn page_route(
+    renderer: web::Data<Renderer>,
+    flags: web::Data<FlagStore>,
+    analytics: web::Data<Analytics>,
+) -> Result<HttpResponse, Error> {
+    let hub = Hub::current();
+    page_handler(renderer, flags, analytics).bind_hub(hub).await
+}
+
+#[tracing::instrument]
+pub async fn page_handler(
+    renderer: web::Data<Renderer>,
+    flags: web::Data<FlagStore>,
+    analytics: web::Data<Analytics>,
+) -> Result<HttpResponse, Error> {
+    let definitions = flags.definitions();
+    let candidates = cleanup_candidates(&definitions, &flags, &analytics, Utc::now());
+    Ok(renderer
+        .render("cleanup.html")
+        .var("candidates", &candidates)
+        .var("stale_after_days", &STALE_AFTER_DAYS)
+        .ok()
+        .finish()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analytics::AnalyticsConfig;
+
+    fn definitions(lifecycle: &str) -> Definitions {
+        serde_yaml::from_str(&format!(
+            r#"
+environments:
+  - key: production
+flags:
+  - key: checkout
+    lifecycle: {}
+    variations: [{{value: false}}, {{value: true}}]
+"#,
+            lifecycle
+        ))
+        .unwrap()
+    }
+
+    fn analytics() -> Arc<Analytics> {
+        Arc::new(Analytics::new(AnalyticsConfig {
+            retention: Duration::days(90),
+            compact_after: Duration::days(7),
+        }))
+    }
+
+    #[test]
+    fn refuses_archiving_flags_evaluated_since_a_restart() {
+        let analytics = analytics();
+        analytics.record("production", "checkout", Some(1), Utc::now(), 3);
+        let guard = archive_guard(analytics);
+        let refused = guard(&definitions("active"), &definitions("archived"));
+        assert!(refused.is_err_and(|e| e.contains("evaluated 3 times")));
+    }
+
+    #[test]
+    fn archives_flags_not_evaluated() {
+        let analytics = analytics();
+        analytics.record("production", "other", Some(1), Utc::now(), 3);
+        let guard = archive_guard(analytics);
+        assert_eq!(
+            guard(&definitions("active"), &definitions("archived")),
+            Ok(())
+        );
+    }
+}
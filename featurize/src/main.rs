@@ -5,16 +5,63 @@ use tera::Tera;
 use tracing_actix_web::TracingLogger;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
-use crate::{ory_client::OryClient, renderer::Renderer};
+use crate::{
+    analytics::{Analytics, AnalyticsConfig},
+    attributes::AttributeRegistry,
+    audit::AuditLog,
+    change_requests::{ChangeRequestError, ChangeRequests},
+    experiments::ExperimentEvents,
+    flags::FlagStore,
+    guardrails::GuardrailMonitor,
+    notifications::{Mailer, Notifications},
+    ory_client::OryClient,
+    references::CodeReferences,
+    renderer::Renderer,
+    webhooks::Webhooks,
+};
 
+mod analytics;
+mod attributes;
+mod audit;
+mod change_requests;
+mod cli;
+mod dashboard;
+mod diff;
+mod edit;
+mod environments;
+mod evaluate;
+mod events;
+mod experiments;
+mod explain;
+mod export;
+mod flagd;
+mod flags;
+mod guardrails;
 mod index;
+mod lifecycle;
+mod notifications;
+mod openfeature;
 mod ory_client;
+mod plan;
+mod promote;
+mod references;
+mod relay;
 mod renderer;
+mod scan;
+mod search;
+mod simulate;
+mod stream;
+mod test_cases;
+mod webhooks;
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error("error deserializing data: {0}")]
     DeserializationError(#[from] serde_json::Error),
+    #[error("error serializing yaml: {0}")]
+    YamlError(#[from] serde_yaml::Error),
+    #[error("error in flag definitions: {0}")]
+    Flags(#[from] flags::Error),
     #[error("error rendering the template: {0}")]
     RenderingError(#[from] tera::Error),
     #[error("An error fetching data has occured: {0}")]
@@ -25,6 +72,16 @@ pub enum Error {
     NoOryClient,
     #[error("No session available")]
     NoSession,
+    #[error("could not simulate: {0}")]
+    Simulation(#[from] flags::SimulationError),
+    #[error("could not promote: {0}")]
+    Promotion(#[from] flags::PromotionError),
+    #[error("{0}")]
+    ChangeRequest(#[from] change_requests::ChangeRequestError),
+    #[error("could not patch: {0}")]
+    Patch(#[from] flags::PatchError),
+    #[error("Not found")]
+    NotFound,
     #[error("An unknown error has occured")]
     Unknown,
 }
@@ -33,12 +90,47 @@ impl actix_web::ResponseError for Error {
     fn status_code(&self) -> actix_web::http::StatusCode {
         match self {
             Error::DeserializationError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Error::YamlError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Error::Flags(e) => flags_status(e),
             Error::RenderingError(_) => StatusCode::INTERNAL_SERVER_ERROR,
             Error::Reqwest(_) => StatusCode::INTERNAL_SERVER_ERROR,
             Error::CookieToString(_) => StatusCode::BAD_REQUEST,
             Error::Unknown => StatusCode::INTERNAL_SERVER_ERROR,
             Error::NoOryClient => StatusCode::INTERNAL_SERVER_ERROR,
             Error::NoSession => StatusCode::UNAUTHORIZED,
+            Error::Simulation(_) => StatusCode::BAD_REQUEST,
+            Error::Promotion(flags::PromotionError::Flags(e)) => flags_status(e),
+            Error::Promotion(_) => StatusCode::BAD_REQUEST,
+            Error::ChangeRequest(e) => match e {
+                ChangeRequestError::NoChanges(_) | ChangeRequestError::ApplyAt(_) => {
+                    StatusCode::BAD_REQUEST
+                }
+                ChangeRequestError::OwnChange | ChangeRequestError::NotReviewer(_) => {
+                    StatusCode::FORBIDDEN
+                }
+                ChangeRequestError::WrongStatus(_)
+                | ChangeRequestError::Conflict(_)
+                | ChangeRequestError::Protected { .. }
+                | ChangeRequestError::RequiresApprovals { .. } => StatusCode::CONFLICT,
+            },
+            Error::Patch(_) => StatusCode::BAD_REQUEST,
+            Error::NotFound => StatusCode::NOT_FOUND,
+        }
+    }
+}
+
+/// Flag errors in requests come from changing the definitions.
+fn flags_status(error: &flags::Error) -> StatusCode {
+    match error {
+        flags::Error::Invalid(_)
+        | flags::Error::TestsFailed(_)
+        | flags::Error::InvalidOverride(_) => StatusCode::BAD_REQUEST,
+        flags::Error::Frozen(_)
+        | flags::Error::NotWritable(_)
+        | flags::Error::Stale(_)
+        | flags::Error::Rejected(_) => StatusCode::CONFLICT,
+        flags::Error::Io { .. } | flags::Error::Parse { .. } | flags::Error::Watch(_) => {
+            StatusCode::INTERNAL_SERVER_ERROR
         }
     }
 }
@@ -46,6 +138,16 @@ impl actix_web::ResponseError for Error {
 fn main() -> color_eyre::Result<()> {
     color_eyre::install()?;
 
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("import") => return cli::import(&args[1..]),
+        Some("scan") => return cli::scan(&args[1..]),
+        Some("relay") => return relay::relay(&args[1..]),
+        Some("plan") => return plan::plan(&args[1..]),
+        Some("apply") => return plan::apply(&args[1..]),
+        _ => {}
+    }
+
     let environment = env::var("ENV").unwrap_or("Dev".to_string());
 
     let _guard = sentry::init(sentry::ClientOptions {
@@ -80,30 +182,138 @@ async fn run_server() -> color_eyre::Result<()> {
         }
     }?;
 
+    let analytics = Arc::new(Analytics::new(AnalyticsConfig::from_env()?));
+    actix_web::rt::spawn(analytics.clone().compact_periodically());
+    let mut flags = match env::var("FLAGS_DIR") {
+        Ok(dir) => FlagStore::load(dir)?,
+        Err(_) => FlagStore::empty(),
+    }
+    .with_guard(lifecycle::archive_guard(analytics.clone()));
+    if let Ok(path) = env::var("OVERRIDES_FILE") {
+        flags = flags.with_overrides_file(path)?;
+    }
+    let flags = Arc::new(flags);
+    let _watcher = flags.watch()?;
+    let experiments = Arc::new(ExperimentEvents::new());
+    let attributes = Arc::new(AttributeRegistry::new());
+    let audit = Arc::new(match env::var("AUDIT_LOG_FILE") {
+        Ok(path) => AuditLog::open(path)?,
+        Err(_) => AuditLog::new(),
+    });
+    let references = Arc::new(match env::var("REFERENCES_FILE") {
+        Ok(path) => CodeReferences::open(path)?,
+        Err(_) => CodeReferences::new(),
+    });
+    let webhooks = Webhooks::new(reqwest::Client::new());
+    let guardrails = Arc::new(GuardrailMonitor::new(
+        flags.clone(),
+        experiments.clone(),
+        audit.clone(),
+        webhooks.clone(),
+    ));
+    actix_web::rt::spawn(guardrails.monitor_periodically());
+    let mut change_requests = ChangeRequests::new(flags.clone(), audit.clone(), webhooks.clone());
+    if let Ok(path) = env::var("CHANGE_REQUESTS_FILE") {
+        change_requests = change_requests.with_file(path)?;
+    }
+    let change_requests = Arc::new(change_requests);
+    actix_web::rt::spawn(change_requests.clone().apply_periodically());
+    let templates_dir = env::var("TEMPLATES_DIR").unwrap_or("templates".to_string());
+    let renderer = Arc::new(Renderer::new(
+        Tera::new(&format!("{}/**/*.html", templates_dir))?,
+        env::var("SENTRY_DSN").unwrap_or_default(),
+    ));
+    let mut notifications = Notifications::new(
+        flags.clone(),
+        audit.clone(),
+        analytics.clone(),
+        renderer.clone(),
+        Mailer::from_env()?,
+    );
+    if let Ok(path) = env::var("NOTIFICATIONS_FILE") {
+        notifications = notifications.with_file(path)?;
+    }
+    let notifications = Arc::new(notifications);
+    if notifications.is_configured() {
+        actix_web::rt::spawn(notifications.clone().notify_periodically());
+    }
+
     println!("Starting on: 0.0.0.0:{}", port);
-    HttpServer::new(|| {
-        let kratos_domain = env::var("KRATOS_DOMAIN").unwrap();
-        let hydra_domain = env::var("HYDRA_DOMAIN").unwrap();
-        let templates_dir = env::var("TEMPLATES_DIR").unwrap_or("templates".to_string());
+    HttpServer::new(move || {
         let public_dir = env::var("PUBLIC_DIR").unwrap_or("public".to_string());
-        let sentry_dsn = env::var("SENTRY_DSN").unwrap();
-        App::new()
+        let app = App::new()
             .wrap(TracingLogger::default())
             .wrap(sentry_actix::Sentry::new())
-            .app_data(web::Data::new(Renderer::new(
-                Tera::new(&format!("{}/**/*.html", templates_dir)).unwrap(),
-                sentry_dsn.clone(),
-            )))
-            .app_data(web::Data::new(OryClient::new(
-                kratos_domain,
-                hydra_domain,
-                reqwest::Client::new(),
-            )))
+            .app_data(web::Data::from(renderer.clone()))
+            .app_data(web::Data::from(flags.clone()))
+            .app_data(web::Data::from(analytics.clone()))
+            .app_data(web::Data::from(experiments.clone()))
+            .app_data(web::Data::from(attributes.clone()))
+            .app_data(web::Data::from(audit.clone()))
+            .app_data(web::Data::from(references.clone()))
+            .app_data(web::Data::from(change_requests.clone()))
+            .app_data(web::Data::from(notifications.clone()))
+            .app_data(web::Data::new(webhooks.clone()))
+            .service(evaluate::route)
+            .service(stream::route)
+            .service(explain::api_route)
+            .service(export::route)
+            .configure(simulate::services)
+            .service(test_cases::all_route)
+            .service(attributes::api_route)
+            .service(lifecycle::api_route)
+            .service(search::api_route)
+            .service(references::upload_route)
+            .service(references::flag_route)
+            .service(edit::get_route)
+            .service(edit::put_route)
+            .service(edit::patch_route)
+            .service(edit::list_route)
+            .service(edit::apply_route)
+            .service(flagd::route)
+            .service(events::summary_route)
+            .service(events::track_route)
+            .service(dashboard::list_route)
+            .service(dashboard::flag_route)
+            .service(dashboard::clear_override_route)
+            .service(environments::kill_route)
+            .service(environments::page_route)
+            .service(environments::freeze_route)
+            .service(environments::unfreeze_route)
+            .service(environments::kill_all_route)
+            .service(attributes::page_route)
+            .service(lifecycle::page_route)
+            .service(promote::page_route)
+            .service(promote::preview_route)
+            .service(promote::apply_route)
+            .service(notifications::page_route)
+            .service(notifications::save_route)
+            .service(change_requests::list_route)
+            .service(change_requests::page_route)
+            .service(change_requests::review_route)
+            .service(change_requests::apply_route)
+            .service(change_requests::rebase_route)
+            .service(explain::page_route)
+            .service(explain::submit_route)
+            .service(edit::page_route)
+            .service(edit::submit_route)
+            .service(test_cases::flag_route)
+            .service(dashboard::experiment_csv_route)
+            .service(dashboard::experiment_route)
             .service(
                 actix_files::Files::new("/public", public_dir)
                     .show_files_listing()
                     .use_last_modified(true),
-            )
+            );
+        // Kratos and Hydra are optional so featurize can run from FLAGS_DIR alone.
+        match (env::var("KRATOS_DOMAIN"), env::var("HYDRA_DOMAIN")) {
+            (Ok(kratos_domain), Ok(hydra_domain)) => app.app_data(web::Data::new(OryClient::new(
+                kratos_domain,
+                hydra_domain,
+                reqwest::Client::new(),
+            ))),
+            _ => app,
+        }
     })
     .bind(("0.0.0.0", port))?
     .run()
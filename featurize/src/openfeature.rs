@@ -0,0 +1,605 @@
+// Featurize, the FOSS feature flagging
+// Copyright (C) 2024  Lucy Ekaterina
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//! The relay as an OpenFeature provider. SDKs evaluate through the OpenFeature
+//! Remote Evaluation Protocol (OFREP) under `/environments/<environment>`, and
+//! follow the provider's ready, configuration changed and stale events, which
+//! come from the relay's sync with upstream.
+use std::{collections::HashMap, convert::Infallible, sync::Mutex};
+
+use actix_web::{
+    get,
+    http::header::{self, EntityTag},
+    post,
+    web::{self, Bytes},
+    HttpMessage, HttpRequest, HttpResponse,
+};
+use chrono::Utc;
+use futures::{
+    channel::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    stream, StreamExt,
+};
+use sentry::{Hub, SentryFutureExt};
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use sha1::{Digest, Sha1};
+
+use crate::{
+    attributes::AttributeRegistry,
+    flags::{
+        self, Context, Definitions, Evaluation, Evaluator, FlagConfig, FlagStore, Reason, Serve,
+    },
+    stream::{event, keepalives},
+    Error,
+};
+
+/// Why a flag resolved to its value, in OpenFeature's terms.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ResolutionReason {
+    /// The flag serves one variation to everyone.
+    Static,
+    /// The flag's fallthrough, or its off variation when a prerequisite
+    /// failed.
+    Default,
+    /// A target or rule matched the context.
+    TargetingMatch,
+    /// The context was bucketed into a rollout or an experiment.
+    Split,
+    /// The flag is off.
+    Disabled,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ErrorCode {
+    /// The relay has no snapshot of the environment yet.
+    ProviderNotReady,
+    FlagNotFound,
+    ParseError,
+    InvalidContext,
+}
+
+/// A flag resolved for a context, as OFREP responds with it.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ResolutionDetails {
+    pub key: String,
+    pub value: Value,
+    pub variant: String,
+    pub reason: ResolutionReason,
+    #[serde(skip_serializing_if = "Map::is_empty")]
+    pub metadata: Map<String, Value>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ResolutionError {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    pub error_code: ErrorCode,
+    pub error_details: String,
+}
+
+impl ResolutionError {
+    fn new(key: Option<&str>, error_code: ErrorCode, error_details: String) -> Self {
+        Self {
+            key: key.map(str::to_string),
+            error_code,
+            error_details,
+        }
+    }
+
+    fn response(&self) -> HttpResponse {
+        match self.error_code {
+            ErrorCode::ProviderNotReady => HttpResponse::ServiceUnavailable().json(self),
+            ErrorCode::FlagNotFound => HttpResponse::NotFound().json(self),
+            ErrorCode::ParseError | ErrorCode::InvalidContext => {
+                HttpResponse::BadRequest().json(self)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProviderEvent {
+    Ready,
+    ConfigurationChanged {
+        flags_changed: Vec<String>,
+    },
+    /// Upstream can't be reached, flags resolve from the cached snapshot.
+    Stale {
+        message: String,
+    },
+    /// Upstream can't be reached and nothing is cached to resolve from.
+    Error {
+        message: String,
+    },
+}
+
+impl ProviderEvent {
+    fn name(&self) -> &'static str {
+        match self {
+            ProviderEvent::Ready => "PROVIDER_READY",
+            ProviderEvent::ConfigurationChanged { .. } => "PROVIDER_CONFIGURATION_CHANGED",
+            ProviderEvent::Stale { .. } => "PROVIDER_STALE",
+            ProviderEvent::Error { .. } => "PROVIDER_ERROR",
+        }
+    }
+
+    fn data(&self) -> Value {
+        match self {
+            ProviderEvent::Ready => json!({}),
+            ProviderEvent::ConfigurationChanged { flags_changed } => {
+                json!({ "flagsChanged": flags_changed })
+            }
+            ProviderEvent::Stale { message } | ProviderEvent::Error { message } => {
+                json!({ "message": message })
+            }
+        }
+    }
+}
+
+/// The provider of one environment.
+#[derive(Debug, Default)]
+struct Provider {
+    /// The definitions as last synced, to tell which flags a sync changed.
+    synced: Option<Definitions>,
+    /// The last ready, stale or error event, which new subscribers get first.
+    status: Option<ProviderEvent>,
+    subscribers: Vec<UnboundedSender<ProviderEvent>>,
+}
+
+impl Provider {
+    fn emit(&mut self, event: ProviderEvent) {
+        if !matches!(event, ProviderEvent::ConfigurationChanged { .. }) {
+            self.status = Some(event.clone());
+        }
+        self.subscribers
+            .retain(|subscriber| subscriber.unbounded_send(event.clone()).is_ok());
+    }
+}
+
+/// The providers of the environments a relay follows, told about every sync
+/// with upstream so they can emit their events.
+#[derive(Debug)]
+pub struct Providers {
+    providers: Mutex<HashMap<String, Provider>>,
+}
+
+impl Providers {
+    /// Providers for `environments`, which compare the first sync to what
+    /// `cached` holds.
+    pub fn new(cached: &Definitions, environments: &[String]) -> Self {
+        let providers = environments
+            .iter()
+            .map(|environment| {
+                let provider = Provider {
+                    synced: cached
+                        .environment(environment)
+                        .map(|_| cached.for_environment(environment)),
+                    ..Default::default()
+                };
+                (environment.clone(), provider)
+            })
+            .collect();
+        Self {
+            providers: Mutex::new(providers),
+        }
+    }
+
+    /// Upstream streamed `definitions` for `environment`.
+    pub fn synced(&self, environment: &str, definitions: &Definitions) {
+        let mut providers = self.providers.lock().unwrap();
+        let Some(provider) = providers.get_mut(environment) else {
+            return;
+        };
+        let flags_changed = match &provider.synced {
+            Some(synced) => changed_flags(synced, definitions),
+            None => Vec::new(),
+        };
+        provider.synced = Some(definitions.clone());
+        if provider.status != Some(ProviderEvent::Ready) {
+            provider.emit(ProviderEvent::Ready);
+        }
+        if !flags_changed.is_empty() {
+            provider.emit(ProviderEvent::ConfigurationChanged { flags_changed });
+        }
+    }
+
+    /// Following upstream failed for `environment`.
+    pub fn failed(&self, environment: &str, message: &str) {
+        let mut providers = self.providers.lock().unwrap();
+        let Some(provider) = providers.get_mut(environment) else {
+            return;
+        };
+        let message = message.to_string();
+        let event = match provider.synced {
+            Some(_) => ProviderEvent::Stale { message },
+            None => ProviderEvent::Error { message },
+        };
+        // Retries fail the same way, only the first failure is news.
+        if provider.status.as_ref().map(ProviderEvent::name) != Some(event.name()) {
+            provider.emit(event);
+        }
+    }
+
+    /// The events of `environment`'s provider, starting with its status.
+    pub fn subscribe(&self, environment: &str) -> Option<UnboundedReceiver<ProviderEvent>> {
+        let mut providers = self.providers.lock().unwrap();
+        let provider = providers.get_mut(environment)?;
+        let (sender, receiver) = mpsc::unbounded();
+        if let Some(status) = &provider.status {
+            let _ = sender.unbounded_send(status.clone());
+        }
+        provider.subscribers.push(sender);
+        Some(receiver)
+    }
+}
+
+/// The flags whose evaluation may differ between `before` and `after`: those
+/// that changed, were added or removed, or all of them when segments, layers
+/// or experiments changed.
+fn changed_flags(before: &Definitions, after: &Definitions) -> Vec<String> {
+    let all = before.segments != after.segments
+        || before.layers != after.layers
+        || before.experiments != after.experiments;
+    let mut changed: Vec<String> = after
+        .flags
+        .iter()
+        .filter(|flag| all || before.flag(&flag.key) != Some(flag))
+        .map(|flag| flag.key.clone())
+        .collect();
+    changed.extend(
+        before
+            .flags
+            .iter()
+            .filter(|flag| after.flag(&flag.key).is_none())
+            .map(|flag| flag.key.clone()),
+    );
+    changed
+}
+
+fn reason(config: &FlagConfig, evaluation: &Evaluation) -> ResolutionReason {
+    if evaluation.experiment.is_some() {
+        return ResolutionReason::Split;
+    }
+    match &evaluation.reason {
+        Reason::Off => ResolutionReason::Disabled,
+        Reason::PrerequisiteFailed { .. } => ResolutionReason::Default,
+        Reason::TargetMatch => ResolutionReason::TargetingMatch,
+        Reason::Holdout | Reason::NotInExperiment => ResolutionReason::Split,
+        Reason::RuleMatch { rule } => match config.rules.get(*rule).map(|rule| &rule.serve) {
+            Some(Serve::Rollout(_)) => ResolutionReason::Split,
+            _ => ResolutionReason::TargetingMatch,
+        },
+        Reason::Fallthrough => match config.fallthrough {
+            Serve::Rollout(_) => ResolutionReason::Split,
+            Serve::Variation(_)
+                if config.prerequisites.is_empty()
+                    && config.targets.is_empty()
+                    && config.rules.is_empty() =>
+            {
+                ResolutionReason::Static
+            }
+            Serve::Variation(_) => ResolutionReason::Default,
+        },
+    }
+}
+
+/// Resolves the flag `key` in `environment` for `context`.
+pub fn resolve(
+    definitions: &Definitions,
+    environment: &str,
+    key: &str,
+    context: &Context,
+) -> Result<ResolutionDetails, ResolutionError> {
+    if definitions.environment(environment).is_none() {
+        return Err(ResolutionError::new(
+            Some(key),
+            ErrorCode::ProviderNotReady,
+            format!("{} isn't synced yet", environment),
+        ));
+    }
+    let not_found = || {
+        ResolutionError::new(
+            Some(key),
+            ErrorCode::FlagNotFound,
+            format!("{} isn't configured in {}", key, environment),
+        )
+    };
+    let flag = definitions.flag(key).ok_or_else(not_found)?;
+    let config = flag.environments.get(environment).ok_or_else(not_found)?;
+    let evaluation = Evaluator::new(definitions, environment)
+        .evaluate(flag, context)
+        .ok_or_else(not_found)?;
+    let mut metadata = Map::new();
+    if let Some(experiment) = &evaluation.experiment {
+        metadata.insert("experiment".to_string(), json!(experiment));
+    }
+    if evaluation.track_exposure {
+        metadata.insert("trackExposure".to_string(), json!(true));
+    }
+    Ok(ResolutionDetails {
+        key: key.to_string(),
+        value: flag.variations[evaluation.variation].value.clone(),
+        variant: flags::flagd::variant_names(flag).swap_remove(evaluation.variation),
+        reason: reason(config, &evaluation),
+        metadata,
+    })
+}
+
+/// The context of an OFREP request body, with its `targetingKey` as the
+/// `key` featurize identifies contexts by.
+fn context(key: Option<&str>, body: &[u8]) -> Result<Context, ResolutionError> {
+    let invalid = |details: String| ResolutionError::new(key, ErrorCode::InvalidContext, details);
+    if body.is_empty() {
+        return Ok(Context::new());
+    }
+    let request: Value = serde_json::from_slice(body)
+        .map_err(|e| ResolutionError::new(key, ErrorCode::ParseError, e.to_string()))?;
+    let mut context = match request.get("context") {
+        None | Some(Value::Null) => Context::new(),
+        Some(Value::Object(context)) => context.clone(),
+        Some(_) => return Err(invalid("the context has to be an object".to_string())),
+    };
+    match context.remove("targetingKey") {
+        None => {}
+        Some(Value::String(targeting_key)) => {
+            context.insert("key".to_string(), Value::String(targeting_key));
+        }
+        Some(_) => return Err(invalid("targetingKey has to be a string".to_string())),
+    }
+    Ok(context)
+}
+
+/// Resolves one flag, OFREP's single flag evaluation.
+#[tracing::instrument]
+#[post("/environments/{environment}/ofrep/v1/evaluate/flags/{key}")]
+pub async fn evaluate_flag_route(
+    flags: web::Data<FlagStore>,
+    attributes: web::Data<AttributeRegistry>,
+    path: web::Path<(String, String)>,
+    body: Bytes,
+) -> Result<HttpResponse, Error> {
+    let hub = Hub::current();
+    evaluate_flag_handler(flags, attributes, path, body)
+        .bind_hub(hub)
+        .await
+}
+
+#[tracing::instrument]
+pub async fn evaluate_flag_handler(
+    flags: web::Data<FlagStore>,
+    attributes: web::Data<AttributeRegistry>,
+    path: web::Path<(String, String)>,
+    body: Bytes,
+) -> Result<HttpResponse, Error> {
+    let (environment, key) = path.into_inner();
+    let context = match context(Some(&key), &body) {
+        Ok(context) => context,
+        Err(e) => return Ok(e.response()),
+    };
+    attributes.record(&environment, &context, Utc::now());
+    Ok(
+        match resolve(&flags.definitions(), &environment, &key, &context) {
+            Ok(details) => HttpResponse::Ok().json(details),
+            Err(e) => e.response(),
+        },
+    )
+}
+
+/// Resolves every flag configured in the environment, OFREP's bulk
+/// evaluation. The ETag covers the response, so polling SDKs get a 304 while
+/// nothing changed for their context.
+#[tracing::instrument]
+#[post("/environments/{environment}/ofrep/v1/evaluate/flags")]
+pub async fn evaluate_flags_route(
+    flags: web::Data<FlagStore>,
+    attributes: web::Data<AttributeRegistry>,
+    request: HttpRequest,
+    environment: web::Path<String>,
+    body: Bytes,
+) -> Result<HttpResponse, Error> {
+    let hub = Hub::current();
+    evaluate_flags_handler(flags, attributes, request, environment, body)
+        .bind_hub(hub)
+        .await
+}
+
+#[tracing::instrument]
+pub async fn evaluate_flags_handler(
+    flags: web::Data<FlagStore>,
+    attributes: web::Data<AttributeRegistry>,
+    request: HttpRequest,
+    environment: web::Path<String>,
+    body: Bytes,
+) -> Result<HttpResponse, Error> {
+    let context = match context(None, &body) {
+        Ok(context) => context,
+        Err(e) => return Ok(e.response()),
+    };
+    let definitions = flags.definitions();
+    if definitions.environment(&environment).is_none() {
+        let details = format!("{} isn't synced yet", environment);
+        return Ok(ResolutionError::new(None, ErrorCode::ProviderNotReady, details).response());
+    }
+    attributes.record(&environment, &context, Utc::now());
+    let resolved: Vec<ResolutionDetails> = definitions
+        .flags
+        .iter()
+        .filter_map(|flag| resolve(&definitions, &environment, &flag.key, &context).ok())
+        .collect();
+    let body = serde_json::to_vec(&json!({ "flags": resolved }))?;
+    let mut prefix = [0; 8];
+    prefix.copy_from_slice(&Sha1::digest(&body)[..8]);
+    let tag = EntityTag::new_strong(format!("{:016x}", u64::from_be_bytes(prefix)));
+    if let Some(header::IfNoneMatch::Items(tags)) = request.get_header::<header::IfNoneMatch>() {
+        if tags.iter().any(|t| t.strong_eq(&tag)) {
+            return Ok(HttpResponse::NotModified()
+                .insert_header(header::ETag(tag))
+                .finish());
+        }
+    }
+    Ok(HttpResponse::Ok()
+        .insert_header(header::ETag(tag))
+        .content_type("application/json")
+        .body(body))
+}
+
+/// Streams the provider's events as server-sent events named after them,
+/// like `PROVIDER_CONFIGURATION_CHANGED` with `{"flagsChanged": [...]}`.
+#[tracing::instrument]
+#[get("/environments/{environment}/ofrep/v1/events")]
+pub async fn events_route(
+    providers: web::Data<Providers>,
+    environment: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let hub = Hub::current();
+    events_handler(providers, environment).bind_hub(hub).await
+}
+
+#[tracing::instrument]
+pub async fn events_handler(
+    providers: web::Data<Providers>,
+    environment: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let events = providers.subscribe(&environment).ok_or(Error::NotFound)?;
+    let events = stream::select(
+        events.map(|e| event(e.name(), &e.data().to_string())),
+        keepalives().map(|()| ": keepalive\n\n".to_string()),
+    )
+    .map(|events| Ok::<_, Infallible>(Bytes::from(events)));
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(events))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn definitions(yaml: &str) -> Definitions {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    const CHECKOUT: &str = r#"
+environments:
+  - key: production
+flags:
+  - key: checkout
+    variations: [{value: false, name: old}, {value: true, name: new}]
+    environments:
+      production:
+        on: true
+        off_variation: 0
+        fallthrough: {rollout: {variations: [{variation: 0, weight: 0}, {variation: 1, weight: 100}]}}
+        targets: [{variation: 0, values: [bob]}]
+  - key: banner
+    variations: [{value: "hi"}, {value: "hello"}]
+    environments:
+      production: {on: true, off_variation: 0, fallthrough: {variation: 1}}
+  - key: search
+    variations: [{value: 1}, {value: 2}]
+    environments:
+      production: {on: false, off_variation: 0, fallthrough: {variation: 1}}
+"#;
+
+    #[test]
+    fn maps_reasons_variants_and_errors() {
+        let definitions = definitions(CHECKOUT);
+        let resolve = |key: &str, body: &str| {
+            let context = context(Some(key), body.as_bytes())?;
+            resolve(&definitions, "production", key, &context)
+        };
+        let alice = r#"{"context": {"targetingKey": "alice"}}"#;
+
+        let checkout = resolve("checkout", alice).unwrap();
+        assert_eq!(
+            (checkout.value, checkout.variant.as_str(), checkout.reason),
+            (json!(true), "new", ResolutionReason::Split)
+        );
+        let checkout = resolve("checkout", r#"{"context": {"targetingKey": "bob"}}"#).unwrap();
+        assert_eq!(
+            (checkout.variant.as_str(), checkout.reason),
+            ("old", ResolutionReason::TargetingMatch)
+        );
+        let banner = resolve("banner", "").unwrap();
+        assert_eq!(
+            (banner.variant.as_str(), banner.reason),
+            ("variation-1", ResolutionReason::Static)
+        );
+        assert_eq!(
+            resolve("search", alice).unwrap().reason,
+            ResolutionReason::Disabled
+        );
+
+        let error_code = |key: &str, body: &str| resolve(key, body).unwrap_err().error_code;
+        assert_eq!(error_code("missing", alice), ErrorCode::FlagNotFound);
+        assert_eq!(error_code("checkout", "{"), ErrorCode::ParseError);
+        assert_eq!(
+            error_code("checkout", r#"{"context": {"targetingKey": 1}}"#),
+            ErrorCode::InvalidContext
+        );
+        let context = Context::new();
+        let staging = super::resolve(&definitions, "staging", "checkout", &context);
+        assert_eq!(staging.unwrap_err().error_code, ErrorCode::ProviderNotReady);
+    }
+
+    #[test]
+    fn emits_ready_changed_and_stale_events() {
+        let before = definitions(CHECKOUT);
+        let providers = Providers::new(&before, &["production".to_string()]);
+        let mut events = providers.subscribe("production").unwrap();
+        let mut after = before.clone();
+        after.flags[1]
+            .environments
+            .get_mut("production")
+            .unwrap()
+            .on = false;
+
+        providers.failed("production", "connection refused");
+        providers.failed("production", "connection refused");
+        providers.synced("production", &after);
+        providers.synced("production", &after);
+        let mut emitted = Vec::new();
+        while let Ok(Some(event)) = events.try_next() {
+            emitted.push(event);
+        }
+        assert_eq!(
+            emitted,
+            [
+                ProviderEvent::Stale {
+                    message: "connection refused".to_string()
+                },
+                ProviderEvent::Ready,
+                ProviderEvent::ConfigurationChanged {
+                    flags_changed: vec!["banner".to_string()]
+                },
+            ]
+        );
+        // Late subscribers learn the status first.
+        let mut late = providers.subscribe("production").unwrap();
+        assert_eq!(late.try_next().unwrap(), Some(ProviderEvent::Ready));
+
+        let uncached = Providers::new(&Definitions::default(), &["production".to_string()]);
+        uncached.failed("production", "connection refused");
+        let mut events = uncached.subscribe("production").unwrap();
+        assert!(matches!(
+            events.try_next().unwrap(),
+            Some(ProviderEvent::Error { .. })
+        ));
+    }
+}
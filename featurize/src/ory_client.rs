@@ -27,6 +27,9 @@ use serde::{Deserialize, Serialize};
 
 use crate::Error;
 
+/// The header Kratos reads session tokens of API flows from.
+pub const SESSION_TOKEN: &str = "X-Session-Token";
+
 #[derive(Deserialize)]
 pub struct LogoutUrlResponse {
     pub logout_url: String,
@@ -206,6 +209,17 @@ impl<'c, R: OryRequestType<NeedsCookie = Yes>> OryRequest<'c, R, NoCookie> {
             req: self.req.header("Cookie", cookie),
         }
     }
+
+    /// Authenticates with a session token from an API flow instead of a
+    /// cookie.
+    pub fn session_token(self, token: &'c [u8]) -> OryRequest<R, WithCookie> {
+        OryRequest {
+            client: self.client,
+            request_type: self.request_type,
+            _state: WithCookie,
+            req: self.req.header(SESSION_TOKEN, token),
+        }
+    }
 }
 
 impl<'c, R: OryRequestType<NeedsCookie = Yes> + Debug> OryRequest<'c, R, WithCookie> {
@@ -329,10 +343,40 @@ impl Future for SessionFut {
 }
 
 pub struct UserSession {
+    /// Empty when signed in with a session token.
     pub cookie: Vec<u8>,
     pub session: Session,
 }
 
+impl UserSession {
+    /// The email of the signed in identity, or its id without one.
+    pub fn name(&self) -> String {
+        let Some(identity) = &self.session.identity else {
+            return self.session.id.clone();
+        };
+        match identity.traits.get("email") {
+            Some(serde_json::Value::String(email)) => email.clone(),
+            _ => identity.id.clone(),
+        }
+    }
+
+    /// The `roles` trait of the signed in identity.
+    pub fn roles(&self) -> Vec<String> {
+        self.session
+            .identity
+            .as_ref()
+            .and_then(|i| i.traits.get("roles"))
+            .and_then(|r| r.as_array())
+            .map(|roles| {
+                roles
+                    .iter()
+                    .filter_map(|r| r.as_str().map(str::to_string))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
+
 impl FromRequest for UserSession {
     type Error = Error;
 
@@ -340,10 +384,11 @@ impl FromRequest for UserSession {
 
     fn from_request(req: &HttpRequest, payload: &mut actix_web::dev::Payload) -> Self::Future {
         let ory = req.app_data::<Data<OryClient>>().cloned();
-        let cookie = req.headers().get(COOKIE).map(|c| c.as_bytes().to_vec());
+        let header = |name| req.headers().get(name).map(|c| c.as_bytes().to_vec());
         UserSessionFut {
             ory,
-            cookie,
+            cookie: header(COOKIE.as_str()),
+            token: header(SESSION_TOKEN),
             fut: None,
         }
     }
@@ -352,6 +397,8 @@ impl FromRequest for UserSession {
 pub struct UserSessionFut {
     ory: Option<web::Data<OryClient>>,
     cookie: Option<Vec<u8>>,
+    /// Sent by clients outside the browser, like `featurize apply`.
+    token: Option<Vec<u8>>,
     fut: Option<Pin<Box<dyn Future<Output = Result<KratosResponse<WhoAmIRequest>, Error>>>>>,
 }
 
@@ -366,8 +413,16 @@ impl Future for UserSessionFut {
             Some(f) => f,
             None => {
                 let ory = self.ory.take().ok_or(Error::NoSession)?;
-                let cookie = self.cookie.clone().ok_or(Error::NoSession)?;
-                let f = async move { ory.new_request(WhoAmIRequest).cookie(&cookie).send().await };
+                let (cookie, token) = (self.cookie.clone(), self.token.clone());
+                let f = async move {
+                    let request = ory.new_request(WhoAmIRequest);
+                    let request = match (&cookie, &token) {
+                        (Some(cookie), _) => request.cookie(cookie),
+                        (None, Some(token)) => request.session_token(token),
+                        (None, None) => return Err(Error::NoSession),
+                    };
+                    request.send().await
+                };
                 Box::pin(f)
             }
         };
@@ -376,7 +431,7 @@ impl Future for UserSessionFut {
             Poll::Ready(r) => Poll::Ready(r.and_then(|s| {
                 if s.status_code == StatusCode::OK {
                     Ok(UserSession {
-                        cookie: self.cookie.take().unwrap(),
+                        cookie: self.cookie.take().unwrap_or_default(),
                         session: s.body,
                     })
                 } else {
@@ -0,0 +1,274 @@
+// Featurize, the FOSS feature flagging
+// Copyright (C) 2024  Lucy Ekaterina
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//! `featurize plan` and `featurize apply` make the flags of a featurize
+//! instance match the ones in a project's files: flags missing from the
+//! instance are created, differing ones replaced and the rest deleted. Both
+//! exit with 0 when there is nothing left to do, `apply` after making every
+//! change at once, and with 1 on errors, so they can run in CI.
+use std::{
+    collections::{BTreeSet, HashSet},
+    path::Path,
+    process,
+};
+
+use color_eyre::eyre::eyre;
+use reqwest::StatusCode;
+
+use crate::{
+    cli::{signed_in, SESSION_TOKEN_VAR},
+    diff,
+    edit::{ApplyRequest, Definition, DefinitionChange, DefinitionList},
+    flags::{read_dir_unchecked, Flag},
+};
+
+const PLAN_USAGE: &str = "usage: featurize plan <featurize url> <directory>";
+
+const APPLY_USAGE: &str = "usage: featurize apply <featurize url> <directory> [comment]";
+
+/// Exit code of `plan` when there are changes to apply.
+const CHANGES_PLANNED: i32 = 2;
+
+/// Exit code when the server refused the changes because the flags changed
+/// since they were read, or an environment requires approvals.
+const CONFLICT: i32 = 3;
+
+/// Prints the changes `apply` would make and checks them with the server.
+pub fn plan(args: &[String]) -> color_eyre::Result<()> {
+    let [url, dir] = args else {
+        return Err(eyre!(PLAN_USAGE));
+    };
+    if run(url, Path::new(dir), String::new(), true)? {
+        process::exit(CHANGES_PLANNED);
+    }
+    Ok(())
+}
+
+/// Makes the changes `plan` prints, all of them or none.
+pub fn apply(args: &[String]) -> color_eyre::Result<()> {
+    let (url, dir, comment) = match args {
+        [url, dir] => (url, dir, String::new()),
+        [url, dir, comment] => (url, dir, comment.clone()),
+        _ => return Err(eyre!(APPLY_USAGE)),
+    };
+    run(url, Path::new(dir), comment, false)?;
+    Ok(())
+}
+
+/// Whether there were changes, exiting with [`CONFLICT`] when the server
+/// refuses them.
+fn run(url: &str, dir: &Path, comment: String, dry_run: bool) -> color_eyre::Result<bool> {
+    let desired = read_dir_unchecked(dir)?;
+    if !desired.environments.is_empty() || !desired.segments.is_empty() {
+        eprintln!("only flags are planned, environments and segments are left as they are");
+    }
+    let mut keys = HashSet::new();
+    if let Some(flag) = desired.flags.iter().find(|f| !keys.insert(&f.key)) {
+        return Err(eyre!("flag \"{}\" is defined more than once", flag.key));
+    }
+
+    let mut endpoint = reqwest::Url::parse(url)?;
+    endpoint
+        .path_segments_mut()
+        .map_err(|_| eyre!("{} is not a featurize url", url))?
+        .pop_if_empty()
+        .extend(["flags", "definitions"]);
+
+    let conflict = actix_web::rt::System::new().block_on(async {
+        let client = reqwest::Client::new();
+        let current: DefinitionList = client
+            .get(endpoint.clone())
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let changes = changes(&current.flags, desired.flags);
+        if changes.is_empty() {
+            println!("no changes");
+            return Ok::<_, color_eyre::Report>(None);
+        }
+        print!("{}", describe(&current.flags, &changes));
+
+        let count = changes.len();
+        let request = signed_in(client.post(endpoint)).json(&ApplyRequest {
+            comment,
+            dry_run,
+            changes,
+        });
+        let response = request.send().await?;
+        match response.status() {
+            status if status.is_success() => {
+                if !dry_run {
+                    println!("applied {} changes", count);
+                }
+                Ok(Some(false))
+            }
+            StatusCode::CONFLICT => {
+                eprintln!("conflict: {}", response.text().await?);
+                Ok(Some(true))
+            }
+            StatusCode::UNAUTHORIZED => Err(eyre!("set {} to sign in", SESSION_TOKEN_VAR)),
+            status => Err(eyre!("{}: {}", status, response.text().await?)),
+        }
+    })?;
+    match conflict {
+        Some(true) => process::exit(CONFLICT),
+        Some(false) => Ok(true),
+        None => Ok(false),
+    }
+}
+
+/// What turns `current` into `desired`: creates and updates in the order of
+/// `desired`, then deletes.
+fn changes(current: &[Definition], desired: Vec<Flag>) -> Vec<DefinitionChange> {
+    let find = |key: &str| current.iter().find(|d| d.flag.key == key);
+    let desired_keys: HashSet<String> = desired.iter().map(|f| f.key.clone()).collect();
+    let mut changes: Vec<DefinitionChange> = desired
+        .into_iter()
+        .filter_map(|flag| match find(&flag.key) {
+            None => Some(DefinitionChange::Create { flag }),
+            Some(current) if current.flag == flag => None,
+            Some(current) => Some(DefinitionChange::Update {
+                version: current.version.clone(),
+                flag,
+            }),
+        })
+        .collect();
+    changes.extend(
+        current
+            .iter()
+            .filter(|d| !desired_keys.contains(&d.flag.key))
+            .map(|d| DefinitionChange::Delete {
+                key: d.flag.key.clone(),
+                version: d.version.clone(),
+            }),
+    );
+    changes
+}
+
+/// Every flag and environment `changes` touch, marked `+` when created, `~`
+/// when changed and `-` when deleted, followed by the lines that change in
+/// updated flags.
+fn describe(current: &[Definition], changes: &[DefinitionChange]) -> String {
+    let find = |key: &str| current.iter().find(|d| d.flag.key == key).map(|d| &d.flag);
+    let yaml = |flag: Option<&Flag>| {
+        flag.and_then(|f| serde_yaml::to_string(f).ok())
+            .unwrap_or_default()
+    };
+    let mut out = String::new();
+    let (mut created, mut updated, mut deleted) = (0, 0, 0);
+    for change in changes {
+        let (mark, key, before, after) = match change {
+            DefinitionChange::Create { flag } => {
+                created += 1;
+                ('+', &flag.key, None, Some(flag))
+            }
+            DefinitionChange::Update { flag, .. } => {
+                updated += 1;
+                ('~', &flag.key, find(&flag.key), Some(flag))
+            }
+            DefinitionChange::Delete { key, .. } => {
+                deleted += 1;
+                ('-', key, find(key), None)
+            }
+        };
+        out += &format!("{} flag \"{}\"\n", mark, key);
+
+        let environments = |flag: Option<&Flag>| -> BTreeSet<String> {
+            flag.map(|f| f.environments.keys().cloned().collect())
+                .unwrap_or_default()
+        };
+        for environment in environments(before).union(&environments(after)) {
+            let config = |flag: Option<&'_ Flag>| flag?.environments.get(environment).cloned();
+            let mark = match (config(before), config(after)) {
+                (None, Some(_)) => '+',
+                (Some(_), None) => '-',
+                (Some(a), Some(b)) if a != b => '~',
+                _ => continue,
+            };
+            out += &format!("    {} {}\n", mark, environment);
+        }
+
+        if let (Some(before), Some(after)) = (before, after) {
+            for line in diff::lines(&yaml(Some(before)), &yaml(Some(after))) {
+                match line.tag {
+                    "insert" => out += &format!("      + {}\n", line.text),
+                    "delete" => out += &format!("      - {}\n", line.text),
+                    _ => {}
+                }
+            }
+        }
+    }
+    out += &format!(
+        "{} to create, {} to update, {} to delete\n",
+        created, updated, deleted
+    );
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flag(yaml: &str) -> Flag {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn plans_creates_updates_and_deletes() {
+        let checkout = flag(
+            r#"
+key: checkout
+variations: [{value: false}, {value: true}]
+environments:
+  staging: {on: true, off_variation: 0, fallthrough: {variation: 1}}
+"#,
+        );
+        let banner = flag("{key: banner, variations: [{value: false}]}");
+        let current: Vec<Definition> = [&checkout, &banner]
+            .into_iter()
+            .map(|flag| Definition {
+                version: flag.version(),
+                flag: flag.clone(),
+            })
+            .collect();
+        let mut served = checkout.clone();
+        served.environments.insert(
+            "production".to_string(),
+            served.environments["staging"].clone(),
+        );
+        let search = flag("{key: search, variations: [{value: false}]}");
+
+        let planned = changes(&current, vec![served.clone(), search.clone()]);
+        assert!(matches!(
+            &planned[..],
+            [
+                DefinitionChange::Update { version, flag },
+                DefinitionChange::Create { flag: created },
+                DefinitionChange::Delete { key, .. },
+            ] if *version == checkout.version()
+                && *flag == served
+                && *created == search
+                && key == "banner"
+        ));
+        let described = describe(&current, &planned);
+        assert!(described.starts_with("~ flag \"checkout\"\n    + production\n"));
+        assert!(described.ends_with("1 to create, 1 to update, 1 to delete\n"));
+
+        let unchanged = vec![checkout.clone(), banner.clone()];
+        assert!(changes(&current, unchanged).is_empty());
+    }
+}
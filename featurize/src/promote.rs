@@ -0,0 +1,279 @@
+// Featurize, the FOSS feature flagging
+// Copyright (C) 2024  Lucy Ekaterina
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use std::collections::HashMap;
+
+use actix_web::{get, http::StatusCode, post, web, HttpResponse};
+use chrono::Utc;
+use sentry::{Hub, SentryFutureExt};
+
+use crate::{
+    audit::{AuditEntry, AuditLog},
+    change_requests::{self, Change, ChangeRequests},
+    diff,
+    flags::{self, Definitions, FlagStore, Lifecycle, Promoted, Promotion, PromotionError},
+    ory_client::UserSession,
+    renderer::Renderer,
+    webhooks::Webhooks,
+    Error,
+};
+
+/// Form fields of the flags to promote are named `flag.<key>`.
+const FLAG_FIELD: &str = "flag.";
+/// The versions of the flags the preview was made from are in
+/// `version.<key>` fields.
+const VERSION_FIELD: &str = "version.";
+
+fn promotion(form: &HashMap<String, String>) -> Promotion {
+    let mut flags: Vec<String> = form
+        .keys()
+        .filter_map(|field| field.strip_prefix(FLAG_FIELD))
+        .map(str::to_string)
+        .collect();
+    flags.sort();
+    Promotion {
+        from: form.get("from").cloned().unwrap_or_default(),
+        to: form.get("to").cloned().unwrap_or_default(),
+        flags,
+        keep_targets: form.contains_key("keep_targets"),
+    }
+}
+
+/// Fails unless every promoted flag is still at the version it was previewed
+/// at.
+fn check_versions(
+    definitions: &Definitions,
+    promotion: &Promotion,
+    form: &HashMap<String, String>,
+) -> Result<(), flags::Error> {
+    promotion.flags.iter().try_for_each(|key| {
+        let version = form
+            .get(&format!("{}{}", VERSION_FIELD, key))
+            .map_or("", String::as_str);
+        flags::check_version(definitions, key, version)
+    })
+}
+
+#[tracing::instrument]
+#[get("/promote")]
+pub async fn page_route(
+    renderer: web::Data<Renderer>,
+    flags: web::Data<FlagStore>,
+) -> Result<HttpResponse, Error> {
+    let hub = Hub::current();
+    page_handler(renderer, flags, None).bind_hub(hub).await
+}
+
+#[tracing::instrument(skip(form))]
+#[post("/promote")]
+pub async fn preview_route(
+    renderer: web::Data<Renderer>,
+    flags: web::Data<FlagStore>,
+    form: web::Form<HashMap<String, String>>,
+) -> Result<HttpResponse, Error> {
+    let hub = Hub::current();
+    page_handler(renderer, flags, Some(form.into_inner()))
+        .bind_hub(hub)
+        .await
+}
+
+#[tracing::instrument(skip(form))]
+pub async fn page_handler(
+    renderer: web::Data<Renderer>,
+    flags: web::Data<FlagStore>,
+    form: Option<HashMap<String, String>>,
+) -> Result<HttpResponse, Error> {
+    render_page(&renderer, &flags, form, None)
+}
+
+/// Shows the promotion form, and with a submitted form the changes it would
+/// make along with anything that would stop them from being applied. With
+/// `stale`, the preview was applied after the flags changed and is shown
+/// again with the current changes.
+fn render_page(
+    renderer: &Renderer,
+    flags: &FlagStore,
+    form: Option<HashMap<String, String>>,
+    stale: Option<flags::Error>,
+) -> Result<HttpResponse, Error> {
+    let loaded = flags.loaded();
+    let promotable: Vec<_> = loaded
+        .flags
+        .iter()
+        .filter(|f| f.lifecycle != Lifecycle::Archived)
+        .collect();
+    let render = renderer
+        .render("promote.html")
+        .var("environments", &loaded.environments)
+        .var("flags", &promotable);
+    let Some(form) = form else {
+        return Ok(render.ok().finish()?);
+    };
+
+    let promotion = promotion(&form);
+    let versions: HashMap<&str, String> = promotion
+        .flags
+        .iter()
+        .filter_map(|key| Some((key.as_str(), loaded.flag(key)?.version())))
+        .collect();
+    let render = render
+        .var("versions", &versions)
+        .var("from", &promotion.from)
+        .var("to", &promotion.to)
+        .var("selected", &promotion.flags)
+        .var("keep_targets", &promotion.keep_targets)
+        .var(
+            "approvals",
+            &loaded
+                .environment(&promotion.to)
+                .and_then(|e| e.approvals.as_ref()),
+        );
+    let mut after = (*loaded).clone();
+    if let Err(error) = flags::promote(&mut after, &promotion) {
+        return Ok(render.var("error", &error.to_string()).ok().finish()?);
+    }
+    let diffs: Vec<_> = flags::config_changes(&loaded, &after, &promotion.to)
+        .iter()
+        .map(diff::config)
+        .collect();
+    let render = render.var("diffs", &diffs);
+    if let Some(stale) = stale {
+        let error = format!("{}, check the changes again", stale);
+        return Ok(render
+            .var("stale", &error)
+            .status(StatusCode::CONFLICT)
+            .finish()?);
+    }
+    Ok(
+        match flags.check_update(|definitions| flags::promote(definitions, &promotion)) {
+            Ok(_) => render.ok().finish()?,
+            Err(error) => render.var("error", &error.to_string()).ok().finish()?,
+        },
+    )
+}
+
+#[tracing::instrument(skip(session, form))]
+#[post("/promote/apply")]
+pub async fn apply_route(
+    renderer: web::Data<Renderer>,
+    flags: web::Data<FlagStore>,
+    audit: web::Data<AuditLog>,
+    webhooks: web::Data<Webhooks>,
+    change_requests: web::Data<ChangeRequests>,
+    session: Result<UserSession, Error>,
+    form: web::Form<HashMap<String, String>>,
+) -> Result<HttpResponse, Error> {
+    let hub = Hub::current();
+    apply_handler(
+        renderer,
+        flags,
+        audit,
+        webhooks,
+        change_requests,
+        session,
+        form.into_inner(),
+    )
+    .bind_hub(hub)
+    .await
+}
+
+/// Writes the promotion to the definition files, all of it or nothing. For
+/// environments that require approvals it becomes a change request instead.
+#[tracing::instrument(skip(session, form))]
+pub async fn apply_handler(
+    renderer: web::Data<Renderer>,
+    flags: web::Data<FlagStore>,
+    audit: web::Data<AuditLog>,
+    webhooks: web::Data<Webhooks>,
+    change_requests: web::Data<ChangeRequests>,
+    session: Result<UserSession, Error>,
+    form: HashMap<String, String>,
+) -> Result<HttpResponse, Error> {
+    let actor = session?.name();
+    let promotion = promotion(&form);
+    let protected = flags
+        .loaded()
+        .environment(&promotion.to)
+        .is_some_and(|e| e.approvals.is_some());
+    if protected {
+        if let Err(stale) = check_versions(&flags.loaded(), &promotion, &form) {
+            return render_page(&renderer, &flags, Some(form), Some(stale));
+        }
+        let apply_at =
+            change_requests::parse_apply_at(form.get("apply_at").map_or("", String::as_str))?;
+        let comment = form.get("comment").map_or("", |c| c.trim()).to_string();
+        let id = change_requests.propose(
+            actor,
+            comment,
+            Change::Promote(promotion),
+            apply_at,
+            Utc::now(),
+        )?;
+        return Ok(HttpResponse::SeeOther()
+            .append_header(("Location", format!("/change-requests/{}", id)))
+            .finish());
+    }
+
+    let promoted = flags.update(|definitions| {
+        check_versions(definitions, &promotion, &form)?;
+        flags::promote(definitions, &promotion)
+    });
+    let promoted = match promoted {
+        Ok(promoted) => promoted,
+        Err(PromotionError::Flags(stale @ flags::Error::Stale(_))) => {
+            return render_page(&renderer, &flags, Some(form), Some(stale));
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    let segments: Vec<&str> = promoted
+        .iter()
+        .filter_map(|subject| match subject {
+            Promoted::Segment(key) => Some(key.as_str()),
+            Promoted::Flag(_) => None,
+        })
+        .collect();
+    let detail = if segments.is_empty() {
+        format!("promoted from {}", promotion.from)
+    } else {
+        format!(
+            "promoted from {} along with segments {}",
+            promotion.from,
+            segments.join(", ")
+        )
+    };
+    let definitions = flags.definitions();
+    let now = Utc::now();
+    for subject in &promoted {
+        let Promoted::Flag(key) = subject else {
+            continue;
+        };
+        let entry = AuditEntry {
+            at: now,
+            actor: actor.clone(),
+            environment: promotion.to.clone(),
+            flag: key.clone(),
+            action: "promote".to_string(),
+            detail: detail.clone(),
+        };
+        if let Some(flag) = definitions.flag(key) {
+            webhooks.notify(&flag.webhooks, &entry);
+        }
+        audit.record(entry);
+    }
+    Ok(HttpResponse::SeeOther()
+        .append_header(("Location", format!("/environments/{}", promotion.to)))
+        .finish())
+}
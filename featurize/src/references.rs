@@ -0,0 +1,172 @@
+// Featurize, the FOSS feature flagging
+// Copyright (C) 2024  Lucy Ekaterina
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use std::{collections::BTreeMap, fs, io, path::PathBuf, sync::RwLock};
+
+use actix_web::{get, post, web, HttpResponse};
+use chrono::{DateTime, Utc};
+use sentry::{Hub, SentryFutureExt};
+use serde::{Deserialize, Serialize};
+
+use crate::{flags::FlagStore, ory_client::UserSession, Error};
+
+/// A line of code that uses a flag.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Reference {
+    pub flag: String,
+    /// Relative to the root of the repository.
+    pub path: String,
+    /// Starting at 1.
+    pub line: usize,
+    pub text: String,
+    /// Set when the flag is referred to by an alias rather than its key.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alias: Option<String>,
+}
+
+/// Every reference found by one scan of a repository, uploaded by
+/// `featurize scan`.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Upload {
+    pub references: Vec<Reference>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RepositoryScan {
+    pub scanned_at: DateTime<Utc>,
+    pub references: Vec<Reference>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FlagReferences {
+    pub repository: String,
+    pub scanned_at: DateTime<Utc>,
+    pub references: Vec<Reference>,
+}
+
+/// The latest scan of each repository.
+#[derive(Debug, Default)]
+pub struct CodeReferences {
+    /// Scans are saved here as JSON when set.
+    file: Option<PathBuf>,
+    scans: RwLock<BTreeMap<String, RepositoryScan>>,
+}
+
+impl CodeReferences {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keeps scans in the JSON file at `path`, reading the ones already in it.
+    pub fn open<P: Into<PathBuf>>(path: P) -> io::Result<Self> {
+        let path = path.into();
+        let scans = match fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Self {
+            file: Some(path),
+            scans: RwLock::new(scans),
+        })
+    }
+
+    /// Replaces the references of `repository`, so references removed from
+    /// the code since its last scan disappear.
+    pub fn upload(&self, repository: &str, scan: RepositoryScan) {
+        let mut scans = self.scans.write().unwrap();
+        scans.insert(repository.to_string(), scan);
+        if let Some(path) = &self.file {
+            let saved = serde_json::to_string(&*scans)
+                .map_err(io::Error::from)
+                .and_then(|content| fs::write(path, content));
+            if let Err(e) = saved {
+                tracing::error!("could not write code references {}: {}", path.display(), e);
+            }
+        }
+    }
+
+    /// The references to `flag` in every scanned repository, including
+    /// repositories without any so callers can tell "no references remain"
+    /// from "never scanned".
+    pub fn for_flag(&self, flag: &str) -> Vec<FlagReferences> {
+        let scans = self.scans.read().unwrap();
+        scans
+            .iter()
+            .map(|(repository, scan)| FlagReferences {
+                repository: repository.clone(),
+                scanned_at: scan.scanned_at,
+                references: scan
+                    .references
+                    .iter()
+                    .filter(|r| r.flag == flag)
+                    .cloned()
+                    .collect(),
+            })
+            .collect()
+    }
+}
+
+#[tracing::instrument(skip(session, upload))]
+#[post("/references/{repository}")]
+pub async fn upload_route(
+    references: web::Data<CodeReferences>,
+    session: Result<UserSession, Error>,
+    repository: web::Path<String>,
+    upload: web::Json<Upload>,
+) -> Result<HttpResponse, Error> {
+    let hub = Hub::current();
+    upload_handler(references, session, repository, upload)
+        .bind_hub(hub)
+        .await
+}
+
+#[tracing::instrument(skip(session, upload))]
+pub async fn upload_handler(
+    references: web::Data<CodeReferences>,
+    session: Result<UserSession, Error>,
+    repository: web::Path<String>,
+    upload: web::Json<Upload>,
+) -> Result<HttpResponse, Error> {
+    session?;
+    let scan = RepositoryScan {
+        scanned_at: Utc::now(),
+        references: upload.into_inner().references,
+    };
+    references.upload(&repository, scan);
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[tracing::instrument]
+#[get("/flags/{key}/references")]
+pub async fn flag_route(
+    flags: web::Data<FlagStore>,
+    references: web::Data<CodeReferences>,
+    key: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let hub = Hub::current();
+    flag_handler(flags, references, key).bind_hub(hub).await
+}
+
+#[tracing::instrument]
+pub async fn flag_handler(
+    flags: web::Data<FlagStore>,
+    references: web::Data<CodeReferences>,
+    key: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let definitions = flags.definitions();
+    let flag = definitions.flag(&key).ok_or(Error::NotFound)?;
+    Ok(HttpResponse::Ok().json(references.for_flag(&flag.key)))
+}
@@ -0,0 +1,423 @@
+// Featurize, the FOSS feature flagging
+// Copyright (C) 2024  Lucy Ekaterina
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//! `featurize relay` serves the flags of an upstream featurize to SDKs from a
+//! copy on disk, so many instances, or a network that can't reach featurize,
+//! only need one connection to it. The relay follows the stream of each
+//! environment it relays, and the copy keeps being served while upstream is
+//! unreachable.
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use actix_web::{
+    rt::time::{sleep, timeout},
+    web, App, HttpServer,
+};
+use color_eyre::eyre::eyre;
+use futures::{channel::oneshot, future::join_all};
+use reqwest::header::ACCEPT;
+use tracing_actix_web::TracingLogger;
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+
+use crate::{
+    attributes::AttributeRegistry,
+    evaluate, export, flagd,
+    flags::{Definitions, FlagStore, Freeze},
+    openfeature::{self, Providers},
+    stream,
+};
+
+const USAGE: &str = "usage: featurize relay <featurize url> <cache directory> <environment>...";
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a stream may stay silent, keepalives included, before it is
+/// opened again.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(45);
+
+/// How long to wait before opening a stream that failed again.
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The snapshot in the cache directory, which is loaded like a `FLAGS_DIR`.
+const SNAPSHOT_FILE: &str = "snapshot.json";
+
+/// Hidden from the store, holds each environment's definitions as last
+/// streamed, which together make up the snapshot.
+const ENVIRONMENTS_DIR: &str = ".environments";
+
+/// Keeps the snapshot and the freezes of the store serving it up to date with
+/// upstream, and tells the OpenFeature providers how syncing goes.
+#[derive(Debug)]
+struct Upstream {
+    client: reqwest::Client,
+    url: reqwest::Url,
+    dir: PathBuf,
+    environments: Vec<String>,
+    flags: Arc<FlagStore>,
+    providers: Arc<Providers>,
+}
+
+impl Upstream {
+    fn new(
+        url: &str,
+        dir: &Path,
+        environments: Vec<String>,
+        flags: Arc<FlagStore>,
+    ) -> color_eyre::Result<Self> {
+        let url = reqwest::Url::parse(url)?;
+        if url.cannot_be_a_base() {
+            return Err(eyre!("{} is not a featurize url", url));
+        }
+        Ok(Self {
+            // Streams have no end, only connecting has a timeout.
+            client: reqwest::Client::builder()
+                .connect_timeout(CONNECT_TIMEOUT)
+                .build()?,
+            url,
+            dir: dir.to_owned(),
+            providers: Arc::new(Providers::new(&flags.definitions(), &environments)),
+            environments,
+            flags,
+        })
+    }
+
+    /// Follows the stream of `environment` until it fails, telling `ready`
+    /// once its flags are in the snapshot.
+    async fn follow(
+        &self,
+        environment: &str,
+        ready: &mut Option<oneshot::Sender<()>>,
+    ) -> color_eyre::Result<()> {
+        let mut url = self.url.clone();
+        url.path_segments_mut()
+            .map_err(|_| eyre!("{} is not a featurize url", self.url))?
+            .pop_if_empty()
+            .extend(["stream", environment]);
+        let mut response = self
+            .client
+            .get(url)
+            .header(ACCEPT, "text/event-stream")
+            .send()
+            .await?
+            .error_for_status()?;
+        let mut events = Events::default();
+        loop {
+            let chunk = timeout(IDLE_TIMEOUT, response.chunk())
+                .await
+                .map_err(|_| eyre!("nothing streamed for {:?}", IDLE_TIMEOUT))??
+                .ok_or_else(|| eyre!("the stream ended"))?;
+            for (name, data) in events.push(&chunk) {
+                self.receive(environment, &name, &data)?;
+                if name == "flags" {
+                    if let Some(ready) = ready.take() {
+                        let _ = ready.send(());
+                    }
+                }
+            }
+        }
+    }
+
+    async fn follow_forever(self: Arc<Self>, environment: String, ready: oneshot::Sender<()>) {
+        let mut ready = Some(ready);
+        loop {
+            if let Err(e) = self.follow(&environment, &mut ready).await {
+                self.providers.failed(&environment, &e.to_string());
+                tracing::warn!(
+                    "keeping the last snapshot of {}, {}: {}",
+                    environment,
+                    self.url,
+                    e
+                );
+            }
+            sleep(RETRY_INTERVAL).await;
+        }
+    }
+
+    fn receive(&self, environment: &str, name: &str, data: &str) -> color_eyre::Result<()> {
+        match name {
+            "flags" => {
+                let definitions = serde_json::from_str(data)?;
+                self.save(environment, &definitions)?;
+                self.providers.synced(environment, &definitions);
+            }
+            "freeze" => match serde_json::from_str::<Option<Freeze>>(data)? {
+                Some(freeze) => self.flags.freeze(environment, freeze)?,
+                None => drop(self.flags.unfreeze(environment)?),
+            },
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Caches the definitions of `environment` and puts them in the snapshot
+    /// with the other environments', when they changed.
+    fn save(&self, environment: &str, definitions: &Definitions) -> color_eyre::Result<()> {
+        let environments = self.dir.join(ENVIRONMENTS_DIR);
+        let cached = environments.join(format!("{}.json", environment));
+        let content = serde_json::to_string_pretty(definitions)?;
+        if fs::read_to_string(&cached).is_ok_and(|current| current == content) {
+            return Ok(());
+        }
+        fs::create_dir_all(&environments)?;
+        fs::write(&cached, content)?;
+
+        let mut parts = Vec::new();
+        for environment in &self.environments {
+            let file = environments.join(format!("{}.json", environment));
+            if let Ok(content) = fs::read_to_string(&file) {
+                parts.push(serde_json::from_str(&content)?);
+            }
+        }
+        let content = serde_json::to_string_pretty(&merge(parts))?;
+        // Hidden, so the store never loads it halfway through.
+        let temporary = self.dir.join(format!(".{}.tmp", SNAPSHOT_FILE));
+        fs::write(&temporary, content)?;
+        fs::rename(&temporary, self.dir.join(SNAPSHOT_FILE))?;
+        self.flags.reload()?;
+        tracing::info!("updated {} in the snapshot from {}", environment, self.url);
+        Ok(())
+    }
+}
+
+/// Puts definitions streamed for different environments back together.
+fn merge(parts: Vec<Definitions>) -> Definitions {
+    let mut merged = Definitions::default();
+    for part in parts {
+        merged.environments.extend(part.environments);
+        for segment in part.segments {
+            match merged.segments.iter_mut().find(|s| s.key == segment.key) {
+                Some(merged) => merged.environments.extend(segment.environments),
+                None => merged.segments.push(segment),
+            }
+        }
+        for flag in part.flags {
+            match merged.flags.iter_mut().find(|f| f.key == flag.key) {
+                Some(merged) => merged.environments.extend(flag.environments),
+                None => merged.flags.push(flag),
+            }
+        }
+        merged.experiments.extend(part.experiments);
+        for layer in part.layers {
+            if !merged.layers.iter().any(|l| l.key == layer.key) {
+                merged.layers.push(layer);
+            }
+        }
+        for attribute in part.attributes {
+            if !merged.attributes.iter().any(|a| a.key == attribute.key) {
+                merged.attributes.push(attribute);
+            }
+        }
+        merged.strict_attributes |= part.strict_attributes;
+    }
+    merged
+}
+
+/// Parses server-sent events out of the chunks of a stream.
+#[derive(Debug, Default)]
+struct Events {
+    /// What arrived of the next events.
+    pending: Vec<u8>,
+}
+
+impl Events {
+    /// The name and data of the events `chunk` completes.
+    fn push(&mut self, chunk: &[u8]) -> Vec<(String, String)> {
+        self.pending.extend_from_slice(chunk);
+        let mut events = Vec::new();
+        while let Some(end) = self.pending.windows(2).position(|w| w == b"\n\n") {
+            let block: Vec<u8> = self.pending.drain(..end + 2).collect();
+            let block = String::from_utf8_lossy(&block);
+            let mut name = "message";
+            let mut data = Vec::new();
+            for line in block.lines() {
+                if let Some(value) = line.strip_prefix("event:") {
+                    name = value.trim_start();
+                } else if let Some(value) = line.strip_prefix("data:") {
+                    data.push(value.strip_prefix(' ').unwrap_or(value));
+                }
+            }
+            // Comments, like keepalives, carry no data.
+            if !data.is_empty() {
+                events.push((name.to_string(), data.join("\n")));
+            }
+        }
+        events
+    }
+}
+
+/// Serves the evaluation, stream, export, flagd and OFREP endpoints of the featurize
+/// at `url` on `PORT`, for the given environments, from a snapshot kept in
+/// the cache directory.
+pub fn relay(args: &[String]) -> color_eyre::Result<()> {
+    let [url, dir, environments @ ..] = args else {
+        return Err(eyre!(USAGE));
+    };
+    if environments.is_empty() {
+        return Err(eyre!(USAGE));
+    }
+    let port: u16 = match env::var("PORT") {
+        Ok(port) => port.parse()?,
+        Err(_) => 80,
+    };
+    let dir = Path::new(dir);
+    fs::create_dir_all(dir)?;
+
+    tracing_subscriber::registry()
+        .with(tracing_subscriber::fmt::layer())
+        .init();
+
+    actix_web::rt::System::new().block_on(async {
+        let flags = Arc::new(FlagStore::load(dir)?.mirroring());
+        let upstream = Arc::new(Upstream::new(
+            url,
+            dir,
+            environments.to_vec(),
+            flags.clone(),
+        )?);
+        let mut ready = Vec::new();
+        for environment in environments {
+            let (sender, receiver) = oneshot::channel();
+            actix_web::rt::spawn(upstream.clone().follow_forever(environment.clone(), sender));
+            ready.push(receiver);
+        }
+        if timeout(CONNECT_TIMEOUT, join_all(ready)).await.is_err() {
+            if !dir.join(SNAPSHOT_FILE).exists() {
+                return Err(eyre!("no snapshot cached yet, {} can't be reached", url));
+            }
+            tracing::warn!("serving the cached snapshot until {} can be reached", url);
+        }
+        let attributes = Arc::new(AttributeRegistry::new());
+        let providers = upstream.providers.clone();
+
+        println!("Relaying {} on: 0.0.0.0:{}", url, port);
+        HttpServer::new(move || {
+            App::new()
+                .wrap(TracingLogger::default())
+                .app_data(web::Data::from(flags.clone()))
+                .app_data(web::Data::from(attributes.clone()))
+                .app_data(web::Data::from(providers.clone()))
+                .service(evaluate::route)
+                .service(stream::route)
+                .service(export::route)
+                .service(flagd::route)
+                .service(openfeature::evaluate_flag_route)
+                .service(openfeature::evaluate_flags_route)
+                .service(openfeature::events_route)
+        })
+        .bind(("0.0.0.0", port))?
+        .run()
+        .await?;
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::{call_and_read_body_json, init_service, TestRequest};
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    /// An empty directory of its own for each test.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("featurize-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn definitions(yaml: &str) -> Definitions {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn parses_events_split_across_chunks() {
+        let mut events = Events::default();
+        assert!(events.push(b": keepalive\n\nevent: fla").is_empty());
+        assert_eq!(
+            events.push(b"gs\ndata: {}\n\nevent: freeze\ndata: null\n\n"),
+            [
+                ("flags".to_string(), "{}".to_string()),
+                ("freeze".to_string(), "null".to_string()),
+            ]
+        );
+        assert!(events.pending.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn serves_the_cached_snapshot_while_upstream_is_down() {
+        let dir = temp_dir("relay");
+        let environments = vec!["staging".to_string(), "production".to_string()];
+        let flags = Arc::new(FlagStore::load(&dir).unwrap().mirroring());
+        // Nothing listens on port 1.
+        let upstream =
+            Upstream::new("http://127.0.0.1:1", &dir, environments, flags.clone()).unwrap();
+        let streamed = |environment: &str, variation: usize| {
+            definitions(&format!(
+                r#"
+environments:
+  - key: {0}
+flags:
+  - key: checkout
+    variations: [{{value: false}}, {{value: true}}]
+    environments:
+      {0}: {{on: true, off_variation: 0, fallthrough: {{variation: {1}}}}}
+"#,
+                environment, variation
+            ))
+        };
+        upstream
+            .save("production", &streamed("production", 1))
+            .unwrap();
+        upstream.save("staging", &streamed("staging", 0)).unwrap();
+        upstream
+            .receive(
+                "production",
+                "freeze",
+                r#"{"reason": "release", "at": "2024-03-01T00:00:00Z"}"#,
+            )
+            .unwrap();
+        upstream
+            .save("production", &streamed("production", 0))
+            .unwrap();
+        upstream
+            .save("production", &streamed("production", 1))
+            .unwrap();
+        assert!(upstream.follow("production", &mut None).await.is_err());
+
+        // As after a restart, from the cache directory alone.
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(FlagStore::load(&dir).unwrap()))
+                .app_data(web::Data::new(AttributeRegistry::new()))
+                .service(evaluate::route),
+        )
+        .await;
+        for (environment, served) in [("production", true), ("staging", false)] {
+            let request = TestRequest::post()
+                .uri(&format!("/evaluate/{}", environment))
+                .set_json(json!({"key": "alice"}))
+                .to_request();
+            let evaluated: Value = call_and_read_body_json(&app, request).await;
+            assert_eq!(evaluated["checkout"]["value"], served, "{}", environment);
+        }
+        assert!(flags.freezes().contains_key("production"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
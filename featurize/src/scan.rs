@@ -0,0 +1,319 @@
+// Featurize, the FOSS feature flagging
+// Copyright (C) 2024  Lucy Ekaterina
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::references::Reference;
+
+/// Matches a key in quotes, which finds most uses in most languages.
+const DEFAULT_PATTERN: &str = r#"["'`]{key}["'`]"#;
+
+/// Larger files are most likely generated or data, not code.
+const MAX_FILE_BYTES: u64 = 1024 * 1024;
+
+/// Longer lines are cut short in references.
+const MAX_TEXT_CHARS: usize = 200;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScanConfig {
+    /// The name references are uploaded under, defaults to the name of the
+    /// scanned directory.
+    pub repository: Option<String>,
+    /// Paths relative to the scanned directory to skip. Hidden files and
+    /// directories are always skipped.
+    pub exclude: Vec<PathBuf>,
+    /// How flags are used in files with the given extensions, files with
+    /// other extensions use `DEFAULT_PATTERN`.
+    pub patterns: Vec<Pattern>,
+    /// How generated code spells flag keys, e.g. `new-checkout` is
+    /// `NEW_CHECKOUT` in `screaming_snake_case`.
+    pub alias_cases: Vec<Case>,
+    /// More names for each flag key.
+    pub aliases: BTreeMap<String, Vec<String>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Pattern {
+    pub extensions: Vec<String>,
+    /// A regular expression where `{key}` stands for any flag key.
+    pub pattern: String,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub enum Case {
+    #[serde(rename = "camel_case")]
+    Camel,
+    #[serde(rename = "pascal_case")]
+    Pascal,
+    #[serde(rename = "snake_case")]
+    Snake,
+    #[serde(rename = "screaming_snake_case")]
+    ScreamingSnake,
+}
+
+impl Case {
+    fn apply(self, key: &str) -> String {
+        let words = key
+            .split(|c: char| !c.is_ascii_alphanumeric())
+            .filter(|w| !w.is_empty())
+            .map(str::to_lowercase);
+        let capitalize = |word: String| {
+            let mut chars = word.chars();
+            chars
+                .next()
+                .map(|first| first.to_ascii_uppercase().to_string() + chars.as_str())
+                .unwrap_or_default()
+        };
+        match self {
+            Case::Camel => words
+                .enumerate()
+                .map(|(i, w)| if i == 0 { w } else { capitalize(w) })
+                .collect(),
+            Case::Pascal => words.map(capitalize).collect(),
+            Case::Snake => words.collect::<Vec<_>>().join("_"),
+            Case::ScreamingSnake => words.collect::<Vec<_>>().join("_").to_uppercase(),
+        }
+    }
+}
+
+/// Finds flag keys and their aliases in source files.
+#[derive(Debug)]
+pub struct Scanner {
+    patterns: Vec<(Vec<String>, Regex)>,
+    default: Regex,
+    /// Matches any alias as a whole word.
+    aliases: Option<Regex>,
+    /// The flag key of each alias.
+    alias_flags: HashMap<String, String>,
+    exclude: Vec<PathBuf>,
+}
+
+impl Scanner {
+    pub fn new(config: &ScanConfig, keys: &[String]) -> Result<Self, String> {
+        let alternatives = |names: Vec<&str>| {
+            let mut names: Vec<String> = names.into_iter().map(regex::escape).collect();
+            // Try longer names first so a key that prefixes another can't win.
+            names.sort_by(|a, b| b.len().cmp(&a.len()).then(a.cmp(b)));
+            names.dedup();
+            names.join("|")
+        };
+        // An empty alternation would match everywhere.
+        let keys_pattern = if keys.is_empty() {
+            "[^\\s\\S]".to_string()
+        } else {
+            alternatives(keys.iter().map(String::as_str).collect())
+        };
+        let compile = |pattern: &str| {
+            if !pattern.contains("{key}") {
+                return Err(format!("pattern \"{}\" does not contain {{key}}", pattern));
+            }
+            let pattern = pattern.replace("{key}", &format!("(?P<key>{})", keys_pattern));
+            Regex::new(&pattern).map_err(|e| format!("invalid pattern: {}", e))
+        };
+
+        let patterns = config
+            .patterns
+            .iter()
+            .map(|p| Ok((p.extensions.clone(), compile(&p.pattern)?)))
+            .collect::<Result<_, String>>()?;
+
+        let mut alias_flags = HashMap::new();
+        for key in keys {
+            let generated = config.alias_cases.iter().map(|case| case.apply(key));
+            let declared = config.aliases.get(key).into_iter().flatten().cloned();
+            for alias in generated.chain(declared) {
+                // The key itself is found by the patterns.
+                if !alias.is_empty() && alias != *key {
+                    alias_flags.entry(alias).or_insert_with(|| key.clone());
+                }
+            }
+        }
+        let aliases = if alias_flags.is_empty() {
+            None
+        } else {
+            let names = alternatives(alias_flags.keys().map(String::as_str).collect());
+            let pattern = format!(r"\b(?P<alias>{})\b", names);
+            Some(Regex::new(&pattern).map_err(|e| format!("invalid alias: {}", e))?)
+        };
+
+        Ok(Self {
+            patterns,
+            default: compile(DEFAULT_PATTERN)?,
+            aliases,
+            alias_flags,
+            exclude: config.exclude.clone(),
+        })
+    }
+
+    /// Scans every file below `dir`, with paths relative to it.
+    pub fn scan_dir(&self, dir: &Path) -> io::Result<Vec<Reference>> {
+        let mut references = Vec::new();
+        self.scan_into(dir, dir, &mut references)?;
+        Ok(references)
+    }
+
+    fn scan_into(
+        &self,
+        root: &Path,
+        dir: &Path,
+        references: &mut Vec<Reference>,
+    ) -> io::Result<()> {
+        let mut entries = fs::read_dir(dir)?
+            .map(|entry| entry.map(|e| e.path()))
+            .collect::<io::Result<Vec<_>>>()?;
+        entries.sort();
+        for path in entries {
+            let relative = path.strip_prefix(root).unwrap_or(&path);
+            let hidden = path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .map_or(false, |n| n.starts_with('.'));
+            if hidden || self.exclude.iter().any(|e| relative.starts_with(e)) {
+                continue;
+            }
+            if path.is_dir() {
+                self.scan_into(root, &path, references)?;
+                continue;
+            }
+            if fs::metadata(&path)?.len() > MAX_FILE_BYTES {
+                continue;
+            }
+            let text = match fs::read_to_string(&path) {
+                Ok(text) => text,
+                // Not UTF-8, most likely a binary file.
+                Err(e) if e.kind() == io::ErrorKind::InvalidData => continue,
+                Err(e) => return Err(e),
+            };
+            let relative: Vec<_> = relative
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy())
+                .collect();
+            references.extend(self.scan_text(&relative.join("/"), &text));
+        }
+        Ok(())
+    }
+
+    /// Finds the references in one file's `text`.
+    pub fn scan_text(&self, path: &str, text: &str) -> Vec<Reference> {
+        let extension = Path::new(path)
+            .extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or_default();
+        let pattern = self
+            .patterns
+            .iter()
+            .find(|(extensions, _)| extensions.iter().any(|e| e == extension))
+            .map_or(&self.default, |(_, pattern)| pattern);
+
+        let mut references = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let reference = |flag: &str, alias: Option<&str>| Reference {
+                flag: flag.to_string(),
+                path: path.to_string(),
+                line: i + 1,
+                text: line.trim().chars().take(MAX_TEXT_CHARS).collect(),
+                alias: alias.map(str::to_string),
+            };
+            for captures in pattern.captures_iter(line) {
+                if let Some(key) = captures.name("key") {
+                    references.push(reference(key.as_str(), None));
+                }
+            }
+            for alias in self.aliases.iter().flat_map(|a| a.find_iter(line)) {
+                if let Some(flag) = self.alias_flags.get(alias.as_str()) {
+                    references.push(reference(flag, Some(alias.as_str())));
+                }
+            }
+        }
+        references
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_keys_with_language_patterns_and_aliases() {
+        let config: ScanConfig = serde_yaml::from_str(
+            r#"
+patterns:
+  - extensions: [rs]
+    pattern: 'flag!\(\s*"{key}"'
+alias_cases: [screaming_snake_case]
+aliases:
+  new-checkout: [CheckoutV2]
+"#,
+        )
+        .unwrap();
+        let keys = vec!["new-checkout".to_string(), "new".to_string()];
+        let scanner = Scanner::new(&config, &keys).unwrap();
+
+        let found = |path, text| -> Vec<(String, usize, Option<String>)> {
+            scanner
+                .scan_text(path, text)
+                .into_iter()
+                .map(|r| (r.flag, r.line, r.alias))
+                .collect()
+        };
+        assert_eq!(
+            found(
+                "src/main.rs",
+                "let a = \"new\";\nif flag!(\"new-checkout\") {}"
+            ),
+            vec![("new-checkout".to_string(), 2, None)]
+        );
+        assert_eq!(
+            found(
+                "app.ts",
+                "variation('new', false)\nFlags.NEW_CHECKOUT || CheckoutV2"
+            ),
+            vec![
+                ("new".to_string(), 1, None),
+                (
+                    "new-checkout".to_string(),
+                    2,
+                    Some("NEW_CHECKOUT".to_string())
+                ),
+                (
+                    "new-checkout".to_string(),
+                    2,
+                    Some("CheckoutV2".to_string())
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn patterns_need_a_key() {
+        let config = ScanConfig {
+            patterns: vec![Pattern {
+                extensions: vec!["py".to_string()],
+                pattern: "is_enabled".to_string(),
+            }],
+            ..Default::default()
+        };
+        assert!(Scanner::new(&config, &[]).is_err());
+    }
+}
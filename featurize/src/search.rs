@@ -0,0 +1,261 @@
+// Featurize, the FOSS feature flagging
+// Copyright (C) 2024  Lucy Ekaterina
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use actix_web::{get, web, HttpResponse};
+use sentry::{Hub, SentryFutureExt};
+use serde::{de::IntoDeserializer, Deserialize, Deserializer, Serialize};
+
+use crate::{
+    flags::{Definitions, Flag, FlagStore, Lifecycle},
+    Error,
+};
+
+const DEFAULT_LIMIT: usize = 50;
+const MAX_LIMIT: usize = 200;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum State {
+    On,
+    Off,
+}
+
+/// Filters for the flag list. Empty parameters, as sent by the dashboard's
+/// form, don't filter anything.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct FlagQuery {
+    /// Words that each have to appear in the key, name, description or tags.
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub q: String,
+    #[serde(
+        deserialize_with = "empty_as_none",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub tag: Option<String>,
+    /// Matches the owning team or the maintainer.
+    #[serde(
+        deserialize_with = "empty_as_none",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub owner: Option<String>,
+    #[serde(
+        deserialize_with = "empty_as_none",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub lifecycle: Option<Lifecycle>,
+    /// Only flags configured in this environment, and `state` only looks at
+    /// it.
+    #[serde(
+        deserialize_with = "empty_as_none",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub environment: Option<String>,
+    /// Without an environment, `on` means on anywhere and `off` off
+    /// everywhere.
+    #[serde(
+        deserialize_with = "empty_as_none",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub state: Option<State>,
+    /// The `next_cursor` of the previous page.
+    #[serde(
+        deserialize_with = "empty_as_none",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub cursor: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<usize>,
+}
+
+fn empty_as_none<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    match Option::<String>::deserialize(deserializer)? {
+        Some(value) if !value.is_empty() => T::deserialize(value.into_deserializer()).map(Some),
+        _ => Ok(None),
+    }
+}
+
+impl FlagQuery {
+    pub fn matches(&self, flag: &Flag) -> bool {
+        let terms: Vec<String> = self.q.split_whitespace().map(str::to_lowercase).collect();
+        if !terms.iter().all(|term| mentions(flag, term)) {
+            return false;
+        }
+        if let Some(tag) = &self.tag {
+            if !flag.tags.iter().any(|t| t.eq_ignore_ascii_case(tag)) {
+                return false;
+            }
+        }
+        if let Some(owner) = &self.owner {
+            let owned = flag.owner.as_ref().is_some_and(|o| {
+                [&o.team, &o.maintainer]
+                    .into_iter()
+                    .flatten()
+                    .any(|name| name.eq_ignore_ascii_case(owner))
+            });
+            if !owned {
+                return false;
+            }
+        }
+        if self.lifecycle.is_some_and(|l| l != flag.lifecycle) {
+            return false;
+        }
+        let mut configs: Vec<bool> = match &self.environment {
+            Some(environment) => match flag.environments.get(environment) {
+                Some(config) => vec![config.on],
+                None => return false,
+            },
+            None => flag.environments.values().map(|c| c.on).collect(),
+        };
+        match self.state {
+            Some(State::On) => configs.iter().any(|on| *on),
+            Some(State::Off) => {
+                configs.retain(|on| *on);
+                configs.is_empty()
+            }
+            None => true,
+        }
+    }
+}
+
+fn mentions(flag: &Flag, term: &str) -> bool {
+    [
+        Some(&flag.key),
+        flag.name.as_ref(),
+        flag.description.as_ref(),
+    ]
+    .into_iter()
+    .flatten()
+    .chain(&flag.tags)
+    .any(|text| text.to_lowercase().contains(term))
+}
+
+#[derive(Debug, Serialize)]
+pub struct SearchResults<'d> {
+    pub flags: Vec<&'d Flag>,
+    /// Matching flags on all pages.
+    pub total: usize,
+    /// Pass as `cursor` for the next page, there is none without it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_cursor: Option<String>,
+}
+
+/// The page of flags matching `query`, ordered by key.
+pub fn search<'d>(definitions: &'d Definitions, query: &FlagQuery) -> SearchResults<'d> {
+    let mut matching: Vec<&Flag> = definitions
+        .flags
+        .iter()
+        .filter(|flag| query.matches(flag))
+        .collect();
+    matching.sort_by(|a, b| a.key.cmp(&b.key));
+    let total = matching.len();
+    let start = match &query.cursor {
+        Some(cursor) => matching.partition_point(|flag| flag.key <= *cursor),
+        None => 0,
+    };
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    let flags: Vec<&Flag> = matching.into_iter().skip(start).take(limit).collect();
+    let next_cursor = (start + flags.len() < total)
+        .then(|| flags.last().map(|flag| flag.key.clone()))
+        .flatten();
+    SearchResults {
+        flags,
+        total,
+        next_cursor,
+    }
+}
+
+/// The flags matching the query as JSON.
+#[tracing::instrument]
+#[get("/flags/search")]
+pub async fn api_route(
+    flags: web::Data<FlagStore>,
+    query: web::Query<FlagQuery>,
+) -> Result<HttpResponse, Error> {
+    let hub = Hub::current();
+    api_handler(flags, query.into_inner()).bind_hub(hub).await
+}
+
+#[tracing::instrument]
+pub async fn api_handler(
+    flags: web::Data<FlagStore>,
+    query: FlagQuery,
+) -> Result<HttpResponse, Error> {
+    let definitions = flags.definitions();
+    Ok(HttpResponse::Ok().json(search(&definitions, &query)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEFINITIONS: &str = r#"
+environments:
+  - key: production
+flags:
+  - key: new-checkout
+    description: One page checkout
+    tags: [payments]
+    owner:
+      team: web
+    variations: [{value: false}, {value: true}]
+    environments:
+      production:
+        on: true
+        off_variation: 0
+        fallthrough: {variation: 1}
+  - key: old-checkout
+    lifecycle: deprecated
+    tags: [payments]
+    variations: [{value: false}]
+  - key: banner
+    name: Holiday banner
+    variations: [{value: false}]
+"#;
+
+    fn keys(results: &SearchResults) -> Vec<String> {
+        results.flags.iter().map(|f| f.key.clone()).collect()
+    }
+
+    #[test]
+    fn filters_and_pages() {
+        let definitions: Definitions = serde_yaml::from_str(DEFINITIONS).unwrap();
+        let query: FlagQuery =
+            serde_urlencoded::from_str("q=CHECKOUT+page&tag=&lifecycle=").unwrap();
+        assert_eq!(keys(&search(&definitions, &query)), vec!["new-checkout"]);
+
+        let query: FlagQuery = serde_urlencoded::from_str("state=off").unwrap();
+        assert_eq!(
+            keys(&search(&definitions, &query)),
+            vec!["banner", "old-checkout"]
+        );
+
+        let query: FlagQuery = serde_urlencoded::from_str("tag=payments&limit=1").unwrap();
+        let first = search(&definitions, &query);
+        assert_eq!(first.total, 2);
+        assert_eq!(first.next_cursor.as_deref(), Some("new-checkout"));
+        let query = FlagQuery {
+            cursor: first.next_cursor.clone(),
+            ..query
+        };
+        let second = search(&definitions, &query);
+        assert_eq!(keys(&second), vec!["old-checkout"]);
+        assert_eq!(second.next_cursor, None);
+    }
+}
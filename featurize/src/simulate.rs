@@ -0,0 +1,187 @@
+// Featurize, the FOSS feature flagging
+// Copyright (C) 2024  Lucy Ekaterina
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use actix_web::{web, HttpResponse};
+use sentry::{Hub, SentryFutureExt};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    attributes::AttributeRegistry,
+    flags::{self, parse_contexts, ContextFormat, Flag, FlagStore, Simulation},
+    renderer::Renderer,
+    Error,
+};
+
+/// Context files can be large, request bodies up to this size are accepted by
+/// the simulation routes.
+const MAX_UPLOAD_BYTES: usize = 16 * 1024 * 1024;
+
+/// Changed contexts listed on the dashboard, the API returns all of them.
+const CHANGED_SHOWN: usize = 1000;
+
+#[derive(Debug, Deserialize)]
+pub struct SimulationRequest {
+    draft: Flag,
+    contexts: String,
+    #[serde(default)]
+    format: ContextFormat,
+}
+
+#[derive(Debug, Serialize)]
+struct SimulationResponse {
+    #[serde(flatten)]
+    simulation: Simulation,
+    /// Clauses of the draft that refer to unknown attributes or compare them
+    /// with the wrong type.
+    warnings: Vec<String>,
+}
+
+/// The simulation API and pages. They are registered as resources rather
+/// than with route attributes so only they accept `MAX_UPLOAD_BYTES`.
+pub fn services(config: &mut web::ServiceConfig) {
+    config
+        .service(
+            web::resource("/simulate/{environment}")
+                .app_data(web::JsonConfig::default().limit(MAX_UPLOAD_BYTES))
+                .route(web::post().to(api_route)),
+        )
+        .service(
+            web::resource("/flags/{key}/simulate")
+                .app_data(web::FormConfig::default().limit(MAX_UPLOAD_BYTES))
+                .route(web::get().to(page_route))
+                .route(web::post().to(submit_route)),
+        );
+}
+
+/// Compares a draft of a flag with the current one over a file of contexts.
+#[tracing::instrument(skip(request))]
+pub async fn api_route(
+    flags: web::Data<FlagStore>,
+    attributes: web::Data<AttributeRegistry>,
+    environment: web::Path<String>,
+    request: web::Json<SimulationRequest>,
+) -> Result<HttpResponse, Error> {
+    let hub = Hub::current();
+    api_handler(flags, attributes, environment, request)
+        .bind_hub(hub)
+        .await
+}
+
+#[tracing::instrument(skip(request))]
+pub async fn api_handler(
+    flags: web::Data<FlagStore>,
+    attributes: web::Data<AttributeRegistry>,
+    environment: web::Path<String>,
+    request: web::Json<SimulationRequest>,
+) -> Result<HttpResponse, Error> {
+    let definitions = flags.definitions();
+    if definitions.environment(&environment).is_none() {
+        return Err(Error::NotFound);
+    }
+    let request = request.into_inner();
+    let contexts = parse_contexts(&request.contexts, request.format)?;
+    let warnings = attributes.warnings(&definitions, &request.draft, &environment);
+    let simulation = flags::simulate(&definitions, &environment, request.draft, &contexts)?;
+    Ok(HttpResponse::Ok().json(SimulationResponse {
+        simulation,
+        warnings,
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SimulationForm {
+    environment: String,
+    draft: String,
+    contexts: String,
+    format: ContextFormat,
+}
+
+#[tracing::instrument]
+pub async fn page_route(
+    renderer: web::Data<Renderer>,
+    flags: web::Data<FlagStore>,
+    attributes: web::Data<AttributeRegistry>,
+    key: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let hub = Hub::current();
+    page_handler(renderer, flags, attributes, key, None)
+        .bind_hub(hub)
+        .await
+}
+
+#[tracing::instrument(skip(form))]
+pub async fn submit_route(
+    renderer: web::Data<Renderer>,
+    flags: web::Data<FlagStore>,
+    attributes: web::Data<AttributeRegistry>,
+    key: web::Path<String>,
+    form: web::Form<SimulationForm>,
+) -> Result<HttpResponse, Error> {
+    let hub = Hub::current();
+    page_handler(renderer, flags, attributes, key, Some(form.into_inner()))
+        .bind_hub(hub)
+        .await
+}
+
+#[tracing::instrument(skip(form))]
+pub async fn page_handler(
+    renderer: web::Data<Renderer>,
+    flags: web::Data<FlagStore>,
+    attributes: web::Data<AttributeRegistry>,
+    key: web::Path<String>,
+    form: Option<SimulationForm>,
+) -> Result<HttpResponse, Error> {
+    let definitions = flags.definitions();
+    let flag = definitions.flag(&key).ok_or(Error::NotFound)?;
+    let render = renderer
+        .render("simulate.html")
+        .var("flag", flag)
+        .var("environments", &definitions.environments);
+    let Some(form) = form else {
+        return Ok(render
+            .var("draft", &serde_yaml::to_string(flag)?)
+            .ok()
+            .finish()?);
+    };
+
+    let draft = serde_yaml::from_str::<Flag>(&form.draft)
+        .map_err(|e| format!("the draft is not a flag: {}", e));
+    let warnings = match &draft {
+        Ok(draft) => attributes.warnings(&definitions, draft, &form.environment),
+        Err(_) => Vec::new(),
+    };
+    let render = render
+        .var("environment", &form.environment)
+        .var("draft", &form.draft)
+        .var("format", &form.format)
+        .var("warnings", &warnings);
+    let result = draft.and_then(|draft| {
+        let contexts = parse_contexts(&form.contexts, form.format).map_err(|e| e.to_string())?;
+        flags::simulate(&definitions, &form.environment, draft, &contexts)
+            .map_err(|e| e.to_string())
+    });
+    Ok(match result {
+        Ok(mut simulation) => {
+            let changed = simulation.changed.len();
+            simulation.changed.truncate(CHANGED_SHOWN);
+            render
+                .var("simulation", &simulation)
+                .var("changed", &changed)
+                .ok()
+                .finish()?
+        }
+        Err(error) => render.var("error", &error).ok().finish()?,
+    })
+}
@@ -0,0 +1,139 @@
+// Featurize, the FOSS feature flagging
+// Copyright (C) 2024  Lucy Ekaterina
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//! `/stream/<environment>` pushes an environment's flags and freeze to SDKs
+//! and relays as server-sent events, so kills, rollbacks and freezes reach
+//! them as soon as they are made instead of on their next poll.
+use std::{convert::Infallible, time::Duration};
+
+use actix_web::{
+    get,
+    rt::time::{interval_at, Instant},
+    web::{self, Bytes},
+    HttpResponse,
+};
+use futures::{
+    stream::{self, LocalBoxStream},
+    Stream, StreamExt,
+};
+use sentry::{Hub, SentryFutureExt};
+
+use crate::{flags::FlagStore, Error};
+
+/// How often idle streams get a comment, so proxies don't close them.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+enum Wakeup {
+    Changed,
+    Keepalive,
+}
+
+/// A client's stream, with what it was sent last so only changes are sent
+/// again.
+struct Subscription {
+    flags: web::Data<FlagStore>,
+    environment: String,
+    wakeups: LocalBoxStream<'static, Wakeup>,
+    sent_flags: String,
+    sent_freeze: String,
+}
+
+impl Subscription {
+    /// The events for what changed since the last ones, `None` once the
+    /// environment is gone.
+    fn changes(&mut self) -> Option<String> {
+        let definitions = self.flags.definitions();
+        definitions.environment(&self.environment)?;
+        // Definitions only hold JSON values, serializing them can't fail.
+        let flags = serde_json::to_string(&definitions.for_environment(&self.environment))
+            .unwrap_or_default();
+        let freeze =
+            serde_json::to_string(&self.flags.freezes().get(&self.environment)).unwrap_or_default();
+        let mut events = String::new();
+        if flags != self.sent_flags {
+            events += &event("flags", &flags);
+            self.sent_flags = flags;
+        }
+        if freeze != self.sent_freeze {
+            events += &event("freeze", &freeze);
+            self.sent_freeze = freeze;
+        }
+        Some(events)
+    }
+}
+
+pub fn event(name: &str, data: &str) -> String {
+    format!("event: {}\ndata: {}\n\n", name, data)
+}
+
+/// Ticks every [`KEEPALIVE_INTERVAL`], when a keepalive comment is due.
+pub fn keepalives() -> impl Stream<Item = ()> {
+    stream::unfold(
+        interval_at(Instant::now() + KEEPALIVE_INTERVAL, KEEPALIVE_INTERVAL),
+        |mut interval| async move {
+            interval.tick().await;
+            Some(((), interval))
+        },
+    )
+}
+
+/// Sends a `flags` event with the definitions evaluating in the environment
+/// needs, overrides applied, and a `freeze` event with its freeze or `null`,
+/// right away and again whenever they change.
+#[tracing::instrument]
+#[get("/stream/{environment}")]
+pub async fn route(
+    flags: web::Data<FlagStore>,
+    environment: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let hub = Hub::current();
+    handler(flags, environment).bind_hub(hub).await
+}
+
+#[tracing::instrument]
+pub async fn handler(
+    flags: web::Data<FlagStore>,
+    environment: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    if flags.definitions().environment(&environment).is_none() {
+        return Err(Error::NotFound);
+    }
+    let changes = flags.subscribe().map(|()| Wakeup::Changed);
+    let keepalive = keepalives().map(|()| Wakeup::Keepalive);
+    let subscription = Subscription {
+        flags,
+        environment: environment.into_inner(),
+        wakeups: stream::once(async { Wakeup::Changed })
+            .chain(stream::select(changes, keepalive))
+            .boxed_local(),
+        sent_flags: String::new(),
+        sent_freeze: String::new(),
+    };
+    let events = stream::unfold(subscription, |mut subscription| async move {
+        loop {
+            let events = match subscription.wakeups.next().await? {
+                Wakeup::Changed => subscription.changes()?,
+                Wakeup::Keepalive => ": keepalive\n\n".to_string(),
+            };
+            if !events.is_empty() {
+                return Some((Ok::<_, Infallible>(Bytes::from(events)), subscription));
+            }
+        }
+    });
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(events))
+}
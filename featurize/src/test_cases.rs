@@ -0,0 +1,63 @@
+// Featurize, the FOSS feature flagging
+// Copyright (C) 2024  Lucy Ekaterina
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use actix_web::{get, web, HttpResponse};
+use sentry::{Hub, SentryFutureExt};
+
+use crate::{
+    flags::{run_tests, FlagStore, Lifecycle, TestResult},
+    Error,
+};
+
+/// Runs the test cases of every flag that is not archived against the current
+/// definitions.
+#[tracing::instrument]
+#[get("/tests")]
+pub async fn all_route(flags: web::Data<FlagStore>) -> Result<HttpResponse, Error> {
+    let hub = Hub::current();
+    all_handler(flags).bind_hub(hub).await
+}
+
+#[tracing::instrument]
+pub async fn all_handler(flags: web::Data<FlagStore>) -> Result<HttpResponse, Error> {
+    let definitions = flags.definitions();
+    let results: Vec<TestResult> = definitions
+        .flags
+        .iter()
+        .filter(|flag| flag.lifecycle != Lifecycle::Archived)
+        .flat_map(|flag| run_tests(&definitions, flag))
+        .collect();
+    Ok(HttpResponse::Ok().json(results))
+}
+
+#[tracing::instrument]
+#[get("/flags/{key}/tests")]
+pub async fn flag_route(
+    flags: web::Data<FlagStore>,
+    key: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let hub = Hub::current();
+    flag_handler(flags, key).bind_hub(hub).await
+}
+
+#[tracing::instrument]
+pub async fn flag_handler(
+    flags: web::Data<FlagStore>,
+    key: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let definitions = flags.definitions();
+    let flag = definitions.flag(&key).ok_or(Error::NotFound)?;
+    Ok(HttpResponse::Ok().json(run_tests(&definitions, flag)))
+}
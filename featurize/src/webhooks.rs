@@ -0,0 +1,42 @@
+// Featurize, the FOSS feature flagging
+// Copyright (C) 2024  Lucy Ekaterina
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use crate::audit::AuditEntry;
+
+/// Posts audit entries to the webhooks of the flag they are about.
+#[derive(Debug, Clone)]
+pub struct Webhooks {
+    client: reqwest::Client,
+}
+
+impl Webhooks {
+    pub fn new(client: reqwest::Client) -> Self {
+        Self { client }
+    }
+
+    /// Sends `entry` to every URL in the background, failures are logged.
+    pub fn notify(&self, urls: &[String], entry: &AuditEntry) {
+        for url in urls {
+            let request = self.client.post(url).json(entry);
+            let url = url.clone();
+            actix_web::rt::spawn(async move {
+                match request.send().await.and_then(|r| r.error_for_status()) {
+                    Ok(_) => tracing::debug!("notified webhook {}", url),
+                    Err(e) => tracing::warn!("webhook {} failed: {}", url, e),
+                }
+            });
+        }
+    }
+}
@@ -11,4 +11,10 @@ in
       ${cell.packages.featurize}/bin/featurize
     '';
   };
+
+  featurize-relay = inputs.flake-utils.lib.mkApp {
+    drv = nixpkgs.writeShellScriptBin "featurize-relay" ''
+      exec ${cell.packages.featurize}/bin/featurize relay "$@"
+    '';
+  };
 }
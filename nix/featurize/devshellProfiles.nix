@@ -8,6 +8,8 @@
         export KRATOS_DOMAIN="http://localhost:4433"
         export HYDRA_DOMAIN="http://localhost:4445"
         export PORT="${toString cfg.port}"
+        export SMTP_URL="smtp://localhost:2525"
+        export DASHBOARD_URL="http://localhost:${toString cfg.port}"
 
         sigint_handler()
         {
@@ -50,7 +52,7 @@
         __services.featurize-watch = {
           command = "${start-command}/bin/featurize";
           enable = cfg.enable;
-          depends = [ "kratos" "hydra" "auth-watch" "featurize-tailwind" ];
+          depends = [ "kratos" "hydra" "auth-watch" "featurize-tailwind" "mailslurper" ];
         };
 
         __services.featurize-tailwind = {